@@ -0,0 +1,87 @@
+use common::level::LevelMapping;
+use criterion::{criterion_group, criterion_main, Criterion};
+use ltbridge::bench::{decode_logs, decode_spans};
+use serde_json::{json, Value};
+
+// JSONCompact bodies shaped like what ck returns for the log and trace
+// tables, a few attributes per row and an escaped string here and there
+fn log_resp(rows: usize) -> String {
+	let data: Vec<Value> = (0..rows)
+		.map(|i| {
+			json!([
+				format!("1700000000.{:09}", i),
+				"4bf92f3577b34da6a3ce929d0e0e4736",
+				format!("{:016x}", i),
+				"INFO",
+				9,
+				"checkout",
+				format!("order {} placed by \"user-{}\"", i, i % 97),
+				{"host.name": "checkout-0", "k8s.namespace.name": "shop"},
+				"",
+				{},
+				{"user_id": format!("{}", i % 97), "region": "eu-west-1"},
+			])
+		})
+		.collect();
+	json!({ "meta": [], "data": data, "rows": rows }).to_string()
+}
+
+fn trace_resp(rows: usize) -> String {
+	let data: Vec<Value> = (0..rows)
+		.map(|i| {
+			json!([
+				format!("1700000000.{:09}", i),
+				"4bf92f3577b34da6a3ce929d0e0e4736",
+				format!("{:016x}", i + 1),
+				format!("{:016x}", i / 2),
+				"",
+				"GET /api/v1/items/:id",
+				"SPAN_KIND_SERVER",
+				"gateway",
+				{"service.name": "gateway", "host.name": "gateway-0"},
+				"net/http",
+				"0.49.0",
+				{"http.method": "GET", "http.status_code": "200"},
+				"1000000",
+				"STATUS_CODE_UNSET",
+				"",
+				["1700000000.500000000"],
+				["sent"],
+				[{"bytes": "512"}],
+				[],
+				[],
+				[],
+				[],
+			])
+		})
+		.collect();
+	json!({ "meta": [], "data": data, "rows": rows }).to_string()
+}
+
+// "value tree" is only the first step of the former path, parsing into
+// serde_json::Value before any field was converted
+fn ck_decode(c: &mut Criterion) {
+	let logs = log_resp(10_000);
+	let mut g = c.benchmark_group("decode 10k log rows");
+	g.bench_function("value tree", |b| {
+		b.iter(|| serde_json::from_str::<Value>(&logs).unwrap())
+	});
+	let levels = LevelMapping::default();
+	g.bench_function("typed rows", |b| {
+		b.iter(|| decode_logs(&logs, &levels, "info").unwrap())
+	});
+	g.finish();
+
+	let spans = trace_resp(10_000);
+	let mut g = c.benchmark_group("decode 10k span rows");
+	g.bench_function("value tree", |b| {
+		b.iter(|| serde_json::from_str::<Value>(&spans).unwrap())
+	});
+	g.bench_function("typed rows", |b| {
+		b.iter(|| decode_spans(&spans).unwrap())
+	});
+	g.finish();
+}
+
+criterion_group!(benches, ck_decode);
+criterion_main!(benches);
@@ -0,0 +1,71 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use ltbridge::bench::{LabelSet, SeriesStore};
+use std::{
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc,
+	},
+	thread,
+};
+
+// label sets of a few services on a few hosts, the user id is the label
+// with many values
+fn label_sets(n: usize) -> Vec<LabelSet> {
+	let services = ["gateway", "checkout", "cart", "payment", "db-proxy"];
+	(0..n)
+		.map(|i| {
+			let svc = services[i % services.len()];
+			vec![
+				("ServiceName".into(), svc.to_string()),
+				("SeverityText".into(), "INFO".to_string()),
+				("resource_host.name".into(), format!("{}-{}", svc, i % 3)),
+				("log_user_id".into(), format!("{}", i % 997)),
+			]
+		})
+		.collect()
+}
+
+// the consumer of the label sets observes them while labels, label
+// values and series requests read the same store. "4 readers" close to
+// "no readers" means the lookups don't hold up recording
+fn series_store(c: &mut Criterion) {
+	let rt = tokio::runtime::Runtime::new().unwrap();
+	let _rt = rt.enter();
+	let sets = label_sets(10_000);
+	let mut g = c.benchmark_group("observe 10k label sets");
+	for readers in [0, 4] {
+		let name = match readers {
+			0 => "no readers".to_string(),
+			n => format!("{} readers", n),
+		};
+		g.bench_function(name, |b| {
+			let (store, _tx) = SeriesStore::new(usize::MAX, None);
+			let stop = Arc::new(AtomicBool::new(false));
+			let handles: Vec<_> = (0..readers)
+				.map(|_| {
+					let (store, stop) = (store.clone(), stop.clone());
+					thread::spawn(move || {
+						while !stop.load(Ordering::Relaxed) {
+							store.labels(None);
+							store.get(&"ServiceName".into(), None, None);
+							store.series(None);
+						}
+					})
+				})
+				.collect();
+			b.iter(|| {
+				for set in &sets {
+					store.observe(set.clone());
+				}
+			});
+			stop.store(true, Ordering::Relaxed);
+			for h in handles {
+				h.join().unwrap();
+			}
+		});
+	}
+	g.finish();
+}
+
+criterion_group!(benches, series_store);
+criterion_main!(benches);
@@ -0,0 +1,69 @@
+use std::collections::HashMap;
+
+use chrono::Utc;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use ltbridge::bench::{spans_into_resourcespans, SpanEvent, SpanItem};
+use serde_json::json;
+
+// a trace spread over a handful of services, each span carrying the
+// attributes a typical http or db span has
+fn trace(spans: usize) -> Vec<SpanItem> {
+	let services = ["gateway", "checkout", "cart", "payment", "db-proxy"];
+	(0..spans)
+		.map(|i| {
+			let svc = services[i % services.len()];
+			let resource: HashMap<_, _> = [
+				("service.name", json!(svc)),
+				("host.name", json!(format!("{}-0", svc))),
+				("telemetry.sdk.language", json!("go")),
+			]
+			.into_iter()
+			.map(|(k, v)| (k.to_string(), v))
+			.collect();
+			let attrs: HashMap<_, _> = [
+				("http.method", json!("GET")),
+				("http.route", json!("/api/v1/items/:id")),
+				("http.status_code", json!(200)),
+				("net.peer.name", json!("10.0.0.1")),
+			]
+			.into_iter()
+			.map(|(k, v)| (k.to_string(), v))
+			.collect();
+			SpanItem {
+				ts: Utc::now(),
+				trace_id: "4bf92f3577b34da6a3ce929d0e0e4736".to_string(),
+				span_id: format!("{:016x}", i + 1),
+				parent_span_id: format!("{:016x}", i / 2),
+				span_name: "GET /api/v1/items/:id".to_string(),
+				span_kind: 2,
+				service_name: svc.to_string(),
+				resource_attributes: resource,
+				scope_name: Some("net/http".to_string()),
+				scope_version: Some("0.49.0".to_string()),
+				span_attributes: attrs.clone(),
+				duration: 1_000_000,
+				span_events: vec![SpanEvent {
+					ts: Utc::now(),
+					dropped_attributes_count: 0,
+					name: "sent".to_string(),
+					attributes: attrs,
+				}],
+				..Default::default()
+			}
+		})
+		.collect()
+}
+
+fn trace_assembly(c: &mut Criterion) {
+	let spans = trace(20_000);
+	c.bench_function("assemble 20k spans", |b| {
+		b.iter_batched(
+			|| spans.clone(),
+			spans_into_resourcespans,
+			BatchSize::LargeInput,
+		)
+	});
+}
+
+criterion_group!(benches, trace_assembly);
+criterion_main!(benches);
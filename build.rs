@@ -3,8 +3,7 @@ use anyhow::Result;
 fn main() -> Result<()> {
 	println!("cargo:rerun-if-changed=protocol/tempo/tempo.proto");
 	let mut cfg = prost_build::Config::new();
-	let mut builder = cfg
-		.type_attribute(".", "#[derive(serde::Serialize, serde::Deserialize)]")
+	cfg.type_attribute(".", "#[derive(serde::Serialize, serde::Deserialize)]")
 		.type_attribute(".", "#[serde(rename_all = \"camelCase\")]")
 		.extern_path(
 			".opentelemetry.proto.trace.v1",
@@ -14,8 +13,7 @@ fn main() -> Result<()> {
 			".opentelemetry.proto.common.v1",
 			"opentelemetry_proto::tonic::common::v1",
 		)
-		.format(true)
-		.out_dir("src/proto");
+		.format(true);
 
 	let i64_fields = [
 		"startTimeUnixNano",
@@ -24,7 +22,7 @@ fn main() -> Result<()> {
 		"totalBlockBytes",
 	];
 	for field in i64_fields {
-		builder = builder.field_attribute(
+		cfg.field_attribute(
 			field,
 			"#[serde(with = \"crate::utils::serde::jsonstr\")]",
 		);
@@ -32,12 +30,18 @@ fn main() -> Result<()> {
 
 	let id_fields = ["traceID", "spanID"];
 	for field in id_fields {
-		builder = builder.field_attribute(
-			field,
-			format!("#[serde(rename = \"{}\")]", field),
-		);
+		cfg.field_attribute(field, format!("#[serde(rename = \"{}\")]", field));
 	}
 
-	builder.compile_protos(&["protocol/tempo/tempo.proto"], &["protocol"])?;
+	// only the server side of the Querier service is needed. tonic sets the
+	// out_dir of cfg to its own, so the checked in file is named here
+	tonic_build::configure()
+		.build_client(false)
+		.out_dir("src/proto")
+		.compile_protos_with_config(
+			cfg,
+			&["protocol/tempo/tempo.proto"],
+			&["protocol"],
+		)?;
 	Ok(())
 }
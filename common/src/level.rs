@@ -1,6 +1,9 @@
 use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::{collections::HashMap, ops::RangeInclusive};
 
-#[derive(Debug, Clone, Hash, Eq, PartialEq, Copy)]
+#[derive(Debug, Clone, Hash, Eq, PartialEq, Copy, Deserialize)]
+#[serde(try_from = "String")]
 pub enum LogLevel {
 	Trace,
 	Debug,
@@ -11,6 +14,18 @@ pub enum LogLevel {
 }
 
 impl LogLevel {
+	// severity_range is the otel SeverityNumber range of the level
+	pub fn severity_range(self) -> RangeInclusive<u32> {
+		use LogLevel::*;
+		match self {
+			Trace => 1..=4,
+			Debug => 5..=8,
+			Info => 9..=12,
+			Warn => 13..=16,
+			Error => 17..=20,
+			Fatal => 21..=24,
+		}
+	}
 	pub fn all_levels() -> Vec<String> {
 		vec![
 			LogLevel::Trace.into(),
@@ -95,3 +110,235 @@ impl From<LogLevel> for String {
 		}
 	}
 }
+
+// names some loggers use that don't start with one of ours,
+// syslog's among them
+static LEVEL_ALIASES: [(&str, LogLevel); 8] = [
+	("verbose", LogLevel::Debug),
+	("notice", LogLevel::Info),
+	("err", LogLevel::Error),
+	("crit", LogLevel::Fatal),
+	("critical", LogLevel::Fatal),
+	("alert", LogLevel::Fatal),
+	("emerg", LogLevel::Fatal),
+	("panic", LogLevel::Fatal),
+];
+
+// SeverityRange maps severity numbers from..=to to level
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct SeverityRange {
+	pub from: u32,
+	pub to: u32,
+	pub level: LogLevel,
+}
+
+// LevelSource is where the level of a record is looked for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LevelSource {
+	// SeverityText
+	Text,
+	// SeverityNumber
+	Number,
+	// the line itself, see LevelMapping::sniff
+	Body,
+}
+
+// keys a json or logfmt line keeps its level under
+static BODY_LEVEL_KEYS: [&str; 4] = ["level", "severity", "lvl", "loglevel"];
+
+// only the start of a line is sniffed, a level isn't buried in a stacktrace
+const SNIFF_BYTES: usize = 256;
+
+// LevelMapping turns the severity of a record into one of our levels.
+// The text is tried first: configured names, then the known ones. The
+// severity number comes next, configured ranges before the otel ones
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct LevelMapping {
+	// matched case insensitively
+	#[serde(default)]
+	pub names: HashMap<String, LogLevel>,
+	#[serde(default)]
+	pub numbers: Vec<SeverityRange>,
+	// the order the sources of a returned line are tried in, the first
+	// one saying anything wins
+	#[serde(default = "default_detect")]
+	pub detect: Vec<LevelSource>,
+}
+
+impl Default for LevelMapping {
+	fn default() -> Self {
+		Self {
+			names: HashMap::new(),
+			numbers: vec![],
+			detect: default_detect(),
+		}
+	}
+}
+
+fn default_detect() -> Vec<LevelSource> {
+	vec![LevelSource::Text, LevelSource::Number]
+}
+
+impl LevelMapping {
+	// level is None when neither text nor number says anything,
+	// a number of 0 is unspecified in otel
+	pub fn level(&self, text: &str, number: u32) -> Option<LogLevel> {
+		self.by_text(text).or_else(|| self.by_number(number))
+	}
+
+	// detect is level for a returned line, the sources are tried in the
+	// configured order
+	pub fn detect(
+		&self,
+		text: &str,
+		number: u32,
+		body: &str,
+	) -> Option<LogLevel> {
+		self.detect.iter().find_map(|source| match source {
+			LevelSource::Text => self.by_text(text),
+			LevelSource::Number => self.by_number(number),
+			LevelSource::Body => self.sniff(body),
+		})
+	}
+
+	fn by_text(&self, text: &str) -> Option<LogLevel> {
+		let text = text.trim().to_lowercase();
+		if text.is_empty() {
+			return None;
+		}
+		self.names
+			.iter()
+			.find(|(k, _)| k.eq_ignore_ascii_case(&text))
+			.map(|(_, l)| *l)
+			.or_else(|| {
+				LEVEL_ALIASES.iter().find(|(k, _)| *k == text).map(|a| a.1)
+			})
+			.or_else(|| LogLevel::try_from(text).ok())
+	}
+
+	fn by_number(&self, number: u32) -> Option<LogLevel> {
+		if number == 0 {
+			return None;
+		}
+		self.numbers
+			.iter()
+			.find(|r| (r.from..=r.to).contains(&number))
+			.map(|r| r.level)
+			.or_else(|| Some(number.into()))
+	}
+
+	// sniff looks for the level at the start of a line: the value of a
+	// level key, as in {"level":"warn"} or lvl=warn, or else a level
+	// written in upper case among the first words, as in "[ERROR] ..."
+	fn sniff(&self, body: &str) -> Option<LogLevel> {
+		let mut end = body.len().min(SNIFF_BYTES);
+		while !body.is_char_boundary(end) {
+			end -= 1;
+		}
+		let head = &body[..end];
+		let keyed = BODY_LEVEL_KEYS
+			.iter()
+			.flat_map(|key| head.match_indices(key))
+			.filter_map(|(i, key)| keyed_value(head, i, key.len()))
+			.find_map(|v| self.by_text(v));
+		if keyed.is_some() {
+			return keyed;
+		}
+		head.split(|c: char| !c.is_ascii_alphabetic())
+			.filter(|w| !w.is_empty())
+			.take(8)
+			.find_map(|w| match w {
+				"WARNING" => Some(LogLevel::Warn),
+				_ => LOG_LEVEL_ENUM.iter().find(|(s, _)| *s == w).map(|l| l.1),
+			})
+	}
+}
+
+// keyed_value returns the word after the key at head[i..i + len] and a
+// ':' or '=', quotes and spaces around them are skipped
+fn keyed_value(head: &str, i: usize, len: usize) -> Option<&str> {
+	let before = head[..i].chars().next_back();
+	if before.is_some_and(|c| c.is_ascii_alphanumeric() || c == '_') {
+		return None;
+	}
+	let rest = head[i + len..].trim_start_matches(['"', '\'']);
+	let rest = rest.trim_start();
+	let rest = rest.strip_prefix([':', '='])?;
+	let rest = rest.trim_start().trim_start_matches(['"', '\'']);
+	let word = rest
+		.split(|c: char| !c.is_ascii_alphabetic())
+		.next()
+		.filter(|w| !w.is_empty())?;
+	Some(word)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_level_mapping() {
+		let mapping = LevelMapping {
+			names: HashMap::from([("Severe".to_string(), LogLevel::Error)]),
+			numbers: vec![SeverityRange {
+				from: 100,
+				to: 199,
+				level: LogLevel::Warn,
+			}],
+			..Default::default()
+		};
+		let cases = [
+			("warning", 0, Some(LogLevel::Warn)),
+			("SEVERE", 0, Some(LogLevel::Error)),
+			("Notice", 0, Some(LogLevel::Info)),
+			("crit", 9, Some(LogLevel::Fatal)),
+			("whatever", 18, Some(LogLevel::Error)),
+			("", 150, Some(LogLevel::Warn)),
+			("whatever", 0, None),
+			("", 0, None),
+		];
+		for (text, number, want) in cases {
+			assert_eq!(
+				mapping.level(text, number),
+				want,
+				"{} {}",
+				text,
+				number
+			);
+		}
+	}
+
+	#[test]
+	fn test_detect() {
+		use LevelSource::*;
+		let body_first = LevelMapping {
+			detect: vec![Body, Text, Number],
+			..Default::default()
+		};
+		let cases = [
+			(
+				"",
+				0,
+				r#"{"level":"warn","msg":"slow"}"#,
+				Some(LogLevel::Warn),
+			),
+			("", 0, r#"{"severity": "Error"}"#, Some(LogLevel::Error)),
+			("", 0, "ts=1 lvl=debug msg=hi", Some(LogLevel::Debug)),
+			("", 0, "2024-06-01 [ERROR] db gone", Some(LogLevel::Error)),
+			("", 0, "WARNING: disk 90% full", Some(LogLevel::Warn)),
+			("info", 0, "[ERROR] db gone", Some(LogLevel::Error)),
+			("info", 0, "no level here", Some(LogLevel::Info)),
+			("", 0, r#"{"log_level_name":"x"}"#, None),
+			("", 0, "the error was ignored", None),
+			("", 0, "", None),
+		];
+		for (text, number, body, want) in cases {
+			assert_eq!(body_first.detect(text, number, body), want, "{}", body);
+		}
+		// body is only sniffed when listed
+		let default = LevelMapping::default();
+		assert_eq!(default.detect("", 0, "[ERROR] db gone"), None);
+		assert_eq!(default.detect("", 18, "[INFO]"), Some(LogLevel::Error));
+	}
+}
@@ -3,7 +3,7 @@ use chrono::NaiveDateTime;
 pub mod level;
 pub use level::LogLevel;
 
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub struct TimeRange {
 	pub start: Option<NaiveDateTime>,
 	pub end: Option<NaiveDateTime>,
@@ -0,0 +1,164 @@
+// format renders a parsed query back into logql, it's what
+// /loki/api/v1/format_query returns
+
+use crate::parser::*;
+use humantime_serde::re::humantime::format_duration;
+use itertools::Itertools;
+use qlcommon::string::quote;
+use std::time::Duration;
+
+pub fn format_query(q: &Query) -> String {
+	match q {
+		Query::LogQuery(q) => format_log_query(q),
+		Query::MetricQuery(q) => format_metric_query(q),
+		Query::Binary(b) => format_binary_query(b),
+		Query::Rank(r) => format_rank_query(r),
+	}
+}
+
+fn format_metric_expr(e: &MetricExpr) -> String {
+	match e {
+		MetricExpr::Metric(q) => format_metric_query(q),
+		MetricExpr::Binary(b) => format_binary_query(b),
+		MetricExpr::Rank(r) => format_rank_query(r),
+	}
+}
+
+fn format_rank_query(r: &RankQuery) -> String {
+	let op = match r.op {
+		RankOp::TopK => "topk",
+		RankOp::BottomK => "bottomk",
+	};
+	format!("{}({}, {})", op, r.k, format_metric_expr(&r.expr))
+}
+
+// nested operations are always put in parentheses, so the result
+// doesn't depend on precedence
+fn format_binary_query(b: &BinaryQuery) -> String {
+	let operand = |e: &MetricExpr| match e {
+		MetricExpr::Binary(b) => format!("({})", format_binary_query(b)),
+		e => format_metric_expr(e),
+	};
+	let op = match b.op {
+		BinaryOp::Add => "+",
+		BinaryOp::Sub => "-",
+		BinaryOp::Mul => "*",
+		BinaryOp::Div => "/",
+	};
+	format!("{} {} {}", operand(&b.lhs), op, operand(&b.rhs))
+}
+
+pub fn format_log_query(q: &LogQuery) -> String {
+	let selector = q.selector.label_paris.iter().map(label_pair).join(", ");
+	let mut out = format!("{{{}}}", selector);
+	for f in q.filters.iter().flatten() {
+		out.push(' ');
+		out.push_str(&filter(f));
+	}
+	out
+}
+
+fn format_metric_query(q: &MetricQuery) -> String {
+	let aggregator = match q.aggregator {
+		Aggregator::Sum => "sum",
+		Aggregator::Avg => "avg",
+	};
+	let func = match q.agg_func {
+		RangeFunction::Rate => "rate",
+		RangeFunction::CountOverTime => "count_over_time",
+	};
+	// sum without labels, the same as an empty by ()
+	let by = if q.agg_by.is_empty() {
+		String::new()
+	} else {
+		format!(" by ({}) ", q.agg_by.join(", "))
+	};
+	let offset = q
+		.offset
+		.map(|d| format!(" offset {}", duration(d)))
+		.unwrap_or_default();
+	format!(
+		"{}{}({}({}[{}]{}))",
+		aggregator,
+		by,
+		func,
+		format_log_query(&q.log_query),
+		duration(q.range),
+		offset,
+	)
+}
+
+fn label_pair(p: &LabelPair) -> String {
+	let op = match p.op {
+		Operator::Equal => "=",
+		Operator::NotEqual => "!=",
+		Operator::RegexMatch => "=~",
+		Operator::RegexNotMatch => "!~",
+	};
+	format!("{}{}{}", p.label, op, quote(&p.value))
+}
+
+fn filter(f: &Filter) -> String {
+	match f {
+		Filter::LogLine(l) => {
+			let op = match l.op {
+				FilterType::Contain => "|=",
+				FilterType::NotContain => "!=",
+				FilterType::RegexMatch => "|~",
+				FilterType::RegexNotMatch => "!~",
+			};
+			format!("{} {}", op, quote(&l.expression))
+		}
+		Filter::Drop(label) => format!("| drop {}", label),
+		Filter::Json => "| json".to_string(),
+		Filter::Label(p) => format!("| {}", label_pair(p)),
+	}
+}
+
+// humantime separates units with spaces, logql doesn't
+fn duration(d: Duration) -> String {
+	format_duration(d).to_string().replace(' ', "")
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use pretty_assertions::assert_eq;
+
+	#[test]
+	fn test_format_query() {
+		let cases = [
+			(
+				r#"{app = "a",level!="info"}|=`x` |~ "\d+"|json|user="\"bob\"""#,
+				r#"{app="a", level!="info"} |= "x" |~ "\\d+" | json | user="\"bob\"""#,
+			),
+			(
+				r#"sum by(level,app)(count_over_time({app="t"}|drop __error__[1h30m]))"#,
+				r#"sum by (level, app) (count_over_time({app="t"} | drop __error__[1h30m]))"#,
+			),
+			(
+				r#"sum by () (rate({app="t"}[5m]))"#,
+				r#"sum(rate({app="t"}[5m]))"#,
+			),
+			(
+				r#"sum(rate({app="t"}[5m]offset 1w))by(level)"#,
+				r#"sum by (level) (rate({app="t"}[5m] offset 7days))"#,
+			),
+			(
+				r#"sum(rate({app="t"}[5m]))/sum(rate({app="t"}[5m]))-sum(rate({app="u"}[5m]))"#,
+				r#"(sum(rate({app="t"}[5m])) / sum(rate({app="t"}[5m]))) - sum(rate({app="u"}[5m]))"#,
+			),
+			(
+				r#"topk( 3,sum(rate({app="t"}[5m]))by(app))"#,
+				r#"topk(3, sum by (app) (rate({app="t"}[5m])))"#,
+			),
+		];
+		for (input, want) in cases {
+			let q = parse_logql_query(input).unwrap();
+			let formatted = format_query(&q);
+			assert_eq!(formatted, want);
+			// formatting again changes nothing
+			assert_eq!(parse_logql_query(&formatted).unwrap(), q);
+		}
+	}
+}
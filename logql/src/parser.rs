@@ -2,13 +2,17 @@ use humantime_serde::re::humantime::parse_duration;
 use itertools::Itertools;
 use nom::{
 	branch::alt,
-	bytes::complete::{tag, take_until, take_until1},
-	character::complete::{alpha1, alphanumeric1, char, multispace0},
-	combinator::{all_consuming, map, map_res, opt, recognize},
-	error::ParseError,
-	multi::{many0_count, many1, separated_list1},
+	bytes::complete::{tag, take_until},
+	character::complete::{alphanumeric1, char, digit1},
+	combinator::{all_consuming, map, map_res, opt, value},
+	multi::{many0, many1, separated_list0, separated_list1},
 	sequence::{delimited, pair, preceded, tuple},
-	IResult, Parser,
+	IResult,
+};
+use qlcommon::{
+	combinator::{self, furthest, ws},
+	error::{did_you_mean, ParseError},
+	string::parse_string,
 };
 use std::time::Duration;
 
@@ -27,17 +31,22 @@ pub enum Operator {
 	RegexNotMatch,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Selector {
 	pub label_paris: Vec<LabelPair>,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub enum Filter {
 	LogLine(LogLineFilter),
-	Drop,
+	// `| drop label`
+	Drop(String),
+	// `| json`, extracts the fields of a json log body as labels
+	Json,
+	// `| label="value"`, after `| json` it applies to the extracted fields
+	Label(LabelPair),
 }
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub enum FilterType {
 	Contain,
 	NotContain,
@@ -45,18 +54,44 @@ pub enum FilterType {
 	RegexNotMatch,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub struct LogLineFilter {
 	pub op: FilterType,
 	pub expression: String,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub struct LogQuery {
 	pub selector: Selector,
 	pub filters: Option<Vec<Filter>>,
 }
 
+impl LogQuery {
+	pub fn has_json_stage(&self) -> bool {
+		self.filters
+			.iter()
+			.flatten()
+			.any(|f| matches!(f, Filter::Json))
+	}
+	// whether any label matcher or line filter is a regular expression
+	pub fn has_regex(&self) -> bool {
+		let in_selector = self.selector.label_paris.iter().any(|p| {
+			matches!(p.op, Operator::RegexMatch | Operator::RegexNotMatch)
+		});
+		let in_filters = self.filters.iter().flatten().any(|f| match f {
+			Filter::LogLine(l) => matches!(
+				l.op,
+				FilterType::RegexMatch | FilterType::RegexNotMatch
+			),
+			Filter::Label(p) => {
+				matches!(p.op, Operator::RegexMatch | Operator::RegexNotMatch)
+			}
+			_ => false,
+		});
+		in_selector || in_filters
+	}
+}
+
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum Aggregator {
 	Sum,
@@ -69,19 +104,94 @@ pub enum RangeFunction {
 	CountOverTime,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub enum Query {
 	LogQuery(LogQuery),
 	MetricQuery(MetricQuery),
+	// arithmetic between metric queries, e.g. an error ratio
+	Binary(BinaryQuery),
+	Rank(RankQuery),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum BinaryOp {
+	Add,
+	Sub,
+	Mul,
+	Div,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct BinaryQuery {
+	pub op: BinaryOp,
+	pub lhs: Box<MetricExpr>,
+	pub rhs: Box<MetricExpr>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum RankOp {
+	TopK,
+	BottomK,
+}
+
+// topk(k, ...) keeps the k series with the highest value at each step,
+// bottomk the lowest
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct RankQuery {
+	pub op: RankOp,
+	pub k: usize,
+	pub expr: Box<MetricExpr>,
+}
+
+// MetricExpr is either side of a binary operation
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum MetricExpr {
+	Metric(MetricQuery),
+	Binary(BinaryQuery),
+	Rank(RankQuery),
+}
+
+impl MetricExpr {
+	// queries returns the metric queries the expression is made of
+	pub fn queries(&self) -> Vec<&MetricQuery> {
+		match self {
+			MetricExpr::Metric(q) => vec![q],
+			MetricExpr::Binary(b) => {
+				let mut v = b.lhs.queries();
+				v.extend(b.rhs.queries());
+				v
+			}
+			MetricExpr::Rank(r) => r.expr.queries(),
+		}
+	}
+}
+
+impl Query {
+	// metric_queries is empty for a log query
+	pub fn metric_queries(&self) -> Vec<&MetricQuery> {
+		match self {
+			Query::LogQuery(_) => vec![],
+			Query::MetricQuery(q) => vec![q],
+			Query::Binary(b) => {
+				let mut v = b.lhs.queries();
+				v.extend(b.rhs.queries());
+				v
+			}
+			Query::Rank(r) => r.expr.queries(),
+		}
+	}
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub struct MetricQuery {
 	pub aggregator: Aggregator,
 	pub agg_func: RangeFunction,
 	pub agg_by: Vec<String>,
 	pub range: Duration,
 	pub log_query: LogQuery,
+	// offset moves the evaluated range back in time, e.g. to compare
+	// with the same hour last week
+	pub offset: Option<Duration>,
 }
 
 fn parse_agg_func(s: &str) -> IResult<&str, RangeFunction> {
@@ -108,14 +218,14 @@ fn parse_metric_query_front_by(s: &str) -> IResult<&str, MetricQuery> {
 				ws(parse_agg_func),
 				delimited(
 					ws(tag("(")),
-					tuple((logql, time_range)),
+					tuple((logql, time_range, opt(offset))),
 					ws(tag(")")),
 				),
 			)),
 			ws(tag(")")),
 		),
 	))(s)
-	.map(|(s, (agg, agg_by, (agg_func, (lq, range))))| {
+	.map(|(s, (agg, agg_by, (agg_func, (lq, range, offset))))| {
 		(
 			s,
 			MetricQuery {
@@ -124,12 +234,13 @@ fn parse_metric_query_front_by(s: &str) -> IResult<&str, MetricQuery> {
 				agg_by,
 				log_query: lq,
 				range,
+				offset,
 			},
 		)
 	})
 }
 
-// sum xxx by (label)
+// sum xxx by (label), or just sum xxx which has a single series
 fn parse_metric_query_tail_by(s: &str) -> IResult<&str, MetricQuery> {
 	tuple((
 		ws(aggregator),
@@ -137,13 +248,17 @@ fn parse_metric_query_tail_by(s: &str) -> IResult<&str, MetricQuery> {
 			ws(tag("(")),
 			tuple((
 				parse_agg_func,
-				delimited(tag("("), tuple((logql, time_range)), tag(")")),
+				delimited(
+					tag("("),
+					tuple((logql, time_range, opt(offset))),
+					tag(")"),
+				),
 			)),
 			ws(tag(")")),
 		),
-		by_label_list,
+		map(opt(by_label_list), Option::unwrap_or_default),
 	))(s)
-	.map(|(s, (agg, (agg_func, (lq, range)), agg_by))| {
+	.map(|(s, (agg, (agg_func, (lq, range, offset)), agg_by))| {
 		(
 			s,
 			MetricQuery {
@@ -152,20 +267,85 @@ fn parse_metric_query_tail_by(s: &str) -> IResult<&str, MetricQuery> {
 				agg_by,
 				log_query: lq,
 				range,
+				offset,
 			},
 		)
 	})
 }
 
 fn parse_metric_query(s: &str) -> IResult<&str, MetricQuery> {
-	alt((parse_metric_query_front_by, parse_metric_query_tail_by))(s)
+	furthest(parse_metric_query_front_by, parse_metric_query_tail_by)(s)
+}
+
+// operands joined by operators of the same precedence, left to right
+fn binary_chain<'a>(
+	operand: fn(&'a str) -> IResult<&'a str, MetricExpr>,
+	ops: [(&'static str, BinaryOp); 2],
+) -> impl FnMut(&'a str) -> IResult<&'a str, MetricExpr> {
+	map(
+		pair(
+			operand,
+			many0(pair(
+				ws(alt((
+					value(ops[0].1, tag(ops[0].0)),
+					value(ops[1].1, tag(ops[1].0)),
+				))),
+				operand,
+			)),
+		),
+		|(first, rest)| {
+			rest.into_iter().fold(first, |lhs, (op, rhs)| {
+				MetricExpr::Binary(BinaryQuery {
+					op,
+					lhs: Box::new(lhs),
+					rhs: Box::new(rhs),
+				})
+			})
+		},
+	)
+}
+
+// * and / bind tighter than + and -
+fn metric_expr(s: &str) -> IResult<&str, MetricExpr> {
+	binary_chain(metric_term, [("+", BinaryOp::Add), ("-", BinaryOp::Sub)])(s)
+}
+
+fn metric_term(s: &str) -> IResult<&str, MetricExpr> {
+	binary_chain(metric_operand, [("*", BinaryOp::Mul), ("/", BinaryOp::Div)])(
+		s,
+	)
+}
+
+fn metric_operand(s: &str) -> IResult<&str, MetricExpr> {
+	furthest(
+		furthest(
+			map(parse_metric_query, MetricExpr::Metric),
+			map(rank_query, MetricExpr::Rank),
+		),
+		delimited(ws(tag("(")), metric_expr, ws(tag(")"))),
+	)(s)
 }
 
-fn ws<'a, F, O, E: ParseError<&'a str>>(inner: F) -> impl Parser<&'a str, O, E>
-where
-	F: Parser<&'a str, O, E>,
-{
-	delimited(multispace0, inner, multispace0)
+// topk(5, sum by (app) (...))
+fn rank_query(s: &str) -> IResult<&str, RankQuery> {
+	tuple((
+		ws(alt((
+			value(RankOp::TopK, tag("topk")),
+			value(RankOp::BottomK, tag("bottomk")),
+		))),
+		preceded(ws(tag("(")), map_res(ws(digit1), str::parse)),
+		delimited(ws(tag(",")), metric_expr, ws(tag(")"))),
+	))(s)
+	.map(|(s, (op, k, expr))| {
+		(
+			s,
+			RankQuery {
+				op,
+				k,
+				expr: Box::new(expr),
+			},
+		)
+	})
 }
 
 fn aggregator(s: &str) -> IResult<&str, Aggregator> {
@@ -186,7 +366,7 @@ fn by_label_list(s: &str) -> IResult<&str, Vec<String>> {
 		ws(tag("by")),
 		delimited(
 			ws(tag("(")),
-			separated_list1(ws(tag(",")), identifier),
+			separated_list0(ws(tag(",")), identifier),
 			ws(tag(")")),
 		),
 	)(s)
@@ -194,10 +374,7 @@ fn by_label_list(s: &str) -> IResult<&str, Vec<String>> {
 }
 
 fn identifier(input: &str) -> IResult<&str, &str> {
-	recognize(pair(
-		alt((alpha1, tag("_"))),
-		many0_count(alt((alphanumeric1, tag("_"), tag(".")))),
-	))(input)
+	combinator::identifier("")(input)
 }
 
 fn time_range(s: &str) -> IResult<&str, Duration> {
@@ -207,6 +384,14 @@ fn time_range(s: &str) -> IResult<&str, Duration> {
 	)(s)
 }
 
+// [5m] offset 1h
+fn offset(s: &str) -> IResult<&str, Duration> {
+	preceded(
+		ws(tag("offset")),
+		map_res(ws(alphanumeric1), parse_duration),
+	)(s)
+}
+
 fn op_eq(s: &str) -> IResult<&str, Operator> {
 	let (r, _) = tag("=")(s)?;
 	Ok((r, Operator::Equal))
@@ -232,17 +417,14 @@ fn operator(s: &str) -> IResult<&str, Operator> {
 }
 
 fn label_pair(s: &str) -> IResult<&str, LabelPair> {
-	let (r, (ident, op, val)) = tuple((
-		identifier,
-		ws(operator),
-		ws(delimited(char('"'), take_until1("\""), char('"'))),
-	))(s)?;
+	let (r, (ident, op, val)) =
+		tuple((identifier, ws(operator), ws(parse_string)))(s)?;
 	Ok((
 		r,
 		LabelPair {
 			label: ident.to_string(),
 			op,
-			value: val.to_string(),
+			value: val,
 		},
 	))
 }
@@ -284,10 +466,14 @@ fn filter_type(s: &str) -> IResult<&str, FilterType> {
 	))(s)
 }
 
-fn string_val(s: &str) -> IResult<&str, &str> {
+// backquoted strings are raw, double quoted ones may contain escapes
+fn string_val(s: &str) -> IResult<&str, String> {
 	alt((
-		delimited(char('`'), take_until("`"), char('`')),
-		delimited(char('"'), take_until("\""), char('"')),
+		map(
+			delimited(char('`'), take_until("`"), char('`')),
+			str::to_string,
+		),
+		parse_string,
 	))(s)
 }
 
@@ -297,7 +483,7 @@ fn line_filter(s: &str) -> IResult<&str, Filter> {
 		s,
 		Filter::LogLine(LogLineFilter {
 			op: t,
-			expression: e.to_string(),
+			expression: e,
 		}),
 	))
 }
@@ -305,12 +491,26 @@ fn line_filter(s: &str) -> IResult<&str, Filter> {
 fn drop_filter(s: &str) -> IResult<&str, Filter> {
 	map(
 		preceded(ws(char('|')), preceded(ws(tag("drop")), ws(identifier))),
-		|_| Filter::Drop,
+		|l| Filter::Drop(l.to_string()),
 	)(s)
 }
 
+fn json_stage(s: &str) -> IResult<&str, Filter> {
+	map(preceded(ws(char('|')), ws(tag("json"))), |_| Filter::Json)(s)
+}
+
+fn label_filter(s: &str) -> IResult<&str, Filter> {
+	map(preceded(ws(char('|')), ws(label_pair)), Filter::Label)(s)
+}
+
 fn filter_chain(s: &str) -> IResult<&str, Vec<Filter>> {
-	many1(alt((ws(line_filter), ws(drop_filter))))(s)
+	// label_filter must go first, `| json_x="y"` starts like a json stage
+	many1(alt((
+		ws(line_filter),
+		ws(label_filter),
+		ws(drop_filter),
+		ws(json_stage),
+	)))(s)
 }
 
 fn logql(s: &str) -> IResult<&str, LogQuery> {
@@ -341,20 +541,69 @@ fn check_opt_vec<T>(opt: Option<Vec<T>>) -> Option<Vec<T>> {
 	opt.filter(|v| !v.is_empty())
 }
 
-pub type LogQLParseError = nom::Err<nom::error::Error<String>>;
+pub type LogQLParseError = ParseError;
 
 fn parse_logql_log_query(s: &str) -> IResult<&str, Query> {
 	logql(s).map(|(s, lq)| (s, Query::LogQuery(lq)))
 }
 
 fn parse_logql_metric_query(s: &str) -> IResult<&str, Query> {
-	parse_metric_query(s).map(|(s, mq)| (s, Query::MetricQuery(mq)))
+	metric_expr(s).map(|(s, e)| {
+		(
+			s,
+			match e {
+				MetricExpr::Metric(mq) => Query::MetricQuery(mq),
+				MetricExpr::Binary(b) => Query::Binary(b),
+				MetricExpr::Rank(r) => Query::Rank(r),
+			},
+		)
+	})
+}
+
+// operators people often mistype, checked against the start of the token
+static LOGQL_OPERATOR_HINTS: [(&str, &str); 5] = [
+	("|==", "|="),
+	("==", "="),
+	("~=", "=~"),
+	("=|", "|="),
+	("=!", "!="),
+];
+
+static LOGQL_KEYWORDS: [&str; 7] = [
+	"json",
+	"drop",
+	"sum",
+	"avg",
+	"by",
+	"rate",
+	"count_over_time",
+];
+
+fn hint(token: &str) -> Option<String> {
+	if token.is_empty() {
+		return Some("is a closing bracket missing?".to_string());
+	}
+	if token.starts_with('\'') {
+		return Some("strings are quoted with \" or `".to_string());
+	}
+	// `==` in a selector fails right after its first `=`
+	if token.starts_with("=\"") {
+		return Some("equality is written with a single `=`".to_string());
+	}
+	LOGQL_OPERATOR_HINTS
+		.iter()
+		.find(|(wrong, _)| token.starts_with(wrong))
+		.map(|(_, right)| format!("did you mean `{}`?", right))
+		.or_else(|| did_you_mean(token, &LOGQL_KEYWORDS))
 }
 
 pub fn parse_logql_query(s: &str) -> Result<Query, LogQLParseError> {
-	all_consuming(alt((parse_logql_log_query, parse_logql_metric_query)))(s)
-		.map(|(_, v)| v)
-		.map_err(|e| e.to_owned())
+	furthest(
+		all_consuming(parse_logql_log_query),
+		all_consuming(parse_logql_metric_query),
+	)(s)
+	.map(|(_, v)| v)
+	.map_err(|e| ParseError::new(s, e).with_hint(hint))
 }
 
 #[cfg(test)]
@@ -367,7 +616,7 @@ mod tests {
 		let input = "| drop __error__";
 		let (s, v) = drop_filter(input).unwrap();
 		assert!(s.is_empty());
-		assert_eq!(Filter::Drop, v);
+		assert_eq!(Filter::Drop("__error__".to_string()), v);
 		let input = r#"{app="t"} |= `giao` | drop __error__"#;
 		let actual = parse_logql_query(input).unwrap();
 		let expect = LogQuery {
@@ -383,10 +632,100 @@ mod tests {
 					op: FilterType::Contain,
 					expression: "giao".to_string(),
 				}),
-				Filter::Drop,
+				Filter::Drop("__error__".to_string()),
+			]),
+		};
+		assert_eq!(Query::LogQuery(expect), actual);
+	}
+	#[test]
+	fn test_json_stage() {
+		let input = r#"{service_name="x"} | json | user_id="42" |= `err`"#;
+		let actual = parse_logql_query(input).unwrap();
+		let expect = LogQuery {
+			selector: Selector {
+				label_paris: vec![LabelPair {
+					label: "service_name".to_string(),
+					op: Operator::Equal,
+					value: "x".to_string(),
+				}],
+			},
+			filters: Some(vec![
+				Filter::Json,
+				Filter::Label(LabelPair {
+					label: "user_id".to_string(),
+					op: Operator::Equal,
+					value: "42".to_string(),
+				}),
+				Filter::LogLine(LogLineFilter {
+					op: FilterType::Contain,
+					expression: "err".to_string(),
+				}),
 			]),
 		};
 		assert_eq!(Query::LogQuery(expect), actual);
+		let Query::LogQuery(q) = actual else {
+			unreachable!()
+		};
+		assert!(q.has_json_stage());
+	}
+
+	#[test]
+	fn test_escaped_label_value() {
+		let input =
+			r#"{msg="say \"hi\" to  bob", path="C:\\tmp"} |= "a \"b\"""#;
+		let (remain, actual) = logql(input).unwrap();
+		assert!(remain.is_empty());
+		let values: Vec<_> = actual
+			.selector
+			.label_paris
+			.into_iter()
+			.map(|p| p.value)
+			.collect();
+		assert_eq!(values, vec![r#"say "hi" to  bob"#, r"C:\tmp"]);
+		assert_eq!(
+			actual.filters,
+			Some(vec![Filter::LogLine(LogLineFilter {
+				op: FilterType::Contain,
+				expression: r#"a "b""#.to_string(),
+			})])
+		);
+	}
+
+	#[test]
+	fn test_parse_error_hint() {
+		let err = |q| parse_logql_query(q).unwrap_err().to_string();
+		assert_eq!(
+			err(r#"{app=="a"}"#),
+			r#"unexpected `="a"}` at line 1, column 6, equality is written with a single `=`"#
+		);
+		assert_eq!(
+			err(r#"{app="a"} |== "x""#),
+			"unexpected `|==` at line 1, column 11, did you mean `|=`?"
+		);
+		assert_eq!(
+			err(r#"{app="a"} |= "x" | jsn"#),
+			"unexpected `| jsn` at line 1, column 18, did you mean `json`?"
+		);
+		assert_eq!(
+			err(r#"sum by (app) (rate({app="a"}[5m])"#),
+			"unexpected end of query at line 1, column 34, \
+			 is a closing bracket missing?"
+		);
+	}
+
+	#[test]
+	fn test_has_regex() {
+		let cases = [
+			(r#"{app="t"} |= `giao`"#, false),
+			(r#"{app=~"t.*"}"#, true),
+			(r#"{app="t"} |= `a` !~ `b+`"#, true),
+		];
+		for (input, expect) in cases {
+			let Query::LogQuery(q) = parse_logql_query(input).unwrap() else {
+				panic!("not a log query: {}", input);
+			};
+			assert_eq!(expect, q.has_regex(), "case: {}", input);
+		}
 	}
 	#[test]
 	fn test_drop_filter_metric() {
@@ -409,14 +748,98 @@ mod tests {
 						op: FilterType::Contain,
 						expression: "giao".to_string(),
 					}),
-					Filter::Drop,
+					Filter::Drop("__error__".to_string()),
 				]),
 			},
 			range: Duration::from_secs(60),
+			offset: None,
 		};
 		assert_eq!(Query::MetricQuery(expect), actual);
 	}
 
+	#[test]
+	fn test_binary_query() {
+		let q = r#"sum(rate({app="x", level="error"}[5m])) / sum(rate({app="x"}[5m])) - (sum(rate({app="y"}[5m])) + sum(rate({app="z"}[5m])))"#;
+		let Ok(Query::Binary(b)) = parse_logql_query(q) else {
+			panic!("not a binary query");
+		};
+		// (a / b) - (c + d)
+		assert_eq!(b.op, BinaryOp::Sub);
+		let MetricExpr::Binary(lhs) = *b.lhs else {
+			panic!("lhs isn't a binary query");
+		};
+		assert_eq!(lhs.op, BinaryOp::Div);
+		let MetricExpr::Binary(rhs) = *b.rhs else {
+			panic!("rhs isn't a binary query");
+		};
+		assert_eq!(rhs.op, BinaryOp::Add);
+		let apps: Vec<_> = MetricExpr::Binary(rhs)
+			.queries()
+			.iter()
+			.map(|q| q.log_query.selector.label_paris[0].value.clone())
+			.collect();
+		assert_eq!(apps, ["y", "z"]);
+		assert!(parse_logql_query(r#"sum(rate({app="x"}[5m])) / {app="x"}"#)
+			.is_err());
+	}
+
+	#[test]
+	fn test_metric_query_without_labels() {
+		for input in [
+			r#"sum(count_over_time({app="x"}[5m]))"#,
+			r#"sum by () (count_over_time({app="x"}[5m]))"#,
+			r#"sum(count_over_time({app="x"}[5m])) by ()"#,
+		] {
+			let Ok(Query::MetricQuery(q)) = parse_logql_query(input) else {
+				panic!("not a metric query: {}", input);
+			};
+			assert!(q.agg_by.is_empty(), "case: {}", input);
+			assert_eq!(q.range, Duration::from_secs(300));
+		}
+	}
+
+	#[test]
+	fn test_rank_query() {
+		let q =
+			r#"topk(5, sum by (ServiceName) (count_over_time({app="x"}[5m])))"#;
+		let Ok(Query::Rank(r)) = parse_logql_query(q) else {
+			panic!("not a rank query");
+		};
+		assert_eq!((r.op, r.k), (RankOp::TopK, 5));
+		assert_eq!(r.expr.queries()[0].agg_by, ["ServiceName"]);
+		let q = r#"bottomk(2, sum(rate({app="x"}[5m])) / sum(rate({app="y"}[5m])))"#;
+		let Ok(Query::Rank(r)) = parse_logql_query(q) else {
+			panic!("not a rank query");
+		};
+		assert_eq!(r.op, RankOp::BottomK);
+		assert!(matches!(*r.expr, MetricExpr::Binary(_)));
+		assert!(
+			parse_logql_query(r#"topk(x, sum(rate({app="x"}[5m])))"#).is_err()
+		);
+	}
+
+	#[test]
+	fn test_offset() {
+		for (input, offset) in [
+			(r#"sum(rate({app="x"}[5m] offset 1h))"#, Some(3600)),
+			(
+				r#"sum by (level) (rate({app="x"} |= `a` [5m]offset 7d))"#,
+				Some(604800),
+			),
+			(r#"sum(rate({app="x"}[5m])) by (level)"#, None),
+		] {
+			let Ok(Query::MetricQuery(q)) = parse_logql_query(input) else {
+				panic!("not a metric query: {}", input);
+			};
+			assert_eq!(
+				q.offset,
+				offset.map(Duration::from_secs),
+				"case: {}",
+				input
+			);
+		}
+	}
+
 	#[test]
 	fn test_query_parse_metric_query() {
 		let test_cases = vec![
@@ -443,6 +866,7 @@ mod tests {
 					})]),
 				},
 				range: Duration::from_secs(300),
+				offset: None,
 			};
 			assert_eq!(Query::MetricQuery(expect), actual);
 		}
@@ -631,7 +1055,7 @@ mod tests {
 				}],
 			},
 			filters: Some(vec![
-				Filter::Drop,
+				Filter::Drop("__error__".to_string()),
 				Filter::LogLine(LogLineFilter {
 					op: FilterType::Contain,
 					expression: "hello".to_string(),
@@ -0,0 +1,121 @@
+// combinators both query languages are built from
+
+use nom::{
+	bytes::complete::take_while,
+	character::complete::{multispace0, satisfy},
+	combinator::recognize,
+	error::ParseError,
+	sequence::{delimited, pair},
+	IResult, Parser,
+};
+use std::ops::Range;
+
+pub fn ws<'a, F, O, E: ParseError<&'a str>>(
+	inner: F,
+) -> impl Parser<&'a str, O, E>
+where
+	F: Parser<&'a str, O, E>,
+{
+	delimited(multispace0, inner, multispace0)
+}
+
+// identifier is a letter or underscore followed by letters, digits,
+// underscores, dots and any of extra
+pub fn identifier<'a, E: ParseError<&'a str>>(
+	extra: &'static str,
+) -> impl FnMut(&'a str) -> IResult<&'a str, &'a str, E> {
+	recognize(pair(
+		satisfy(|c| c.is_ascii_alphabetic() || c == '_'),
+		take_while(move |c: char| {
+			c.is_ascii_alphanumeric()
+				|| c == '_' || c == '.'
+				|| extra.contains(c)
+		}),
+	))
+}
+
+// furthest tries a and then b like alt, but when both fail it reports
+// the error of whichever got further into the input
+pub fn furthest<'a, O, A, B>(
+	mut a: A,
+	mut b: B,
+) -> impl FnMut(&'a str) -> IResult<&'a str, O>
+where
+	A: Parser<&'a str, O, nom::error::Error<&'a str>>,
+	B: Parser<&'a str, O, nom::error::Error<&'a str>>,
+{
+	move |input: &'a str| match a.parse(input) {
+		Err(nom::Err::Error(ea)) => match b.parse(input) {
+			Err(nom::Err::Error(eb)) if eb.input.len() >= ea.input.len() => {
+				Err(nom::Err::Error(ea))
+			}
+			r => r,
+		},
+		r => r,
+	}
+}
+
+// Span locates a piece of the query. It's kept as the length of the
+// input left at both ends, which nom knows without the full query.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+	start_left: usize,
+	end_left: usize,
+}
+
+impl Span {
+	pub fn new(before: &str, after: &str) -> Self {
+		Self {
+			start_left: before.len(),
+			end_left: after.len(),
+		}
+	}
+	// range returns the byte offsets of the span within query
+	pub fn range(&self, query: &str) -> Range<usize> {
+		let len = query.len();
+		len.saturating_sub(self.start_left)..len.saturating_sub(self.end_left)
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Spanned<T> {
+	pub node: T,
+	pub span: Span,
+}
+
+// spanned records where the output of inner came from
+pub fn spanned<'a, O, E, F>(
+	mut inner: F,
+) -> impl FnMut(&'a str) -> IResult<&'a str, Spanned<O>, E>
+where
+	E: ParseError<&'a str>,
+	F: Parser<&'a str, O, E>,
+{
+	move |input: &'a str| {
+		let (rest, node) = inner.parse(input)?;
+		let span = Span::new(input, rest);
+		Ok((rest, Spanned { node, span }))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use pretty_assertions::assert_eq;
+
+	#[test]
+	fn test_spanned_identifier() {
+		let query = "  foo.bar-baz = 1";
+		let (rest, id) =
+			ws(spanned(identifier::<nom::error::Error<&str>>("-")))
+				.parse(query)
+				.unwrap();
+		assert_eq!(id.node, "foo.bar-baz");
+		assert_eq!(&query[id.span.range(query)], "foo.bar-baz");
+		assert_eq!(rest, "= 1");
+		let (rest, id) =
+			identifier::<nom::error::Error<&str>>("")("foo.bar-baz").unwrap();
+		assert_eq!((id, rest), ("foo.bar", "-baz"));
+		assert!(identifier::<nom::error::Error<&str>>("")("1abc").is_err());
+	}
+}
@@ -0,0 +1,155 @@
+use crate::combinator::Span;
+use nom::error::ErrorKind;
+use std::fmt::Display;
+
+// longest piece of the remaining input quoted in an error
+const TOKEN_MAX_CHARS: usize = 20;
+
+// ParseError tells where in the query parsing stopped
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+	pub span: Span,
+	// byte offset into the query
+	pub offset: usize,
+	// both 1 based, column counts chars
+	pub line: usize,
+	pub column: usize,
+	// what was found there, empty at the end of the query
+	pub token: String,
+	pub kind: ErrorKind,
+	// what the user most likely meant, e.g. "did you mean `|=`?"
+	pub hint: Option<String>,
+}
+
+impl ParseError {
+	pub fn new(query: &str, e: nom::Err<nom::error::Error<&str>>) -> Self {
+		let (rest, kind) = match e {
+			nom::Err::Error(e) | nom::Err::Failure(e) => (e.input, e.code),
+			nom::Err::Incomplete(_) => ("", ErrorKind::Eof),
+		};
+		// point at the token rather than the spaces before it
+		let rest = rest.trim_start();
+		let offset = query.len().saturating_sub(rest.len());
+		let before = &query[..offset];
+		let line = before.matches('\n').count() + 1;
+		let column =
+			before.rsplit('\n').next().map_or(0, |l| l.chars().count()) + 1;
+		let token: String =
+			token_at(rest).chars().take(TOKEN_MAX_CHARS).collect();
+		let end = &rest[token.len()..];
+		Self {
+			span: Span::new(rest, end),
+			offset,
+			line,
+			column,
+			token,
+			kind,
+			hint: None,
+		}
+	}
+	// with_hint lets each language explain its own common mistakes
+	pub fn with_hint(
+		mut self,
+		hint: impl FnOnce(&str) -> Option<String>,
+	) -> Self {
+		self.hint = hint(&self.token);
+		self
+	}
+}
+
+// token_at returns the word rest starts with, a lone symbol like `|`
+// says little so the word following it is taken as well
+fn token_at(rest: &str) -> &str {
+	let word_end = |s: &str| s.find(char::is_whitespace).unwrap_or(s.len());
+	let end = word_end(rest);
+	let mut chars = rest[..end].chars();
+	let lone_symbol = matches!(
+		(chars.next(), chars.next()),
+		(Some(c), None) if !c.is_alphanumeric()
+	);
+	if !lone_symbol {
+		return &rest[..end];
+	}
+	let next = rest[end..].trim_start();
+	let next_start = rest.len() - next.len();
+	&rest[..next_start + word_end(next)]
+}
+
+// did_you_mean suggests the candidate closest to the word the token starts
+// with, if it's only a typo or two away
+pub fn did_you_mean(token: &str, candidates: &[&str]) -> Option<String> {
+	let word: String = token
+		.trim_start_matches(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+		.chars()
+		.take_while(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '.')
+		.collect();
+	if word.is_empty() {
+		return None;
+	}
+	candidates
+		.iter()
+		.map(|c| (distance(&word, c), c))
+		.filter(|(d, c)| *d > 0 && *d <= 2 && *d < c.len())
+		.min_by_key(|(d, _)| *d)
+		.map(|(_, c)| format!("did you mean `{}`?", c))
+}
+
+// levenshtein distance
+fn distance(a: &str, b: &str) -> usize {
+	let b: Vec<char> = b.chars().collect();
+	let mut prev: Vec<usize> = (0..=b.len()).collect();
+	for (i, ca) in a.chars().enumerate() {
+		let mut cur = vec![i + 1];
+		for (j, cb) in b.iter().enumerate() {
+			let sub = prev[j] + usize::from(ca != *cb);
+			cur.push(sub.min(prev[j + 1] + 1).min(cur[j] + 1));
+		}
+		prev = cur;
+	}
+	prev[b.len()]
+}
+
+impl Display for ParseError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		if self.token.is_empty() {
+			write!(f, "unexpected end of query")?;
+		} else {
+			write!(f, "unexpected `{}`", self.token)?;
+		}
+		write!(f, " at line {}, column {}", self.line, self.column)?;
+		if let Some(hint) = &self.hint {
+			write!(f, ", {}", hint)?;
+		}
+		Ok(())
+	}
+}
+
+impl std::error::Error for ParseError {}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use pretty_assertions::assert_eq;
+
+	#[test]
+	fn test_parse_error_position() {
+		let query = "{a=\"b\"}\n  | bad stuff";
+		let rest = &query[10..];
+		let e = ParseError::new(
+			query,
+			nom::Err::Error(nom::error::Error::new(rest, ErrorKind::Tag)),
+		);
+		assert_eq!((e.offset, e.line, e.column), (10, 2, 3));
+		assert_eq!(e.token, "| bad");
+		assert_eq!(&query[e.span.range(query)], "| bad");
+		assert_eq!(e.to_string(), "unexpected `| bad` at line 2, column 3");
+		let e = ParseError::new(
+			query,
+			nom::Err::Error(nom::error::Error::new("", ErrorKind::Eof)),
+		);
+		assert_eq!(
+			e.to_string(),
+			"unexpected end of query at line 2, column 14"
+		);
+	}
+}
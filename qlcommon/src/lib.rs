@@ -0,0 +1,3 @@
+pub mod combinator;
+pub mod error;
+pub mod string;
@@ -21,6 +21,13 @@ pub enum PlaceValue {
 	String(String),
 	Integer(i64),
 	Float(ordered_float::OrderedFloat<f64>),
+	Bool(bool),
+}
+
+// escape_str makes s safe to put between single quotes,
+// e.g. a map key that comes from the query
+pub fn escape_str(s: &str) -> String {
+	s.replace('\\', "\\\\").replace('\'', "\\'")
 }
 
 impl Display for PlaceValue {
@@ -29,10 +36,32 @@ impl Display for PlaceValue {
 			PlaceValue::String(s) => write!(f, "'{}'", s),
 			PlaceValue::Integer(i) => write!(f, "{}", i),
 			PlaceValue::Float(fl) => write!(f, "{}", fl),
+			PlaceValue::Bool(b) => write!(f, "{}", b),
 		}
 	}
 }
 
+// Params holds the bind values of a parameterized query, in the same
+// order as the `?` placeholders appear in the generated sql
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Params(Vec<PlaceValue>);
+
+impl Params {
+	pub fn bind(&mut self, v: PlaceValue) -> &'static str {
+		self.0.push(v);
+		"?"
+	}
+	pub fn values(&self) -> &[PlaceValue] {
+		&self.0
+	}
+	pub fn len(&self) -> usize {
+		self.0.len()
+	}
+	pub fn is_empty(&self) -> bool {
+		self.0.is_empty()
+	}
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Selection {
 	Unit(Condition),
@@ -55,6 +84,8 @@ pub enum Column {
 	Resources(String),
 	Attributes(String),
 	Raw(String),
+	// a field of the json encoded message, nested keys are separated by `.`
+	BodyJson(String),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -90,6 +121,9 @@ pub struct QueryPlan<T: TableSchema, C: QueryConverter> {
 	pub sorting: Vec<(String, SortType)>,
 	pub timing: Vec<(OrdType, NaiveDateTime)>,
 	pub limit: Option<u32>,
+	// when set, rows are read from the UNION ALL of these tables
+	// instead of schema.table(), see with_tables
+	pub tables: Vec<String>,
 }
 
 impl<T: TableSchema, C: QueryConverter> QueryPlan<T, C> {
@@ -113,8 +147,14 @@ impl<T: TableSchema, C: QueryConverter> QueryPlan<T, C> {
 			sorting,
 			timing,
 			limit,
+			tables: vec![],
 		}
 	}
+	// with_tables spreads the plan over partitions of the same schema
+	pub fn with_tables(mut self, tables: Vec<String>) -> Self {
+		self.tables = tables;
+		self
+	}
 }
 
 impl<T, C> QueryPlan<T, C>
@@ -123,11 +163,35 @@ where
 	C: QueryConverter,
 {
 	pub fn as_sql(&self) -> String {
+		self.render(&mut None)
+	}
+	// as_sql_with_params renders literals as placeholders, the values
+	// are returned separately so the sql text only depends on query shape
+	pub fn as_sql_with_params(&self) -> (String, Params) {
+		let mut params = Params::default();
+		let sql = self.render(&mut Some(&mut params));
+		(sql, params)
+	}
+	// where_sql is only the filter of the plan, for statements other
+	// than SELECT, e.g. deletes
+	pub fn where_sql(&self) -> String {
+		self.where_part(&mut None)
+	}
+	pub fn where_sql_with_params(&self) -> (String, Params) {
+		let mut params = Params::default();
+		let sql = self.where_part(&mut Some(&mut params));
+		(sql, params)
+	}
+	fn render(&self, params: &mut Option<&mut Params>) -> String {
 		let mut sql = self.projection_part();
-		sql.push_str(&format!(" FROM {}", self.schema.table()));
-		let where_part = self.where_part();
-		if !where_part.is_empty() {
-			sql.push_str(&format!(" WHERE {}", where_part));
+		if self.tables.is_empty() {
+			sql.push_str(&format!(" FROM {}", self.schema.table()));
+			let where_part = self.where_part(params);
+			if !where_part.is_empty() {
+				sql.push_str(&format!(" WHERE {}", where_part));
+			}
+		} else {
+			sql.push_str(&format!(" FROM ({})", self.union_part(params)));
 		}
 		if let Some(grouping) = self.grouping_part() {
 			sql.push(' ');
@@ -143,9 +207,33 @@ where
 		}
 		sql
 	}
-	fn where_part(&self) -> String {
-		let mut where_part = self.selection_part();
-		let timing = self.timing_part();
+	// every table is filtered on its own, grouping and sorting happen once
+	// the rows are put together. Without grouping a table can already
+	// stop at the limit
+	fn union_part(&self, params: &mut Option<&mut Params>) -> String {
+		let mut parts = vec![];
+		for t in &self.tables {
+			let mut sql = format!("SELECT * FROM {}", t);
+			let where_part = self.where_part(params);
+			if !where_part.is_empty() {
+				sql.push_str(&format!(" WHERE {}", where_part));
+			}
+			if let Some(limit) = self.limit_part() {
+				if self.grouping.is_empty() {
+					if !self.sorting.is_empty() {
+						sql.push_str(" ORDER BY ");
+						sql.push_str(&self.sorting_part());
+					}
+					sql = format!("({} {})", sql, limit);
+				}
+			}
+			parts.push(sql);
+		}
+		parts.join(" UNION ALL ")
+	}
+	fn where_part(&self, params: &mut Option<&mut Params>) -> String {
+		let mut where_part = self.selection_part(params);
+		let timing = self.timing_part(params);
 		if !timing.is_empty() {
 			if !where_part.is_empty() {
 				where_part.push_str(" AND ");
@@ -157,24 +245,31 @@ where
 	fn projection_part(&self) -> String {
 		format!("SELECT {}", self.projection.join(","))
 	}
-	fn selection_to_sql(&self, s: &Selection) -> String {
+	fn selection_to_sql(
+		&self,
+		s: &Selection,
+		params: &mut Option<&mut Params>,
+	) -> String {
 		match s {
-			Selection::Unit(ref c) => self.converter.convert_condition(c),
+			Selection::Unit(ref c) => match params {
+				Some(p) => self.converter.convert_condition_params(c, p),
+				None => self.converter.convert_condition(c),
+			},
 			Selection::LogicalAnd(ref l, ref r) => {
-				let l = self.selection_to_sql(l);
-				let r = self.selection_to_sql(r);
+				let l = self.selection_to_sql(l, params);
+				let r = self.selection_to_sql(r, params);
 				format!("({} AND {})", l, r)
 			}
 			Selection::LogicalOr(ref l, ref r) => {
-				let l = self.selection_to_sql(l);
-				let r = self.selection_to_sql(r);
+				let l = self.selection_to_sql(l, params);
+				let r = self.selection_to_sql(r, params);
 				format!("({} OR {})", l, r)
 			}
 		}
 	}
-	fn selection_part(&self) -> String {
+	fn selection_part(&self, params: &mut Option<&mut Params>) -> String {
 		if let Some(s) = &self.selection {
-			self.selection_to_sql(s)
+			self.selection_to_sql(s, params)
 		} else {
 			"".to_string()
 		}
@@ -197,11 +292,16 @@ where
 			.collect::<Vec<String>>()
 			.join(",")
 	}
-	fn timing_part(&self) -> Vec<String> {
+	fn timing_part(&self, params: &mut Option<&mut Params>) -> Vec<String> {
 		let ts_key = self.schema.ts_key();
 		self.timing
 			.iter()
-			.map(|(o, t)| self.converter.convert_timing(ts_key, o, t))
+			.map(|(o, t)| match params {
+				Some(p) => {
+					self.converter.convert_timing_params(ts_key, o, t, p)
+				}
+				None => self.converter.convert_timing(ts_key, o, t),
+			})
 			.collect()
 	}
 	fn limit_part(&self) -> Option<String> {
@@ -239,6 +339,23 @@ pub trait QueryConverter {
 		o: &OrdType,
 		t: &NaiveDateTime,
 	) -> String;
+	// converters that can't emit placeholders just inline the literals
+	fn convert_condition_params(
+		&self,
+		c: &Condition,
+		_: &mut Params,
+	) -> String {
+		self.convert_condition(c)
+	}
+	fn convert_timing_params(
+		&self,
+		ts_key: &str,
+		o: &OrdType,
+		t: &NaiveDateTime,
+		_: &mut Params,
+	) -> String {
+		self.convert_timing(ts_key, o, t)
+	}
 }
 
 #[cfg(test)]
@@ -255,4 +372,16 @@ mod tests {
 		let f = PlaceValue::Float(OrderedFloat(1.23));
 		assert_eq!(format!("{}", f), "1.23");
 	}
+
+	#[test]
+	fn bind_params_in_order() {
+		let mut p = Params::default();
+		assert!(p.is_empty());
+		assert_eq!(p.bind(PlaceValue::Integer(1)), "?");
+		p.bind(PlaceValue::String("a".to_string()));
+		assert_eq!(
+			p.values(),
+			&[PlaceValue::Integer(1), PlaceValue::String("a".to_string())]
+		);
+	}
 }
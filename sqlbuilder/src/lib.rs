@@ -1,3 +1,4 @@
 pub mod builder;
+pub mod partition;
 pub mod trace;
 pub mod visit;
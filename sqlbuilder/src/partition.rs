@@ -0,0 +1,69 @@
+use chrono::{Datelike, NaiveDateTime, NaiveTime, TimeDelta, Timelike};
+
+// TableTemplate names time partitioned tables, e.g. logs_{yyyy}_{mm}_{dd}.
+// A template with {hh} has a table per hour, otherwise one per day, in UTC
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableTemplate(String);
+
+impl TableTemplate {
+	pub fn new(template: impl Into<String>) -> Self {
+		Self(template.into())
+	}
+	fn hourly(&self) -> bool {
+		self.0.contains("{hh}")
+	}
+	pub fn name(&self, t: NaiveDateTime) -> String {
+		self.0
+			.replace("{yyyy}", &format!("{:04}", t.year()))
+			.replace("{mm}", &format!("{:02}", t.month()))
+			.replace("{dd}", &format!("{:02}", t.day()))
+			.replace("{hh}", &format!("{:02}", t.hour()))
+	}
+	// tables yields the partitions from start to end in time order,
+	// it's lazy so callers can stop at a cap
+	pub fn tables(
+		&self,
+		start: NaiveDateTime,
+		end: NaiveDateTime,
+	) -> impl Iterator<Item = String> + '_ {
+		let (first, step) = if self.hourly() {
+			let h = NaiveTime::from_hms_opt(start.hour(), 0, 0).unwrap();
+			(start.date().and_time(h), TimeDelta::hours(1))
+		} else {
+			(start.date().and_time(NaiveTime::MIN), TimeDelta::days(1))
+		};
+		std::iter::successors(Some(first), move |t| t.checked_add_signed(step))
+			.take_while(move |t| *t <= end)
+			.map(|t| self.name(t))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use chrono::NaiveDate;
+
+	fn at(d: u32, h: u32, m: u32) -> NaiveDateTime {
+		NaiveDate::from_ymd_opt(2024, 6, d)
+			.unwrap()
+			.and_hms_opt(h, m, 0)
+			.unwrap()
+	}
+
+	#[test]
+	fn test_partition_tables() {
+		let daily = TableTemplate::new("logs_{yyyy}_{mm}_{dd}");
+		assert_eq!(
+			daily.tables(at(1, 23, 10), at(3, 0, 5)).collect::<Vec<_>>(),
+			vec!["logs_2024_06_01", "logs_2024_06_02", "logs_2024_06_03"]
+		);
+		let hourly = TableTemplate::new("logs_{yyyy}{mm}{dd}_{hh}");
+		assert_eq!(
+			hourly
+				.tables(at(1, 22, 59), at(1, 23, 0))
+				.collect::<Vec<_>>(),
+			vec!["logs_20240601_22", "logs_20240601_23"]
+		);
+		assert_eq!(daily.tables(at(2, 0, 0), at(1, 0, 0)).count(), 0);
+	}
+}
@@ -3,10 +3,12 @@ use super::builder::{
 	TableSchema,
 };
 use itertools::Itertools as _;
-use opentelemetry_proto::tonic::trace::v1::status::StatusCode as PBStatusCode;
+use opentelemetry_proto::tonic::trace::v1::{
+	span::SpanKind as PBSpanKind, status::StatusCode as PBStatusCode,
+};
 use traceql::{
 	ComparisonOperator, Expression, FieldExpr, FieldType, FieldValue,
-	IntrisincField, LogicalOperator, SpanSet, StatusCode,
+	IntrisincField, LogicalOperator, SpanKind, SpanSet, StatusCode,
 };
 
 #[allow(dead_code)]
@@ -33,7 +35,8 @@ where
 	{
 		match expr {
 			Expression::SpanSet(spanset) => {
-				let selection = spanset_to_selection(spanset);
+				let selection =
+					spanset_to_selection(spanset, &StatusNames::default());
 				let mut qp = QueryPlan::new(
 					converter.clone(),
 					schema.clone(),
@@ -54,6 +57,9 @@ where
 			Expression::Logical(_, _, _) => {
 				unimplemented!("logical expression")
 			}
+			Expression::Structural(_, _, _) => {
+				unimplemented!("structural expression")
+			}
 		}
 	}
 	fn as_sql(&self) -> String {
@@ -79,7 +85,40 @@ where
 	}
 }
 
-fn spanset_to_selection(spanset: &SpanSet) -> Selection {
+// StatusNames is how the table spells each status code, the collector
+// writes the proto names while some exporters write Ok, Error and Unset
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatusNames {
+	pub ok: String,
+	pub error: String,
+	pub unset: String,
+}
+
+impl Default for StatusNames {
+	fn default() -> Self {
+		let name = |c: PBStatusCode| c.as_str_name().to_string();
+		Self {
+			ok: name(PBStatusCode::Ok),
+			error: name(PBStatusCode::Error),
+			unset: name(PBStatusCode::Unset),
+		}
+	}
+}
+
+impl StatusNames {
+	fn name(&self, s: StatusCode) -> &str {
+		match s {
+			StatusCode::Ok => &self.ok,
+			StatusCode::Err => &self.error,
+			StatusCode::Unset => &self.unset,
+		}
+	}
+}
+
+pub fn spanset_to_selection(
+	spanset: &SpanSet,
+	status: &StatusNames,
+) -> Selection {
 	match spanset {
 		SpanSet::Expr(expr) => {
 			// expand unscoped into (resource or span)
@@ -93,16 +132,16 @@ fn spanset_to_selection(spanset: &SpanSet) -> Selection {
 					operator: expr.operator,
 				});
 				return Selection::LogicalOr(
-					Box::new(spanset_to_selection(&left)),
-					Box::new(spanset_to_selection(&right)),
+					Box::new(spanset_to_selection(&left, status)),
+					Box::new(spanset_to_selection(&right, status)),
 				);
 			}
-			let c = field_expr_to_condition(expr);
+			let c = field_expr_to_condition(expr, status);
 			Selection::Unit(c)
 		}
 		SpanSet::Logical(left, op, right) => {
-			let l = spanset_to_selection(left);
-			let r = spanset_to_selection(right);
+			let l = spanset_to_selection(left, status);
+			let r = spanset_to_selection(right, status);
 			match op {
 				LogicalOperator::And => {
 					Selection::LogicalAnd(Box::new(l), Box::new(r))
@@ -162,22 +201,27 @@ fn construct_condition(
 	}
 }
 
-fn convert_status_code(s: StatusCode) -> PBStatusCode {
-	match s {
-		StatusCode::Err => PBStatusCode::Error,
-		StatusCode::Ok => PBStatusCode::Ok,
-		StatusCode::Unset => PBStatusCode::Unset,
+// the collector stores kinds by their proto names, e.g. SPAN_KIND_SERVER
+fn convert_span_kind(k: SpanKind) -> PBSpanKind {
+	match k {
+		SpanKind::Unspecified => PBSpanKind::Unspecified,
+		SpanKind::Internal => PBSpanKind::Internal,
+		SpanKind::Server => PBSpanKind::Server,
+		SpanKind::Client => PBSpanKind::Client,
+		SpanKind::Producer => PBSpanKind::Producer,
+		SpanKind::Consumer => PBSpanKind::Consumer,
 	}
 }
 
-fn field_expr_to_condition(expr: &FieldExpr) -> Condition {
+fn field_expr_to_condition(
+	expr: &FieldExpr,
+	status_names: &StatusNames,
+) -> Condition {
 	match &expr.kv {
 		FieldType::Intrinsic(intrisinc) => match intrisinc {
 			IntrisincField::Status(status) => construct_condition(
 				Column::Raw("StatusCode".to_string()),
-				PlaceValue::String(
-					convert_status_code(*status).as_str_name().to_string(),
-				),
+				PlaceValue::String(status_names.name(*status).to_string()),
 				expr.operator,
 			),
 			IntrisincField::Duraion(d) => construct_condition(
@@ -187,7 +231,9 @@ fn field_expr_to_condition(expr: &FieldExpr) -> Condition {
 			),
 			IntrisincField::Kind(kind) => construct_condition(
 				Column::Raw("SpanKind".to_string()),
-				PlaceValue::Integer((*kind).into()),
+				PlaceValue::String(
+					convert_span_kind(*kind).as_str_name().to_string(),
+				),
 				expr.operator,
 			),
 			IntrisincField::Name(name) => construct_condition(
@@ -227,14 +273,20 @@ fn field_value_to_place_value(f: &FieldValue) -> PlaceValue {
 		FieldValue::String(s) => PlaceValue::String(s.clone()),
 		FieldValue::Integer(i) => PlaceValue::Integer(*i),
 		FieldValue::Float(f) => PlaceValue::Float(*f),
+		// ck keeps attributes as strings
+		FieldValue::Bool(b) => PlaceValue::String(b.to_string()),
 		_ => unimplemented!("field value to place value"),
 	}
 }
 
+// spans a search returns when it isn't given a limit
+pub const DEFAULT_SEARCH_LIMIT: u32 = 500;
+
 pub struct ComplexQuery<T: TableSchema, C: QueryConverter> {
 	schema: T,
 	span_selections: Vec<QueryPlan<T, C>>,
 	trace_selections: SubQuery<T, C>,
+	limit: u32,
 }
 
 impl<T, C> ComplexQuery<T, C>
@@ -254,8 +306,13 @@ where
 			schema: schema.clone(),
 			span_selections: spans,
 			trace_selections,
+			limit: DEFAULT_SEARCH_LIMIT,
 		}
 	}
+	pub fn with_limit(mut self, limit: u32) -> Self {
+		self.limit = limit;
+		self
+	}
 	pub fn as_sql(&self) -> String {
 		let mut sql = format!(
 			"SELECT * FROM {} sp WHERE sp.{} GLOBAL IN (SELECT {} FROM (",
@@ -271,7 +328,7 @@ where
 		sql.push_str(&w);
 		sql.push_str(") AS sub WHERE ");
 		sql.push_str(self.trace_selections.as_sql().as_ref());
-		sql.push_str(") LIMIT 500");
+		sql.push_str(&format!(") LIMIT {}", self.limit));
 		sql
 	}
 }
@@ -282,12 +339,14 @@ pub fn single_spanset_query<T, C>(
 	projection: Vec<String>,
 	time_range: common::TimeRange,
 	converter: C,
+	status: &StatusNames,
+	limit: Option<u32>,
 ) -> String
 where
 	T: TableSchema,
 	C: QueryConverter,
 {
-	let selection = spanset_to_selection(spanset);
+	let selection = spanset_to_selection(spanset, status);
 	QueryPlan::new(
 		converter,
 		schema,
@@ -296,7 +355,92 @@ where
 		vec![],
 		vec![],
 		super::builder::time_range_into_timing(&time_range),
-		Some(500),
+		limit,
 	)
 	.as_sql()
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::builder::OrdType;
+	use chrono::NaiveDateTime;
+	use traceql::parse_traceql;
+
+	#[derive(Clone)]
+	struct Spans;
+
+	impl TableSchema for Spans {
+		fn table(&self) -> &str {
+			"spans"
+		}
+		fn ts_key(&self) -> &str {
+			"Timestamp"
+		}
+		fn msg_key(&self) -> &str {
+			""
+		}
+		fn level_key(&self) -> &str {
+			""
+		}
+		fn trace_key(&self) -> &str {
+			"TraceId"
+		}
+		fn span_id_key(&self) -> &str {
+			"SpanId"
+		}
+		fn resources_key(&self) -> &str {
+			"ResourceAttributes"
+		}
+		fn attributes_key(&self) -> &str {
+			"SpanAttributes"
+		}
+	}
+
+	#[derive(Clone)]
+	struct Conds;
+
+	impl QueryConverter for Conds {
+		fn convert_condition(&self, _: &Condition) -> String {
+			"c".to_string()
+		}
+		fn convert_timing(
+			&self,
+			_: &str,
+			_: &OrdType,
+			_: &NaiveDateTime,
+		) -> String {
+			"t".to_string()
+		}
+	}
+
+	#[test]
+	fn test_complex_query_limit() {
+		let expr = parse_traceql(r#"{name="query"}"#).unwrap();
+		let q = ComplexQuery::new(&expr, Spans, Conds);
+		assert!(q.as_sql().ends_with(") LIMIT 500"));
+		let q = q.with_limit(20);
+		assert!(q.as_sql().ends_with(") LIMIT 20"));
+	}
+
+	#[test]
+	fn test_single_spanset_limit() {
+		let expr = parse_traceql(r#"{name="query"}"#).unwrap();
+		let Expression::SpanSet(sp) = &expr else {
+			unreachable!()
+		};
+		let sql = |limit| {
+			single_spanset_query(
+				sp,
+				Spans,
+				vec!["SpanId".to_string()],
+				common::TimeRange::default(),
+				Conds,
+				&StatusNames::default(),
+				limit,
+			)
+		};
+		assert_eq!(sql(Some(20)), "SELECT SpanId FROM spans WHERE c LIMIT 20");
+		assert_eq!(sql(None), "SELECT SpanId FROM spans WHERE c");
+	}
+}
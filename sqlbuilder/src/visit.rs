@@ -9,6 +9,7 @@ pub const ATTRIBUTES_PREFIX: &str = "attributes_";
 pub trait IRVisitor {
 	fn label_pair(&self, label: &LabelPair) -> Condition;
 	fn log_filter(&self, filter: &LogLineFilter) -> Condition;
+	fn json_label(&self, label: &LabelPair) -> Condition;
 }
 
 pub struct LogQLVisitor<T> {
@@ -32,18 +33,24 @@ impl<T: IRVisitor> LogQLVisitor<T> {
 		labels.iter().map(|p| self.udf.label_pair(p)).collect()
 	}
 	fn visit_filters(&self, filters: &Option<Vec<Filter>>) -> Vec<Condition> {
-		if let Some(filters) = filters {
-			filters
-				.iter()
-				.filter_map(|f| match f {
-					Filter::LogLine(l) => Some(l),
-					_ => None,
-				})
-				.map(|l| self.udf.log_filter(l))
-				.collect()
-		} else {
-			vec![]
-		}
+		let Some(filters) = filters else {
+			return vec![];
+		};
+		// label filters before `| json` still refer to stream labels
+		let mut after_json = false;
+		filters
+			.iter()
+			.filter_map(|f| match f {
+				Filter::LogLine(l) => Some(self.udf.log_filter(l)),
+				Filter::Label(p) if after_json => Some(self.udf.json_label(p)),
+				Filter::Label(p) => Some(self.udf.label_pair(p)),
+				Filter::Json => {
+					after_json = true;
+					None
+				}
+				Filter::Drop(_) => None,
+			})
+			.collect()
 	}
 }
 
@@ -51,15 +58,16 @@ pub struct DefaultIRVisitor;
 
 impl IRVisitor for DefaultIRVisitor {
 	fn label_pair(&self, p: &LabelPair) -> Condition {
-		if matches!(p.label.to_lowercase().as_str(), "trace_id" | "traceid") {
+		let column = label_column(&p.label);
+		if column == Column::TraceID {
 			return Condition {
-				column: Column::TraceID,
+				column,
 				cmp: Cmp::Equal(PlaceValue::String(p.value.to_string())),
 			};
 		}
-		if matches!(p.label.to_lowercase().as_str(), "level" | "severitytext") {
+		if column == Column::Level {
 			return Condition {
-				column: Column::Level,
+				column,
 				cmp: match p.op {
 					Operator::NotEqual => {
 						Cmp::NotEqual(PlaceValue::String(p.value.to_string()))
@@ -77,19 +85,8 @@ impl IRVisitor for DefaultIRVisitor {
 			};
 		}
 		Condition {
-			column: maybe_nested_key(&p.label),
-			cmp: match p.op {
-				Operator::Equal => {
-					Cmp::Equal(PlaceValue::String(p.value.to_string()))
-				}
-				Operator::NotEqual => {
-					Cmp::NotEqual(PlaceValue::String(p.value.to_string()))
-				}
-				Operator::RegexMatch => Cmp::RegexMatch(p.value.to_string()),
-				Operator::RegexNotMatch => {
-					Cmp::RegexNotMatch(p.value.to_string())
-				}
-			},
+			column,
+			cmp: label_cmp(p),
 		}
 	}
 
@@ -109,6 +106,33 @@ impl IRVisitor for DefaultIRVisitor {
 			cmp,
 		}
 	}
+
+	fn json_label(&self, p: &LabelPair) -> Condition {
+		Condition {
+			column: Column::BodyJson(p.label.to_string()),
+			cmp: label_cmp(p),
+		}
+	}
+}
+
+fn label_cmp(p: &LabelPair) -> Cmp {
+	match p.op {
+		Operator::Equal => Cmp::Equal(PlaceValue::String(p.value.to_string())),
+		Operator::NotEqual => {
+			Cmp::NotEqual(PlaceValue::String(p.value.to_string()))
+		}
+		Operator::RegexMatch => Cmp::RegexMatch(p.value.to_string()),
+		Operator::RegexNotMatch => Cmp::RegexNotMatch(p.value.to_string()),
+	}
+}
+
+// label_column is the column a stream label is read from
+pub fn label_column(label: &str) -> Column {
+	match label.to_lowercase().as_str() {
+		"trace_id" | "traceid" => Column::TraceID,
+		"level" | "severitytext" => Column::Level,
+		_ => maybe_nested_key(label),
+	}
 }
 
 fn maybe_nested_key(key: &str) -> Column {
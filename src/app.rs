@@ -1,19 +1,57 @@
 use crate::{
-	config::AppConfig,
-	logquery, metrics, routes, state,
-	storage::{new_log_source, new_trace_source},
+	config::{AppConfig, ListenAddr, Runtime},
+	logquery::{self, label_names::LabelNames, warmup::WarmupProgress},
+	metrics, routes, state,
+	storage::{self, new_log_source, new_trace_source},
+	tenant::{Tenant, TenantSources},
+	tls, trace, utils,
 };
-use anyhow::Result;
-use std::{fs::OpenOptions, sync::Arc};
-use tracing::{debug, info};
+use anyhow::{anyhow, bail, Result};
+use axum::Router;
+use hyper_util::{
+	rt::{TokioExecutor, TokioIo},
+	server::conn::auto,
+	service::TowerToHyperService,
+};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+use std::{fs::OpenOptions, net::SocketAddr, sync::Arc, time::Duration};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::{
+	io::{AsyncRead, AsyncWrite},
+	net::TcpListener,
+	task::JoinSet,
+};
+use tracing::{debug, error, info};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
-use validator::Validate;
 
-pub async fn start() -> Result<()> {
-	// load configuration
-	let cfg = AppConfig::new().unwrap();
-	cfg.validate().unwrap();
+pub(crate) fn start(cfg: AppConfig) -> Result<()> {
+	runtime(&cfg)?.block_on(serve(cfg))
+}
+
+// the runtime is sized from the config, so it can't come from
+// #[tokio::main]. The process wide settings of the sources are set here
+// too, every subcommand builds them on this runtime
+pub(crate) fn runtime(cfg: &AppConfig) -> Result<tokio::runtime::Runtime> {
+	storage::set_http_pool(cfg.server.runtime.http_pool.clone());
+	utils::log::set_redaction(&cfg.server.log.redact);
+	build_runtime(&cfg.server.runtime)
+}
+
+fn build_runtime(cfg: &Runtime) -> Result<tokio::runtime::Runtime> {
+	let mut b = tokio::runtime::Builder::new_multi_thread();
+	b.enable_all();
+	if let Some(n) = cfg.worker_threads {
+		b.worker_threads(n);
+	}
+	if let Some(n) = cfg.max_blocking_threads {
+		b.max_blocking_threads(n);
+	}
+	Ok(b.build()?)
+}
 
+async fn serve(cfg: AppConfig) -> Result<()> {
 	init_tracing_subscriber(
 		cfg.server.log.file.clone(),
 		cfg.server.log.filter_directives.as_str(),
@@ -22,10 +60,14 @@ pub async fn start() -> Result<()> {
 	// init metrics
 	let metrics_handle = metrics::setup_metrcis();
 	// init cache
-	let cache = state::new_cache(&cfg.cache);
+	let cache = state::TenantCache::new(
+		state::new_cache(&cfg.cache),
+		&Tenant(cfg.tenant.default_tenant.clone()),
+	);
 
 	let trace_handle = new_trace_source(cfg.trace_source.clone()).await?;
 	let log_handle = new_log_source(cfg.log_source.clone()).await?;
+	let tenants = TenantSources::new(&cfg).await?;
 
 	let app_state = state::AppState {
 		config: Arc::new(cfg.clone()),
@@ -33,10 +75,45 @@ pub async fn start() -> Result<()> {
 		log_handle,
 		cache,
 		metrics: Arc::new(metrics_handle),
+		tenants: Arc::new(tenants),
+		deletes: Default::default(),
+		warmup: Arc::new(match cfg.warmup {
+			Some(_) => WarmupProgress::default(),
+			None => WarmupProgress::finished(),
+		}),
+		label_names: LabelNames::new(cfg.server.sanitize_label_names)
+			.with_service_label(cfg.server.service_label),
 	};
 	// build our application with a route
 	let app = routes::new_router(app_state.clone());
+	if let Some(addr) = cfg.server.grpc_listen_addr.clone() {
+		spawn_grpc(app_state.clone(), &addr, cfg.server.timeout)?;
+	}
+	if let Some(addr) = cfg.server.admin_listen_addr.clone() {
+		let listener = Listener::bind(&addr, None).await?;
+		info!("Listening for admin endpoints on: {}", addr);
+		let admin = routes::new_admin_router(app_state.clone());
+		tokio::spawn(async move {
+			if let Err(e) = listener.serve(admin).await {
+				error!("admin server stopped: {}", e);
+			}
+		});
+	}
+	// every address is bound before anything is served, one that can't be
+	// listened on fails the start
+	let tls = cfg.server.tls.clone().map(tls::spawn).transpose()?;
+	let mut listeners = vec![];
+	let addrs = std::iter::once(&cfg.server.listen_addr)
+		.chain(&cfg.server.extra_listen_addrs);
+	for addr in addrs {
+		listeners
+			.push((addr.clone(), Listener::bind(addr, tls.as_ref()).await?));
+	}
 
+	if let Some(w) = cfg.warmup.clone() {
+		tokio::spawn(logquery::warmup::run(app_state.clone(), w));
+	}
+	logquery::hot::spawn(app_state.clone());
 	// start a background task to refresh the series cache
 	// so that user won't wait for too long when cache is expired
 	if let Some(interval) = cfg.cache.refresh_interval {
@@ -48,13 +125,138 @@ pub async fn start() -> Result<()> {
 			.await;
 		});
 	}
-	// run our app with hyper, listening globally on port 3000
-	let listener =
-		tokio::net::TcpListener::bind(cfg.server.listen_addr.clone())
-			.await
-			.unwrap();
-	info!("Listening on: {}", cfg.server.listen_addr);
-	axum::serve(listener, app).await.unwrap();
+	let mut servers = JoinSet::new();
+	for (addr, listener) in listeners {
+		info!("Listening on: {}", addr);
+		let app = app.clone();
+		servers.spawn(async move { (addr, listener.serve(app).await) });
+	}
+	// the listeners never stop on their own, the first one that does takes
+	// the process down
+	match servers.join_next().await {
+		Some(Ok((addr, Err(e)))) => bail!("listener {} stopped: {}", addr, e),
+		Some(Ok((addr, Ok(())))) => bail!("listener {} stopped", addr),
+		Some(Err(e)) => Err(e.into()),
+		None => Ok(()),
+	}
+}
+
+enum Listener {
+	Tcp(TcpListener),
+	Tls(TcpListener, tls::Acceptor),
+	#[cfg(unix)]
+	Unix(UnixListener),
+}
+
+impl Listener {
+	async fn bind(addr: &str, tls: Option<&tls::Acceptor>) -> Result<Self> {
+		let addr = ListenAddr::parse(addr)
+			.ok_or_else(|| anyhow!("invalid listen address {}", addr))?;
+		match addr {
+			ListenAddr::Tcp(a) => {
+				let l = TcpListener::bind(a).await?;
+				Ok(match tls {
+					Some(tls) => Self::Tls(l, tls.clone()),
+					None => Self::Tcp(l),
+				})
+			}
+			#[cfg(unix)]
+			ListenAddr::Unix(path) => {
+				// the socket of a previous run is left behind when it
+				// wasn't shut down cleanly, other files are never removed
+				let stale = std::fs::symlink_metadata(&path)
+					.is_ok_and(|m| m.file_type().is_socket());
+				if stale {
+					std::fs::remove_file(&path)?;
+				}
+				Ok(Self::Unix(UnixListener::bind(path)?))
+			}
+			#[cfg(not(unix))]
+			ListenAddr::Unix(path) => {
+				bail!("can't listen on {}, no unix sockets", path.display())
+			}
+		}
+	}
+
+	// axum 0.7 only serves plain tcp, the other connections are served
+	// with hyper directly
+	async fn serve(self, app: Router) -> Result<()> {
+		match self {
+			Self::Tcp(l) => axum::serve(l, app).await?,
+			Self::Tls(l, tls) => loop {
+				let Some(socket) = accepted(l.accept().await).await else {
+					continue;
+				};
+				let (tls, app) = (tls.clone(), app.clone());
+				tokio::spawn(async move {
+					// a client that never finishes the handshake doesn't
+					// keep its connection open
+					let handshake = tls.accept(socket);
+					match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, handshake)
+						.await
+					{
+						Ok(Ok(stream)) => serve_connection(stream, app).await,
+						Ok(Err(e)) => debug!("tls handshake fails: {}", e),
+						Err(_) => debug!("tls handshake timed out"),
+					}
+				});
+			},
+			#[cfg(unix)]
+			Self::Unix(l) => loop {
+				if let Some(socket) = accepted(l.accept().await).await {
+					tokio::spawn(serve_connection(socket, app.clone()));
+				}
+			},
+		}
+		Ok(())
+	}
+}
+
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// an accept error is e.g. running out of file descriptors, like
+// axum::serve it waits for some to be released
+async fn accepted<S, A>(res: std::io::Result<(S, A)>) -> Option<S> {
+	match res {
+		Ok((socket, _)) => Some(socket),
+		Err(e) => {
+			error!("accept fails: {}", e);
+			tokio::time::sleep(Duration::from_secs(1)).await;
+			None
+		}
+	}
+}
+
+async fn serve_connection<IO>(io: IO, app: Router)
+where
+	IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+	let conn = auto::Builder::new(TokioExecutor::new())
+		.serve_connection_with_upgrades(
+			TokioIo::new(io),
+			TowerToHyperService::new(app),
+		)
+		.await;
+	if let Err(e) = conn {
+		debug!("connection closed: {}", e);
+	}
+}
+
+fn spawn_grpc(
+	state: state::AppState,
+	addr: &str,
+	timeout: Duration,
+) -> Result<()> {
+	let addr: SocketAddr = addr.parse()?;
+	let server = tonic::transport::Server::builder()
+		.timeout(timeout)
+		.add_service(trace::grpc::new_querier_server(state));
+	info!("Listening for grpc on: {}", addr);
+	tokio::spawn(async move {
+		if let Err(e) = server.serve(addr).await {
+			error!("grpc server stopped: {}", e);
+		}
+	});
 	Ok(())
 }
 
@@ -0,0 +1,302 @@
+use crate::{
+	app,
+	config::{AppConfig, DataSource},
+	storage::{
+		explain::{self, ExplainKind},
+		log::LogStorage,
+		new_log_source, new_trace_source, Direction, QueryLimits,
+	},
+	trace,
+};
+use anyhow::{bail, Result};
+use chrono::{TimeDelta, Utc};
+use clap::{Parser, Subcommand};
+use common::TimeRange;
+use logql::parser::{parse_logql_query, Query};
+use std::time::{Duration, Instant};
+
+// what bench-backend runs when no query is given
+const BENCH_LOGQL: [&str; 2] = [
+	r#"{level=~".+"}"#,
+	r#"sum by (level) (count_over_time({level=~".+"}[1m]))"#,
+];
+const BENCH_TRACEQL: [&str; 2] = ["{duration > 1s}", "{status = error}"];
+
+// the config is read from $LGTMRS_CONFIG, config.yaml by default
+#[derive(Parser)]
+#[command(
+	version,
+	about = "Loki and Tempo APIs over ClickHouse, Databend and Quickwit"
+)]
+struct Cli {
+	// same as check-config, from before the subcommands
+	#[arg(long, hide = true)]
+	validate_config: bool,
+	#[command(subcommand)]
+	command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+	/// Run the server, the default
+	Serve,
+	/// List every problem of the config, exits non-zero if there is any
+	CheckConfig,
+	/// Print the statements a query sends to the configured source, without
+	/// running them
+	PrintSql {
+		/// The query is TraceQL for the trace source instead of LogQL
+		#[arg(long)]
+		trace: bool,
+		/// How far back the query looks
+		#[arg(long, default_value = "1h", value_parser = humantime::parse_duration)]
+		since: Duration,
+		query: String,
+	},
+	/// Run a fixed set of queries against the sources and report latencies
+	BenchBackend {
+		#[arg(long, default_value_t = 10)]
+		iterations: usize,
+		#[arg(long, default_value = "1h", value_parser = humantime::parse_duration)]
+		since: Duration,
+		/// Replaces the default LogQL queries, may be repeated
+		#[arg(long)]
+		logql: Vec<String>,
+		/// Replaces the default TraceQL queries, may be repeated
+		#[arg(long)]
+		traceql: Vec<String>,
+	},
+}
+
+pub fn run() -> Result<()> {
+	let cli = Cli::parse();
+	// every problem found is listed before exiting
+	let cfg = AppConfig::load()?;
+	let command = match cli.command {
+		_ if cli.validate_config => Command::CheckConfig,
+		None => Command::Serve,
+		Some(c) => c,
+	};
+	match command {
+		Command::Serve => app::start(cfg),
+		Command::CheckConfig => {
+			println!("config ok");
+			Ok(())
+		}
+		Command::PrintSql {
+			trace,
+			since,
+			query,
+		} => app::runtime(&cfg)?.block_on(print_sql(cfg, trace, since, query)),
+		Command::BenchBackend {
+			iterations,
+			since,
+			logql,
+			traceql,
+		} => {
+			let logql = or_default(logql, &BENCH_LOGQL);
+			let traceql = or_default(traceql, &BENCH_TRACEQL);
+			app::runtime(&cfg)?
+				.block_on(bench(cfg, iterations, since, logql, traceql))
+		}
+	}
+}
+
+fn or_default(queries: Vec<String>, default: &[&str]) -> Vec<String> {
+	if queries.is_empty() {
+		default.iter().map(|q| q.to_string()).collect()
+	} else {
+		queries
+	}
+}
+
+fn limits(since: Duration) -> Result<QueryLimits> {
+	let end = Utc::now().naive_utc();
+	Ok(QueryLimits {
+		limit: Some(100),
+		range: TimeRange {
+			start: Some(end - TimeDelta::from_std(since)?),
+			end: Some(end),
+		},
+		direction: Some(Direction::Backward),
+		step: None,
+		spss: None,
+		unbounded: false,
+	})
+}
+
+// quickwit is queried with its own dsl, there's no sql to print
+fn sends_sql(d: &DataSource) -> bool {
+	match d {
+		#[cfg(feature = "quickwit")]
+		DataSource::Quickwit(_) => false,
+		#[cfg(feature = "databend")]
+		DataSource::Databend(_) => true,
+		#[cfg(feature = "clickhouse")]
+		DataSource::Clickhouse(_) => true,
+		DataSource::Fanout(f) => f.sources.iter().all(|s| sends_sql(&s.source)),
+		DataSource::Tiered(t) => sends_sql(&t.hot) && sends_sql(&t.archive),
+		DataSource::Shadow(s) => sends_sql(&s.primary) && sends_sql(&s.shadow),
+	}
+}
+
+async fn print_sql(
+	cfg: AppConfig,
+	trace: bool,
+	since: Duration,
+	query: String,
+) -> Result<()> {
+	let source = if trace {
+		&cfg.trace_source
+	} else {
+		&cfg.log_source
+	};
+	if !sends_sql(source) {
+		bail!(
+			"the source is or contains quickwit, which isn't queried with sql"
+		);
+	}
+	let opt = limits(since)?;
+	let (res, plans) = if trace {
+		let expr = traceql::parse_traceql(&query)?;
+		let expr = trace::prepare(expr, &cfg.server);
+		let source = new_trace_source(source.without_startup_tasks()).await?;
+		let search = async { source.search_span(&expr, opt).await.map(|_| ()) };
+		explain::collect(ExplainKind::Sql, search).await
+	} else {
+		let ql = parse_logql_query(&query)?;
+		let source = new_log_source(source.without_startup_tasks()).await?;
+		let run =
+			async { run_logql(source.as_ref(), &ql, opt).await.map(|_| ()) };
+		explain::collect(ExplainKind::Sql, run).await
+	};
+	res?;
+	for p in plans {
+		println!("{};\n", p.sql.trim());
+	}
+	Ok(())
+}
+
+// returns the number of lines or samples, a binary or rank query runs
+// each of its metric queries
+async fn run_logql(
+	source: &dyn LogStorage,
+	ql: &Query,
+	opt: QueryLimits,
+) -> Result<usize> {
+	match ql {
+		Query::LogQuery(q) => Ok(source.query_stream(q, opt).await?.len()),
+		_ => {
+			let mut rows = 0;
+			for mq in ql.metric_queries() {
+				rows += source.query_metrics(mq, opt.clone()).await?.len();
+			}
+			Ok(rows)
+		}
+	}
+}
+
+#[derive(Debug, Default)]
+struct Report {
+	latencies: Vec<Duration>,
+	errors: usize,
+	rows: usize,
+}
+
+impl Report {
+	fn record(&mut self, start: Instant, res: Result<usize>) {
+		match res {
+			Ok(rows) => {
+				self.latencies.push(start.elapsed());
+				self.rows = rows;
+			}
+			Err(e) => {
+				self.errors += 1;
+				eprintln!("  {:#}", e);
+			}
+		}
+	}
+	fn print(mut self, query: &str) {
+		self.latencies.sort();
+		let l = &self.latencies;
+		println!(
+			"{}\n  ok={} errors={} rows={} p50={:.1?} p95={:.1?} max={:.1?}",
+			query,
+			l.len(),
+			self.errors,
+			self.rows,
+			percentile(l, 0.5),
+			percentile(l, 0.95),
+			l.last().copied().unwrap_or_default(),
+		);
+	}
+}
+
+// nearest rank, latencies is sorted
+fn percentile(latencies: &[Duration], p: f64) -> Duration {
+	if latencies.is_empty() {
+		return Duration::ZERO;
+	}
+	let rank = (latencies.len() as f64 * p).ceil() as usize;
+	latencies[rank.clamp(1, latencies.len()) - 1]
+}
+
+// queries run one after another so the latencies are the backend's, not
+// the result of them competing with each other
+async fn bench(
+	cfg: AppConfig,
+	iterations: usize,
+	since: Duration,
+	log_queries: Vec<String>,
+	trace_queries: Vec<String>,
+) -> Result<()> {
+	let logs = new_log_source(cfg.log_source.without_startup_tasks()).await?;
+	for q in &log_queries {
+		let ql = parse_logql_query(q)?;
+		let mut report = Report::default();
+		for _ in 0..iterations {
+			let start = Instant::now();
+			let res = run_logql(logs.as_ref(), &ql, limits(since)?).await;
+			report.record(start, res);
+		}
+		report.print(q);
+	}
+	let traces =
+		new_trace_source(cfg.trace_source.without_startup_tasks()).await?;
+	for q in &trace_queries {
+		let expr = trace::prepare(traceql::parse_traceql(q)?, &cfg.server);
+		let mut report = Report::default();
+		for _ in 0..iterations {
+			let start = Instant::now();
+			let res = traces.search_span(&expr, limits(since)?).await;
+			report.record(start, res.map(|spans| spans.len()));
+		}
+		report.print(q);
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_percentile() {
+		let ms = Duration::from_millis;
+		let l: Vec<_> = (1..=20).map(ms).collect();
+		assert_eq!(percentile(&l, 0.5), ms(10));
+		assert_eq!(percentile(&l, 0.95), ms(19));
+		assert_eq!(percentile(&l[..1], 0.95), ms(1));
+		assert_eq!(percentile(&[], 0.5), Duration::ZERO);
+	}
+
+	#[test]
+	fn test_default_queries_parse() {
+		for q in BENCH_LOGQL {
+			assert!(parse_logql_query(q).is_ok(), "{}", q);
+		}
+		for q in BENCH_TRACEQL {
+			assert!(traceql::parse_traceql(q).is_ok(), "{}", q);
+		}
+	}
+}
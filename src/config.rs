@@ -1,16 +1,35 @@
+use common::level::LevelMapping;
 use config::{Config, ConfigError, File};
 use serde::Deserialize;
-use std::{env, net::SocketAddr, str::FromStr, time::Duration};
+use std::{
+	collections::{BTreeMap, HashMap, HashSet},
+	env, fmt,
+	net::SocketAddr,
+	path::PathBuf,
+	str::FromStr,
+	time::Duration,
+};
 use tracing_subscriber::filter::Builder;
-use validator::{Validate, ValidationError};
+use validator::{
+	Validate, ValidationError, ValidationErrors, ValidationErrorsKind,
+};
 
 #[derive(Clone, Deserialize, Validate)]
+#[validate(schema(function = "validate_timeouts"))]
 pub struct AppConfig {
 	#[validate(nested)]
 	pub server: Server,
 	#[serde(default = "default_cache")]
 	#[validate(nested)]
 	pub cache: Cache,
+	#[serde(default)]
+	pub limits: Limits,
+	#[serde(default)]
+	pub tenant: Tenant,
+	#[serde(default)]
+	pub warmup: Option<Warmup>,
+	#[serde(default)]
+	pub hot_queries: Vec<HotQuery>,
 	pub log_source: DataSource,
 	pub trace_source: DataSource,
 }
@@ -28,6 +47,200 @@ pub struct Cache {
 	pub refresh_interval: Option<Duration>,
 }
 
+#[derive(Clone, Deserialize)]
+pub struct Limits {
+	// same as loki's max_query_series
+	#[serde(default = "default_max_series")]
+	pub max_series: usize,
+	// largest k topk and bottomk accept
+	#[serde(default = "default_max_rank_k")]
+	pub max_rank_k: usize,
+	// how far back labels, label values and series look when the
+	// request carries neither start nor since
+	#[serde(with = "humantime_serde", default = "default_label_lookback")]
+	pub label_lookback: Duration,
+	// serialized size a single response may reach, 0 disables the check
+	#[serde(default = "default_max_response_bytes")]
+	pub max_response_bytes: usize,
+	// a trace export keeps this much in memory, the rest goes to a temp
+	// file in export_spill_dir, the system's temp dir by default
+	#[serde(default = "default_export_memory_bytes")]
+	pub export_memory_bytes: usize,
+	#[serde(default)]
+	pub export_spill_dir: Option<PathBuf>,
+	// an export may take longer than server.timeout, it reads whole traces
+	#[serde(with = "humantime_serde", default = "default_export_timeout")]
+	pub export_timeout: Duration,
+	// a larger body, e.g. of a POST query_range or series, gets a 413
+	#[serde(default = "default_max_request_body_bytes")]
+	pub max_request_body_bytes: usize,
+	// more headers get a 431. hyper refuses more than 100 on its own, so
+	// only a lower value changes anything
+	#[serde(default = "default_max_request_headers")]
+	pub max_request_headers: usize,
+	// path and query string, a longer one gets a 414
+	#[serde(default = "default_max_uri_bytes")]
+	pub max_uri_bytes: usize,
+	// log labels and span attributes are cut to this many chars in query
+	// responses, 0 keeps them whole
+	#[serde(default)]
+	pub max_attribute_length: usize,
+}
+
+impl Default for Limits {
+	fn default() -> Self {
+		Self {
+			max_series: default_max_series(),
+			max_rank_k: default_max_rank_k(),
+			label_lookback: default_label_lookback(),
+			max_response_bytes: default_max_response_bytes(),
+			export_memory_bytes: default_export_memory_bytes(),
+			export_spill_dir: None,
+			export_timeout: default_export_timeout(),
+			max_request_body_bytes: default_max_request_body_bytes(),
+			max_request_headers: default_max_request_headers(),
+			max_uri_bytes: default_max_uri_bytes(),
+			max_attribute_length: 0,
+		}
+	}
+}
+
+const fn default_max_series() -> usize {
+	500
+}
+
+const fn default_max_rank_k() -> usize {
+	100
+}
+
+const fn default_label_lookback() -> Duration {
+	Duration::from_secs(2 * 60 * 60)
+}
+
+const fn default_max_response_bytes() -> usize {
+	64 << 20
+}
+
+const fn default_export_memory_bytes() -> usize {
+	32 << 20
+}
+
+const fn default_export_timeout() -> Duration {
+	Duration::from_secs(10 * 60)
+}
+
+const fn default_max_request_body_bytes() -> usize {
+	2 << 20
+}
+
+const fn default_max_request_headers() -> usize {
+	100
+}
+
+const fn default_max_uri_bytes() -> usize {
+	64 << 10
+}
+
+// work done before /ready reports ok, so a restarted instance
+// doesn't answer grafana with cold caches
+#[derive(Clone, Deserialize, Debug, PartialEq, Eq)]
+pub struct Warmup {
+	// fetch label names and the values of each of them
+	#[serde(default = "default_true")]
+	pub labels: bool,
+	// usually the panels of the busiest dashboards
+	#[serde(default)]
+	pub queries: Vec<WarmupQuery>,
+}
+
+#[derive(Clone, Deserialize, Debug, PartialEq, Eq)]
+pub struct WarmupQuery {
+	pub query: String,
+	// the query covers [now - range, now]
+	#[serde(with = "humantime_serde", default = "default_warmup_range")]
+	pub range: Duration,
+	#[serde(with = "humantime_serde", default)]
+	pub step: Option<Duration>,
+}
+
+// a dashboard panel refreshed in the background, query_range requests
+// with the same query and step are answered from its last run
+#[derive(Clone, Deserialize, Debug, PartialEq, Eq)]
+pub struct HotQuery {
+	// metric queries only, a limited log query can't answer a sub range
+	pub query: String,
+	#[serde(with = "humantime_serde", default = "default_warmup_range")]
+	pub range: Duration,
+	#[serde(with = "humantime_serde", default)]
+	pub step: Option<Duration>,
+	// also how stale an answer may be, keep it below cache.time_to_live
+	#[serde(with = "humantime_serde", default = "default_hot_interval")]
+	pub interval: Duration,
+}
+
+const fn default_hot_interval() -> Duration {
+	Duration::from_secs(60)
+}
+
+const fn default_true() -> bool {
+	true
+}
+
+const fn default_warmup_range() -> Duration {
+	Duration::from_secs(3600)
+}
+
+#[derive(Clone, Deserialize, Debug, PartialEq, Eq)]
+pub struct Tenant {
+	// checked in order, the first one present wins
+	#[serde(default = "default_tenant_headers")]
+	pub headers: Vec<String>,
+	#[serde(default)]
+	pub default_policy: DefaultTenantPolicy,
+	// used when none of the headers is sent and the policy is fallback
+	#[serde(default = "default_tenant_id")]
+	pub default_tenant: String,
+	#[serde(default)]
+	pub overrides: HashMap<String, TenantOverride>,
+}
+
+impl Default for Tenant {
+	fn default() -> Self {
+		Self {
+			headers: default_tenant_headers(),
+			default_policy: DefaultTenantPolicy::default(),
+			default_tenant: default_tenant_id(),
+			overrides: HashMap::new(),
+		}
+	}
+}
+
+#[derive(Clone, Copy, Deserialize, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DefaultTenantPolicy {
+	#[default]
+	Fallback,
+	Reject,
+}
+
+// where the data of a tenant lives, unset fields keep the source's value
+#[derive(Clone, Deserialize, Debug, PartialEq, Eq, Default)]
+pub struct TenantOverride {
+	pub database: Option<String>,
+	// table for clickhouse, index for quickwit
+	pub log_table: Option<String>,
+	pub trace_table: Option<String>,
+}
+
+fn default_tenant_headers() -> Vec<String> {
+	vec!["X-Scope-OrgID".to_string()]
+}
+
+// same as loki with auth disabled
+fn default_tenant_id() -> String {
+	"fake".to_string()
+}
+
 fn validate_cache_config(cfg: &Cache) -> Result<(), ValidationError> {
 	if cfg.time_to_idle > cfg.time_to_live {
 		return Err(ValidationError::new(
@@ -44,6 +257,21 @@ fn validate_cache_config(cfg: &Cache) -> Result<(), ValidationError> {
 	Ok(())
 }
 
+// a backend still running after the server gave up on the request
+// only wastes resources, so none may wait longer than server.timeout
+fn validate_timeouts(cfg: &AppConfig) -> Result<(), ValidationError> {
+	let too_long = [&cfg.log_source, &cfg.trace_source]
+		.into_iter()
+		.flat_map(|s| s.query_timeouts())
+		.any(|t| t.is_some_and(|t| t > cfg.server.timeout));
+	if too_long {
+		return Err(ValidationError::new(
+			"query timeout of a source must be no greater than server.timeout",
+		));
+	}
+	Ok(())
+}
+
 const fn default_cache() -> Cache {
 	Cache {
 		max_capacity: default_cache_max_capacity(),
@@ -68,6 +296,9 @@ pub struct Log {
 	// see https://docs.rs/tracing-subscriber/latest/tracing_subscriber/filter/struct.EnvFilter.html#directives
 	#[validate(custom(function = "validate_log_filter_directives"))]
 	pub filter_directives: String,
+	#[serde(default)]
+	#[validate(nested)]
+	pub redact: Redact,
 }
 
 impl Default for Log {
@@ -75,8 +306,31 @@ impl Default for Log {
 		Self {
 			file: "info.log".to_string(),
 			filter_directives: "info".to_string(),
+			redact: Redact::default(),
+		}
+	}
+}
+
+// masking of what ends up in the log: request query strings, the sql
+// sent to ck and the responses that fail to parse
+#[derive(Clone, Deserialize, Debug, Default, Validate)]
+pub struct Redact {
+	// regexes whose matches are replaced by ***
+	#[serde(default)]
+	#[validate(custom(function = "validate_regexes"))]
+	pub patterns: Vec<String>,
+	// replace every string literal of the logged sql
+	#[serde(default)]
+	pub sql_literals: bool,
+}
+
+fn validate_regexes(patterns: &Vec<String>) -> Result<(), ValidationError> {
+	for p in patterns {
+		if regex::Regex::new(p).is_err() {
+			return Err(ValidationError::new("invalid redact pattern"));
 		}
 	}
+	Ok(())
 }
 
 fn validate_log_filter_directives(dirs: &str) -> Result<(), ValidationError> {
@@ -86,15 +340,29 @@ fn validate_log_filter_directives(dirs: &str) -> Result<(), ValidationError> {
 		.map(|_| ())
 }
 
+#[cfg(feature = "quickwit")]
 #[derive(Clone, Deserialize, PartialEq, Eq, Debug)]
 pub struct Quickwit {
 	pub domain: String,
 	pub index: String,
-	#[serde(with = "humantime_serde")]
-	#[serde(default = "default_query_timeout")]
-	pub timeout: Duration, // seconds
+	// defaults to server.timeout
+	#[serde(default, with = "humantime_serde")]
+	pub timeout: Option<Duration>,
+	// fields whose values are combined into series
+	#[serde(default = "default_series_labels")]
+	pub series_labels: Vec<String>,
+	#[serde(default)]
+	pub http: HttpClient,
+	#[serde(default)]
+	pub levels: LevelMapping,
+}
+
+#[cfg(feature = "quickwit")]
+fn default_series_labels() -> Vec<String> {
+	vec!["service_name".to_string(), "level".to_string()]
 }
 
+#[cfg(feature = "databend")]
 #[derive(Clone, Deserialize, PartialEq, Eq, Debug)]
 pub struct Databend {
 	#[serde(default = "default_driver")]
@@ -109,38 +377,270 @@ pub struct Databend {
 	#[serde(with = "humantime_serde")]
 	#[serde(default = "default_connect_timeout")]
 	pub connect_timeout: Duration, // seconds
+	// defaults to server.timeout
+	#[serde(default, with = "humantime_serde")]
+	pub query_timeout: Option<Duration>,
 	#[serde(default)]
 	pub inverted_index: bool,
+	// line filters with a shorter word don't use the index
+	#[serde(default = "default_min_token_len")]
+	pub inverted_index_min_token_len: usize,
+	// stop decoding a log query once the rows read so far exceed this size
+	#[serde(default = "default_max_result_bytes")]
+	pub max_result_bytes: usize,
+	#[serde(default)]
+	pub schema_check: SchemaCheck,
+	#[serde(default)]
+	pub bootstrap: bool,
+	#[serde(default)]
+	pub retention: Option<Retention>,
+	// the session timezone, timestamps are read and written in it
+	#[serde(default = "default_timezone")]
+	pub timezone: chrono_tz::Tz,
+	// keep one row of a span stored twice, only trace_source may set it
+	#[serde(default)]
+	pub dedup_spans: bool,
+	// the level column holds SeverityNumber, there's no text to match
+	#[serde(default)]
+	pub levels: LevelMapping,
 }
 
-#[derive(Clone, Deserialize, PartialEq, Eq, Debug)]
+// how the bridge connects to a backend, the pool settings override
+// server.runtime.http_pool for this source
+#[derive(Clone, Deserialize, PartialEq, Eq, Debug, Default)]
+pub struct HttpClient {
+	pub pool_max_idle_per_host: Option<usize>,
+	#[serde(default, with = "humantime_serde")]
+	pub pool_idle_timeout: Option<Duration>,
+	// start with h2 instead of negotiating, the backend has to serve
+	// http/2 on that port
+	#[serde(default)]
+	pub http2_prior_knowledge: bool,
+	#[serde(default, with = "humantime_serde")]
+	pub tcp_keepalive: Option<Duration>,
+}
+
+#[cfg(feature = "clickhouse")]
+#[derive(Clone, Deserialize, PartialEq, Eq, Debug, Default)]
 pub struct Clickhouse {
 	pub url: String,
 	pub database: String,
 	pub username: String,
 	pub password: String,
 	pub table: String,
+	// sent with every query, e.g. max_execution_time, max_threads
+	#[serde(default)]
+	pub clickhouse_settings: BTreeMap<String, serde_json::Value>,
+	// append the settings as a SETTINGS clause instead of url parameters,
+	// for proxies in front of ck that drop unknown parameters
+	#[serde(default)]
+	pub settings_clause: bool,
+	// read with FINAL, for ReplacingMergeTree tables
+	#[serde(default, rename = "final")]
+	pub use_final: bool,
+	// cap for queries that don't carry a limit, ck stops reading once hit
+	#[serde(default = "default_ck_max_result_rows")]
+	pub max_result_rows: u32,
+	#[serde(default = "default_ck_max_result_bytes")]
+	pub max_result_bytes: u64,
+	#[serde(default)]
+	pub schema_version: SchemaVersion,
+	#[serde(default)]
+	pub schema_check: SchemaCheck,
+	// create the tables at startup if they don't exist
+	#[serde(default)]
+	pub bootstrap: bool,
+	#[serde(default)]
+	pub retention: Option<Retention>,
+	#[serde(default)]
+	pub http: HttpClient,
+	// defaults to server.timeout
+	#[serde(default, with = "humantime_serde")]
+	pub query_timeout: Option<Duration>,
+	#[serde(default)]
+	pub hedge: Option<Hedge>,
+}
+
+// send a query again when it hasn't answered within delay, the first
+// answer wins and the other query is cancelled
+#[cfg(feature = "clickhouse")]
+#[derive(Clone, Deserialize, PartialEq, Eq, Debug)]
+pub struct Hedge {
+	// around the p95 latency, lower sends more queries twice
+	#[serde(with = "humantime_serde")]
+	pub delay: Duration,
+	// another replica for the second query, url of the source by default
+	#[serde(default)]
+	pub url: Option<String>,
+}
+
+// how long the tables keep data, applied by the bridge at startup
+#[derive(Clone, Deserialize, PartialEq, Eq, Debug)]
+pub struct Retention {
+	#[serde(with = "humantime_serde")]
+	pub ttl: Duration,
+	// how often to run OPTIMIZE (ck) or delete expired rows and purge
+	// (databend), ck drops expired rows on merges even without it
+	#[serde(with = "humantime_serde", default)]
+	pub maintenance_interval: Option<Duration>,
+}
+
+// what to do at startup when the tables lack columns the bridge reads
+#[derive(Clone, Copy, Deserialize, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SchemaCheck {
+	Off,
+	#[default]
+	Warn,
+	Fail,
+}
+
+// hasToken only finds words split by ascii separators, a CJK sentence is
+// one big token to it
+#[cfg(feature = "clickhouse")]
+#[derive(Clone, Copy, Deserialize, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum NonAsciiFilter {
+	// the body is tokenized upstream, inner words of a phrase still go
+	// through hasToken first
+	Token,
+	// position(), no index can help
+	#[default]
+	Position,
+	// LIKE, which an ngrambf_v1 index on the body can skip granules for
+	Like,
+}
+
+// layout of the tables created by otel-collector's clickhouse exporter
+#[cfg(feature = "clickhouse")]
+#[derive(Clone, Copy, Deserialize, PartialEq, Eq, Debug, Default)]
+pub enum SchemaVersion {
+	#[default]
+	#[serde(rename = "v0.90")]
+	V0_90,
+	// logs are sorted by a second precision TimestampTime column
+	#[serde(rename = "v0.100")]
+	V0_100,
+	// v0.100 with attributes in the JSON type instead of Map,
+	// what the exporter writes with json: true
+	#[serde(rename = "json")]
+	Json,
 }
 
+#[cfg(feature = "clickhouse")]
 #[derive(Clone, Deserialize, PartialEq, Eq, Debug)]
 pub struct ClickhouseTrace {
 	#[serde(flatten)]
 	pub common: Clickhouse,
 	pub trace_ts_table: String,
+	// split the span query of a long trace into hourly queries run in parallel
+	#[serde(default)]
+	pub shard_by_hour: bool,
+	#[serde(default)]
+	pub status_codes: StatusCodeNames,
+	// limit of a search that doesn't ask for one, and the largest one
+	// asked for that's honored
+	#[serde(default = "default_search_limit")]
+	pub default_search_limit: u32,
+	#[serde(default = "default_max_search_limit")]
+	pub max_search_limit: u32,
+	// keep one row of a span stored twice, e.g. by a collector retry
+	#[serde(default)]
+	pub dedup_spans: bool,
+}
+
+#[cfg(feature = "clickhouse")]
+impl ClickhouseTrace {
+	pub fn search_limit(&self, requested: Option<u32>) -> u32 {
+		requested
+			.unwrap_or(self.default_search_limit)
+			.min(self.max_search_limit)
+	}
+}
+
+#[cfg(feature = "clickhouse")]
+fn default_search_limit() -> u32 {
+	500
 }
 
+#[cfg(feature = "clickhouse")]
+fn default_max_search_limit() -> u32 {
+	5000
+}
+
+// spelling of the StatusCode column, status = error is compared against it
+#[cfg(feature = "clickhouse")]
+#[derive(Clone, Deserialize, PartialEq, Eq, Debug)]
+#[serde(default)]
+pub struct StatusCodeNames {
+	pub ok: String,
+	pub error: String,
+	pub unset: String,
+}
+
+#[cfg(feature = "clickhouse")]
+impl Default for StatusCodeNames {
+	fn default() -> Self {
+		Self {
+			ok: "STATUS_CODE_OK".to_string(),
+			error: "STATUS_CODE_ERROR".to_string(),
+			unset: "STATUS_CODE_UNSET".to_string(),
+		}
+	}
+}
+
+#[cfg(feature = "clickhouse")]
 #[derive(Clone, Deserialize, PartialEq, Eq, Debug)]
 pub struct CKLogLabel {
 	#[serde(rename = "resources", default = "empty_vec")]
 	pub resource_attributes: Vec<String>,
 	#[serde(rename = "attributes", default = "empty_vec")]
 	pub log_attributes: Vec<String>,
+	// learn more keys from the table, in addition to the listed ones
+	#[serde(default)]
+	pub discovery: Option<LabelDiscovery>,
+	// values kept per label, a label with more is reported as
+	// high cardinality and left out of series
+	#[serde(default = "default_max_label_values")]
+	pub max_values: usize,
+}
+
+#[cfg(feature = "clickhouse")]
+const fn default_max_label_values() -> usize {
+	1000
+}
+
+#[cfg(feature = "clickhouse")]
+#[derive(Clone, Deserialize, PartialEq, Eq, Debug)]
+pub struct LabelDiscovery {
+	#[serde(with = "humantime_serde", default = "default_discovery_interval")]
+	pub interval: Duration,
+	// how far back each round looks for keys
+	#[serde(with = "humantime_serde", default = "default_discovery_lookback")]
+	pub lookback: Duration,
+	// globs matched against the key, an empty include list accepts all
+	#[serde(default)]
+	pub include: Vec<String>,
+	#[serde(default)]
+	pub exclude: Vec<String>,
+}
+
+#[cfg(feature = "clickhouse")]
+const fn default_discovery_interval() -> Duration {
+	Duration::from_secs(10 * 60)
+}
+
+#[cfg(feature = "clickhouse")]
+const fn default_discovery_lookback() -> Duration {
+	Duration::from_secs(15 * 60)
 }
 
+#[cfg(feature = "clickhouse")]
 fn empty_vec() -> Vec<String> {
 	vec![]
 }
 
+#[cfg(feature = "clickhouse")]
 #[derive(Clone, Deserialize, PartialEq, Eq, Debug)]
 pub struct ClickhouseLog {
 	#[serde(flatten)]
@@ -149,13 +649,109 @@ pub struct ClickhouseLog {
 	pub replace_dash_to_dot: Option<bool>,
 	#[serde(default = "default_log_level")]
 	pub default_log_level: String,
+	// maps severities to levels before default_log_level applies
+	#[serde(default)]
+	pub levels: LevelMapping,
 	pub level_case_sensitive: Option<bool>,
+	// filter levels on SeverityNumber instead of SeverityText
+	pub level_by_number: Option<bool>,
+	// how line filters with non-ascii text, e.g. CJK, are matched
+	#[serde(default)]
+	pub non_ascii_filter: NonAsciiFilter,
+	// read archived files through ck's s3 table function instead of table
+	#[serde(default)]
+	pub s3: Option<S3Archive>,
+	#[serde(default)]
+	pub rollup: Option<Rollup>,
+	#[serde(default)]
+	pub value_index: Option<ValueIndex>,
+	// read from time partitioned tables instead of `table`
+	#[serde(default)]
+	pub partitions: Option<Partitions>,
+}
+
+#[cfg(feature = "clickhouse")]
+#[derive(Clone, Deserialize, PartialEq, Eq, Debug)]
+pub struct Partitions {
+	// table name with {yyyy}, {mm}, {dd} and optionally {hh}, in UTC
+	pub template: String,
+	// queries spanning more tables are rejected
+	#[serde(default = "default_max_partitions")]
+	pub max_tables: usize,
+}
+
+#[cfg(feature = "clickhouse")]
+const fn default_max_partitions() -> usize {
+	31
+}
+
+// bloom filters of the values a few selective labels had recently,
+// queries matching a value that surely doesn't exist skip ck
+#[cfg(feature = "clickhouse")]
+#[derive(Clone, Deserialize, PartialEq, Eq, Debug)]
+pub struct ValueIndex {
+	// logql label names, e.g. resources_k8s.pod.name
+	pub labels: Vec<String>,
+	#[serde(with = "humantime_serde", default = "default_value_index_refresh")]
+	pub refresh_interval: Duration,
+	// only queries within this window of the last refresh are checked
+	#[serde(
+		with = "humantime_serde",
+		default = "default_value_index_lookback"
+	)]
+	pub lookback: Duration,
+}
+
+#[cfg(feature = "clickhouse")]
+const fn default_value_index_refresh() -> Duration {
+	Duration::from_secs(5 * 60)
+}
+
+#[cfg(feature = "clickhouse")]
+const fn default_value_index_lookback() -> Duration {
+	Duration::from_secs(60 * 60)
+}
+
+// per-minute counts by service and level, kept by a materialized view,
+// log volume queries with a large step read it instead of the raw logs
+#[cfg(feature = "clickhouse")]
+#[derive(Clone, Deserialize, PartialEq, Eq, Debug)]
+pub struct Rollup {
+	pub table: String,
+	// smaller steps, or queries filtering on more than service and level,
+	// read the raw table
+	#[serde(with = "humantime_serde", default = "default_rollup_min_step")]
+	pub min_step: Duration,
+}
+
+#[cfg(feature = "clickhouse")]
+const fn default_rollup_min_step() -> Duration {
+	Duration::from_secs(5 * 60)
+}
+
+#[cfg(feature = "clickhouse")]
+#[derive(Clone, Deserialize, PartialEq, Eq, Debug)]
+pub struct S3Archive {
+	// may contain globs, e.g. https://bucket.s3.amazonaws.com/logs/*/*.parquet
+	pub url: String,
+	// without keys ck uses its own credentials
+	pub access_key_id: Option<String>,
+	pub secret_access_key: Option<String>,
+	#[serde(default = "default_s3_format")]
+	pub format: String,
+}
+
+#[cfg(feature = "clickhouse")]
+fn default_s3_format() -> String {
+	"Parquet".to_string()
 }
 
+#[cfg(feature = "clickhouse")]
 fn default_log_level() -> String {
 	"info".to_string()
 }
 
+#[cfg(feature = "clickhouse")]
 #[derive(Clone, Deserialize, PartialEq, Eq, Debug)]
 pub enum ClickhouseConf {
 	#[serde(rename = "trace")]
@@ -166,30 +762,467 @@ pub enum ClickhouseConf {
 
 #[derive(Clone, Deserialize, PartialEq, Eq, Debug)]
 pub enum DataSource {
+	#[cfg(feature = "databend")]
 	#[serde(rename = "databend")]
 	Databend(Databend),
+	#[cfg(feature = "quickwit")]
 	#[serde(rename = "quickwit")]
 	Quickwit(Quickwit),
+	#[cfg(feature = "clickhouse")]
 	#[serde(rename = "clickhouse")]
 	Clickhouse(ClickhouseConf),
+	// query several sources at once, e.g. while migrating between backends
+	#[serde(rename = "fanout")]
+	Fanout(Fanout),
+	// recent logs from hot, older ones from archive
+	#[serde(rename = "tiered")]
+	Tiered(Tiered),
+	// answer from primary and compare with shadow in the background
+	#[serde(rename = "shadow")]
+	Shadow(Shadow),
 }
 
+#[derive(Clone, Deserialize, PartialEq, Eq, Debug)]
+pub struct Shadow {
+	pub primary: Box<DataSource>,
+	pub shadow: Box<DataSource>,
+	// shadow reads running at the same time, more are skipped
+	#[serde(default = "default_shadow_inflight")]
+	pub max_inflight: usize,
+}
+
+const fn default_shadow_inflight() -> usize {
+	16
+}
+
+#[derive(Clone, Deserialize, PartialEq, Eq, Debug)]
+pub struct Tiered {
+	pub hot: Box<DataSource>,
+	pub archive: Box<DataSource>,
+	// how far back hot keeps data, anything older is read from archive
+	#[serde(with = "humantime_serde")]
+	pub hot_retention: Duration,
+}
+
+#[derive(Clone, Deserialize, PartialEq, Eq, Debug)]
+pub struct Fanout {
+	pub sources: Vec<NamedSource>,
+	#[serde(default)]
+	pub merge: MergeStrategy,
+}
+
+#[derive(Clone, Deserialize, PartialEq, Eq, Debug)]
+pub struct NamedSource {
+	// value of the __source__ label of the streams read from it
+	pub name: String,
+	#[serde(flatten)]
+	pub source: DataSource,
+}
+
+#[derive(Clone, Copy, Deserialize, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MergeStrategy {
+	// keep everything each source returns
+	#[default]
+	Union,
+	// the sources hold copies of the same logs, drop lines already
+	// returned by an earlier source
+	Dedup,
+}
+
+impl DataSource {
+	// the key of the source in the config, sources are created by the
+	// factory registered under it
+	pub fn tag(&self) -> &'static str {
+		match self {
+			#[cfg(feature = "databend")]
+			DataSource::Databend(_) => "databend",
+			#[cfg(feature = "quickwit")]
+			DataSource::Quickwit(_) => "quickwit",
+			#[cfg(feature = "clickhouse")]
+			DataSource::Clickhouse(_) => "clickhouse",
+			DataSource::Fanout(_) => "fanout",
+			DataSource::Tiered(_) => "tiered",
+			DataSource::Shadow(_) => "shadow",
+		}
+	}
+
+	pub fn with_override(
+		&self,
+		database: Option<&String>,
+		table: Option<&String>,
+	) -> Self {
+		let mut d = self.clone();
+		match &mut d {
+			#[cfg(feature = "databend")]
+			DataSource::Databend(cfg) => {
+				if let Some(db) = database {
+					cfg.database = db.clone();
+				}
+			}
+			#[cfg(feature = "quickwit")]
+			DataSource::Quickwit(cfg) => {
+				if let Some(index) = table {
+					cfg.index = index.clone();
+				}
+			}
+			#[cfg(feature = "clickhouse")]
+			DataSource::Clickhouse(ClickhouseConf::Log(ClickhouseLog {
+				common,
+				..
+			}))
+			| DataSource::Clickhouse(ClickhouseConf::Trace(
+				ClickhouseTrace { common, .. },
+			)) => {
+				if let Some(db) = database {
+					common.database = db.clone();
+				}
+				if let Some(t) = table {
+					common.table = t.clone();
+				}
+			}
+			DataSource::Fanout(f) => {
+				for s in &mut f.sources {
+					s.source = s.source.with_override(database, table);
+				}
+			}
+			DataSource::Tiered(t) => {
+				*t.hot = t.hot.with_override(database, table);
+				*t.archive = t.archive.with_override(database, table);
+			}
+			DataSource::Shadow(s) => {
+				*s.primary = s.primary.with_override(database, table);
+				*s.shadow = s.shadow.with_override(database, table);
+			}
+		}
+		d
+	}
+	// the cli only reads, it leaves the tables as they are and doesn't
+	// start the background refreshes
+	pub fn without_startup_tasks(&self) -> Self {
+		let mut d = self.clone();
+		match &mut d {
+			#[cfg(feature = "databend")]
+			DataSource::Databend(cfg) => {
+				cfg.bootstrap = false;
+				cfg.retention = None;
+				cfg.schema_check = SchemaCheck::Off;
+			}
+			#[cfg(feature = "quickwit")]
+			DataSource::Quickwit(_) => {}
+			#[cfg(feature = "clickhouse")]
+			DataSource::Clickhouse(ClickhouseConf::Log(cfg)) => {
+				cfg.common.without_startup_tasks();
+				cfg.label.discovery = None;
+				cfg.value_index = None;
+			}
+			#[cfg(feature = "clickhouse")]
+			DataSource::Clickhouse(ClickhouseConf::Trace(cfg)) => {
+				cfg.common.without_startup_tasks();
+			}
+			DataSource::Fanout(f) => {
+				for s in &mut f.sources {
+					s.source = s.source.without_startup_tasks();
+				}
+			}
+			DataSource::Tiered(t) => {
+				*t.hot = t.hot.without_startup_tasks();
+				*t.archive = t.archive.without_startup_tasks();
+			}
+			DataSource::Shadow(s) => {
+				*s.primary = s.primary.without_startup_tasks();
+				*s.shadow = s.shadow.without_startup_tasks();
+			}
+		}
+		d
+	}
+	// the query timeouts of the backends behind this source
+	fn query_timeouts(&self) -> Vec<Option<Duration>> {
+		match self {
+			#[cfg(feature = "databend")]
+			DataSource::Databend(cfg) => vec![cfg.query_timeout],
+			#[cfg(feature = "quickwit")]
+			DataSource::Quickwit(cfg) => vec![cfg.timeout],
+			#[cfg(feature = "clickhouse")]
+			DataSource::Clickhouse(ClickhouseConf::Log(ClickhouseLog {
+				common,
+				..
+			}))
+			| DataSource::Clickhouse(ClickhouseConf::Trace(
+				ClickhouseTrace { common, .. },
+			)) => vec![common.query_timeout],
+			DataSource::Fanout(f) => f
+				.sources
+				.iter()
+				.flat_map(|s| s.source.query_timeouts())
+				.collect(),
+			DataSource::Tiered(t) => {
+				let mut v = t.hot.query_timeouts();
+				v.extend(t.archive.query_timeouts());
+				v
+			}
+			DataSource::Shadow(s) => {
+				let mut v = s.primary.query_timeouts();
+				v.extend(s.shadow.query_timeouts());
+				v
+			}
+		}
+	}
+	fn query_timeouts_mut(&mut self) -> Vec<&mut Option<Duration>> {
+		match self {
+			#[cfg(feature = "databend")]
+			DataSource::Databend(cfg) => vec![&mut cfg.query_timeout],
+			#[cfg(feature = "quickwit")]
+			DataSource::Quickwit(cfg) => vec![&mut cfg.timeout],
+			#[cfg(feature = "clickhouse")]
+			DataSource::Clickhouse(ClickhouseConf::Log(ClickhouseLog {
+				common,
+				..
+			}))
+			| DataSource::Clickhouse(ClickhouseConf::Trace(
+				ClickhouseTrace { common, .. },
+			)) => vec![&mut common.query_timeout],
+			DataSource::Fanout(f) => f
+				.sources
+				.iter_mut()
+				.flat_map(|s| s.source.query_timeouts_mut())
+				.collect(),
+			DataSource::Tiered(t) => {
+				let mut v = t.hot.query_timeouts_mut();
+				v.extend(t.archive.query_timeouts_mut());
+				v
+			}
+			DataSource::Shadow(s) => {
+				let mut v = s.primary.query_timeouts_mut();
+				v.extend(s.shadow.query_timeouts_mut());
+				v
+			}
+		}
+	}
+}
+
+impl DataSource {
+	// problems deserialization can't catch, each prefixed by its key
+	fn problems(&self, path: &str, out: &mut Vec<String>) {
+		match self {
+			#[cfg(feature = "databend")]
+			DataSource::Databend(cfg) => {
+				let path = format!("{}.databend", path);
+				not_empty(&path, "domain", &cfg.domain, out);
+				not_empty(&path, "database", &cfg.database, out);
+				not_zero(&path, "connect_timeout", cfg.connect_timeout, out);
+				if let Some(r) = &cfg.retention {
+					not_zero(&path, "retention.ttl", r.ttl, out);
+				}
+			}
+			#[cfg(feature = "quickwit")]
+			DataSource::Quickwit(cfg) => {
+				let path = format!("{}.quickwit", path);
+				valid_url(&path, "domain", &cfg.domain, out);
+				not_empty(&path, "index", &cfg.index, out);
+			}
+			#[cfg(feature = "clickhouse")]
+			DataSource::Clickhouse(ClickhouseConf::Log(cfg)) => {
+				let path = format!("{}.clickhouse.log", path);
+				cfg.common.problems(&path, out);
+				if let Some(p) = &cfg.partitions {
+					not_empty(&path, "partitions.template", &p.template, out);
+				}
+				if let Some(r) = &cfg.rollup {
+					not_empty(&path, "rollup.table", &r.table, out);
+				}
+			}
+			#[cfg(feature = "clickhouse")]
+			DataSource::Clickhouse(ClickhouseConf::Trace(cfg)) => {
+				let path = format!("{}.clickhouse.trace", path);
+				cfg.common.problems(&path, out);
+				not_empty(&path, "trace_ts_table", &cfg.trace_ts_table, out);
+				if cfg.default_search_limit == 0 {
+					out.push(format!(
+						"{}.default_search_limit: must be above 0",
+						path
+					));
+				}
+				if cfg.max_search_limit < cfg.default_search_limit {
+					out.push(format!(
+						"{}.max_search_limit: must be at least \
+						 default_search_limit",
+						path
+					));
+				}
+			}
+			DataSource::Fanout(f) => {
+				let path = format!("{}.fanout.sources", path);
+				if f.sources.is_empty() {
+					out.push(format!("{}: needs at least one source", path));
+				}
+				let mut names = HashSet::new();
+				for (i, s) in f.sources.iter().enumerate() {
+					let path = format!("{}[{}]", path, i);
+					if !names.insert(s.name.as_str()) {
+						out.push(format!(
+							"{}.name: {:?} is used twice",
+							path, s.name
+						));
+					}
+					s.source.problems(&path, out);
+				}
+			}
+			DataSource::Tiered(t) => {
+				let path = format!("{}.tiered", path);
+				not_zero(&path, "hot_retention", t.hot_retention, out);
+				t.hot.problems(&format!("{}.hot", path), out);
+				t.archive.problems(&format!("{}.archive", path), out);
+			}
+			DataSource::Shadow(s) => {
+				let path = format!("{}.shadow", path);
+				s.primary.problems(&format!("{}.primary", path), out);
+				s.shadow.problems(&format!("{}.shadow", path), out);
+			}
+		}
+	}
+}
+
+#[cfg(feature = "clickhouse")]
+impl Clickhouse {
+	fn without_startup_tasks(&mut self) {
+		self.bootstrap = false;
+		self.retention = None;
+		self.schema_check = SchemaCheck::Off;
+	}
+	fn problems(&self, path: &str, out: &mut Vec<String>) {
+		valid_url(path, "url", &self.url, out);
+		not_empty(path, "database", &self.database, out);
+		not_empty(path, "table", &self.table, out);
+		if let Some(r) = &self.retention {
+			not_zero(path, "retention.ttl", r.ttl, out);
+		}
+		if let Some(h) = &self.hedge {
+			not_zero(path, "hedge.delay", h.delay, out);
+			if let Some(u) = &h.url {
+				valid_url(path, "hedge.url", u, out);
+			}
+		}
+	}
+}
+
+fn not_empty(path: &str, key: &str, v: &str, out: &mut Vec<String>) {
+	if v.trim().is_empty() {
+		out.push(format!("{}.{}: must not be empty", path, key));
+	}
+}
+
+fn not_zero(path: &str, key: &str, d: Duration, out: &mut Vec<String>) {
+	if d.is_zero() {
+		out.push(format!("{}.{}: must be longer than 0s", path, key));
+	}
+}
+
+fn valid_url(path: &str, key: &str, v: &str, out: &mut Vec<String>) {
+	if let Err(e) = url::Url::parse(v) {
+		out.push(format!("{}.{}: invalid url {:?}, {}", path, key, v, e));
+	}
+}
+
+// the errors of nested structs are keyed by field, and the ones of
+// schema level checks by __all__
+fn flatten_errors(path: &str, errs: &ValidationErrors, out: &mut Vec<String>) {
+	for (field, kind) in errs.errors() {
+		let path = match (path, *field) {
+			(p, "__all__") => p.to_string(),
+			("", f) => f.to_string(),
+			(p, f) => format!("{}.{}", p, f),
+		};
+		match kind {
+			ValidationErrorsKind::Field(v) => {
+				for e in v {
+					let mut msg =
+						e.message.clone().unwrap_or_else(|| e.code.clone());
+					let mut params = e
+						.params
+						.iter()
+						.filter(|(k, _)| *k != "value")
+						.map(|(k, v)| format!("{}={}", k, v))
+						.collect::<Vec<_>>();
+					if !params.is_empty() {
+						params.sort();
+						msg = format!("{} ({})", msg, params.join(", ")).into();
+					}
+					if path.is_empty() {
+						out.push(msg.into_owned());
+					} else {
+						out.push(format!("{}: {}", path, msg));
+					}
+				}
+			}
+			ValidationErrorsKind::Struct(e) => flatten_errors(&path, e, out),
+			ValidationErrorsKind::List(m) => {
+				for (i, e) in m {
+					flatten_errors(&format!("{}[{}]", path, i), e, out);
+				}
+			}
+		}
+	}
+}
+
+// every problem of a config, so they can be fixed in one go instead of
+// one per restart
+#[derive(Debug, PartialEq, Eq)]
+pub struct ConfigReport(pub Vec<String>);
+
+impl fmt::Display for ConfigReport {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{} problem(s) in the config:", self.0.len())?;
+		for p in &self.0 {
+			write!(f, "\n  - {}", p)?;
+		}
+		Ok(())
+	}
+}
+
+impl std::error::Error for ConfigReport {}
+
+#[cfg(feature = "databend")]
 fn default_driver() -> String {
 	"databend".to_string()
 }
+#[cfg(feature = "databend")]
 const fn default_ssl_mode() -> bool {
 	false
 }
 
-const fn default_query_timeout() -> Duration {
-	Duration::from_secs(60)
-}
-
+#[cfg(feature = "databend")]
 const fn default_connect_timeout() -> Duration {
 	Duration::from_secs(10)
 }
 
+#[cfg(feature = "databend")]
+fn default_timezone() -> chrono_tz::Tz {
+	chrono_tz::Tz::UTC
+}
+
+#[cfg(feature = "clickhouse")]
+const fn default_ck_max_result_rows() -> u32 {
+	1000
+}
+
+#[cfg(feature = "clickhouse")]
+const fn default_ck_max_result_bytes() -> u64 {
+	10_000_000
+}
+
+#[cfg(feature = "databend")]
+const fn default_min_token_len() -> usize {
+	3
+}
+
+#[cfg(feature = "databend")]
+const fn default_max_result_bytes() -> usize {
+	64 * 1024 * 1024
+}
+
 // databend dns, for details see https://github.com/datafuselabs/bendsql?tab=readme-ov-file#dsn
+#[cfg(feature = "databend")]
 impl From<Databend> for String {
 	fn from(value: Databend) -> Self {
 		format!(
@@ -206,6 +1239,7 @@ impl From<Databend> for String {
 	}
 }
 
+#[cfg(feature = "databend")]
 impl TryFrom<Databend> for databend_driver::Client {
 	type Error = databend_driver::Error;
 
@@ -217,12 +1251,193 @@ impl TryFrom<Databend> for databend_driver::Client {
 
 #[derive(Clone, Deserialize, Validate)]
 pub struct Server {
-	#[validate(custom(function = "validate_ip_addr"))]
+	#[validate(custom(function = "validate_listen_addr"))]
 	pub listen_addr: String,
+	// served the same way as listen_addr, e.g. a unix socket for a sidecar
+	// next to the public tcp port
+	#[serde(default)]
+	#[validate(custom(function = "validate_listen_addrs"))]
+	pub extra_listen_addrs: Vec<String>,
+	// serve tempo's grpc Querier service (FindTraceByID) here as well
+	#[validate(custom(function = "validate_ip_addr"))]
+	pub grpc_listen_addr: Option<String>,
+	// serve /metrics here instead of on listen_addr, so the query port
+	// doesn't expose it
+	#[validate(custom(function = "validate_listen_addr"))]
+	pub admin_listen_addr: Option<String>,
+	// https on the tcp addresses of listen_addr and extra_listen_addrs,
+	// unix sockets and admin_listen_addr stay plain
+	#[serde(default)]
+	#[validate(nested)]
+	pub tls: Option<Tls>,
 	#[serde(with = "humantime_serde")]
 	pub timeout: Duration,
 	#[validate(nested)]
 	pub log: Log,
+	// answer X-LTB-Debug: 1 with the generated sql and stage timings
+	#[serde(default)]
+	pub debug_headers: bool,
+	// serve /loki/api/v1/delete, off unless asked for
+	#[serde(default)]
+	pub allow_deletes: bool,
+	// expose attribute keys as valid prometheus label names,
+	// e.g. resources_k8s_pod_name instead of resources_k8s.pod.name
+	#[serde(default)]
+	pub sanitize_label_names: bool,
+	// the stream label the service is returned as, grafana's logs app
+	// looks for service_name
+	#[serde(default)]
+	pub service_label: ServiceLabel,
+	// label log lines carrying a trace id with the name and duration of
+	// the trace's root span, one lookup on the trace source per query
+	#[serde(default)]
+	pub log_trace_context: bool,
+	// span attributes kept in trace search results, "*" keeps them all
+	#[serde(default = "default_search_result_attributes")]
+	pub search_result_attributes: Vec<String>,
+	// first match wins, unscoped attributes no rule matches are looked up
+	// in both span and resource attributes
+	#[serde(default)]
+	pub unscoped_attributes: Vec<UnscopedRule>,
+	// put the conditions shared by every `||` branch of a spanset in front
+	// of it, so the backend can prune with them
+	#[serde(default = "default_true")]
+	pub hoist_span_conditions: bool,
+	#[serde(default)]
+	#[validate(nested)]
+	pub concurrency: Concurrency,
+	#[serde(default)]
+	#[validate(nested)]
+	pub runtime: Runtime,
+}
+
+// requests run at once, by how heavy the route is. The routes of a class
+// share one budget, and each class has its own so label lookups never
+// wait behind searches. The requests over it wait in a queue of queue
+// per class, those that find it full get a 503
+#[derive(Clone, Deserialize, Debug, PartialEq, Eq, Validate)]
+pub struct Concurrency {
+	// query_range, trace search and export, trace by id and explain
+	#[serde(default = "default_heavy_concurrency")]
+	#[validate(range(min = 1))]
+	pub heavy: usize,
+	// labels, label values, series and tags
+	#[serde(default = "default_light_concurrency")]
+	#[validate(range(min = 1))]
+	pub light: usize,
+	#[serde(default = "default_concurrency_queue")]
+	#[validate(range(min = 1))]
+	pub queue: usize,
+}
+
+impl Default for Concurrency {
+	fn default() -> Self {
+		Self {
+			heavy: default_heavy_concurrency(),
+			light: default_light_concurrency(),
+			queue: default_concurrency_queue(),
+		}
+	}
+}
+
+const fn default_heavy_concurrency() -> usize {
+	32
+}
+
+const fn default_light_concurrency() -> usize {
+	256
+}
+
+const fn default_concurrency_queue() -> usize {
+	128
+}
+
+// pem files, they're read again once any of them changes
+#[derive(Clone, Deserialize, Debug, PartialEq, Eq, Validate)]
+pub struct Tls {
+	pub cert: PathBuf,
+	pub key: PathBuf,
+	// ask clients for a certificate signed by this CA, and refuse those
+	// without one
+	#[serde(default)]
+	pub client_ca: Option<PathBuf>,
+	// how often the files are checked for changes
+	#[serde(with = "humantime_serde", default = "default_tls_reload_interval")]
+	#[validate(custom(function = "validate_reload_interval"))]
+	pub reload_interval: Duration,
+}
+
+const fn default_tls_reload_interval() -> Duration {
+	Duration::from_secs(60)
+}
+
+fn validate_reload_interval(d: &Duration) -> Result<(), ValidationError> {
+	if d.is_zero() {
+		return Err(ValidationError::new("reload_interval must be above 0"));
+	}
+	Ok(())
+}
+
+// UnscopedRule decides where an unscoped `key` in traceql is looked up,
+// pattern is a glob over the attribute name
+#[derive(Clone, Deserialize, PartialEq, Eq, Debug)]
+pub struct UnscopedRule {
+	pub pattern: String,
+	pub scope: AttributeScope,
+}
+
+// selectors may use either name whichever is returned
+#[derive(Clone, Copy, Deserialize, PartialEq, Eq, Debug, Default)]
+pub enum ServiceLabel {
+	#[default]
+	ServiceName,
+	#[serde(rename = "service_name")]
+	Otel,
+	#[serde(rename = "both")]
+	Both,
+}
+
+#[derive(Clone, Copy, Deserialize, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum AttributeScope {
+	Span,
+	Resource,
+	Both,
+}
+
+// tokio and outgoing http settings, anything unset keeps the
+// library default
+#[derive(Clone, Deserialize, Debug, Default, PartialEq, Eq, Validate)]
+pub struct Runtime {
+	// tokio defaults to one per core
+	#[validate(range(min = 1))]
+	pub worker_threads: Option<usize>,
+	// tokio defaults to 512
+	#[validate(range(min = 1))]
+	pub max_blocking_threads: Option<usize>,
+	#[serde(default)]
+	pub http_pool: HttpPool,
+}
+
+// connection pool of the clients talking to clickhouse and quickwit
+#[derive(Clone, Deserialize, Debug, Default, PartialEq, Eq)]
+pub struct HttpPool {
+	pub max_idle_per_host: Option<usize>,
+	#[serde(default, with = "humantime_serde")]
+	pub idle_timeout: Option<Duration>,
+}
+
+fn default_search_result_attributes() -> Vec<String> {
+	[
+		"http.method",
+		"http.route",
+		"http.status_code",
+		"rpc.method",
+		"db.system",
+	]
+	.iter()
+	.map(|s| s.to_string())
+	.collect()
 }
 
 fn validate_ip_addr(addr: &str) -> Result<(), ValidationError> {
@@ -231,22 +1446,108 @@ fn validate_ip_addr(addr: &str) -> Result<(), ValidationError> {
 		.map(|_| ())
 }
 
+// ListenAddr is what the http endpoints can listen on, `host:port`,
+// `[v6]:port`, or `unix:<path>` for a unix domain socket
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListenAddr {
+	Tcp(SocketAddr),
+	Unix(PathBuf),
+}
+
+impl ListenAddr {
+	pub fn parse(addr: &str) -> Option<Self> {
+		match addr.strip_prefix("unix:") {
+			Some("") => None,
+			Some(path) => Some(Self::Unix(PathBuf::from(path))),
+			None => SocketAddr::from_str(addr).ok().map(Self::Tcp),
+		}
+	}
+}
+
+fn validate_listen_addr(addr: &str) -> Result<(), ValidationError> {
+	ListenAddr::parse(addr)
+		.map(|_| ())
+		.ok_or_else(|| ValidationError::new("invalid listen address"))
+}
+
+fn validate_listen_addrs(addrs: &Vec<String>) -> Result<(), ValidationError> {
+	addrs.iter().try_for_each(|a| validate_listen_addr(a))
+}
+
 impl AppConfig {
 	pub fn new() -> Result<Self, ConfigError> {
 		let default_config =
 			env::var("LGTMRS_CONFIG").unwrap_or("config.yaml".to_string());
-		Config::builder()
+		let mut cfg: Self = Config::builder()
 			.add_source(File::with_name(&default_config))
 			.build()?
-			.try_deserialize()
+			.try_deserialize()?;
+		cfg.inherit_timeouts();
+		Ok(cfg)
+	}
+	// load reads and checks the config, a file that doesn't parse is
+	// reported on its own since nothing else can be checked then
+	pub fn load() -> anyhow::Result<Self> {
+		let cfg = Self::new().map_err(|e| ConfigReport(vec![e.to_string()]))?;
+		cfg.check()?;
+		Ok(cfg)
+	}
+	pub fn check(&self) -> Result<(), ConfigReport> {
+		let mut problems = vec![];
+		if let Err(e) = self.validate() {
+			flatten_errors("", &e, &mut problems);
+		}
+		if self.server.timeout.is_zero() {
+			problems.push("server.timeout: must be longer than 0s".to_string());
+		}
+		for (i, q) in self.hot_queries.iter().enumerate() {
+			let path = format!("hot_queries[{}]", i);
+			match logql::parser::parse_logql_query(&q.query) {
+				Ok(logql::parser::Query::LogQuery(_)) => problems
+					.push(format!("{}.query: must be a metric query", path)),
+				Ok(_) => {}
+				Err(e) => problems.push(format!("{}.query: {}", path, e)),
+			}
+			not_zero(&path, "interval", q.interval, &mut problems);
+		}
+		self.log_source.problems("log_source", &mut problems);
+		self.trace_source.problems("trace_source", &mut problems);
+		#[cfg(feature = "databend")]
+		if let DataSource::Databend(cfg) = &self.log_source {
+			if cfg.dedup_spans {
+				problems.push(
+					"log_source.databend.dedup_spans: only trace_source has \
+					 spans to dedup"
+						.to_string(),
+				);
+			}
+		}
+		problems.sort();
+		if problems.is_empty() {
+			Ok(())
+		} else {
+			Err(ConfigReport(problems))
+		}
+	}
+	// sources without their own query timeout use the server's
+	pub fn inherit_timeouts(&mut self) {
+		let server = self.server.timeout;
+		for s in [&mut self.log_source, &mut self.trace_source] {
+			for t in s.query_timeouts_mut() {
+				t.get_or_insert(server);
+			}
+		}
 	}
 }
 
 #[cfg(test)]
 mod tests {
 	use super::*;
+	#[cfg(feature = "quickwit")]
+	use common::{level::SeverityRange, LogLevel};
 	use pretty_assertions::assert_eq;
 
+	#[cfg(feature = "quickwit")]
 	#[test]
 	fn test_quickwit_enum() {
 		let j = serde_json::json!({
@@ -254,17 +1555,33 @@ mod tests {
 				"domain": "http://localhost:1234",
 				"index": "xxx_index",
 				"timeout": "300s",
+				"levels": {
+					"names": {"severe": "error"},
+					"numbers": [{"from": 100, "to": 199, "level": "WARNING"}],
+				},
 			}}
 		);
 		let actual = serde_json::from_value(j).unwrap();
 		let expect = DataSource::Quickwit(Quickwit {
 			domain: "http://localhost:1234".to_string(),
 			index: "xxx_index".to_string(),
-			timeout: Duration::from_secs(300),
+			timeout: Some(Duration::from_secs(300)),
+			series_labels: default_series_labels(),
+			http: HttpClient::default(),
+			levels: LevelMapping {
+				names: HashMap::from([("severe".to_string(), LogLevel::Error)]),
+				numbers: vec![SeverityRange {
+					from: 100,
+					to: 199,
+					level: LogLevel::Warn,
+				}],
+				..Default::default()
+			},
 		});
 		assert_eq!(expect, actual);
 	}
 
+	#[cfg(feature = "clickhouse")]
 	#[test]
 	fn test_deser_cklog() {
 		let j = r#"
@@ -275,9 +1592,22 @@ mod tests {
 				"table": "otel_logs",
 				"username": "default",
 				"password": "a11221122a",
+				"clickhouse_settings": {
+					"max_threads": 4,
+					"use_query_cache": "1"
+				},
+				"final": true,
+				"schema_version": "v0.100",
+				"retention": {"ttl": "30d"},
+				"http": {"tcp_keepalive": "60s", "http2_prior_knowledge": true},
+				"hedge": {"delay": "800ms"},
 				"label": {
 					"resources": ["a"],
-					"attributes": ["b"]
+					"attributes": ["b"],
+					"discovery": {
+						"interval": "1m",
+						"include": ["k8s.*"]
+					}
 				}
 			}
 		}"#;
@@ -289,18 +1619,100 @@ mod tests {
 				table: "otel_logs".to_string(),
 				username: "default".to_string(),
 				password: "a11221122a".to_string(),
+				clickhouse_settings: BTreeMap::from([
+					("max_threads".to_string(), 4.into()),
+					("use_query_cache".to_string(), "1".into()),
+				]),
+				settings_clause: false,
+				use_final: true,
+				max_result_rows: 1000,
+				max_result_bytes: 10_000_000,
+				schema_version: SchemaVersion::V0_100,
+				schema_check: SchemaCheck::Warn,
+				bootstrap: false,
+				retention: Some(Retention {
+					ttl: Duration::from_secs(30 * 86400),
+					maintenance_interval: None,
+				}),
+				http: HttpClient {
+					http2_prior_knowledge: true,
+					tcp_keepalive: Some(Duration::from_secs(60)),
+					..Default::default()
+				},
+				query_timeout: None,
+				hedge: Some(Hedge {
+					delay: Duration::from_millis(800),
+					url: None,
+				}),
 			},
 			label: CKLogLabel {
 				resource_attributes: vec!["a".to_string()],
 				log_attributes: vec!["b".to_string()],
+				discovery: Some(LabelDiscovery {
+					interval: Duration::from_secs(60),
+					lookback: default_discovery_lookback(),
+					include: vec!["k8s.*".to_string()],
+					exclude: vec![],
+				}),
+				max_values: default_max_label_values(),
 			},
 			replace_dash_to_dot: None,
 			default_log_level: "info".to_string(),
+			levels: LevelMapping::default(),
 			level_case_sensitive: None,
+			level_by_number: None,
+			non_ascii_filter: NonAsciiFilter::Position,
+			s3: None,
+			rollup: None,
+			value_index: None,
+			partitions: None,
 		});
 		assert_eq!(expect, actual);
 	}
 
+	#[cfg(feature = "quickwit")]
+	#[test]
+	fn test_deser_fanout() {
+		let j = serde_json::json!({
+			"fanout": {
+				"sources": [
+					{
+						"name": "old",
+						"quickwit": {"domain": "http://qw:7280", "index": "logs"}
+					},
+					{
+						"name": "new",
+						"quickwit": {"domain": "http://qw2:7280", "index": "logs"}
+					}
+				],
+				"merge": "dedup"
+			}
+		});
+		let DataSource::Fanout(f) = serde_json::from_value(j).unwrap() else {
+			panic!("not a fanout source");
+		};
+		assert_eq!(f.merge, MergeStrategy::Dedup);
+		assert_eq!(
+			f.sources
+				.iter()
+				.map(|s| s.name.as_str())
+				.collect::<Vec<_>>(),
+			vec!["old", "new"]
+		);
+		assert_eq!(
+			f.sources[1].source,
+			DataSource::Quickwit(Quickwit {
+				domain: "http://qw2:7280".to_string(),
+				index: "logs".to_string(),
+				timeout: None,
+				series_labels: default_series_labels(),
+				http: HttpClient::default(),
+				levels: LevelMapping::default(),
+			})
+		);
+	}
+
+	#[cfg(feature = "databend")]
 	#[test]
 	fn test_databend_enum() {
 		let j = r#"
@@ -326,11 +1738,21 @@ mod tests {
 			password: "password".to_string(),
 			ssl_mode: false,
 			connect_timeout: Duration::from_secs(10),
+			query_timeout: None,
 			inverted_index: true,
+			inverted_index_min_token_len: 3,
+			max_result_bytes: 64 * 1024 * 1024,
+			schema_check: SchemaCheck::Warn,
+			bootstrap: false,
+			retention: None,
+			timezone: chrono_tz::Tz::UTC,
+			dedup_spans: false,
+			levels: LevelMapping::default(),
 		});
 		assert_eq!(cfg, expect);
 	}
 
+	#[cfg(feature = "clickhouse")]
 	#[test]
 	fn test_decode_whole_file() -> anyhow::Result<()> {
 		let cfg: AppConfig = Config::builder()
@@ -344,6 +1766,9 @@ mod tests {
 				table: "otel_logs".to_string(),
 				username: "default".to_string(),
 				password: "a11221122a".to_string(),
+				max_result_rows: 1000,
+				max_result_bytes: 10_000_000,
+				..Default::default()
 			},
 			label: CKLogLabel {
 				resource_attributes: vec![
@@ -355,10 +1780,19 @@ mod tests {
 					"quantity".to_string(),
 					"code.function".to_string(),
 				],
+				discovery: None,
+				max_values: default_max_label_values(),
 			},
 			replace_dash_to_dot: Some(true),
 			default_log_level: "debug".to_string(),
+			levels: LevelMapping::default(),
 			level_case_sensitive: Some(false),
+			level_by_number: None,
+			non_ascii_filter: NonAsciiFilter::Position,
+			s3: None,
+			rollup: None,
+			value_index: None,
+			partitions: None,
 		};
 		assert_eq!(
 			cfg.log_source,
@@ -369,6 +1803,7 @@ mod tests {
 		Ok(())
 	}
 
+	#[cfg(feature = "clickhouse")]
 	#[test]
 	fn test_cache_config() -> anyhow::Result<()> {
 		let cfg: AppConfig = Config::builder()
@@ -382,6 +1817,7 @@ mod tests {
 		Ok(())
 	}
 
+	#[cfg(feature = "clickhouse")]
 	#[test]
 	fn test_whole_file_validation() -> anyhow::Result<()> {
 		let cfg: AppConfig = Config::builder()
@@ -392,6 +1828,112 @@ mod tests {
 		Ok(())
 	}
 
+	#[cfg(feature = "clickhouse")]
+	#[test]
+	fn test_timeout_validation() -> anyhow::Result<()> {
+		let mut cfg: AppConfig = Config::builder()
+			.add_source(File::with_name("./config.yaml"))
+			.build()?
+			.try_deserialize()?;
+		cfg.inherit_timeouts();
+		let server = cfg.server.timeout;
+		let timeouts = cfg.log_source.query_timeouts();
+		assert!(timeouts.iter().all(|t| *t == Some(server)));
+		cfg.validate()?;
+		for t in cfg.trace_source.query_timeouts_mut() {
+			*t = Some(server + Duration::from_secs(1));
+		}
+		assert!(cfg.validate().is_err());
+		Ok(())
+	}
+
+	#[cfg(feature = "clickhouse")]
+	#[test]
+	fn test_check_lists_all_problems() -> anyhow::Result<()> {
+		let mut cfg: AppConfig = Config::builder()
+			.add_source(File::with_name("./config.yaml"))
+			.build()?
+			.try_deserialize()?;
+		cfg.inherit_timeouts();
+		assert_eq!(cfg.check(), Ok(()));
+		cfg.server.listen_addr = "localhost".to_string();
+		cfg.cache.time_to_idle = cfg.cache.time_to_live * 2;
+		let DataSource::Clickhouse(ClickhouseConf::Log(log)) =
+			&mut cfg.log_source
+		else {
+			unreachable!()
+		};
+		log.common.url = "127.0.0.1:8123".to_string();
+		log.common.table = "".to_string();
+		assert_eq!(
+			cfg.check(),
+			Err(ConfigReport(vec![
+				"cache: time_to_idle must be no greater than time_to_live"
+					.to_string(),
+				"log_source.clickhouse.log.table: must not be empty"
+					.to_string(),
+				"log_source.clickhouse.log.url: invalid url \"127.0.0.1:8123\", \
+				 relative URL without a base"
+					.to_string(),
+				"server.listen_addr: invalid bind address".to_string(),
+			]))
+		);
+		Ok(())
+	}
+
+	#[cfg(all(feature = "clickhouse", feature = "databend"))]
+	#[test]
+	fn test_dedup_spans_of_logs() -> anyhow::Result<()> {
+		let mut cfg: AppConfig = Config::builder()
+			.add_source(File::with_name("./config.yaml"))
+			.build()?
+			.try_deserialize()?;
+		cfg.log_source = serde_json::from_str(
+			r#"{"databend": {"driver": "databend", "domain": "localhost",
+			"port": 3306, "database": "db", "username": "root",
+			"password": "password", "dedup_spans": true}}"#,
+		)?;
+		cfg.inherit_timeouts();
+		assert_eq!(
+			cfg.check(),
+			Err(ConfigReport(vec![
+				"log_source.databend.dedup_spans: only trace_source has spans \
+				 to dedup"
+					.to_string()
+			]))
+		);
+		Ok(())
+	}
+
+	#[cfg(feature = "clickhouse")]
+	#[test]
+	fn test_search_limit() -> anyhow::Result<()> {
+		let cfg: AppConfig = Config::builder()
+			.add_source(File::with_name("./config.yaml"))
+			.build()?
+			.try_deserialize()?;
+		let DataSource::Clickhouse(ClickhouseConf::Trace(mut trace)) =
+			cfg.trace_source
+		else {
+			unreachable!()
+		};
+		assert_eq!(trace.search_limit(None), 500);
+		assert_eq!(trace.search_limit(Some(20)), 20);
+		assert_eq!(trace.search_limit(Some(100_000)), 5000);
+		trace.max_search_limit = 100;
+		let mut out = vec![];
+		DataSource::Clickhouse(ClickhouseConf::Trace(trace))
+			.problems("trace_source", &mut out);
+		assert_eq!(
+			out,
+			vec![
+				"trace_source.clickhouse.trace.max_search_limit: must be at \
+				 least default_search_limit"
+			]
+		);
+		Ok(())
+	}
+
 	#[test]
 	fn test_cache_config_validate() {
 		let test_cases = vec![
@@ -433,44 +1975,257 @@ mod tests {
 		}
 	}
 
+	#[test]
+	fn test_deser_tls() {
+		let tls: Tls =
+			serde_json::from_str(r#"{"cert": "/tls/crt", "key": "/tls/key"}"#)
+				.unwrap();
+		assert_eq!(tls.reload_interval, Duration::from_secs(60));
+		assert_eq!(tls.client_ca, None);
+		assert!(tls.validate().is_ok());
+		let tls: Tls = serde_json::from_str(
+			r#"{"cert": "/tls/crt", "key": "/tls/key",
+			"client_ca": "/tls/ca", "reload_interval": "0s"}"#,
+		)
+		.unwrap();
+		assert_eq!(tls.client_ca, Some(PathBuf::from("/tls/ca")));
+		assert!(tls.validate().is_err());
+	}
+
+	#[test]
+	fn test_deser_concurrency() {
+		let c: Concurrency = serde_json::from_str(r#"{"heavy": 4}"#).unwrap();
+		assert_eq!(
+			c,
+			Concurrency {
+				heavy: 4,
+				..Default::default()
+			}
+		);
+		let c: Concurrency = serde_json::from_str(r#"{"queue": 0}"#).unwrap();
+		assert!(c.validate().is_err());
+	}
+
+	#[test]
+	fn test_listen_addr() {
+		let tcp = |a: &str| Some(ListenAddr::Tcp(a.parse().unwrap()));
+		let cases = [
+			("0.0.0.0:6778", tcp("0.0.0.0:6778")),
+			("[::]:6778", tcp("[::]:6778")),
+			("[::1]:6778", tcp("[::1]:6778")),
+			(
+				"unix:/run/ltbridge.sock",
+				Some(ListenAddr::Unix(PathBuf::from("/run/ltbridge.sock"))),
+			),
+			("unix:", None),
+			(":6778", None),
+			("::1:6778", None),
+		];
+		for (input, want) in cases {
+			assert_eq!(ListenAddr::parse(input), want, "{}", input);
+		}
+	}
+
 	#[test]
 	fn test_server_config_validate() {
 		let test_cases = vec![
 			(
 				Server {
 					listen_addr: "0.0.0.0:6778".to_string(),
+					extra_listen_addrs: vec![],
+					grpc_listen_addr: None,
+					admin_listen_addr: None,
+					tls: None,
 					timeout: Duration::from_secs(30),
 					log: Log::default(),
+					debug_headers: false,
+					allow_deletes: false,
+					sanitize_label_names: false,
+					service_label: ServiceLabel::ServiceName,
+					log_trace_context: false,
+					search_result_attributes: default_search_result_attributes(
+					),
+					unscoped_attributes: vec![],
+					hoist_span_conditions: true,
+					concurrency: Concurrency::default(),
+					runtime: Runtime::default(),
 				},
 				0,
 			),
 			(
 				Server {
 					listen_addr: ":6778".to_string(),
+					extra_listen_addrs: vec![],
+					grpc_listen_addr: None,
+					admin_listen_addr: None,
+					tls: None,
 					timeout: Duration::from_secs(30),
 					log: Log::default(),
+					debug_headers: false,
+					allow_deletes: false,
+					sanitize_label_names: false,
+					service_label: ServiceLabel::ServiceName,
+					log_trace_context: false,
+					search_result_attributes: default_search_result_attributes(
+					),
+					unscoped_attributes: vec![],
+					hoist_span_conditions: true,
+					concurrency: Concurrency::default(),
+					runtime: Runtime::default(),
 				},
 				1,
 			),
 			(
 				Server {
 					listen_addr: "0.0.0.0".to_string(),
+					extra_listen_addrs: vec![],
+					grpc_listen_addr: None,
+					admin_listen_addr: None,
+					tls: None,
 					timeout: Duration::from_secs(30),
 					log: Log::default(),
+					debug_headers: false,
+					allow_deletes: false,
+					sanitize_label_names: false,
+					service_label: ServiceLabel::ServiceName,
+					log_trace_context: false,
+					search_result_attributes: default_search_result_attributes(
+					),
+					unscoped_attributes: vec![],
+					hoist_span_conditions: true,
+					concurrency: Concurrency::default(),
+					runtime: Runtime::default(),
 				},
 				1,
 			),
 			(
 				Server {
 					listen_addr: "0.0.0.0:6778".to_string(),
+					extra_listen_addrs: vec![],
+					grpc_listen_addr: None,
+					admin_listen_addr: None,
+					tls: None,
 					timeout: Duration::from_secs(30),
 					log: Log {
 						file: "info.log".to_string(),
 						filter_directives: "wtf,,;asd".to_string(),
+						redact: Redact::default(),
+					},
+					debug_headers: false,
+					allow_deletes: false,
+					sanitize_label_names: false,
+					service_label: ServiceLabel::ServiceName,
+					log_trace_context: false,
+					search_result_attributes: default_search_result_attributes(
+					),
+					unscoped_attributes: vec![],
+					hoist_span_conditions: true,
+					concurrency: Concurrency::default(),
+					runtime: Runtime::default(),
+				},
+				1,
+			),
+			(
+				Server {
+					listen_addr: "0.0.0.0:6778".to_string(),
+					extra_listen_addrs: vec![],
+					grpc_listen_addr: None,
+					admin_listen_addr: None,
+					tls: None,
+					timeout: Duration::from_secs(30),
+					log: Log::default(),
+					debug_headers: false,
+					allow_deletes: false,
+					sanitize_label_names: false,
+					service_label: ServiceLabel::ServiceName,
+					log_trace_context: false,
+					search_result_attributes: default_search_result_attributes(
+					),
+					unscoped_attributes: vec![],
+					hoist_span_conditions: true,
+					concurrency: Concurrency::default(),
+					runtime: Runtime {
+						worker_threads: Some(0),
+						..Default::default()
 					},
 				},
 				1,
 			),
+			(
+				Server {
+					listen_addr: "0.0.0.0:6778".to_string(),
+					extra_listen_addrs: vec![],
+					grpc_listen_addr: None,
+					admin_listen_addr: None,
+					tls: None,
+					timeout: Duration::from_secs(30),
+					log: Log {
+						redact: Redact {
+							patterns: vec!["token=(".to_string()],
+							sql_literals: false,
+						},
+						..Default::default()
+					},
+					debug_headers: false,
+					allow_deletes: false,
+					sanitize_label_names: false,
+					service_label: ServiceLabel::ServiceName,
+					log_trace_context: false,
+					search_result_attributes: default_search_result_attributes(
+					),
+					unscoped_attributes: vec![],
+					hoist_span_conditions: true,
+					concurrency: Concurrency::default(),
+					runtime: Runtime::default(),
+				},
+				1,
+			),
+			(
+				Server {
+					listen_addr: "0.0.0.0:6778".to_string(),
+					extra_listen_addrs: vec![],
+					grpc_listen_addr: Some(":9095".to_string()),
+					admin_listen_addr: None,
+					tls: None,
+					timeout: Duration::from_secs(30),
+					log: Log::default(),
+					debug_headers: false,
+					allow_deletes: false,
+					sanitize_label_names: false,
+					service_label: ServiceLabel::ServiceName,
+					log_trace_context: false,
+					search_result_attributes: default_search_result_attributes(
+					),
+					unscoped_attributes: vec![],
+					hoist_span_conditions: true,
+					concurrency: Concurrency::default(),
+					runtime: Runtime::default(),
+				},
+				1,
+			),
+			(
+				Server {
+					listen_addr: "0.0.0.0:6778".to_string(),
+					extra_listen_addrs: vec![],
+					grpc_listen_addr: None,
+					admin_listen_addr: Some("localhost".to_string()),
+					tls: None,
+					timeout: Duration::from_secs(30),
+					log: Log::default(),
+					debug_headers: false,
+					allow_deletes: false,
+					sanitize_label_names: false,
+					service_label: ServiceLabel::ServiceName,
+					log_trace_context: false,
+					search_result_attributes: default_search_result_attributes(
+					),
+					unscoped_attributes: vec![],
+					hoist_span_conditions: true,
+					concurrency: Concurrency::default(),
+					runtime: Runtime::default(),
+				},
+				1,
+			),
 		];
 		for (i, (input, expect)) in test_cases.into_iter().enumerate() {
 			let actual = input.validate();
@@ -0,0 +1,229 @@
+use crate::{
+	errors::AppError,
+	logquery::{query_range::explain_query_range, QueryRangeRequest},
+	state::AppState,
+	storage::{
+		explain::{ExplainKind, Plan},
+		labels::Snapshot,
+		stats::QueryStats,
+	},
+	tenant::Tenant,
+	trace::{explain_search, SearchTraceRequest},
+	utils::validate::describe,
+};
+use axum::{
+	async_trait,
+	extract::{FromRequestParts, Query, State},
+	http::{request::Parts, HeaderValue, StatusCode, Uri},
+	response::Response,
+	Json,
+};
+use serde::{Deserialize, Serialize};
+use std::{convert::Infallible, time::Duration};
+use validator::Validate;
+
+pub const DEBUG_HEADER: &str = "X-LTB-Debug";
+pub const SQL_HEADER: &str = "X-LTB-SQL";
+pub const NOTE_HEADER: &str = "X-LTB-Note";
+
+// DebugRequest tells whether the client asked for the generated sql,
+// the header is ignored unless server.debug_headers is on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DebugRequest(pub bool);
+
+#[async_trait]
+impl FromRequestParts<AppState> for DebugRequest {
+	type Rejection = Infallible;
+
+	async fn from_request_parts(
+		parts: &mut Parts,
+		state: &AppState,
+	) -> Result<Self, Self::Rejection> {
+		let asked = parts
+			.headers
+			.get(DEBUG_HEADER)
+			.is_some_and(|v| v.as_bytes() == b"1");
+		Ok(Self(asked && state.config.server.debug_headers))
+	}
+}
+
+// header values can't span lines, the statement is flattened so it
+// still pastes into clickhouse-client as is
+fn one_line(sql: &str) -> HeaderValue {
+	let flat: Vec<u8> = sql
+		.trim()
+		.bytes()
+		.map(|b| if b.is_ascii_control() { b' ' } else { b })
+		.collect();
+	HeaderValue::from_bytes(&flat).unwrap_or(HeaderValue::from_static(""))
+}
+
+// server_timing renders the stages and every statement in the
+// Server-Timing format, durations are in milliseconds
+fn server_timing(stats: &QueryStats, stages: &[(&str, Duration)]) -> String {
+	let ms = |d: &Duration| d.as_secs_f64() * 1000.0;
+	stages
+		.iter()
+		.map(|(name, d)| format!("{};dur={:.3}", name, ms(d)))
+		.chain(
+			stats
+				.statements
+				.iter()
+				.enumerate()
+				.map(|(i, s)| format!("sql{};dur={:.3}", i, ms(&s.elapsed))),
+		)
+		.collect::<Vec<_>>()
+		.join(", ")
+}
+
+// with_debug_headers adds one X-LTB-SQL header per statement, the n-th
+// of them is timed as sqlN in Server-Timing, and one X-LTB-Note per note
+pub fn with_debug_headers(
+	mut resp: Response,
+	stats: &QueryStats,
+	stages: &[(&str, Duration)],
+) -> Response {
+	let headers = resp.headers_mut();
+	for s in &stats.statements {
+		headers.append(SQL_HEADER, one_line(&s.sql));
+	}
+	for n in &stats.notes {
+		headers.append(NOTE_HEADER, one_line(n));
+	}
+	if let Ok(v) = HeaderValue::from_str(&server_timing(stats, stages)) {
+		headers.insert("Server-Timing", v);
+	}
+	resp
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExplainRequest {
+	#[serde(default)]
+	kind: ExplainKind,
+	// logql, named as in loki's query_range
+	query: Option<String>,
+	// traceql, named as in tempo's search
+	q: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExplainResponse {
+	plans: Vec<Plan>,
+}
+
+// explain answers /debug/explain with the plan of every statement the
+// query would send. The rest of the parameters are the ones of
+// query_range or search, start and end matter most for partitioned tables
+pub async fn explain(
+	State(state): State<AppState>,
+	tenant: Tenant,
+	uri: Uri,
+	Query(req): Query<ExplainRequest>,
+) -> Result<Json<ExplainResponse>, AppError> {
+	if !state.config.server.debug_headers {
+		return Err(AppError::DebugDisabled);
+	}
+	let state = state.for_tenant(&tenant);
+	let invalid = |e: String| AppError::InvalidQueryString(e);
+	let plans = match (req.query, req.q) {
+		(Some(_), _) => {
+			let Query(r) = Query::<QueryRangeRequest>::try_from_uri(&uri)
+				.map_err(|e| invalid(e.body_text()))?;
+			r.validate().map_err(|e| invalid(describe(&e)))?;
+			explain_query_range(r, state, req.kind).await?
+		}
+		(None, Some(_)) => {
+			let Query(r) = Query::<SearchTraceRequest>::try_from_uri(&uri)
+				.map_err(|e| invalid(e.body_text()))?;
+			r.validate().map_err(|e| invalid(describe(&e)))?;
+			explain_search(r, state, req.kind).await?
+		}
+		(None, None) => {
+			return Err(invalid(
+				"either query (logql) or q (traceql) is required".to_string(),
+			))
+		}
+	};
+	Ok(Json(ExplainResponse { plans }))
+}
+
+#[derive(Deserialize)]
+pub struct SeriesStoreRequest {
+	label: Option<String>,
+}
+
+// series_store dumps the label index of the log source, only what
+// involves label if given. Users attach it to label issues, and POSTing
+// it to another instance reproduces them there
+pub async fn series_store(
+	State(state): State<AppState>,
+	tenant: Tenant,
+	Query(req): Query<SeriesStoreRequest>,
+) -> Result<Json<Snapshot>, AppError> {
+	if !state.config.server.debug_headers {
+		return Err(AppError::DebugDisabled);
+	}
+	let state = state.for_tenant(&tenant);
+	state
+		.log_handle
+		.series_snapshot(req.label.as_deref())
+		.map(Json)
+		.ok_or_else(|| {
+			AppError::UnsupportedFeature(
+				"the log source keeps no label index".to_string(),
+			)
+		})
+}
+
+// the snapshot is added to what the store knows, cached labels responses
+// still answer until they expire
+pub async fn restore_series_store(
+	State(state): State<AppState>,
+	tenant: Tenant,
+	Json(snapshot): Json<Snapshot>,
+) -> Result<StatusCode, AppError> {
+	if !state.config.server.debug_headers {
+		return Err(AppError::DebugDisabled);
+	}
+	let state = state.for_tenant(&tenant);
+	state.log_handle.restore_series(snapshot)?;
+	Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::storage::stats::Statement;
+	use axum::response::IntoResponse;
+	use pretty_assertions::assert_eq;
+
+	#[test]
+	fn test_debug_headers() {
+		let stats = QueryStats {
+			statements: vec![Statement {
+				sql: "SELECT 1\nFROM t\tWHERE x = 'y'".to_string(),
+				elapsed: Duration::from_micros(2500),
+			}],
+			notes: vec!["contains \"x\" on message: like".to_string()],
+			..Default::default()
+		};
+		let resp = with_debug_headers(
+			"ok".into_response(),
+			&stats,
+			&[("parse", Duration::from_micros(100))],
+		);
+		let get = |k: &str| {
+			resp.headers()
+				.get_all(k)
+				.iter()
+				.map(|v| v.to_str().unwrap().to_string())
+				.collect::<Vec<_>>()
+		};
+		assert_eq!(get(SQL_HEADER), vec!["SELECT 1 FROM t WHERE x = 'y'"]);
+		assert_eq!(get(NOTE_HEADER), vec![r#"contains "x" on message: like"#]);
+		assert_eq!(
+			get("Server-Timing"),
+			vec!["parse;dur=0.100, sql0;dur=2.500"]
+		);
+	}
+}
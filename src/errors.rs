@@ -1,9 +1,13 @@
 use axum::{
 	http::StatusCode,
 	response::{IntoResponse, Response},
+	Json,
 };
+#[cfg(feature = "databend")]
 use databend_driver::Error as DBError;
 use logql::parser::LogQLParseError;
+use serde::Serialize;
+use std::sync::Arc;
 use thiserror::Error;
 use traceql::TraceQLError;
 
@@ -16,6 +20,7 @@ pub enum AppError {
 	InvalidTraceQL(TraceQLError),
 	#[error("Invalid time format: {0}")]
 	InvalidTimeFormat(String),
+	#[cfg(feature = "databend")]
 	#[error("db error: {0}")]
 	DBError(#[from] DBError),
 	#[error("Serde error: {0}")]
@@ -28,18 +33,52 @@ pub enum AppError {
 	MultiMatch(usize),
 	#[error("Invalid query string: {0}")]
 	InvalidQueryString(String),
+	// the parameters of a loki request, answered the way loki does
+	#[error("invalid parameters: {0}")]
+	InvalidParams(String),
 	#[error("Trace not found")]
 	TraceNotFound,
+	#[error("invalid trace id: {0}")]
+	InvalidTraceID(String),
 	#[error("IO error: {0}")]
 	IOError(#[from] std::io::Error),
 	#[error("Rmp error: {0}")]
 	RmpDecodeError(#[from] rmp_serde::decode::Error),
 	#[error("Rmp encode error: {0}")]
 	RmpEncodeError(#[from] rmp_serde::encode::Error),
+	#[error("Unsupported by backend: {0}")]
+	UnsupportedFeature(String),
+	#[error("maximum of series ({0}) reached for a single query")]
+	TooManySeries(usize),
+	#[error("no org id")]
+	MissingTenant,
+	#[error("deleting logs is disabled, see server.allow_deletes")]
+	DeletesDisabled,
+	#[error("debugging endpoints are disabled, see server.debug_headers")]
+	DebugDisabled,
+	#[error("response exceeds max_response_bytes ({0})")]
+	ResponseTooLarge(usize),
+	// what an identical request running at the same time failed with
+	#[error("{0}")]
+	Shared(Arc<AppError>),
+}
+
+// the body loki answers a bad request with, grafana shows the message
+#[derive(Serialize)]
+struct LokiError {
+	code: u16,
+	status: &'static str,
+	message: String,
 }
 
 impl IntoResponse for AppError {
 	fn into_response(self) -> Response {
+		self.response()
+	}
+}
+
+impl AppError {
+	fn response(&self) -> Response {
 		match self {
 			AppError::StorageError(e) => (
 				StatusCode::INTERNAL_SERVER_ERROR,
@@ -70,6 +109,7 @@ impl IntoResponse for AppError {
 				format!("Invalid time format: {}", e),
 			)
 				.into_response(),
+			#[cfg(feature = "databend")]
 			AppError::DBError(e) => (
 				StatusCode::INTERNAL_SERVER_ERROR,
 				format!("DB error: {}", e),
@@ -85,10 +125,22 @@ impl IntoResponse for AppError {
 				format!("Invalid query string: {}", e),
 			)
 				.into_response(),
+			AppError::InvalidParams(_) => {
+				let code = StatusCode::BAD_REQUEST;
+				let body = LokiError {
+					code: code.as_u16(),
+					status: "error",
+					message: self.to_string(),
+				};
+				(code, Json(body)).into_response()
+			}
 			AppError::TraceNotFound => {
 				(StatusCode::NOT_FOUND, "Trace not found".to_string())
 					.into_response()
 			}
+			AppError::InvalidTraceID(_) => {
+				(StatusCode::BAD_REQUEST, self.to_string()).into_response()
+			}
 			AppError::IOError(e) => (
 				StatusCode::INTERNAL_SERVER_ERROR,
 				format!("IO error: {}", e),
@@ -104,6 +156,42 @@ impl IntoResponse for AppError {
 				format!("Rmp encode error: {}", e),
 			)
 				.into_response(),
+			AppError::UnsupportedFeature(e) => (
+				StatusCode::BAD_REQUEST,
+				format!("Unsupported by the configured backend: {}", e),
+			)
+				.into_response(),
+			AppError::TooManySeries(_) => {
+				(StatusCode::BAD_REQUEST, self.to_string()).into_response()
+			}
+			AppError::MissingTenant => {
+				(StatusCode::UNAUTHORIZED, self.to_string()).into_response()
+			}
+			AppError::DeletesDisabled | AppError::DebugDisabled => {
+				(StatusCode::FORBIDDEN, self.to_string()).into_response()
+			}
+			AppError::ResponseTooLarge(_) => {
+				(StatusCode::PAYLOAD_TOO_LARGE, self.to_string())
+					.into_response()
+			}
+			AppError::Shared(e) => e.response(),
+		}
+	}
+}
+
+// only the errors finding a trace by id can run into are told apart
+impl From<AppError> for tonic::Status {
+	fn from(e: AppError) -> Self {
+		match e {
+			AppError::TraceNotFound => Self::not_found(e.to_string()),
+			AppError::InvalidTraceID(_) => {
+				Self::invalid_argument(e.to_string())
+			}
+			AppError::MissingTenant => Self::unauthenticated(e.to_string()),
+			AppError::ResponseTooLarge(_) => {
+				Self::resource_exhausted(e.to_string())
+			}
+			e => Self::internal(e.to_string()),
 		}
 	}
 }
@@ -1,11 +1,37 @@
-pub mod app;
+// a build leaving out a backend also leaves out the only users of some
+// helpers, the full build is the one linted
+#![cfg_attr(
+	not(all(
+		feature = "clickhouse",
+		feature = "databend",
+		feature = "quickwit"
+	)),
+	allow(dead_code, unused_imports)
+)]
+
+pub(crate) mod app;
+pub mod cli;
 pub(crate) mod config;
+pub(crate) mod debug;
 pub(crate) mod errors;
 pub(crate) mod logquery;
 pub(crate) mod metrics;
 pub(crate) mod proto;
+pub(crate) mod query_tags;
 pub(crate) mod routes;
 pub(crate) mod state;
 pub(crate) mod storage;
+pub(crate) mod tenant;
+pub(crate) mod tls;
 pub(crate) mod trace;
 pub(crate) mod utils;
+
+// only for benches/, not a stable api
+#[doc(hidden)]
+pub mod bench {
+	#[cfg(feature = "clickhouse")]
+	pub use crate::storage::ck::{log::decode_logs, trace::decode_spans};
+	pub use crate::storage::labels::{LabelSet, SeriesStore};
+	pub use crate::storage::trace::{Links, SpanEvent, SpanItem};
+	pub use crate::trace::spans_into_resourcespans;
+}
@@ -0,0 +1,213 @@
+use super::{post_filter::needs_post_filter, LokiDate};
+use crate::{errors::AppError, state::AppState, tenant::Tenant};
+use axum::{
+	extract::{Query, State},
+	http::StatusCode,
+	Json,
+};
+use chrono::Utc;
+use common::TimeRange;
+use logql::parser;
+use serde::{Deserialize, Serialize};
+use std::sync::{
+	atomic::{AtomicU64, Ordering},
+	Arc, Mutex,
+};
+use tracing::{error, info};
+
+// the oldest finished requests are forgotten past this
+const MAX_DELETE_REQUESTS: usize = 1000;
+
+#[derive(Deserialize, Debug)]
+pub struct DeleteLogsRequest {
+	pub query: String,
+	pub start: LokiDate,
+	// defaults to now
+	pub end: Option<LokiDate>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DeleteStatus {
+	Received,
+	Processing,
+	Processed,
+	Failed,
+}
+
+// same fields as a loki delete request, times are unix seconds
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct DeleteRequest {
+	pub request_id: String,
+	pub start_time: i64,
+	pub end_time: i64,
+	pub query: String,
+	pub status: DeleteStatus,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub error: Option<String>,
+	#[serde(skip)]
+	tenant: String,
+}
+
+// DeleteJobs remembers the delete requests since startup,
+// it's shared by all clones of the app state
+#[derive(Clone, Default)]
+pub struct DeleteJobs {
+	next_id: Arc<AtomicU64>,
+	requests: Arc<Mutex<Vec<DeleteRequest>>>,
+}
+
+impl DeleteJobs {
+	fn add(
+		&self,
+		tenant: &Tenant,
+		query: String,
+		start_time: i64,
+		end_time: i64,
+	) -> String {
+		let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+		let request_id = format!("{:08x}", id);
+		let mut requests = self.requests.lock().unwrap();
+		if requests.len() >= MAX_DELETE_REQUESTS {
+			if let Some(i) = requests.iter().position(|r| {
+				matches!(
+					r.status,
+					DeleteStatus::Processed | DeleteStatus::Failed
+				)
+			}) {
+				requests.remove(i);
+			}
+		}
+		requests.push(DeleteRequest {
+			request_id: request_id.clone(),
+			start_time,
+			end_time,
+			query,
+			status: DeleteStatus::Received,
+			error: None,
+			tenant: tenant.0.clone(),
+		});
+		request_id
+	}
+	fn set_status(
+		&self,
+		request_id: &str,
+		status: DeleteStatus,
+		error: Option<String>,
+	) {
+		let mut requests = self.requests.lock().unwrap();
+		if let Some(r) =
+			requests.iter_mut().find(|r| r.request_id == request_id)
+		{
+			r.status = status;
+			r.error = error;
+		}
+	}
+	pub fn list(&self, tenant: &Tenant) -> Vec<DeleteRequest> {
+		self.requests
+			.lock()
+			.unwrap()
+			.iter()
+			.filter(|r| r.tenant == tenant.0)
+			.cloned()
+			.collect()
+	}
+}
+
+// https://grafana.com/docs/loki/latest/reference/loki-http-api/#request-log-deletion
+// the delete runs in the background, its progress is listed by list_deletes
+pub async fn delete_logs(
+	State(state): State<AppState>,
+	tenant: Tenant,
+	Query(req): Query<DeleteLogsRequest>,
+) -> Result<StatusCode, AppError> {
+	if !state.config.server.allow_deletes {
+		return Err(AppError::DeletesDisabled);
+	}
+	let state = state.for_tenant(&tenant);
+	let parser::Query::LogQuery(mut q) = parser::parse_logql_query(&req.query)?
+	else {
+		return Err(AppError::InvalidQueryString(req.query));
+	};
+	state.label_names.restore_query(&mut q)?;
+	let handle = state.log_handle;
+	if !handle.can_delete() {
+		return Err(AppError::UnsupportedFeature(
+			"deleting logs from this source".to_string(),
+		));
+	}
+	// deleting more than asked for is worse than not deleting
+	if needs_post_filter(&q, handle.capabilities()) {
+		return Err(AppError::UnsupportedFeature(
+			"deleting by regex or json stages".to_string(),
+		));
+	}
+	let start = req.start.0;
+	let end = req.end.map_or_else(Utc::now, |e| e.0);
+	if end < start {
+		return Err(AppError::InvalidTimeFormat(
+			"end is before start".to_string(),
+		));
+	}
+	let jobs = state.deletes;
+	let id = jobs.add(&tenant, req.query, start.timestamp(), end.timestamp());
+	let range = TimeRange {
+		start: Some(start.naive_utc()),
+		end: Some(end.naive_utc()),
+	};
+	let cache = state.cache;
+	tokio::spawn(async move {
+		jobs.set_status(&id, DeleteStatus::Processing, None);
+		let res = handle.delete(&q, range).await;
+		// cached results may still have the deleted lines, even a failed
+		// delete may have removed some of them
+		cache.clear();
+		match res {
+			Ok(()) => {
+				info!("delete request {} processed", id);
+				jobs.set_status(&id, DeleteStatus::Processed, None);
+			}
+			Err(e) => {
+				error!("delete request {} failed: {}", id, e);
+				jobs.set_status(&id, DeleteStatus::Failed, Some(e.to_string()));
+			}
+		}
+	});
+	Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn list_deletes(
+	State(state): State<AppState>,
+	tenant: Tenant,
+) -> Json<Vec<DeleteRequest>> {
+	Json(state.deletes.list(&tenant))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use pretty_assertions::assert_eq;
+
+	#[test]
+	fn test_delete_jobs() {
+		let jobs = DeleteJobs::default();
+		let (a, b) = (Tenant("a".to_string()), Tenant("b".to_string()));
+		let id = jobs.add(&a, r#"{app="x"}"#.to_string(), 10, 20);
+		jobs.add(&b, r#"{app="y"}"#.to_string(), 10, 20);
+		jobs.set_status(&id, DeleteStatus::Failed, Some("boom".to_string()));
+		let got = jobs.list(&a);
+		assert_eq!(got.len(), 1);
+		assert_eq!(got[0].status, DeleteStatus::Failed);
+		assert_eq!(
+			serde_json::to_value(&got[0]).unwrap(),
+			serde_json::json!({
+				"request_id": id,
+				"start_time": 10,
+				"end_time": 20,
+				"query": "{app=\"x\"}",
+				"status": "failed",
+				"error": "boom",
+			})
+		);
+	}
+}
@@ -0,0 +1,204 @@
+use super::query_range::regroup;
+use crate::{
+	errors::AppError,
+	state::AppState,
+	storage::{log::MetricItem, QueryLimits},
+};
+use chrono::{DateTime, TimeDelta, Utc};
+use common::TimeRange;
+use logql::parser::{BinaryOp, MetricExpr, MetricQuery, RankOp};
+use std::{collections::BTreeMap, future::Future, pin::Pin};
+
+pub(super) type Labels = BTreeMap<String, String>;
+// Series holds the points of every series of a result, keyed by its labels
+pub(super) type Series = BTreeMap<Labels, BTreeMap<DateTime<Utc>, f64>>;
+
+// evaluate runs every metric query of expr, both sides of an operation
+// at the same time, and combines their results
+pub(super) fn evaluate<'a>(
+	expr: &'a MetricExpr,
+	state: &'a AppState,
+	opt: &'a QueryLimits,
+) -> Pin<Box<dyn Future<Output = Result<Series, AppError>> + Send + 'a>> {
+	Box::pin(async move {
+		match expr {
+			MetricExpr::Metric(mq) => query(mq, state, opt.clone()).await,
+			MetricExpr::Binary(b) => {
+				let (lhs, rhs) = tokio::try_join!(
+					evaluate(&b.lhs, state, opt),
+					evaluate(&b.rhs, state, opt)
+				)?;
+				Ok(apply(b.op, lhs, rhs))
+			}
+			MetricExpr::Rank(r) => {
+				let max = state.config.limits.max_rank_k;
+				if r.k > max {
+					return Err(AppError::InvalidQueryString(format!(
+						"k of topk and bottomk can't exceed {}",
+						max
+					)));
+				}
+				let series = evaluate(&r.expr, state, opt).await?;
+				Ok(rank(r.op, r.k, series))
+			}
+		}
+	})
+}
+
+// an offset query reads an earlier range and its points are moved
+// forward again, so they line up with the rest of the result
+async fn query(
+	mq: &MetricQuery,
+	state: &AppState,
+	mut opt: QueryLimits,
+) -> Result<Series, AppError> {
+	let mut mq = mq.clone();
+	state.label_names.restore_metric(&mut mq)?;
+	let offset = mq.offset.and_then(|d| TimeDelta::from_std(d).ok());
+	if let Some(d) = offset {
+		opt.range = shift(&opt.range, -d);
+	}
+	let mut rows = state.log_handle.query_metrics(&mq, opt).await?;
+	if let Some(d) = offset {
+		rows.iter_mut().for_each(|r| r.ts += d);
+	}
+	Ok(into_series(regroup(rows, &mq.agg_by)))
+}
+
+fn shift(range: &TimeRange, d: TimeDelta) -> TimeRange {
+	TimeRange {
+		start: range.start.map(|t| t + d),
+		end: range.end.map(|t| t + d),
+	}
+}
+
+pub(super) fn into_series(rows: Vec<MetricItem>) -> Series {
+	let mut series = Series::new();
+	for r in rows {
+		*series.entry(r.labels).or_default().entry(r.ts).or_default() +=
+			r.total as f64;
+	}
+	series
+}
+
+// apply matches series by their exact label set and points by timestamp.
+// Anything without a counterpart on the other side is dropped, and so is
+// a division by zero, rather than showing up as Inf
+fn apply(op: BinaryOp, lhs: Series, mut rhs: Series) -> Series {
+	lhs.into_iter()
+		.filter_map(|(labels, l)| {
+			let r = rhs.remove(&labels)?;
+			let points: BTreeMap<_, _> = l
+				.into_iter()
+				.filter_map(|(ts, a)| {
+					let b = *r.get(&ts)?;
+					let v = match op {
+						BinaryOp::Add => a + b,
+						BinaryOp::Sub => a - b,
+						BinaryOp::Mul => a * b,
+						BinaryOp::Div if b == 0.0 => return None,
+						BinaryOp::Div => a / b,
+					};
+					Some((ts, v))
+				})
+				.collect();
+			(!points.is_empty()).then_some((labels, points))
+		})
+		.collect()
+}
+
+// rank keeps the k highest or lowest series at each timestamp, which
+// can't be pushed down as the ranking is over the aggregated series
+fn rank(op: RankOp, k: usize, series: Series) -> Series {
+	let mut steps: BTreeMap<_, Vec<_>> = BTreeMap::new();
+	for (labels, points) in &series {
+		for (ts, v) in points {
+			steps.entry(*ts).or_default().push((labels, *v));
+		}
+	}
+	let mut out = Series::new();
+	for (ts, mut values) in steps {
+		values.sort_by(|a, b| match op {
+			RankOp::TopK => b.1.total_cmp(&a.1),
+			RankOp::BottomK => a.1.total_cmp(&b.1),
+		});
+		for (labels, v) in values.into_iter().take(k) {
+			out.entry(labels.clone()).or_default().insert(ts, v);
+		}
+	}
+	out
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use common::LogLevel;
+	use pretty_assertions::assert_eq;
+
+	#[test]
+	fn test_rank() {
+		let ts = |s| DateTime::from_timestamp(s, 0).unwrap();
+		let series = into_series(vec![
+			MetricItem::by_level(LogLevel::Error, 5, ts(0)),
+			MetricItem::by_level(LogLevel::Error, 1, ts(60)),
+			MetricItem::by_level(LogLevel::Info, 3, ts(0)),
+			MetricItem::by_level(LogLevel::Info, 4, ts(60)),
+			MetricItem::by_level(LogLevel::Warn, 2, ts(0)),
+		]);
+		let level = |l: &str| Labels::from([("level".to_string(), l.into())]);
+		let got = rank(RankOp::TopK, 1, series.clone());
+		assert_eq!(
+			got,
+			Series::from([
+				(level("ERROR"), [(ts(0), 5.0)].into()),
+				(level("INFO"), [(ts(60), 4.0)].into()),
+			])
+		);
+		let got = rank(RankOp::BottomK, 1, series);
+		assert_eq!(
+			got,
+			Series::from([
+				(level("ERROR"), [(ts(60), 1.0)].into()),
+				(level("WARN"), [(ts(0), 2.0)].into()),
+			])
+		);
+	}
+
+	#[test]
+	fn test_shift() {
+		let t = DateTime::from_timestamp(7200, 0).unwrap().naive_utc();
+		let range = TimeRange {
+			start: Some(t),
+			end: None,
+		};
+		let got = shift(&range, -TimeDelta::hours(1));
+		assert_eq!(got.start.map(|t| t.and_utc().timestamp()), Some(3600));
+		assert_eq!(got.end, None);
+	}
+
+	#[test]
+	fn test_apply() {
+		let ts = |s| DateTime::from_timestamp(s, 0).unwrap();
+		let errors = into_series(vec![
+			MetricItem::by_level(LogLevel::Error, 1, ts(0)),
+			MetricItem::by_level(LogLevel::Error, 0, ts(60)),
+			MetricItem::by_level(LogLevel::Info, 3, ts(0)),
+		]);
+		let total = into_series(vec![
+			MetricItem::by_level(LogLevel::Error, 4, ts(0)),
+			MetricItem::by_level(LogLevel::Error, 0, ts(60)),
+			MetricItem::by_level(LogLevel::Error, 2, ts(120)),
+		]);
+		let got = apply(BinaryOp::Div, errors.clone(), total.clone());
+		let error = Labels::from([("level".to_string(), "ERROR".to_string())]);
+		assert_eq!(
+			got,
+			Series::from([(error.clone(), [(ts(0), 0.25)].into())])
+		);
+		let got = apply(BinaryOp::Sub, total, errors);
+		assert_eq!(
+			got,
+			Series::from([(error, [(ts(0), 3.0), (ts(60), 0.0)].into())])
+		);
+	}
+}
@@ -0,0 +1,56 @@
+use super::{
+	post_filter::{needs_post_filter, POST_FILTER_MAX_ROWS},
+	query_range::check_capabilities,
+	ResponseStatus,
+};
+use crate::{errors::AppError, state::AppState, tenant::Tenant};
+use axum::{
+	extract::{Form, State},
+	Json,
+};
+use logql::{format::format_query as format_logql, parser};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Deserialize)]
+pub struct FormatQueryRequest {
+	pub query: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FormatQueryResponse {
+	pub status: ResponseStatus,
+	// the query as it's understood, in a normalized form
+	pub data: String,
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	pub warnings: Vec<String>,
+}
+
+// https://grafana.com/docs/loki/latest/reference/loki-http-api/#format-a-logql-query
+// Form reads the query string for GET and the body for POST
+pub async fn format_query(
+	State(state): State<AppState>,
+	tenant: Tenant,
+	Form(req): Form<FormatQueryRequest>,
+) -> Result<Json<FormatQueryResponse>, AppError> {
+	let state = state.for_tenant(&tenant);
+	let ql = parser::parse_logql_query(&req.query)?;
+	let caps = state.log_handle.capabilities();
+	let mut warnings = vec![];
+	if let Err(e) = check_capabilities(&ql, caps) {
+		warnings.push(e.to_string());
+	}
+	if let parser::Query::LogQuery(q) = &ql {
+		if needs_post_filter(q, caps) {
+			warnings.push(format!(
+				"some stages are applied by the bridge to at most {} rows, \
+				 matching logs may be missed",
+				POST_FILTER_MAX_ROWS
+			));
+		}
+	}
+	Ok(Json(FormatQueryResponse {
+		status: ResponseStatus::Success,
+		data: format_logql(&ql),
+		warnings,
+	}))
+}
@@ -0,0 +1,200 @@
+use super::{
+	query_range::{check_capabilities, run_query},
+	Direction, LokiDate, QueryRangeRequest, QueryRangeResponse, QueryResult,
+};
+use crate::{
+	config::HotQuery, errors::AppError, state::AppState, tenant::Tenant,
+};
+use chrono::Utc;
+use logql::parser;
+use serde::{Deserialize, Serialize};
+use std::{
+	collections::hash_map::RandomState,
+	hash::{BuildHasher, Hash, Hasher},
+	sync::Arc,
+	time::{Duration, Instant},
+};
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, warn};
+
+// the last run of a hot query, bounds are unix seconds
+#[derive(Serialize, Deserialize, Debug)]
+struct HotEntry {
+	start: i64,
+	end: i64,
+	resp: QueryRangeResponse,
+}
+
+impl HotEntry {
+	// answer covers requests inside the range of the run that end at most
+	// one interval after it, the points outside the request are dropped
+	fn answer(
+		self,
+		interval: Duration,
+		start: i64,
+		end: i64,
+	) -> Option<QueryRangeResponse> {
+		let stale = end > self.end + interval.as_secs() as i64;
+		if start < self.start || stale {
+			return None;
+		}
+		let mut resp = self.resp;
+		if let QueryResult::Matrix(m) = &mut resp.data {
+			for s in &mut m.result {
+				s.values.retain(|[ts, _]| {
+					ts.as_f64().is_some_and(|ts| {
+						ts >= start as f64 && ts <= end as f64
+					})
+				});
+			}
+			m.result.retain(|s| !s.values.is_empty());
+		}
+		Some(resp)
+	}
+}
+
+// keys of the regular entries are json, they never start with a NUL
+fn cache_key(q: &HotQuery) -> String {
+	format!("\0hot\0{:?}\0{}", q.step, q.query)
+}
+
+// lookup answers req from the last run of the hot query with the same
+// query and step, if there is one and it's recent enough. The runs cut
+// long values, a request for full ones is never answered from them
+pub(super) fn lookup(
+	state: &AppState,
+	req: &QueryRangeRequest,
+) -> Option<QueryRangeResponse> {
+	if req.full_attributes && state.config.limits.max_attribute_length > 0 {
+		return None;
+	}
+	let q = state
+		.config
+		.hot_queries
+		.iter()
+		.find(|h| h.query == req.query && h.step == req.step)?;
+	let start = req.start.as_ref()?.0.timestamp();
+	let end = req.end.as_ref()?.0.timestamp();
+	let v = state.cache.get(&cache_key(q))?;
+	let entry: HotEntry = serde_json::from_slice(&v).ok()?;
+	entry.answer(q.interval, start, end)
+}
+
+// spawn refreshes every hot query on its own interval, as the default
+// tenant. Replicas don't coordinate, each runs every hot query. The first
+// run is delayed by a random part of the interval though, so replicas
+// started together spread their runs instead of querying the backend at
+// the same moment every time
+pub fn spawn(state: AppState) {
+	let seed = RandomState::new();
+	for q in state.config.hot_queries.clone() {
+		let mut h = seed.build_hasher();
+		q.query.hash(&mut h);
+		let offset = q.interval.mul_f64((h.finish() % 1000) as f64 / 1000.0);
+		tokio::spawn(refresh_loop(state.clone(), q, offset));
+	}
+}
+
+async fn refresh_loop(state: AppState, q: HotQuery, offset: Duration) {
+	let tenant = Tenant(state.config.tenant.default_tenant.clone());
+	let state = state.for_tenant(&tenant);
+	tokio::time::sleep(offset).await;
+	let mut ticker = tokio::time::interval(q.interval);
+	// a run slower than the interval delays the next one, runs of the same
+	// query never overlap
+	ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+	loop {
+		ticker.tick().await;
+		let start = Instant::now();
+		match refresh(&state, &q).await {
+			Ok(()) => {
+				debug!("hot query {} took {:?}", q.query, start.elapsed())
+			}
+			Err(e) => warn!("hot query {} fails: {}", q.query, e),
+		}
+	}
+}
+
+async fn refresh(state: &AppState, q: &HotQuery) -> Result<(), AppError> {
+	let end = Utc::now();
+	let start = end - q.range;
+	let req = QueryRangeRequest {
+		query: q.query.clone(),
+		start: Some(LokiDate(start)),
+		end: Some(LokiDate(end)),
+		limit: None,
+		direction: Direction::Backward,
+		step: q.step,
+		full_attributes: false,
+	};
+	let ql = parser::parse_logql_query(&q.query)?;
+	let caps = state.log_handle.capabilities();
+	check_capabilities(&ql, caps)?;
+	let resp = run_query(ql, req, state.clone(), caps).await?;
+	let entry = HotEntry {
+		start: start.timestamp(),
+		end: end.timestamp(),
+		resp,
+	};
+	let d = serde_json::to_vec(&entry)?;
+	state.cache.insert(cache_key(q), Arc::new(d));
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::logquery::{
+		MatrixResponse, MatrixValue, ResponseStatus, ResultType,
+	};
+	use pretty_assertions::assert_eq;
+	use std::collections::HashMap;
+
+	#[test]
+	fn test_answer() {
+		let entry = || HotEntry {
+			start: 1000,
+			end: 2000,
+			resp: QueryRangeResponse {
+				status: ResponseStatus::Success,
+				data: QueryResult::Matrix(MatrixResponse {
+					result_type: ResultType::Matrix,
+					result: vec![
+						MatrixValue {
+							metric: HashMap::new(),
+							values: vec![
+								[1000.into(), "1".into()],
+								[1500.into(), "2".into()],
+								[2000.into(), "3".into()],
+							],
+						},
+						MatrixValue {
+							metric: HashMap::new(),
+							values: vec![[1000.into(), "4".into()]],
+						},
+					],
+					stats: None,
+				}),
+				warnings: vec![],
+			},
+		};
+		let minute = Duration::from_secs(60);
+		let values = |resp: QueryRangeResponse| match resp.data {
+			QueryResult::Matrix(m) => {
+				m.result.into_iter().map(|s| s.values).collect::<Vec<_>>()
+			}
+			_ => unreachable!(),
+		};
+		let got = entry().answer(minute, 1200, 2050).map(values);
+		assert_eq!(
+			got,
+			Some(vec![vec![
+				[1500.into(), "2".into()],
+				[2000.into(), "3".into()]
+			]])
+		);
+		// older than the run, or newer than one interval after it
+		assert!(entry().answer(minute, 900, 2000).is_none());
+		assert!(entry().answer(minute, 1200, 2061).is_none());
+	}
+}
@@ -0,0 +1,281 @@
+use crate::{config::ServiceLabel, errors::AppError, tenant::Tenant};
+use dashmap::DashMap;
+use logql::parser::{Filter, LogQuery, MetricQuery};
+use std::{
+	collections::{BTreeSet, HashMap},
+	sync::Arc,
+};
+
+// the service label as the backends name it, and as otel does
+const SERVICE_NAME: &str = "ServiceName";
+const OTEL_SERVICE_NAME: &str = "service_name";
+
+// LabelNames turns attribute keys into valid prometheus label names,
+// e.g. resources_k8s.pod.name into resources_k8s_pod_name. The names it
+// hands out are remembered per tenant so selectors using them can be
+// mapped back, a name two keys map to, like a.b and a_b, is rejected.
+// It also returns ServiceName under the name server.service_label asks for
+#[derive(Debug, Clone, Default)]
+pub struct LabelNames {
+	enabled: bool,
+	service: ServiceLabel,
+	// keys are prefixed with the tenant as in TenantCache
+	prefix: String,
+	originals: Arc<DashMap<String, BTreeSet<String>>>,
+}
+
+// [a-zA-Z_][a-zA-Z0-9_]*
+fn sanitized(name: &str) -> String {
+	let mut s: String = name
+		.chars()
+		.map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+		.collect();
+	if s.starts_with(|c: char| c.is_ascii_digit()) {
+		s.insert(0, '_');
+	}
+	s
+}
+
+impl LabelNames {
+	pub fn new(enabled: bool) -> Self {
+		Self {
+			enabled,
+			..Default::default()
+		}
+	}
+	pub fn with_service_label(mut self, service: ServiceLabel) -> Self {
+		self.service = service;
+		self
+	}
+	pub fn for_tenant(&self, t: &Tenant) -> Self {
+		Self {
+			prefix: format!("{}\0", t.0),
+			..self.clone()
+		}
+	}
+	fn service_names(&self) -> &'static [&'static str] {
+		match self.service {
+			ServiceLabel::ServiceName => &[SERVICE_NAME],
+			ServiceLabel::Otel => &[OTEL_SERVICE_NAME],
+			ServiceLabel::Both => &[SERVICE_NAME, OTEL_SERVICE_NAME],
+		}
+	}
+	// names that are already valid are remembered too, they are what
+	// another key may collide with
+	pub fn sanitize(&self, name: &str) -> String {
+		if !self.enabled {
+			return name.to_string();
+		}
+		let s = sanitized(name);
+		let key = self.prefix.clone() + &s;
+		let known = self.originals.get(&key).is_some_and(|o| o.contains(name));
+		if !known {
+			self.originals
+				.entry(key)
+				.or_default()
+				.insert(name.to_string());
+		}
+		s
+	}
+	// sanitize_names is sanitize for a list of label names, where the
+	// service label may become two
+	pub fn sanitize_names(&self, names: &[String]) -> Vec<String> {
+		let mut out = Vec::with_capacity(names.len() + 1);
+		for name in names {
+			match name.as_str() {
+				SERVICE_NAME => out
+					.extend(self.service_names().iter().map(|n| n.to_string())),
+				_ => out.push(self.sanitize(name)),
+			}
+		}
+		out
+	}
+	pub fn sanitize_keys(
+		&self,
+		mut labels: HashMap<String, String>,
+	) -> HashMap<String, String> {
+		if self.service != ServiceLabel::ServiceName {
+			if let Some(v) = labels.remove(SERVICE_NAME) {
+				for n in self.service_names() {
+					labels.insert(n.to_string(), v.clone());
+				}
+			}
+		}
+		if !self.enabled {
+			return labels;
+		}
+		labels
+			.into_iter()
+			.map(|(k, v)| (self.sanitize(&k), v))
+			.collect()
+	}
+	// names never handed out are taken as they are
+	pub fn original(&self, name: &str) -> Result<String, AppError> {
+		if name == OTEL_SERVICE_NAME
+			&& self.service != ServiceLabel::ServiceName
+		{
+			return Ok(SERVICE_NAME.to_string());
+		}
+		let Some(originals) = self.originals.get(&(self.prefix.clone() + name))
+		else {
+			return Ok(name.to_string());
+		};
+		match originals.len() {
+			1 => Ok(originals.first().cloned().unwrap_or_default()),
+			_ => Err(AppError::InvalidQueryString(format!(
+				"label {} is ambiguous, it stands for {}",
+				name,
+				originals.iter().cloned().collect::<Vec<_>>().join(" and ")
+			))),
+		}
+	}
+	// restore_query maps the stream labels of q back, labels extracted
+	// by `| json` are left alone
+	pub fn restore_query(&self, q: &mut LogQuery) -> Result<(), AppError> {
+		if !self.enabled && self.service == ServiceLabel::ServiceName {
+			return Ok(());
+		}
+		for p in &mut q.selector.label_paris {
+			p.label = self.original(&p.label)?;
+		}
+		for f in q.filters.iter_mut().flatten() {
+			match f {
+				Filter::Json => break,
+				Filter::Label(p) => p.label = self.original(&p.label)?,
+				Filter::Drop(label) => *label = self.original(label)?,
+				Filter::LogLine(_) => {}
+			}
+		}
+		Ok(())
+	}
+	// restore_metric also maps the labels the query sums by
+	pub fn restore_metric(&self, q: &mut MetricQuery) -> Result<(), AppError> {
+		self.restore_query(&mut q.log_query)?;
+		if !self.enabled && self.service == ServiceLabel::ServiceName {
+			return Ok(());
+		}
+		for label in &mut q.agg_by {
+			*label = self.original(label)?;
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use logql::parser::{parse_logql_query, Query};
+	use pretty_assertions::assert_eq;
+
+	#[test]
+	fn test_label_names() {
+		let names = LabelNames::new(true);
+		assert_eq!(
+			names.sanitize("resources_k8s.pod.name"),
+			"resources_k8s_pod_name"
+		);
+		assert_eq!(names.sanitize("9lives"), "_9lives");
+		assert_eq!(names.sanitize("ServiceName"), "ServiceName");
+		let Ok(Query::LogQuery(mut q)) = parse_logql_query(
+			r#"{resources_k8s_pod_name="a"} | resources_k8s_pod_name!="b" | json | resources_k8s_pod_name="c""#,
+		) else {
+			unreachable!()
+		};
+		names.restore_query(&mut q).unwrap();
+		assert_eq!(q.selector.label_paris[0].label, "resources_k8s.pod.name");
+		let labels: Vec<_> = q
+			.filters
+			.unwrap()
+			.into_iter()
+			.filter_map(|f| match f {
+				Filter::Label(p) => Some(p.label),
+				_ => None,
+			})
+			.collect();
+		assert_eq!(
+			labels,
+			vec!["resources_k8s.pod.name", "resources_k8s_pod_name"]
+		);
+		// off means untouched
+		assert_eq!(
+			LabelNames::new(false).sanitize("resources_k8s.pod.name"),
+			"resources_k8s.pod.name"
+		);
+	}
+
+	#[test]
+	fn test_service_label() {
+		let labels = vec!["ServiceName".to_string(), "level".to_string()];
+		let stream = HashMap::from([
+			("ServiceName".to_string(), "api".to_string()),
+			("level".to_string(), "info".to_string()),
+		]);
+		let cases = [
+			(ServiceLabel::ServiceName, vec!["ServiceName", "level"]),
+			(ServiceLabel::Otel, vec!["service_name", "level"]),
+			(
+				ServiceLabel::Both,
+				vec!["ServiceName", "service_name", "level"],
+			),
+		];
+		for (service, want) in cases {
+			let names = LabelNames::new(false).with_service_label(service);
+			assert_eq!(names.sanitize_names(&labels), want);
+			let mut keys: Vec<_> =
+				names.sanitize_keys(stream.clone()).into_keys().collect();
+			keys.sort();
+			let mut want = want;
+			want.sort();
+			assert_eq!(keys, want);
+		}
+		let names =
+			LabelNames::new(false).with_service_label(ServiceLabel::Otel);
+		let Ok(Query::LogQuery(mut q)) =
+			parse_logql_query(r#"{service_name="api"} | service_name!="db""#)
+		else {
+			unreachable!()
+		};
+		names.restore_query(&mut q).unwrap();
+		assert_eq!(q.selector.label_paris[0].label, "ServiceName");
+		// the default leaves a service_name selector to the backend
+		assert_eq!(
+			LabelNames::new(false).original("service_name").unwrap(),
+			"service_name"
+		);
+	}
+
+	#[test]
+	fn test_restore_metric() {
+		let names = LabelNames::new(true)
+			.with_service_label(ServiceLabel::Otel)
+			.for_tenant(&Tenant("a".to_string()));
+		names.sanitize("resources_k8s.pod.name");
+		let Ok(Query::MetricQuery(mut q)) = parse_logql_query(
+			r#"sum by (resources_k8s_pod_name, service_name) (count_over_time({service_name="api"}[1m]))"#,
+		) else {
+			unreachable!()
+		};
+		names.restore_metric(&mut q).unwrap();
+		assert_eq!(q.agg_by, vec!["resources_k8s.pod.name", "ServiceName"]);
+		// another tenant never saw the key
+		let b = names.for_tenant(&Tenant("b".to_string()));
+		assert_eq!(
+			b.original("resources_k8s_pod_name").unwrap(),
+			"resources_k8s_pod_name"
+		);
+	}
+
+	#[test]
+	fn test_collision() {
+		let names = LabelNames::new(true);
+		assert_eq!(names.sanitize("attributes_a.b"), "attributes_a_b");
+		assert_eq!(names.original("attributes_a_b").unwrap(), "attributes_a.b");
+		assert_eq!(names.sanitize("attributes_a_b"), "attributes_a_b");
+		assert!(matches!(
+			names.original("attributes_a_b"),
+			Err(AppError::InvalidQueryString(_))
+		));
+		// valid names map to themselves
+		assert_eq!(names.original("level").unwrap(), "level");
+	}
+}
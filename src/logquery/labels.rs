@@ -1,15 +1,21 @@
 use std::{cmp::Ordering, sync::Arc};
 
 use super::*;
-use crate::{errors::AppError, state::AppState};
+use crate::{
+	errors::AppError,
+	state::{AppState, TenantCache},
+	storage::log::ValueFilter,
+	tenant::Tenant,
+	utils::validate::{params, ValidQuery},
+};
 use axum::{
-	extract::{rejection::QueryRejection, Path, Query, State},
+	extract::{Path, State},
 	Json,
 };
 use common::TimeRange;
 use flate2::{read::GzDecoder, write::GzEncoder, Compression};
 use logql::parser;
-use moka::{sync::Cache, Expiry};
+use moka::Expiry;
 use serde::de::DeserializeOwned;
 use tokio::time::{interval_at, Instant};
 use tracing::{debug, error};
@@ -24,77 +30,161 @@ const LABEL_VALUES_CACHE_KEY_PREFIX: &str = "lbvs:";
 
 pub async fn query_labels(
 	State(state): State<AppState>,
-	_: Query<QueryLabelsRequest>,
+	tenant: Tenant,
+	req: ValidQuery<QueryLabelsRequest>,
 ) -> Result<QueryLabelsResponse, AppError> {
+	let req = params(req).map_err(AppError::InvalidParams)?;
+	let state = state.for_tenant(&tenant);
+	let (range, window) = label_window(
+		req.start.as_ref(),
+		req.end.as_ref(),
+		req.since,
+		state.config.limits.label_lookback,
+	);
 	let cache = state.cache;
-	if let Some(c) = cache.get(LABELS_CACHE_KEY) {
+	let cache_key = LABELS_CACHE_KEY.to_string() + &window;
+	if let Some(c) = cache.get(&cache_key) {
 		return deserialize_from_slice(&c);
 	}
 	let labels = state
 		.log_handle
 		.labels(QueryLimits {
 			limit: None,
-			range: t_hours_before(2),
+			range,
 			direction: None,
 			step: None,
+			spss: None,
+			unbounded: false,
 		})
 		.await?;
 	let should_cache = !labels.is_empty();
 	let resp = QueryLabelsResponse {
 		status: ResponseStatus::Success,
-		data: labels,
+		data: state.label_names.sanitize_names(&labels),
+		warnings: state
+			.log_handle
+			.high_cardinality_labels()
+			.iter()
+			.map(|l| high_cardinality_warning(&state.label_names.sanitize(l)))
+			.collect(),
 	};
 	if should_cache {
 		let d = serialize_to_vec(&resp)?;
-		cache.insert(LABELS_CACHE_KEY.to_string(), Arc::new(d));
+		cache.insert(cache_key, Arc::new(d));
 	}
 	Ok(resp)
 }
 
-fn t_hours_before(hours: u64) -> TimeRange {
-	let start = Utc::now() - Duration::from_secs(hours * 60 * 60);
+fn high_cardinality_warning(label: &str) -> String {
+	format!(
+		"label {} has too many values, only some of them are listed \
+		 and it's left out of series",
+		label
+	)
+}
+
+fn lookback(d: Duration) -> TimeRange {
 	TimeRange {
-		start: Some(start.naive_utc()),
+		start: Some((Utc::now() - d).naive_utc()),
 		end: None,
 	}
 }
 
+// the range a labels lookup covers: start/end when given, otherwise
+// since or the configured lookback before now. The second value
+// suffixes the cache key, an explicit window is cached per minute and
+// the default one shares the plain key with the background refresh
+fn label_window(
+	start: Option<&LokiDate>,
+	end: Option<&LokiDate>,
+	since: Option<Duration>,
+	default_lookback: Duration,
+) -> (TimeRange, String) {
+	let explicit = start.is_some() || end.is_some() || since.is_some();
+	let start = start
+		.map(|v| v.0)
+		.unwrap_or_else(|| Utc::now() - since.unwrap_or(default_lookback));
+	let end = end.map(|v| v.0);
+	let range = TimeRange {
+		start: Some(start.naive_utc()),
+		end: end.map(|v| v.naive_utc()),
+	};
+	let window = if explicit {
+		format!(
+			"@{}-{}",
+			start.timestamp() / 60,
+			end.map(|e| (e.timestamp() / 60).to_string())
+				.unwrap_or_default()
+		)
+	} else {
+		String::new()
+	};
+	(range, window)
+}
+
 fn label_values_cache_key(k: &str) -> String {
 	LABEL_VALUES_CACHE_KEY_PREFIX.to_string() + k
 }
 
+fn not_a_selector(matches: &str) -> AppError {
+	AppError::InvalidParams(format!(
+		"match[] must be a stream selector with labels, got {}",
+		matches
+	))
+}
+
 fn series_cache_key_with_matches(matches: &str) -> String {
 	SERIES_CACHE_KEY.to_string() + KEY_SPLITER + matches
 }
 
 pub async fn query_label_values(
 	State(state): State<AppState>,
+	tenant: Tenant,
 	Path(label): Path<String>,
-	_: Query<QueryLabelValuesRequest>,
+	req: ValidQuery<QueryLabelValuesRequest>,
 ) -> Result<QueryLabelsResponse, AppError> {
+	let req = params(req).map_err(AppError::InvalidParams)?;
+	let state = state.for_tenant(&tenant);
+	let label = state.label_names.original(&label)?;
+	let filter = req.query.as_deref().and_then(ValueFilter::parse);
+	let (range, window) = label_window(
+		req.start.as_ref(),
+		req.end.as_ref(),
+		req.since,
+		state.config.limits.label_lookback,
+	);
 	let cache = state.cache;
-	let cache_key = label_values_cache_key(&label);
+	let cache_key = label_values_cache_key(&label) + &window;
 	if let Some(c) = cache.get(&cache_key) {
 		debug!("hit cache for label values: {}", cache_key);
-		return deserialize_from_slice(&c);
+		let mut resp: QueryLabelsResponse = deserialize_from_slice(&c)?;
+		if let Some(f) = &filter {
+			resp.data.retain(|v| f.matches(v));
+		}
+		return Ok(resp);
 	}
 	debug!("miss cache for label values: {}", cache_key);
 	let values = state
 		.log_handle
 		.label_values(
 			&label,
+			filter.as_ref(),
 			QueryLimits {
 				limit: None,
-				range: t_hours_before(2),
+				range,
 				direction: None,
 				step: None,
+				spss: None,
+				unbounded: false,
 			},
 		)
 		.await?;
-	let should_cache = !values.is_empty();
+	// only complete lists are cached, a filtered one would hide values
+	let should_cache = !values.is_empty() && filter.is_none();
 	let resp = QueryLabelsResponse {
 		status: ResponseStatus::Success,
 		data: values,
+		warnings: vec![],
 	};
 	if should_cache {
 		let d = serialize_to_vec(&resp)?;
@@ -105,24 +195,32 @@ pub async fn query_label_values(
 
 pub async fn query_series(
 	State(state): State<AppState>,
-	req: Result<Query<QuerySeriesRequest>, QueryRejection>,
+	tenant: Tenant,
+	req: ValidQuery<QuerySeriesRequest>,
 ) -> Result<Json<QuerySeriesResponse>, AppError> {
-	let req = req
-		.map_err(|e| AppError::InvalidQueryString(e.to_string()))?
-		.0;
-	let matches = if let parser::Query::LogQuery(lq) =
+	let req = params(req).map_err(AppError::InvalidParams)?;
+	let state = state.for_tenant(&tenant);
+	let matches = if let parser::Query::LogQuery(mut lq) =
 		parser::parse_logql_query(req.matches.as_str())?
 	{
+		state.label_names.restore_query(&mut lq)?;
 		lq
 	} else {
-		return Err(AppError::InvalidQueryString(req.matches));
+		return Err(not_a_selector(&req.matches));
 	};
 	// if no label pairs, client should not call this api
 	// instead, it should call query_labels
 	if matches.selector.label_paris.is_empty() {
-		return Err(AppError::InvalidQueryString(
-			req.matches.as_str().to_string(),
-		));
+		return Err(not_a_selector(&req.matches));
+	}
+	let (range, window) = label_window(
+		req.start.as_ref(),
+		req.end.as_ref(),
+		req.since,
+		state.config.limits.label_lookback,
+	);
+	if !window.is_empty() {
+		return series_in_window(&state, matches, range, window).await;
 	}
 	let canonicalized_matches =
 		canonicalize_matches(&matches.selector.label_paris);
@@ -130,9 +228,13 @@ pub async fn query_series(
 		series_cache_key_with_matches(&canonicalized_matches);
 	if let Some(v) = state.cache.get(&cache_key_with_matches) {
 		debug!("hit cache for series: {}", cache_key_with_matches);
+		let values: Vec<HashMap<String, String>> = deserialize_from_slice(&v)?;
 		return Ok(Json(QuerySeriesResponse {
 			status: ResponseStatus::Success,
-			data: deserialize_from_slice(&v)?,
+			data: values
+				.into_iter()
+				.map(|m| state.label_names.sanitize_keys(m))
+				.collect(),
 		}));
 	}
 	debug!("miss cache for series: {}", cache_key_with_matches);
@@ -141,7 +243,7 @@ pub async fn query_series(
 	// todo: this is inefficient, we should use a better way to find the longest prefix like trie
 	let mut longest_prefix = None;
 	for (k, _) in state.cache.iter() {
-		if cache_key_with_matches.starts_with(k.as_ref()) {
+		if cache_key_with_matches.starts_with(k.as_str()) {
 			match longest_prefix {
 				None => {
 					longest_prefix = Some(k);
@@ -156,7 +258,7 @@ pub async fn query_series(
 
 	let cache_key = if let Some(v) = longest_prefix {
 		debug!("use longest prefix cache: {}", v);
-		(*v).clone()
+		v
 	} else {
 		SERIES_CACHE_KEY.to_string()
 	};
@@ -174,9 +276,11 @@ pub async fn query_series(
 				None,
 				QueryLimits {
 					limit: None,
-					range: t_hours_before(2),
+					range: lookback(state.config.limits.label_lookback),
 					direction: None,
 					step: None,
+					spss: None,
+					unbounded: false,
 				},
 			)
 			.await?;
@@ -211,7 +315,57 @@ pub async fn query_series(
 	}
 	Ok(Json(QuerySeriesResponse {
 		status: ResponseStatus::Success,
-		data: values,
+		data: values
+			.into_iter()
+			.map(|m| state.label_names.sanitize_keys(m))
+			.collect(),
+	}))
+}
+
+// series of an explicit window come straight from the backend, the
+// cached lists and their prefixes only cover the default lookback
+async fn series_in_window(
+	state: &AppState,
+	matches: parser::LogQuery,
+	range: TimeRange,
+	window: String,
+) -> Result<Json<QuerySeriesResponse>, AppError> {
+	let cache_key = SERIES_CACHE_KEY.to_string()
+		+ &window
+		+ KEY_SPLITER
+		+ &canonicalize_matches(&matches.selector.label_paris);
+	let values: Vec<HashMap<String, String>> =
+		if let Some(v) = state.cache.get(&cache_key) {
+			deserialize_from_slice(&v)?
+		} else {
+			let pairs = matches.selector.label_paris.clone();
+			let mut values = state
+				.log_handle
+				.series(
+					Some(matches),
+					QueryLimits {
+						limit: None,
+						range,
+						direction: None,
+						step: None,
+						spss: None,
+						unbounded: false,
+					},
+				)
+				.await?;
+			values.retain(|m| filter_by_matches(m, &pairs));
+			if !values.is_empty() {
+				let d = serialize_to_vec(&values)?;
+				state.cache.insert(cache_key, Arc::new(d));
+			}
+			values
+		};
+	Ok(Json(QuerySeriesResponse {
+		status: ResponseStatus::Success,
+		data: values
+			.into_iter()
+			.map(|m| state.label_names.sanitize_keys(m))
+			.collect(),
 	}))
 }
 
@@ -230,9 +384,11 @@ pub async fn background_refresh_series_cache(
 				None,
 				QueryLimits {
 					limit: None,
-					range: t_hours_before(2),
+					range: lookback(state.config.limits.label_lookback),
 					direction: None,
 					step: None,
+					spss: None,
+					unbounded: false,
 				},
 			)
 			.await;
@@ -385,10 +541,7 @@ fn regex_match(actual: &str, value: &str) -> bool {
 	}
 }
 
-fn cache_values(
-	cache: &Cache<String, Arc<Vec<u8>>>,
-	values: &HashMap<&String, Vec<&String>>,
-) {
+fn cache_values(cache: &TenantCache, values: &HashMap<&String, Vec<&String>>) {
 	for (k, v) in values {
 		let key = label_values_cache_key(k);
 		let resp = CacheLabelResponse {
@@ -442,7 +595,8 @@ struct CacheLabelResponse<'a> {
 	pub data: &'a Vec<&'a String>,
 }
 
-// extend the cache expiry time when the key is updated
+// extend the cache expiry time when the key is updated, keys are
+// prefixed with the tenant by TenantCache
 pub struct LabelCacheExpiry {
 	pub extend_when_update: Duration,
 }
@@ -455,6 +609,7 @@ impl Expiry<String, Arc<Vec<u8>>> for LabelCacheExpiry {
 		_updated_at: std::time::Instant,
 		duration_until_expiry: Option<Duration>,
 	) -> Option<Duration> {
+		let key = key.split_once('\0').map_or(key.as_str(), |(_, k)| k);
 		if !key.eq(SERIES_CACHE_KEY)
 			&& !key.starts_with(LABEL_VALUES_CACHE_KEY_PREFIX)
 		{
@@ -473,6 +628,25 @@ mod tests {
 	use super::*;
 	use logql::parser::{self, LabelPair};
 
+	#[test]
+	fn test_label_window() {
+		let hour = Duration::from_secs(3600);
+		let (range, window) = label_window(None, None, None, hour);
+		assert!(window.is_empty());
+		let start = range.start.unwrap();
+		assert!(Utc::now().naive_utc() - start >= chrono::Duration::hours(1));
+		let (range, window) = label_window(None, None, Some(hour / 4), hour);
+		assert!(!window.is_empty());
+		assert!(
+			Utc::now().naive_utc() - range.start.unwrap()
+				< chrono::Duration::minutes(20)
+		);
+		let t = LokiDate(DateTime::from_timestamp(600, 0).unwrap());
+		let (range, window) = label_window(Some(&t), None, Some(hour), hour);
+		assert_eq!(range.start, Some(t.0.naive_utc()));
+		assert_eq!(window, "@10-");
+	}
+
 	#[test]
 	fn test_get_rest_label_pairs() {
 		let test_cases = vec![
@@ -624,4 +798,21 @@ mod tests {
 		let m2 = deserialize_from_slice::<HashMap<String, String>>(&d).unwrap();
 		assert_eq!(m, m2);
 	}
+	#[test]
+	fn test_expiry_extended_through_tenant_cache() {
+		let inner = moka::sync::Cache::builder()
+			.expire_after(LabelCacheExpiry {
+				extend_when_update: Duration::from_millis(50),
+			})
+			.build();
+		let cache = TenantCache::new(inner, &Tenant("a".to_string()));
+		for key in [SERIES_CACHE_KEY, "q"] {
+			cache.insert(key.to_string(), Arc::new(vec![1]));
+			cache.insert(key.to_string(), Arc::new(vec![2]));
+		}
+		std::thread::sleep(Duration::from_millis(150));
+		// only the label cache keys get an expiry when updated
+		assert_eq!(cache.get(SERIES_CACHE_KEY), None);
+		assert_eq!(cache.get("q"), Some(Arc::new(vec![2])));
+	}
 }
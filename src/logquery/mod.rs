@@ -1,4 +1,7 @@
-use crate::{errors::AppError, storage::QueryLimits};
+use crate::{
+	storage::{stats::QueryStats, QueryLimits},
+	utils::{serde::json_str_size, time::parse_timestamp},
+};
 use axum::{
 	http::StatusCode,
 	response::{IntoResponse, Json, Response},
@@ -6,13 +9,22 @@ use axum::{
 use chrono::{DateTime, Utc};
 use common::TimeRange as StorageTimeRange;
 use serde::{de, Deserialize, Deserializer, Serialize};
-use std::str::FromStr;
 use std::{collections::HashMap, time::Duration};
 use validator::Validate;
 
+pub mod delete;
+mod eval;
+mod format;
+pub mod hot;
+pub mod label_names;
 pub mod labels;
+mod post_filter;
 pub mod query_range;
+pub mod trace_context;
+pub mod warmup;
 
+pub use delete::{delete_logs, list_deletes};
+pub use format::format_query;
 pub use labels::{query_label_values, query_labels, query_series};
 pub use query_range::{loki_is_working, query_range};
 
@@ -35,6 +47,9 @@ pub struct QueryRangeRequest {
 	pub direction: Direction,
 	#[serde(with = "humantime_serde")]
 	pub step: Option<Duration>,
+	// skips limits.max_attribute_length
+	#[serde(default)]
+	pub full_attributes: bool,
 }
 
 const fn default_direction() -> Direction {
@@ -54,6 +69,8 @@ impl From<QueryRangeRequest> for QueryLimits {
 				Direction::Backward => crate::storage::Direction::Backward,
 			}),
 			step: value.step,
+			spss: None,
+			unbounded: false,
 		}
 	}
 }
@@ -69,6 +86,9 @@ pub enum ResponseStatus {
 pub struct QueryRangeResponse {
 	pub status: ResponseStatus,
 	pub data: QueryResult,
+	// shown by grafana above the query result
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub warnings: Vec<String>,
 }
 
 impl IntoResponse for QueryRangeResponse {
@@ -102,6 +122,8 @@ pub struct MatrixResponse {
 	#[serde(rename = "resultType")]
 	pub result_type: ResultType,
 	pub result: Vec<MatrixValue>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub stats: Option<Stats>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -109,6 +131,8 @@ pub struct StreamResponse {
 	#[serde(rename = "resultType")]
 	pub result_type: ResultType,
 	pub result: Vec<StreamValue>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub stats: Option<Stats>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -116,6 +140,69 @@ pub struct VectorResponse {
 	#[serde(rename = "resultType")]
 	pub result_type: ResultType,
 	pub result: Vec<VectorValue>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub stats: Option<Stats>,
+}
+
+// only the summary of loki's stats is filled, it's what grafana shows
+// in the query inspector
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Stats {
+	pub summary: StatsSummary,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct StatsSummary {
+	pub bytes_processed_per_second: u64,
+	pub lines_processed_per_second: u64,
+	pub total_bytes_processed: u64,
+	pub total_lines_processed: u64,
+	// seconds
+	pub exec_time: f64,
+	pub total_entries_returned: u64,
+}
+
+impl Stats {
+	pub fn new(q: &QueryStats, exec_time: Duration, returned: usize) -> Self {
+		let secs = exec_time.as_secs_f64();
+		let per_second = |n: u64| {
+			if secs > 0.0 {
+				(n as f64 / secs) as u64
+			} else {
+				0
+			}
+		};
+		Self {
+			summary: StatsSummary {
+				bytes_processed_per_second: per_second(q.bytes_processed),
+				lines_processed_per_second: per_second(q.rows_processed),
+				total_bytes_processed: q.bytes_processed,
+				total_lines_processed: q.rows_processed,
+				exec_time: secs,
+				total_entries_returned: returned as u64,
+			},
+		}
+	}
+}
+
+impl QueryRangeResponse {
+	pub fn set_stats(&mut self, stats: Stats) {
+		match &mut self.data {
+			QueryResult::Streams(r) => r.stats = Some(stats),
+			QueryResult::Matrix(r) => r.stats = Some(stats),
+			QueryResult::Vector(r) => r.stats = Some(stats),
+		}
+	}
+	fn entries(&self) -> usize {
+		match &self.data {
+			QueryResult::Streams(r) => {
+				r.result.iter().map(|s| s.values.len()).sum()
+			}
+			QueryResult::Matrix(r) => r.result.len(),
+			QueryResult::Vector(r) => r.result.len(),
+		}
+	}
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -124,6 +211,26 @@ pub struct StreamValue {
 	pub values: Vec<[String; 2]>,
 }
 
+impl StreamValue {
+	// the length of the stream as json, counted from its strings so the
+	// response size is known without serializing every stream twice
+	pub fn json_size(&self) -> usize {
+		let list = |n: usize| n.saturating_sub(1);
+		let stream = self
+			.stream
+			.iter()
+			.map(|(k, v)| json_str_size(k) + 1 + json_str_size(v))
+			.sum::<usize>();
+		let values = self
+			.values
+			.iter()
+			.map(|[ts, line]| json_str_size(ts) + json_str_size(line) + 3)
+			.sum::<usize>();
+		// {"stream":{...},"values":[...]}
+		25 + stream + list(self.stream.len()) + values + list(self.values.len())
+	}
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct VectorValue {
 	pub metric: HashMap<String, String>,
@@ -182,39 +289,12 @@ impl From<(Option<LokiDate>, Option<LokiDate>)> for TimeRange {
 	}
 }
 
-// support different loki time format
-fn parse_timestamp(value: &str) -> Result<DateTime<Utc>, AppError> {
-	if let Ok(seconds) = value.parse::<i64>() {
-		if seconds.to_string().len() <= 10 {
-			if let Some(dt) = DateTime::from_timestamp(seconds, 0) {
-				return Ok(dt);
-			}
-			return Err(AppError::InvalidTimeFormat(value.to_string()));
-		} else {
-			let nanos = (seconds % 1_000_000_000) as u32;
-			let secs = seconds / 1_000_000_000;
-			if let Some(dt) = DateTime::from_timestamp(secs, nanos) {
-				return Ok(dt);
-			}
-			return Err(AppError::InvalidTimeFormat(value.to_string()));
-		}
-	} else if let Ok(timestamp) = value.parse::<f64>() {
-		let secs = timestamp.trunc() as i64;
-		let nanos = (timestamp.fract() * 1_000_000_000.0) as u32;
-		if let Some(dt) = DateTime::from_timestamp(secs, nanos) {
-			return Ok(dt);
-		}
-		return Err(AppError::InvalidTimeFormat(value.to_string()));
-	} else if let Ok(dt) = DateTime::<Utc>::from_str(value) {
-		return Ok(dt);
-	}
-	Err(AppError::InvalidTimeFormat(value.to_string()))
-}
-
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Validate)]
 pub struct QuerySeriesRequest {
-	pub _start: Option<LokiDate>,
-	pub _end: Option<LokiDate>,
+	pub start: Option<LokiDate>,
+	pub end: Option<LokiDate>,
+	#[serde(with = "humantime_serde", default)]
+	pub since: Option<Duration>,
 	#[serde(rename = "match[]")]
 	pub matches: String,
 }
@@ -225,16 +305,20 @@ pub struct QuerySeriesResponse {
 	pub data: Vec<HashMap<String, String>>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Default, Validate)]
 pub struct QueryLabelsRequest {
-	_start: Option<LokiDate>,
-	_end: Option<LokiDate>,
+	start: Option<LokiDate>,
+	end: Option<LokiDate>,
+	#[serde(with = "humantime_serde", default)]
+	since: Option<Duration>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct QueryLabelsResponse {
 	pub status: ResponseStatus,
 	pub data: Vec<String>,
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub warnings: Vec<String>,
 }
 
 impl IntoResponse for QueryLabelsResponse {
@@ -243,16 +327,20 @@ impl IntoResponse for QueryLabelsResponse {
 	}
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Default, Validate)]
 pub struct QueryLabelValuesRequest {
-	_start: Option<LokiDate>,
-	_end: Option<LokiDate>,
-	_query: Option<String>,
+	start: Option<LokiDate>,
+	end: Option<LokiDate>,
+	#[serde(with = "humantime_serde", default)]
+	since: Option<Duration>,
+	// typeahead text, see ValueFilter
+	query: Option<String>,
 }
 
 #[cfg(test)]
 mod tests {
 	use super::*;
+	use crate::utils::serde::json_size;
 	use pretty_assertions::assert_eq;
 	use std::collections::HashMap;
 	#[test]
@@ -271,7 +359,9 @@ mod tests {
 						["3".to_string(), "4".to_string()],
 					],
 				}],
+				stats: None,
 			}),
+			warnings: vec![],
 		};
 		let expect = serde_json::json!(
 			{
@@ -296,4 +386,20 @@ mod tests {
 				.unwrap();
 		assert_eq!(expect, actual);
 	}
+
+	#[test]
+	fn test_stream_json_size() {
+		let mut stream = StreamValue {
+			stream: HashMap::new(),
+			values: vec![],
+		};
+		assert_eq!(stream.json_size(), json_size(&stream));
+		stream.stream.insert("k\"1".to_string(), "v\n1".to_string());
+		stream.stream.insert("k2".to_string(), "v2".to_string());
+		stream
+			.values
+			.push(["1".to_string(), "line \\ 1".to_string()]);
+		stream.values.push(["2".to_string(), "line 2".to_string()]);
+		assert_eq!(stream.json_size(), json_size(&stream));
+	}
 }
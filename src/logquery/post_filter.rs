@@ -0,0 +1,275 @@
+use crate::{
+	errors::AppError,
+	storage::{fanout::SOURCE_LABEL, log::LogItem, Capabilities},
+};
+use logql::parser::{
+	Filter, FilterType, LabelPair, LogLineFilter, LogQuery, Operator, Selector,
+};
+use regex::Regex;
+use serde_json::Value as JSONValue;
+
+// when some stages can't be pushed down we fetch this many rows at most
+// and apply the rest in the bridge, so the result may miss matching logs
+pub const POST_FILTER_MAX_ROWS: u32 = 5000;
+pub const PARTIAL_PUSHDOWN_HEADER: &str = "X-Ltbridge-Partial-Pushdown";
+
+// stages of a logql query that are evaluated against fetched rows
+#[derive(Debug, Default)]
+pub struct PostFilter {
+	labels: Vec<(String, Matcher)>,
+	lines: Vec<(Regex, bool)>,
+	// fields extracted by `| json`, keyed by their path in the body
+	json: Vec<(String, Matcher)>,
+}
+
+#[derive(Debug)]
+enum Matcher {
+	Equal(String),
+	NotEqual(String),
+	Regex(Regex, bool),
+}
+
+impl Matcher {
+	fn new(p: &LabelPair) -> Result<Self, AppError> {
+		// label matchers are fully anchored in logql
+		let anchored = || compile(&format!("^(?:{})$", p.value));
+		Ok(match p.op {
+			Operator::Equal => Matcher::Equal(p.value.clone()),
+			Operator::NotEqual => Matcher::NotEqual(p.value.clone()),
+			Operator::RegexMatch => Matcher::Regex(anchored()?, false),
+			Operator::RegexNotMatch => Matcher::Regex(anchored()?, true),
+		})
+	}
+	fn is_match(&self, v: &str) -> bool {
+		match self {
+			Matcher::Equal(e) => e == v,
+			Matcher::NotEqual(e) => e != v,
+			Matcher::Regex(re, negate) => re.is_match(v) != *negate,
+		}
+	}
+}
+
+impl PostFilter {
+	pub fn is_match(&self, item: &LogItem) -> bool {
+		let labels_ok = self.labels.iter().all(|(label, m)| {
+			m.is_match(label_value(item, label).unwrap_or_default())
+		});
+		let lines_ok = self
+			.lines
+			.iter()
+			.all(|(re, negate)| re.is_match(&item.message) != *negate);
+		labels_ok && lines_ok && self.json_match(&item.message)
+	}
+	fn json_match(&self, message: &str) -> bool {
+		if self.json.is_empty() {
+			return true;
+		}
+		// loki drops lines that fail to parse when filtering on json fields
+		let Ok(body) = serde_json::from_str::<JSONValue>(message) else {
+			return false;
+		};
+		self.json.iter().all(|(path, m)| {
+			let v = path
+				.split('.')
+				.try_fold(&body, |v, k| v.get(k))
+				.map(|v| match v {
+					JSONValue::String(s) => s.clone(),
+					v => v.to_string(),
+				})
+				.unwrap_or_default();
+			m.is_match(&v)
+		})
+	}
+}
+
+fn is_regex(op: Operator) -> bool {
+	matches!(op, Operator::RegexMatch | Operator::RegexNotMatch)
+}
+
+// a __source__ regex in the selector is left to the fanout's routing
+pub fn needs_post_filter(q: &LogQuery, caps: Capabilities) -> bool {
+	let routed = LogQuery {
+		selector: Selector {
+			label_paris: q
+				.selector
+				.label_paris
+				.iter()
+				.filter(|p| p.label != SOURCE_LABEL)
+				.cloned()
+				.collect(),
+		},
+		filters: q.filters.clone(),
+	};
+	(!caps.regex && routed.has_regex())
+		|| (!caps.json_stage && q.has_json_stage())
+}
+
+// split_pushdown keeps what the backend can run in the returned query,
+// the remaining stages are compiled into a PostFilter
+pub fn split_pushdown(
+	q: LogQuery,
+	caps: Capabilities,
+) -> Result<(LogQuery, Option<PostFilter>), AppError> {
+	if !needs_post_filter(&q, caps) {
+		return Ok((q, None));
+	}
+	let mut pf = PostFilter::default();
+	let mut label_paris = vec![];
+	for p in q.selector.label_paris {
+		if !caps.regex && is_regex(p.op) && p.label != SOURCE_LABEL {
+			pf.labels.push((p.label.clone(), Matcher::new(&p)?));
+		} else {
+			label_paris.push(p);
+		}
+	}
+	let mut pushed = vec![];
+	let mut after_json = false;
+	for f in q.filters.into_iter().flatten() {
+		match f {
+			Filter::Json => {
+				after_json = true;
+				if caps.json_stage {
+					pushed.push(f);
+				}
+			}
+			Filter::Label(p)
+				if after_json
+					&& (!caps.json_stage || !caps.regex && is_regex(p.op)) =>
+			{
+				pf.json.push((p.label.clone(), Matcher::new(&p)?));
+			}
+			Filter::Label(p) if !caps.regex && is_regex(p.op) => {
+				pf.labels.push((p.label.clone(), Matcher::new(&p)?));
+			}
+			Filter::LogLine(LogLineFilter { op, expression })
+				if !caps.regex
+					&& matches!(
+						op,
+						FilterType::RegexMatch | FilterType::RegexNotMatch
+					) =>
+			{
+				let negate = op == FilterType::RegexNotMatch;
+				pf.lines.push((compile(&expression)?, negate));
+			}
+			f => pushed.push(f),
+		}
+	}
+	Ok((
+		LogQuery {
+			selector: Selector { label_paris },
+			filters: (!pushed.is_empty()).then_some(pushed),
+		},
+		Some(pf),
+	))
+}
+
+fn compile(re: &str) -> Result<Regex, AppError> {
+	Regex::new(re).map_err(|e| {
+		AppError::InvalidQueryString(format!("invalid regex {}: {}", re, e))
+	})
+}
+
+// label names follow the stream labels built in query_range
+fn label_value<'a>(item: &'a LogItem, label: &str) -> Option<&'a str> {
+	let v = match label {
+		"ServiceName" | "service_name" => &item.service_name,
+		"TraceId" | "trace_id" => &item.trace_id,
+		"SpanId" | "span_id" => &item.span_id,
+		"SeverityText" | "level" => &item.level,
+		"scope_name" => &item.scope_name,
+		SOURCE_LABEL => return item.source.as_deref(),
+		_ => {
+			let (m, k) = if let Some(k) = label.strip_prefix("resources_") {
+				(&item.resource_attributes, k)
+			} else if let Some(k) = label.strip_prefix("scopes_") {
+				(&item.scope_attributes, k)
+			} else if let Some(k) = label.strip_prefix("attributes_") {
+				(&item.log_attributes, k)
+			} else {
+				return None;
+			};
+			return m.get(k).map(String::as_str);
+		}
+	};
+	Some(v.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use logql::parser::{parse_logql_query, Query};
+	use pretty_assertions::assert_eq;
+
+	fn log_query(s: &str) -> LogQuery {
+		match parse_logql_query(s).unwrap() {
+			Query::LogQuery(q) => q,
+			_ => unreachable!(),
+		}
+	}
+
+	fn item(service: &str, message: &str) -> LogItem {
+		LogItem {
+			ts: Default::default(),
+			trace_id: String::new(),
+			span_id: String::new(),
+			level: "info".to_string(),
+			service_name: service.to_string(),
+			message: message.to_string(),
+			resource_attributes: Default::default(),
+			scope_name: String::new(),
+			scope_attributes: Default::default(),
+			log_attributes: Default::default(),
+			source: None,
+		}
+	}
+
+	#[test]
+	fn test_split_pushdown() {
+		let no_regex = Capabilities {
+			regex: false,
+			..Default::default()
+		};
+		let q = log_query(
+			r#"{level="info", ServiceName=~"api|web"} |= `GET` !~ `\d{3}ms`"#,
+		);
+		let (pushed, pf) = split_pushdown(q, no_regex).unwrap();
+		assert_eq!(pushed, log_query(r#"{level="info"} |= `GET`"#));
+		let pf = pf.unwrap();
+		assert!(pf.is_match(&item("api", "GET /a")));
+		assert!(!pf.is_match(&item("api-2", "GET /a")));
+		assert!(!pf.is_match(&item("web", "GET /a 120ms")));
+
+		let q = log_query(r#"{ServiceName=~"api"}"#);
+		let (_, pf) = split_pushdown(q, Capabilities::default()).unwrap();
+		assert!(pf.is_none());
+
+		// left to the fanout, or matched against the source of the row
+		let q = log_query(r#"{__source__=~"hot|cold", level="info"}"#);
+		let (pushed, pf) = split_pushdown(q.clone(), no_regex).unwrap();
+		assert_eq!(pushed, q);
+		assert!(pf.is_none());
+		let q = log_query(r#"{level="info"} | __source__=~"hot|cold""#);
+		let pf = split_pushdown(q, no_regex).unwrap().1.unwrap();
+		let mut row = item("api", "GET /a");
+		assert!(!pf.is_match(&row));
+		row.source = Some("cold".to_string());
+		assert!(pf.is_match(&row));
+	}
+
+	#[test]
+	fn test_split_json_stage() {
+		let no_json = Capabilities {
+			json_stage: false,
+			..Default::default()
+		};
+		let q = log_query(
+			r#"{ServiceName="api"} |= `login` | json | user.id="42""#,
+		);
+		let (pushed, pf) = split_pushdown(q, no_json).unwrap();
+		assert_eq!(pushed, log_query(r#"{ServiceName="api"} |= `login`"#));
+		let pf = pf.unwrap();
+		assert!(pf.is_match(&item("api", r#"{"user":{"id":"42"}}"#)));
+		assert!(!pf.is_match(&item("api", r#"{"user":{"id":"7"}}"#)));
+		assert!(!pf.is_match(&item("api", "user 42 login")));
+	}
+}
@@ -1,39 +1,202 @@
-use super::*;
+use super::{
+	eval::{self, Series},
+	label_names::LabelNames,
+	post_filter::*,
+	trace_context::TraceContext,
+	*,
+};
 use crate::{
+	debug::{with_debug_headers, DebugRequest},
 	errors::AppError,
-	state::AppState,
-	storage::log::{LogItem, MetricItem},
+	state::{AppState, Joined, TenantCache},
+	storage::{
+		explain::{self, ExplainKind, Plan},
+		fanout::SOURCE_LABEL,
+		log::{LogItem, MetricItem, LEVEL_LABEL},
+		stats, Capabilities,
+	},
+	tenant::Tenant,
+	utils::{
+		serde::json_size,
+		truncate::truncate,
+		validate::{params, ValidQuery},
+	},
 };
-use axum::extract::{Query, State};
-use axum_valid::Valid;
+use axum::extract::State;
 use itertools::Itertools;
 use logql::parser;
-use moka::sync::Cache;
-use std::{collections::HashMap, sync::Arc};
+use std::{
+	collections::{BTreeMap, HashMap},
+	sync::Arc,
+	time::Instant,
+};
 
 pub async fn query_range(
 	State(state): State<AppState>,
-	Valid(Query(req)): Valid<Query<QueryRangeRequest>>,
-) -> Result<QueryRangeResponse, AppError> {
+	tenant: Tenant,
+	DebugRequest(debug): DebugRequest,
+	req: ValidQuery<QueryRangeRequest>,
+) -> Result<Response, AppError> {
+	let mut req = params(req).map_err(AppError::InvalidParams)?;
+	let state = state.for_tenant(&tenant);
+	let start = Instant::now();
+	// parse the logql query and convert the logql query to databend sql
+	let ql = parser::parse_logql_query(req.query.as_str())?;
+	if !matches!(ql, parser::Query::LogQuery(_)) {
+		align_to_step(&mut req);
+	}
+	let caps = state.log_handle.capabilities();
+	check_capabilities(&ql, caps)?;
+	let partial = matches!(
+		&ql,
+		parser::Query::LogQuery(q) if needs_post_filter(q, caps)
+	);
 	let cache_key = serde_json::to_string(&req).unwrap();
-	if let Some(resp) = get_cached_query(&cache_key, state.cache.clone()) {
+	// a cached response ran no sql, so debugging requests skip it
+	if !debug {
+		if let Some(resp) = get_cached_query(&cache_key, state.cache.clone()) {
+			return Ok(with_pushdown_header(resp, partial));
+		}
+		if let Some(resp) = hot::lookup(&state, &req) {
+			return Ok(resp.into_response());
+		}
+	}
+	// identical requests wait for the first one and answer with what it
+	// got, the response or the error
+	let flight = if debug {
+		None
+	} else {
+		match state.cache.join(&cache_key).await {
+			Joined::Leader(flight) => Some(flight),
+			Joined::Landed(Ok(d)) => {
+				let resp: QueryRangeResponse = serde_json::from_slice(&d)?;
+				return Ok(with_pushdown_header(resp, partial));
+			}
+			Joined::Landed(Err(e)) => return Err(AppError::Shared(e)),
+		}
+	};
+	let parsed = start.elapsed();
+	let (resp, stats) =
+		stats::collect(run_query(ql, req, state.clone(), caps)).await;
+	let queried = start.elapsed() - parsed;
+	let mut resp = match resp {
+		Ok(resp) => resp,
+		Err(e) => {
+			let e = Arc::new(e);
+			if let Some(flight) = flight {
+				flight.land(Err(e.clone()));
+			}
+			return Err(AppError::Shared(e));
+		}
+	};
+	let returned = resp.entries();
+	resp.set_stats(Stats::new(&stats, start.elapsed(), returned));
+	let d = Arc::new(serde_json::to_vec(&resp)?);
+	state.cache.insert(cache_key, d.clone());
+	if let Some(flight) = flight {
+		flight.land(Ok(d));
+	}
+	let resp = with_pushdown_header(resp, partial);
+	if !debug {
 		return Ok(resp);
 	}
-	// parse the logql query and convert the logql query to databend sql
-	let ql = parser::parse_logql_query(req.query.as_str())?;
-	let resp = match ql {
+	let stages = [
+		("parse", parsed),
+		("query", queried),
+		("total", start.elapsed()),
+	];
+	Ok(with_debug_headers(resp, &stats, &stages))
+}
+
+pub(super) async fn run_query(
+	ql: parser::Query,
+	req: QueryRangeRequest,
+	state: AppState,
+	caps: Capabilities,
+) -> Result<QueryRangeResponse, AppError> {
+	let expr = match ql {
 		parser::Query::LogQuery(ql) => {
-			handle_log_query(ql, req, state.clone()).await
-		}
-		parser::Query::MetricQuery(mq) => {
-			handle_metric_query(mq, req, state.clone()).await
+			return handle_log_query(ql, req, state, caps).await;
 		}
+		parser::Query::MetricQuery(mq) => parser::MetricExpr::Metric(mq),
+		parser::Query::Binary(b) => parser::MetricExpr::Binary(b),
+		parser::Query::Rank(r) => parser::MetricExpr::Rank(r),
+	};
+	handle_metric_query(expr, req, state).await
+}
+
+// explain_query_range returns the plans of the statements query_range
+// would send for req
+pub(crate) async fn explain_query_range(
+	req: QueryRangeRequest,
+	state: AppState,
+	kind: ExplainKind,
+) -> Result<Vec<Plan>, AppError> {
+	let ql = parser::parse_logql_query(req.query.as_str())?;
+	let caps = state.log_handle.capabilities();
+	check_capabilities(&ql, caps)?;
+	let (resp, plans) =
+		explain::collect(kind, run_query(ql, req, state, caps)).await;
+	resp?;
+	Ok(plans)
+}
+
+// log queries can fall back to filtering in the bridge,
+// metric queries would need every row so they're rejected instead
+pub(super) fn check_capabilities(
+	ql: &parser::Query,
+	caps: Capabilities,
+) -> Result<(), AppError> {
+	if ql
+		.metric_queries()
+		.iter()
+		.any(|mq| needs_post_filter(&mq.log_query, caps))
+	{
+		return Err(AppError::UnsupportedFeature(
+			"regex or json stages in metric queries".to_string(),
+		));
+	}
+	// such backends only count by level
+	if !caps.group_by_labels
+		&& ql
+			.metric_queries()
+			.iter()
+			.any(|mq| mq.agg_by.iter().any(|l| l != LEVEL_LABEL))
+	{
+		return Err(AppError::UnsupportedFeature(
+			"metric queries summed by labels other than level".to_string(),
+		));
+	}
+	Ok(())
+}
+
+// metric points are at multiples of step, so widening the range to them
+// changes no point and lets requests within the same step, e.g. a
+// dashboard refreshed every few seconds or the warm-up, share a cache
+// entry. limit doesn't apply to metric queries
+fn align_to_step(req: &mut QueryRangeRequest) {
+	req.limit = None;
+	let step = match req.step.map(|s| s.as_secs() as i64) {
+		Some(step) if step > 0 => step,
+		_ => return,
 	};
-	if let Ok(inner) = &resp {
-		let d = serde_json::to_vec(inner).unwrap();
-		state.cache.insert(cache_key, Arc::new(d));
+	let at = |secs: i64| DateTime::from_timestamp(secs, 0);
+	if let Some(LokiDate(t)) = &mut req.start {
+		let secs = t.timestamp();
+		*t = at(secs - secs.rem_euclid(step)).unwrap_or(*t);
+	}
+	if let Some(LokiDate(t)) = &mut req.end {
+		let secs = t.timestamp() + i64::from(t.timestamp_subsec_nanos() > 0);
+		*t = at(secs + (step - secs.rem_euclid(step)) % step).unwrap_or(*t);
+	}
+}
+
+fn with_pushdown_header(resp: QueryRangeResponse, partial: bool) -> Response {
+	if partial {
+		([(PARTIAL_PUSHDOWN_HEADER, "true")], resp).into_response()
+	} else {
+		resp.into_response()
 	}
-	resp
 }
 
 pub async fn loki_is_working() -> Result<QueryRangeResponse, AppError> {
@@ -46,13 +209,15 @@ pub async fn loki_is_working() -> Result<QueryRangeResponse, AppError> {
 				metric: HashMap::new(),
 				value: [now.into(), "2".to_string().into()],
 			}],
+			stats: None,
 		}),
+		warnings: vec![],
 	})
 }
 
 fn get_cached_query(
 	key: &str,
-	cache: Cache<String, Arc<Vec<u8>>>,
+	cache: TenantCache,
 ) -> Option<QueryRangeResponse> {
 	if let Some(v) = cache.get(key) {
 		if let Ok(d) = serde_json::from_slice(&v) {
@@ -76,62 +241,158 @@ ORDER BY
   nts
 */
 async fn handle_metric_query(
-	mq: parser::MetricQuery,
+	expr: parser::MetricExpr,
 	req: QueryRangeRequest,
 	state: AppState,
 ) -> Result<QueryRangeResponse, AppError> {
-	let handle = state.log_handle;
-	let rows = handle.query_metrics(&mq, req.into()).await?;
-	Ok(to_metric_query_range_response(&rows))
+	let mut opt: QueryLimits = req.into();
+	// limit counts lines in loki, a row limit here would cut arbitrary buckets
+	opt.limit = None;
+	let series = eval::evaluate(&expr, &state, &opt).await?;
+	let limits = &state.config.limits;
+	to_metric_query_range_response(
+		&series,
+		&state.label_names,
+		limits.max_series,
+		limits.max_response_bytes,
+	)
 }
 
 async fn handle_log_query(
-	ql: parser::LogQuery,
+	mut ql: parser::LogQuery,
 	mut req: QueryRangeRequest,
 	state: AppState,
+	caps: Capabilities,
 ) -> Result<QueryRangeResponse, AppError> {
 	const DEFAULT_LIMIT: u32 = 1000;
+	state.label_names.restore_query(&mut ql)?;
 	let handle = state.log_handle;
-	if req.limit.is_none() {
-		req.limit = Some(DEFAULT_LIMIT);
+	let limit = *req.limit.get_or_insert(DEFAULT_LIMIT);
+	let limits = &state.config.limits;
+	let max_attr = match req.full_attributes {
+		true => 0,
+		false => limits.max_attribute_length,
+	};
+	let max_bytes = limits.max_response_bytes;
+	let (ql, post_filter) = split_pushdown(ql, caps)?;
+	let mut opt: QueryLimits = req.into();
+	if post_filter.is_some() {
+		opt.limit = Some(limit.min(POST_FILTER_MAX_ROWS));
+	}
+	let mut rows = handle.query_stream(&ql, opt).await?;
+	if let Some(pf) = post_filter {
+		rows.retain(|r| pf.is_match(r));
+		rows.truncate(limit as usize);
+	}
+	let ctx = match state.config.server.log_trace_context {
+		true => TraceContext::lookup(state.trace_handle.as_ref(), &rows).await,
+		false => TraceContext::default(),
+	};
+	let (mut resp, _) = to_log_query_range_response(
+		&rows,
+		&state.label_names,
+		&ctx,
+		max_bytes,
+		max_attr,
+	);
+	if rows.len() >= limit as usize {
+		resp.warnings.push(truncated_warning(limit));
 	}
-	let rows = handle.query_stream(&ql, req.into()).await?;
-	let (resp, _) = to_log_query_range_response(&rows);
 	Ok(resp)
 }
 
-fn to_metric_query_range_response(value: &[MetricItem]) -> QueryRangeResponse {
-	let matrix = value
-		.iter()
-		.into_group_map_by(|v| v.level)
-		.iter()
-		.map(|(level, elements)| MatrixValue {
-			metric: HashMap::from_iter(vec![(
-				"level".to_string(),
-				(*level).into(),
-			)]),
-			values: elements
+pub(crate) fn truncated_warning(limit: u32) -> String {
+	format!(
+		"results truncated at {} rows, narrow the query or raise the limit",
+		limit
+	)
+}
+
+// regroup keeps the labels the query sums by and adds up the rows that
+// end up in the same series. Backends that always split by level return
+// one series for `sum(...)` this way
+pub(super) fn regroup(rows: Vec<MetricItem>, by: &[String]) -> Vec<MetricItem> {
+	if rows.iter().all(|r| r.labels.keys().all(|k| by.contains(k))) {
+		return rows;
+	}
+	let mut series: BTreeMap<_, u64> = BTreeMap::new();
+	for mut r in rows {
+		r.labels.retain(|k, _| by.contains(k));
+		*series.entry((r.labels, r.ts)).or_default() += r.total;
+	}
+	series
+		.into_iter()
+		.map(|((labels, ts), total)| MetricItem { labels, total, ts })
+		.sorted_by_key(|r| r.ts)
+		.collect()
+}
+
+// series come back labeled as the backend names them, and are given the
+// names the query used
+fn to_metric_query_range_response(
+	series: &Series,
+	names: &LabelNames,
+	max_series: usize,
+	max_bytes: usize,
+) -> Result<QueryRangeResponse, AppError> {
+	if series.len() > max_series {
+		return Err(AppError::TooManySeries(max_series));
+	}
+	let mut size = 0;
+	let mut matrix = Vec::with_capacity(series.len());
+	for (labels, points) in series {
+		let m = MatrixValue {
+			metric: names.sanitize_keys(labels.clone().into_iter().collect()),
+			values: points
 				.iter()
-				.map(|e| [e.ts.timestamp().into(), e.total.to_string().into()])
+				.map(|(ts, v)| [sample_time(ts), v.to_string().into()])
 				.collect(),
-		})
-		.collect();
-	QueryRangeResponse {
+		};
+		// a partial matrix would read as missing data, so it's all or nothing
+		if max_bytes > 0 {
+			size += json_size(&m);
+			if size > max_bytes {
+				return Err(AppError::ResponseTooLarge(max_bytes));
+			}
+		}
+		matrix.push(m);
+	}
+	Ok(QueryRangeResponse {
 		status: ResponseStatus::Success,
 		data: QueryResult::Matrix(MatrixResponse {
 			result_type: ResultType::Matrix,
 			result: matrix,
+			stats: None,
 		}),
+		warnings: vec![],
+	})
+}
+
+// steps under a second have buckets inside a second, their samples keep
+// the fraction. Whole seconds stay integers as loki writes them
+fn sample_time(ts: &DateTime<Utc>) -> serde_json::Value {
+	match ts.timestamp_subsec_millis() {
+		0 => ts.timestamp().into(),
+		_ => (ts.timestamp_millis() as f64 / 1000.0).into(),
 	}
 }
 
-fn to_log_query_range_response(
+// lines are added until the streams reach max_bytes, the rest are
+// dropped with a warning rather than building an unbounded response.
+// Attribute labels are cut to max_attr chars, the line itself never is
+pub(crate) fn to_log_query_range_response(
 	value: &[LogItem],
+	names: &LabelNames,
+	ctx: &TraceContext,
+	max_bytes: usize,
+	max_attr: usize,
 ) -> (QueryRangeResponse, Vec<HashMap<String, String>>) {
 	let mut tag_list = vec![];
-	let streams = value
-		.iter()
-		.map(|r| {
+	let mut warnings = vec![];
+	let mut size = 0;
+	let mut streams = Vec::with_capacity(value.len());
+	for r in value {
+		let stream = {
 			let mut tags = HashMap::from_iter(vec![
 				("ServiceName".to_string(), r.service_name.clone()),
 				("TraceId".to_string(), r.trace_id.clone()),
@@ -143,25 +404,35 @@ fn to_log_query_range_response(
 			if !r.scope_name.is_empty() {
 				tags.insert("scope_name".to_string(), r.scope_name.clone());
 			}
+			if let Some(source) = &r.source {
+				tags.insert(SOURCE_LABEL.to_string(), source.clone());
+			}
+			ctx.add_labels(&r.trace_id, &mut tags);
 			r.resource_attributes
 				.iter()
 				.filter(|(_, v)| !v.is_empty())
 				.for_each(|(k, v)| {
-					tags.insert(format!("resources_{}", k), v.clone());
+					let mut v = v.clone();
+					truncate(&mut v, max_attr);
+					tags.insert(format!("resources_{}", k), v);
 				});
 			r.scope_attributes
 				.iter()
 				.filter(|(_, v)| !v.is_empty())
 				.for_each(|(k, v)| {
-					tags.insert(format!("scopes_{}", k), v.clone());
+					let mut v = v.clone();
+					truncate(&mut v, max_attr);
+					tags.insert(format!("scopes_{}", k), v);
 				});
 			r.log_attributes
 				.iter()
 				.filter(|(_, v)| !v.is_empty())
 				.for_each(|(k, v)| {
-					tags.insert(format!("attributes_{}", k), v.clone());
+					let mut v = v.clone();
+					truncate(&mut v, max_attr);
+					tags.insert(format!("attributes_{}", k), v);
 				});
-			tag_list.push(tags.clone());
+			let tags = names.sanitize_keys(tags);
 			StreamValue {
 				stream: tags,
 				values: vec![[
@@ -169,16 +440,245 @@ fn to_log_query_range_response(
 					r.message.clone(),
 				]],
 			}
-		})
-		.collect();
+		};
+		if max_bytes > 0 {
+			size += stream.json_size();
+			if size > max_bytes {
+				warnings.push(format!(
+					"results truncated at {} of {} lines, the response reached {} bytes",
+					streams.len(),
+					value.len(),
+					max_bytes
+				));
+				break;
+			}
+		}
+		tag_list.push(stream.stream.clone());
+		streams.push(stream);
+	}
 	(
 		QueryRangeResponse {
 			status: ResponseStatus::Success,
 			data: QueryResult::Streams(StreamResponse {
 				result_type: ResultType::Streams,
 				result: streams,
+				stats: None,
 			}),
+			warnings,
 		},
 		tag_list,
 	)
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::logquery::eval::into_series;
+	use common::LogLevel;
+
+	#[test]
+	fn test_max_series() {
+		let item = |level, ts| {
+			MetricItem::by_level(
+				level,
+				1,
+				DateTime::from_timestamp(ts, 0).unwrap(),
+			)
+		};
+		let rows = into_series(vec![
+			item(LogLevel::Info, 0),
+			item(LogLevel::Info, 60),
+			item(LogLevel::Error, 0),
+		]);
+		let names = LabelNames::default();
+		assert!(to_metric_query_range_response(&rows, &names, 2, 0).is_ok());
+		assert!(matches!(
+			to_metric_query_range_response(&rows, &names, 1, 0),
+			Err(AppError::TooManySeries(1))
+		));
+		assert!(matches!(
+			to_metric_query_range_response(&rows, &names, 2, 16),
+			Err(AppError::ResponseTooLarge(16))
+		));
+	}
+
+	#[test]
+	fn test_sub_second_step() {
+		let at = |ms| DateTime::from_timestamp_millis(ms).unwrap();
+		let rows = (0..5)
+			.map(|i| {
+				MetricItem::by_level(LogLevel::Info, 1, at(1000 + i * 250))
+			})
+			.collect();
+		let resp = to_metric_query_range_response(
+			&into_series(rows),
+			&LabelNames::default(),
+			10,
+			0,
+		)
+		.unwrap();
+		let json = serde_json::to_value(&resp).unwrap();
+		assert_eq!(
+			json["data"]["result"][0]["values"],
+			serde_json::json!([
+				[1, "1"],
+				[1.25, "1"],
+				[1.5, "1"],
+				[1.75, "1"],
+				[2, "1"]
+			])
+		);
+	}
+
+	#[test]
+	fn test_align_to_step() {
+		let at =
+			|secs| Some(LokiDate(DateTime::from_timestamp(secs, 0).unwrap()));
+		let req = |start, end| QueryRangeRequest {
+			query: "rate({app=\"api\"}[1m])".to_string(),
+			start: at(start),
+			end: at(end),
+			limit: Some(100),
+			direction: Direction::Backward,
+			step: Some(Duration::from_secs(60)),
+			full_attributes: false,
+		};
+		let key = |mut r: QueryRangeRequest| {
+			align_to_step(&mut r);
+			serde_json::to_string(&r).unwrap()
+		};
+		// refreshed within the same step
+		assert_eq!(key(req(130, 3610)), key(req(170, 3650)));
+		assert_eq!(key(req(120, 3600)), key(req(179, 3600)));
+		assert_ne!(key(req(130, 3610)), key(req(190, 3670)));
+		let mut r = req(130, 3610);
+		align_to_step(&mut r);
+		assert_eq!(r.start, at(120));
+		assert_eq!(r.end, at(3660));
+		assert_eq!(r.limit, None);
+	}
+
+	#[test]
+	fn test_group_by_labels() {
+		let caps = Capabilities {
+			group_by_labels: false,
+			..Default::default()
+		};
+		let q = |s| parser::parse_logql_query(s).unwrap();
+		let by_level = q(r#"sum by (level) (count_over_time({a="b"}[1m]))"#);
+		assert!(check_capabilities(&by_level, caps).is_ok());
+		let total = q(r#"sum(count_over_time({a="b"}[1m]))"#);
+		assert!(check_capabilities(&total, caps).is_ok());
+		let by_host =
+			q(r#"sum by (resources_host) (count_over_time({a="b"}[1m]))"#);
+		assert!(check_capabilities(&by_host, caps).is_err());
+		assert!(check_capabilities(&by_host, Capabilities::default()).is_ok());
+	}
+
+	#[test]
+	fn test_regroup() {
+		let ts = |s| DateTime::from_timestamp(s, 0).unwrap();
+		let rows = vec![
+			MetricItem::by_level(LogLevel::Info, 2, ts(0)),
+			MetricItem::by_level(LogLevel::Error, 1, ts(0)),
+			MetricItem::by_level(LogLevel::Info, 4, ts(60)),
+		];
+		let by_level = regroup(rows.clone(), &["level".to_string()]);
+		assert_eq!(by_level.len(), 3);
+		let summed = regroup(rows, &[]);
+		let got: Vec<_> = summed
+			.iter()
+			.map(|r| (r.labels.is_empty(), r.total, r.ts))
+			.collect();
+		assert_eq!(got, [(true, 3, ts(0)), (true, 4, ts(60))]);
+	}
+
+	#[test]
+	fn test_service_name_volume() {
+		use crate::config::ServiceLabel;
+		let names =
+			LabelNames::new(false).with_service_label(ServiceLabel::Otel);
+		let Ok(parser::Query::MetricQuery(mut q)) = parser::parse_logql_query(
+			r#"sum by (service_name) (count_over_time({service_name="api"}[1m]))"#,
+		) else {
+			unreachable!()
+		};
+		names.restore_metric(&mut q).unwrap();
+		assert_eq!(q.agg_by, vec!["ServiceName"]);
+		let ts = DateTime::from_timestamp(0, 0).unwrap();
+		let row = |level: &str, total| MetricItem {
+			labels: BTreeMap::from([
+				("ServiceName".to_string(), "api".to_string()),
+				(LEVEL_LABEL.to_string(), level.to_string()),
+			]),
+			total,
+			ts,
+		};
+		let rows = regroup(vec![row("info", 2), row("error", 1)], &q.agg_by);
+		let resp =
+			to_metric_query_range_response(&into_series(rows), &names, 10, 0)
+				.unwrap();
+		let QueryResult::Matrix(m) = resp.data else {
+			unreachable!()
+		};
+		assert_eq!(m.result.len(), 1);
+		assert_eq!(
+			m.result[0].metric,
+			HashMap::from([("service_name".to_string(), "api".to_string())])
+		);
+	}
+
+	#[test]
+	fn test_max_response_bytes() {
+		let rows: Vec<LogItem> = (0..10)
+			.map(|i| LogItem {
+				ts: DateTime::from_timestamp(i, 0).unwrap(),
+				trace_id: String::new(),
+				span_id: String::new(),
+				level: "info".to_string(),
+				service_name: "api".to_string(),
+				message: "x".repeat(100),
+				resource_attributes: HashMap::new(),
+				scope_name: String::new(),
+				scope_attributes: HashMap::new(),
+				log_attributes: HashMap::new(),
+				source: None,
+			})
+			.collect();
+		let names = LabelNames::new(false);
+		let ctx = TraceContext::default();
+		let (resp, _) = to_log_query_range_response(&rows, &names, &ctx, 0, 0);
+		assert_eq!(resp.entries(), 10);
+		assert!(resp.warnings.is_empty());
+		let one = json_size(&resp.data) / 10;
+		let (resp, _) =
+			to_log_query_range_response(&rows, &names, &ctx, one * 3, 0);
+		assert!(resp.entries() < 10 && resp.entries() > 0);
+		assert_eq!(resp.warnings.len(), 1);
+	}
+
+	#[test]
+	fn test_max_attribute_length() {
+		let row = LogItem {
+			ts: DateTime::from_timestamp(0, 0).unwrap(),
+			trace_id: String::new(),
+			span_id: String::new(),
+			level: "error".to_string(),
+			service_name: "api".to_string(),
+			message: "x".repeat(100),
+			resource_attributes: HashMap::new(),
+			scope_name: String::new(),
+			scope_attributes: HashMap::new(),
+			log_attributes: HashMap::from([(
+				"stacktrace".to_string(),
+				"panicked at src/main.rs".to_string(),
+			)]),
+			source: None,
+		};
+		let names = LabelNames::new(false);
+		let ctx = TraceContext::default();
+		let (_, tags) = to_log_query_range_response(&[row], &names, &ctx, 0, 8);
+		assert_eq!(tags[0]["attributes_stacktrace"], "panicke…");
+		assert_eq!(tags[0]["ServiceName"], "api");
+	}
+}
@@ -0,0 +1,150 @@
+use crate::storage::{log::LogItem, trace::TraceStorage, QueryLimits};
+use chrono::TimeDelta;
+use common::TimeRange;
+use itertools::Itertools;
+use std::collections::HashMap;
+use tracing::warn;
+
+pub const TRACE_ROOT_LABEL: &str = "trace_root";
+pub const TRACE_DURATION_LABEL: &str = "trace_duration_ms";
+
+// traces looked up for one response, lines of the others go without
+const MAX_TRACES: usize = 100;
+
+// how long before the first line of a trace its root span may start
+const ROOT_LOOKBACK: TimeDelta = TimeDelta::hours(1);
+
+// TraceContext holds the root span name and the duration in ms of the
+// traces a response's lines belong to
+#[derive(Debug, Default)]
+pub struct TraceContext(HashMap<String, (String, i64)>);
+
+impl TraceContext {
+	// lookup asks traces for the roots in one batch. The lines are
+	// returned without context rather than failing the query
+	pub async fn lookup(traces: &dyn TraceStorage, rows: &[LogItem]) -> Self {
+		let ids = rows
+			.iter()
+			.filter(|r| !r.trace_id.is_empty())
+			.map(|r| r.trace_id.clone())
+			.unique()
+			.take(MAX_TRACES)
+			.collect_vec();
+		let (Some(first), Some(last)) = (
+			rows.iter().map(|r| r.ts).min(),
+			rows.iter().map(|r| r.ts).max(),
+		) else {
+			return Self::default();
+		};
+		if ids.is_empty() {
+			return Self::default();
+		}
+		let opt = QueryLimits {
+			limit: None,
+			range: TimeRange {
+				start: Some((first - ROOT_LOOKBACK).naive_utc()),
+				end: Some((last + TimeDelta::seconds(1)).naive_utc()),
+			},
+			direction: None,
+			step: None,
+			spss: None,
+			unbounded: false,
+		};
+		match traces.trace_roots(&ids, opt).await {
+			Ok(roots) => Self(
+				roots
+					.into_iter()
+					.map(|sp| {
+						let ms = sp.duration / 1_000_000;
+						(sp.trace_id, (sp.span_name, ms))
+					})
+					.collect(),
+			),
+			Err(e) => {
+				warn!("trace context lookup fails: {:#}", e);
+				Self::default()
+			}
+		}
+	}
+
+	pub fn add_labels(
+		&self,
+		trace_id: &str,
+		labels: &mut HashMap<String, String>,
+	) {
+		if let Some((root, ms)) = self.0.get(trace_id) {
+			labels.insert(TRACE_ROOT_LABEL.to_string(), root.clone());
+			labels.insert(TRACE_DURATION_LABEL.to_string(), ms.to_string());
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::storage::trace::SpanItem;
+	use anyhow::Result;
+	use async_trait::async_trait;
+	use chrono::DateTime;
+	use traceql::Expression;
+
+	#[derive(Clone)]
+	struct Roots;
+
+	#[async_trait]
+	impl TraceStorage for Roots {
+		async fn query_trace(
+			&self,
+			trace_id: &str,
+			_: QueryLimits,
+		) -> Result<Vec<SpanItem>> {
+			let span = |span_id: &str, parent: &str, name: &str| SpanItem {
+				trace_id: trace_id.to_string(),
+				span_id: span_id.to_string(),
+				parent_span_id: parent.to_string(),
+				span_name: name.to_string(),
+				duration: 1_500_000_000,
+				..Default::default()
+			};
+			match trace_id {
+				"t1" => {
+					Ok(vec![span("2", "1", "SELECT"), span("1", "", "GET /")])
+				}
+				_ => Ok(vec![]),
+			}
+		}
+		async fn search_span(
+			&self,
+			_: &Expression,
+			_: QueryLimits,
+		) -> Result<Vec<SpanItem>> {
+			Ok(vec![])
+		}
+	}
+
+	#[tokio::test]
+	async fn test_lookup() {
+		let row = |trace_id: &str| LogItem {
+			ts: DateTime::from_timestamp(0, 0).unwrap(),
+			trace_id: trace_id.to_string(),
+			span_id: String::new(),
+			level: "info".to_string(),
+			service_name: "api".to_string(),
+			message: String::new(),
+			resource_attributes: HashMap::new(),
+			scope_name: String::new(),
+			scope_attributes: HashMap::new(),
+			log_attributes: HashMap::new(),
+			source: None,
+		};
+		let rows = [row("t1"), row("t2"), row("")];
+		let ctx = TraceContext::lookup(&Roots, &rows).await;
+		let mut labels = HashMap::new();
+		ctx.add_labels("t1", &mut labels);
+		assert_eq!(labels[TRACE_ROOT_LABEL], "GET /");
+		assert_eq!(labels[TRACE_DURATION_LABEL], "1500");
+		let mut labels = HashMap::new();
+		ctx.add_labels("t2", &mut labels);
+		assert!(labels.is_empty());
+	}
+}
@@ -0,0 +1,149 @@
+use super::{
+	query_label_values, query_labels, query_range, Direction, LokiDate,
+	QueryLabelValuesRequest, QueryLabelsRequest, QueryRangeRequest,
+};
+use crate::{
+	config::{Warmup, WarmupQuery},
+	debug::DebugRequest,
+	state::AppState,
+	tenant::Tenant,
+};
+use axum::extract::{Path, Query, State};
+use axum_valid::Valid;
+use chrono::Utc;
+use std::{
+	sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+	time::Instant,
+};
+use tracing::{info, warn};
+
+// WarmupProgress is what /ready reports, it isn't ready until finished
+#[derive(Debug, Default)]
+pub struct WarmupProgress {
+	done: AtomicUsize,
+	total: AtomicUsize,
+	finished: AtomicBool,
+}
+
+impl WarmupProgress {
+	// for instances configured without a warm-up
+	pub fn finished() -> Self {
+		Self {
+			finished: AtomicBool::new(true),
+			..Default::default()
+		}
+	}
+	// pending returns how many of the steps are done so far,
+	// or None once the warm-up is over
+	pub fn pending(&self) -> Option<(usize, usize)> {
+		if self.finished.load(Ordering::Acquire) {
+			return None;
+		}
+		Some((
+			self.done.load(Ordering::Relaxed),
+			self.total.load(Ordering::Relaxed),
+		))
+	}
+	fn add(&self, steps: usize) {
+		self.total.fetch_add(steps, Ordering::Relaxed);
+	}
+	fn step(&self, what: &str, start: Instant) {
+		let done = self.done.fetch_add(1, Ordering::Relaxed) + 1;
+		let total = self.total.load(Ordering::Relaxed);
+		info!(
+			"warm-up {}/{}: {} took {:?}",
+			done,
+			total,
+			what,
+			start.elapsed()
+		);
+	}
+}
+
+// run primes the caches through the same handlers grafana calls, as the
+// default tenant. Failures are logged and skipped, a warm-up that can't
+// finish a step shouldn't keep the instance from serving
+pub async fn run(state: AppState, cfg: Warmup) {
+	let progress = state.warmup.clone();
+	let tenant = Tenant(state.config.tenant.default_tenant.clone());
+	progress.add(cfg.queries.len() + usize::from(cfg.labels));
+	if cfg.labels {
+		let start = Instant::now();
+		let labels = query_labels(
+			State(state.clone()),
+			tenant.clone(),
+			Ok(Valid(Query(QueryLabelsRequest::default()))),
+		)
+		.await
+		.map(|r| r.data)
+		.unwrap_or_else(|e| {
+			warn!("warm-up fails to query labels: {}", e);
+			vec![]
+		});
+		progress.step("labels", start);
+		progress.add(labels.len());
+		for label in labels {
+			let start = Instant::now();
+			if let Err(e) = query_label_values(
+				State(state.clone()),
+				tenant.clone(),
+				Path(label.clone()),
+				Ok(Valid(Query(QueryLabelValuesRequest::default()))),
+			)
+			.await
+			{
+				warn!("warm-up fails to query values of {}: {}", label, e);
+			}
+			progress.step(&format!("values of {}", label), start);
+		}
+	}
+	for q in cfg.queries {
+		let start = Instant::now();
+		let what = q.query.clone();
+		let req = Ok(Valid(Query(range_request(q))));
+		let resp = query_range(
+			State(state.clone()),
+			tenant.clone(),
+			DebugRequest(false),
+			req,
+		)
+		.await;
+		if let Err(e) = resp {
+			warn!("warm-up fails to run {}: {}", what, e);
+		}
+		progress.step(&what, start);
+	}
+	progress.finished.store(true, Ordering::Release);
+	info!("warm-up finished");
+}
+
+fn range_request(q: WarmupQuery) -> QueryRangeRequest {
+	let end = Utc::now();
+	QueryRangeRequest {
+		query: q.query,
+		start: Some(LokiDate(end - q.range)),
+		end: Some(LokiDate(end)),
+		limit: Some(100),
+		direction: Direction::Backward,
+		step: q.step,
+		full_attributes: false,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use pretty_assertions::assert_eq;
+
+	#[test]
+	fn test_progress() {
+		let p = WarmupProgress::default();
+		p.add(2);
+		assert_eq!(p.pending(), Some((0, 2)));
+		p.step("labels", Instant::now());
+		assert_eq!(p.pending(), Some((1, 2)));
+		p.finished.store(true, Ordering::Release);
+		assert_eq!(p.pending(), None);
+		assert_eq!(WarmupProgress::finished().pending(), None);
+	}
+}
@@ -1,7 +1,6 @@
 use anyhow::Result;
-use ltbridge::app;
+use ltbridge::cli;
 
-#[tokio::main]
-async fn main() -> Result<()> {
-	app::start().await
+fn main() -> Result<()> {
+	cli::run()
 }
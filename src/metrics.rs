@@ -1,4 +1,7 @@
-use crate::state::AppState;
+use crate::{
+	query_tags::{self, QueryTags},
+	state::AppState,
+};
 use axum::{
 	extract::{Request, State},
 	http::StatusCode,
@@ -7,14 +10,31 @@ use axum::{
 };
 use chrono::{TimeDelta, Utc};
 use opentelemetry::{
+	global,
 	metrics::{Counter, Histogram, MeterProvider as _},
 	KeyValue,
 };
 use opentelemetry_sdk::metrics::{self, SdkMeterProvider};
 use prometheus::{Encoder, Registry, TextEncoder};
+use std::{
+	collections::HashSet,
+	sync::{Arc, Mutex},
+};
 
 const HTTP_REQUEST_TOTAL_NAME: &str = "http_requests_total";
 const HTTP_REQUEST_DURATION_SECONDS: &str = "http_request_duration_seconds";
+const DB_ROWS_SCANNED_TOTAL: &str = "db_rows_scanned_total";
+const DB_ROWS_RETURNED_TOTAL: &str = "db_rows_returned_total";
+const SHADOW_QUERIES_TOTAL: &str = "shadow_queries_total";
+const HEDGED_QUERIES_TOTAL: &str = "hedged_queries_total";
+const DASHBOARD_REQUESTS_TOTAL: &str = "dashboard_requests_total";
+const DASHBOARD_REQUEST_DURATION_SECONDS: &str =
+	"dashboard_request_duration_seconds";
+// distinct dashboard uids given their own label value, the ones seen
+// after that are counted as "other"
+const MAX_DASHBOARDS: usize = 200;
+// the same for the source a client claims, grafana only sends a few
+const MAX_SOURCES: usize = 20;
 
 #[derive(Clone)]
 pub struct Instrumentations {
@@ -22,6 +42,10 @@ pub struct Instrumentations {
 	_provider: SdkMeterProvider,
 	pub http_request_total: Counter<u64>,
 	pub http_request_duration: Histogram<f64>,
+	dashboard_requests: Counter<u64>,
+	dashboard_request_duration: Histogram<f64>,
+	dashboards: Arc<Mutex<HashSet<String>>>,
+	sources: Arc<Mutex<HashSet<String>>>,
 }
 
 #[derive(Clone)]
@@ -55,6 +79,119 @@ impl Instrumentations {
 			],
 		)
 	}
+	// requests by the dashboard and source grafana tagged them with
+	pub fn observe_query_tags(&self, seconds: f64, tags: &QueryTags) {
+		let dashboard = match &tags.dashboard_uid {
+			Some(uid) => capped(&self.dashboards, MAX_DASHBOARDS, uid),
+			None => String::new(),
+		};
+		let source = match &tags.source {
+			Some(s) => capped(&self.sources, MAX_SOURCES, s),
+			None => String::new(),
+		};
+		let labels = [
+			KeyValue::new("dashboard", dashboard),
+			KeyValue::new("source", source),
+		];
+		self.dashboard_requests.add(1, &labels);
+		self.dashboard_request_duration.record(seconds, &labels);
+	}
+}
+
+// capped keeps the label values a client sets from growing without bound,
+// the first max distinct values are kept and the rest become "other"
+fn capped(seen: &Mutex<HashSet<String>>, max: usize, v: &str) -> String {
+	let mut seen = seen.lock().unwrap();
+	if seen.contains(v) {
+		return v.to_string();
+	}
+	if seen.len() < max {
+		seen.insert(v.to_string());
+		return v.to_string();
+	}
+	"other".to_string()
+}
+
+// RowsInstrumentations is owned by storage backends, they're created after
+// setup_metrcis so the instruments are bound to the global provider
+#[derive(Clone)]
+pub struct RowsInstrumentations {
+	backend: &'static str,
+	rows_scanned: Counter<u64>,
+	rows_returned: Counter<u64>,
+}
+
+impl RowsInstrumentations {
+	pub fn new(backend: &'static str) -> Self {
+		let meter = global::meter(env!("CARGO_PKG_NAME"));
+		let rows_scanned = meter
+			.u64_counter(DB_ROWS_SCANNED_TOTAL)
+			.with_description("Total number of rows read from the backend")
+			.init();
+		let rows_returned = meter
+			.u64_counter(DB_ROWS_RETURNED_TOTAL)
+			.with_description("Total number of rows returned to the client")
+			.init();
+		Self {
+			backend,
+			rows_scanned,
+			rows_returned,
+		}
+	}
+	pub fn record(&self, scanned: u64, returned: u64) {
+		let tags = [KeyValue::new("backend", self.backend)];
+		self.rows_scanned.add(scanned, &tags);
+		self.rows_returned.add(returned, &tags);
+	}
+}
+
+// ShadowInstrumentations counts the reads replayed against a shadow source
+// by outcome: match, mismatch, error or skipped
+#[derive(Clone)]
+pub struct ShadowInstrumentations {
+	queries: Counter<u64>,
+}
+
+impl Default for ShadowInstrumentations {
+	fn default() -> Self {
+		let queries = global::meter(env!("CARGO_PKG_NAME"))
+			.u64_counter(SHADOW_QUERIES_TOTAL)
+			.with_description("Total number of reads compared with the shadow")
+			.init();
+		Self { queries }
+	}
+}
+
+impl ShadowInstrumentations {
+	pub fn record(&self, op: &'static str, result: &'static str) {
+		self.queries.add(
+			1,
+			&[KeyValue::new("op", op), KeyValue::new("result", result)],
+		);
+	}
+}
+
+// HedgeInstrumentations counts the queries that were sent a second time,
+// by which one answered: first, second or error
+#[derive(Clone)]
+pub struct HedgeInstrumentations {
+	queries: Counter<u64>,
+}
+
+impl Default for HedgeInstrumentations {
+	fn default() -> Self {
+		let queries = global::meter(env!("CARGO_PKG_NAME"))
+			.u64_counter(HEDGED_QUERIES_TOTAL)
+			.with_description("Total number of queries sent a second time")
+			.init();
+		Self { queries }
+	}
+}
+
+impl HedgeInstrumentations {
+	pub fn record(&self, winner: &'static str) {
+		self.queries.add(1, &[KeyValue::new("winner", winner)]);
+	}
 }
 
 pub fn setup_metrcis() -> Instrumentations {
@@ -80,6 +217,7 @@ pub fn setup_metrcis() -> Instrumentations {
 			.unwrap(),
 		)
 		.build();
+	global::set_meter_provider(provider.clone());
 	let meter = provider.meter(env!("CARGO_PKG_NAME"));
 	let http_request_total = meter
 		.u64_counter(HTTP_REQUEST_TOTAL_NAME)
@@ -90,11 +228,24 @@ pub fn setup_metrcis() -> Instrumentations {
 		.with_unit("s")
 		.with_description("The HTTP request latencies in seconds")
 		.init();
+	let dashboard_requests = meter
+		.u64_counter(DASHBOARD_REQUESTS_TOTAL)
+		.with_description("Total number of requests tagged by grafana")
+		.init();
+	let dashboard_request_duration = meter
+		.f64_histogram(DASHBOARD_REQUEST_DURATION_SECONDS)
+		.with_unit("s")
+		.with_description("Latencies of the requests tagged by grafana")
+		.init();
 	Instrumentations {
 		registry,
 		_provider: provider,
 		http_request_total,
 		http_request_duration,
+		dashboard_requests,
+		dashboard_request_duration,
+		dashboards: Default::default(),
+		sources: Default::default(),
 	}
 }
 
@@ -114,10 +265,11 @@ pub async fn record_middleware(
 
 	tags.status = response.status().as_u16() as i64;
 	state.metrics.add_req_total(&tags);
-	let duration = Utc::now() - start;
-	state
-		.metrics
-		.observe_req_duration(delta_to_seconds(duration), &tags);
+	let seconds = delta_to_seconds(Utc::now() - start);
+	state.metrics.observe_req_duration(seconds, &tags);
+	if let Some(qt) = query_tags::current() {
+		state.metrics.observe_query_tags(seconds, &qt);
+	}
 	response
 }
 
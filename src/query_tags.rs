@@ -0,0 +1,151 @@
+use axum::{extract::Request, middleware::Next, response::Response};
+use http::HeaderMap;
+use serde::Serialize;
+use std::{future::Future, sync::Arc};
+use tracing::{info_span, Instrument};
+
+const QUERY_TAGS_HEADER: &str = "X-Query-Tags";
+const DASHBOARD_UID_HEADER: &str = "X-Dashboard-Uid";
+const PANEL_ID_HEADER: &str = "X-Panel-Id";
+// longer values are cut, they end up in metric labels and ck's query_log
+const MAX_VALUE_LEN: usize = 64;
+
+// QueryTags tells who sent the request, as far as grafana says so
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct QueryTags {
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub source: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub dashboard_uid: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub panel_id: Option<String>,
+}
+
+impl QueryTags {
+	// X-Query-Tags is a comma separated list of key=value, the same
+	// format loki reads, e.g. Source=grafana,Feature=logsVolume
+	pub fn from_headers(headers: &HeaderMap) -> Self {
+		let get = |name| {
+			headers
+				.get(name)
+				.and_then(|v| v.to_str().ok())
+				.and_then(sanitize)
+		};
+		let source = headers
+			.get(QUERY_TAGS_HEADER)
+			.and_then(|v| v.to_str().ok())
+			.and_then(|tags| {
+				tags.split(',')
+					.filter_map(|kv| kv.split_once('='))
+					.find(|(k, _)| k.trim().eq_ignore_ascii_case("source"))
+					.and_then(|(_, v)| sanitize(v))
+			});
+		Self {
+			source,
+			dashboard_uid: get(DASHBOARD_UID_HEADER),
+			panel_id: get(PANEL_ID_HEADER),
+		}
+	}
+	fn is_empty(&self) -> bool {
+		self.source.is_none()
+			&& self.dashboard_uid.is_none()
+			&& self.panel_id.is_none()
+	}
+}
+
+// only characters that are safe in labels, logs and sql settings
+fn sanitize(v: &str) -> Option<String> {
+	let v: String = v
+		.trim()
+		.chars()
+		.filter(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+		.take(MAX_VALUE_LEN)
+		.collect();
+	(!v.is_empty()).then_some(v)
+}
+
+tokio::task_local! {
+	static TAGS: Arc<QueryTags>;
+}
+
+// middleware keeps the tags of the request for everything it runs and
+// records them on a span, so they show up in every log line
+pub async fn middleware(request: Request, next: Next) -> Response {
+	let tags = QueryTags::from_headers(request.headers());
+	if tags.is_empty() {
+		return next.run(request).await;
+	}
+	let span = info_span!(
+		"query_tags",
+		source = tags.source.as_deref(),
+		dashboard_uid = tags.dashboard_uid.as_deref(),
+		panel_id = tags.panel_id.as_deref(),
+	);
+	TAGS.scope(Arc::new(tags), next.run(request).instrument(span))
+		.await
+}
+
+// current returns the tags of the request being served, if any
+pub fn current() -> Option<Arc<QueryTags>> {
+	TAGS.try_with(Arc::clone).ok()
+}
+
+// log_comment is sent to ck with each query, so the tags can be
+// read back from system.query_log
+pub fn log_comment() -> Option<String> {
+	current().and_then(|t| serde_json::to_string(&*t).ok())
+}
+
+// spawned tasks don't inherit task locals, same as stats::inherit
+pub fn inherit<F: Future>(f: F) -> impl Future<Output = F::Output> {
+	let t = current();
+	async move {
+		match t {
+			Some(t) => TAGS.scope(t, f).await,
+			None => f.await,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use pretty_assertions::assert_eq;
+
+	#[test]
+	fn test_from_headers() {
+		let mut headers = HeaderMap::new();
+		assert_eq!(QueryTags::from_headers(&headers), QueryTags::default());
+		headers.insert(
+			QUERY_TAGS_HEADER,
+			"Feature=logsVolume, source=grafana".parse().unwrap(),
+		);
+		headers.insert(DASHBOARD_UID_HEADER, "abc'; DROP".parse().unwrap());
+		headers.insert(PANEL_ID_HEADER, "4".parse().unwrap());
+		assert_eq!(
+			QueryTags::from_headers(&headers),
+			QueryTags {
+				source: Some("grafana".to_string()),
+				dashboard_uid: Some("abcDROP".to_string()),
+				panel_id: Some("4".to_string()),
+			}
+		);
+	}
+
+	#[tokio::test]
+	async fn test_log_comment() {
+		assert_eq!(log_comment(), None);
+		let tags = QueryTags {
+			dashboard_uid: Some("abc".to_string()),
+			..Default::default()
+		};
+		let got = TAGS
+			.scope(Arc::new(tags), async {
+				tokio::spawn(inherit(async { log_comment() }))
+					.await
+					.unwrap()
+			})
+			.await;
+		assert_eq!(got.as_deref(), Some(r#"{"dashboard_uid":"abc"}"#));
+	}
+}
@@ -1,13 +1,19 @@
-use crate::{logquery, metrics, state};
+use crate::{
+	config::Limits, debug, logquery, metrics, query_tags, state,
+	utils::log::redact_query,
+};
 use axum::{
-	extract::{Json, Request},
+	extract::{DefaultBodyLimit, Json, Request, State},
 	http::StatusCode,
-	middleware::from_fn_with_state,
+	middleware::{from_fn, from_fn_with_state, Next},
+	response::{IntoResponse, Response},
 	routing::{any, get, on, MethodFilter},
 	Router,
 };
 use http::Request as HttpRequest;
 use serde::Serialize;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
 use tower::ServiceBuilder;
 use tower_http::trace::DefaultOnResponse;
 use tower_http::{
@@ -21,18 +27,31 @@ static SKIP_LOGGING_PATHS: [&str; 3] = ["/ready", "/metrics", "/api/echo"];
 // Loki HTTP API, see https://grafana.com/docs/loki/latest/reference/api/#query-endpoints
 pub fn new_router(state: state::AppState) -> Router {
 	let cfg = state.config.clone();
-	let app = Router::new()
-		.route("/ready", any(ok))
-		.route("/metrics", get(metrics::export_metrics))
-		// loki API
-		// /loki/api/v1/query grafana use this endpoint to check if the datasource is working
-		.route("/loki/api/v1/query", get(logquery::loki_is_working))
+	let mut api = Router::new();
+	if cfg.server.admin_listen_addr.is_none() {
+		api = api.merge(admin_routes());
+	}
+	let c = &cfg.server.concurrency;
+	let heavy = Router::new()
+		.route("/loki/api/v1/query_range", get(logquery::query_range))
+		.route(
+			"/api/traces/:trace_id",
+			get(crate::trace::get_trace_by_id),
+		)
+		.route(
+			"/api/traces/:trace_id/logs",
+			get(crate::trace::trace_logs),
+		)
+		.route("/api/search", get(crate::trace::search_trace_v2))
+		.route("/api/v2/search", get(crate::trace::search_trace_v2))
+		// EXPLAIN of the sql a logql or traceql query generates
+		.route("/debug/explain", get(debug::explain));
+	let light = Router::new()
 		.route("/loki/api/v1/labels", get(logquery::query_labels))
 		.route(
 			"/loki/api/v1/label/:label/values",
 			get(logquery::query_label_values),
 		)
-		.route("/loki/api/v1/query_range", get(logquery::query_range))
 		.route(
 			"/loki/api/v1/series",
 			on(
@@ -40,20 +59,55 @@ pub fn new_router(state: state::AppState) -> Router {
 				logquery::query_series,
 			),
 		)
+		.route("/api/v2/search/tags", get(crate::trace::search_tags))
+		.route(
+			"/api/v2/search/tag/:tag_name/values",
+			get(crate::trace::search_tag_values),
+		);
+	// the export below is heavy too
+	let heavy_budget = Budget::new(c.heavy, c.queue);
+	let app = api
+		.merge(limit_concurrency(heavy, heavy_budget.clone()))
+		.merge(limit_concurrency(light, Budget::new(c.light, c.queue)))
+		.route("/ready", any(ready))
+		// loki API
+		// /loki/api/v1/query grafana use this endpoint to check if the datasource is working
+		.route("/loki/api/v1/query", get(logquery::loki_is_working))
+		.route(
+			"/loki/api/v1/format_query",
+			on(
+				MethodFilter::GET.or(MethodFilter::POST),
+				logquery::format_query,
+			),
+		)
+		.route(
+			"/loki/api/v1/delete",
+			on(
+				MethodFilter::POST.or(MethodFilter::PUT),
+				logquery::delete_logs,
+			)
+			.get(logquery::list_deletes),
+		)
 		// collector API for ingesting traces, just for test
 		// tempo API
 		.route("/api/status/buildinfo", get(build_info))
 		.route(
-			"/api/traces/:trace_id",
-			get(crate::trace::get_trace_by_id),
+			"/api/format_query",
+			on(
+				MethodFilter::GET.or(MethodFilter::POST),
+				crate::trace::format_traceql,
+			),
 		)
-		.route("/api/search", get(crate::trace::search_trace_v2))
-		.route("/api/v2/search", get(crate::trace::search_trace_v2))
-		.route("/api/v2/search/tags", get(crate::trace::search_tags))
-		.route("/api/v2/search/tag/:tag_name/values", get(crate::trace::search_tag_values))
 		// https://grafana.com/docs/tempo/latest/api_docs/#query-echo-endpoint
 		.route("/api/echo", get(|| async { "echo" }))
 		.fallback(handler_404)
+		.layer(TimeoutLayer::new(cfg.server.timeout));
+	// an export reads whole traces, it gets a timeout of its own
+	let export = Router::new()
+		.route("/api/export/traces", get(crate::trace::export_traces))
+		.layer(TimeoutLayer::new(cfg.limits.export_timeout));
+	let app = app
+		.merge(limit_concurrency(export, heavy_budget))
 		.with_state(state.clone())
 		.layer(
 			ServiceBuilder::new()
@@ -65,7 +119,7 @@ pub fn new_router(state: state::AppState) -> Router {
 								if SKIP_LOGGING_PATHS.contains(&p) {
 									return;
 								}
-								info!(method = ?req.method(), path = p, query = req.uri().query(), "request received");
+								info!(method = ?req.method(), path = p, query = req.uri().query().map(redact_query).as_deref(), "request received");
 							}
 						)
 						.on_response(
@@ -73,16 +127,118 @@ pub fn new_router(state: state::AppState) -> Router {
 								.level(tracing::Level::INFO),
 						),
 				)
+				.layer(from_fn_with_state(
+					Arc::new(cfg.limits.clone()),
+					request_limits,
+				))
+				.layer(DefaultBodyLimit::max(
+					cfg.limits.max_request_body_bytes,
+				))
+				.layer(from_fn(query_tags::middleware))
 				.layer(from_fn_with_state(state, metrics::record_middleware))
-				.layer(TimeoutLayer::new(cfg.server.timeout))
 				.layer(CompressionLayer::new())
 				.layer(RequestDecompressionLayer::new()),
 		);
 	app
 }
 
-async fn ok() -> StatusCode {
-	StatusCode::OK
+// request_limits turns away requests of broken clients before a handler
+// parses them, the body is limited by DefaultBodyLimit as it's read.
+// hyper already answers a request with more than 100 headers with a 431
+async fn request_limits(
+	State(limits): State<Arc<Limits>>,
+	req: Request,
+	next: Next,
+) -> Response {
+	let uri_len = req.uri().path_and_query().map_or(0, |p| p.as_str().len());
+	if uri_len > limits.max_uri_bytes {
+		return (
+			StatusCode::URI_TOO_LONG,
+			format!("uri longer than {} bytes", limits.max_uri_bytes),
+		)
+			.into_response();
+	}
+	if req.headers().len() > limits.max_request_headers {
+		return (
+			StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+			format!("more than {} headers", limits.max_request_headers),
+		)
+			.into_response();
+	}
+	next.run(req).await
+}
+
+// Budget is shared by the routes of a class, max requests run at once
+// and up to queue more wait for their turn, the others get a 503
+#[derive(Clone)]
+struct Budget {
+	running: Arc<Semaphore>,
+	admitted: Arc<Semaphore>,
+}
+
+impl Budget {
+	fn new(max: usize, queue: usize) -> Self {
+		Self {
+			running: Arc::new(Semaphore::new(max)),
+			admitted: Arc::new(Semaphore::new(max + queue)),
+		}
+	}
+}
+
+// limit_concurrency puts every route of router on budget, a layer of
+// tower would be cloned into a limit of its own for each route
+fn limit_concurrency<S>(router: Router<S>, budget: Budget) -> Router<S>
+where
+	S: Clone + Send + Sync + 'static,
+{
+	router.route_layer(from_fn_with_state(budget, within_budget))
+}
+
+async fn within_budget(
+	State(budget): State<Budget>,
+	req: Request,
+	next: Next,
+) -> Response {
+	let Ok(_admitted) = budget.admitted.try_acquire() else {
+		return (
+			StatusCode::SERVICE_UNAVAILABLE,
+			"too many requests in flight, try again later",
+		)
+			.into_response();
+	};
+	// the semaphores are never closed
+	let Ok(_running) = budget.running.acquire().await else {
+		return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+	};
+	next.run(req).await
+}
+
+// admin_routes get their own listener when admin_listen_addr is set,
+// they're on the query port otherwise
+fn admin_routes() -> Router<state::AppState> {
+	Router::new()
+		.route("/metrics", get(metrics::export_metrics))
+		// the label index as json, see debug::series_store
+		.route(
+			"/debug/series_store",
+			get(debug::series_store).post(debug::restore_series_store),
+		)
+}
+
+pub fn new_admin_router(state: state::AppState) -> Router {
+	admin_routes().with_state(state)
+}
+
+// not ready while warming up, the body tells how far it got
+async fn ready(State(state): State<state::AppState>) -> Response {
+	match state.warmup.pending() {
+		None => StatusCode::OK.into_response(),
+		Some((done, total)) => (
+			StatusCode::SERVICE_UNAVAILABLE,
+			format!("warming up: {}/{}", done, total),
+		)
+			.into_response(),
+	}
 }
 
 async fn handler_404(req: Request) -> StatusCode {
@@ -114,3 +270,103 @@ struct BuildInfo {
 	#[serde(rename = "goVersion")]
 	go_version: String,
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use axum::{body::Body, routing::post};
+	use tower::ServiceExt;
+
+	// the limits are applied in the same order as in new_router
+	fn limited(limits: Limits) -> Router {
+		Router::new()
+			.route("/echo", post(|body: String| async move { body }))
+			.layer(
+				ServiceBuilder::new()
+					.layer(from_fn_with_state(
+						Arc::new(limits.clone()),
+						request_limits,
+					))
+					.layer(DefaultBodyLimit::max(
+						limits.max_request_body_bytes,
+					)),
+			)
+	}
+
+	async fn status(app: &Router, req: HttpRequest<Body>) -> StatusCode {
+		app.clone().oneshot(req).await.unwrap().status()
+	}
+
+	#[tokio::test]
+	async fn test_shared_budget() {
+		let release = Arc::new(Semaphore::new(0));
+		let held = release.clone();
+		let wait = move || {
+			let held = held.clone();
+			async move {
+				let _ = held.acquire().await;
+			}
+		};
+		let budget = Budget::new(1, 1);
+		let app = limit_concurrency(
+			Router::new()
+				.route("/a", get(wait.clone()))
+				.route("/b", get(wait)),
+			budget.clone(),
+		);
+		let req =
+			|uri: &str| HttpRequest::get(uri).body(Body::empty()).unwrap();
+		// one runs and the other waits, whatever their route
+		let first = tokio::spawn({
+			let app = app.clone();
+			async move { status(&app, req("/a")).await }
+		});
+		let second = tokio::spawn({
+			let app = app.clone();
+			async move { status(&app, req("/b")).await }
+		});
+		while budget.admitted.available_permits() > 0 {
+			tokio::task::yield_now().await;
+		}
+		assert_eq!(
+			status(&app, req("/a")).await,
+			StatusCode::SERVICE_UNAVAILABLE
+		);
+		release.add_permits(2);
+		assert_eq!(first.await.unwrap(), StatusCode::OK);
+		assert_eq!(second.await.unwrap(), StatusCode::OK);
+	}
+
+	#[tokio::test]
+	async fn test_request_limits() {
+		let app = limited(Limits {
+			max_request_body_bytes: 8,
+			max_request_headers: 2,
+			max_uri_bytes: 16,
+			..Default::default()
+		});
+		let req = |uri: &str, headers: usize, body: &str| {
+			let mut b = HttpRequest::post(uri);
+			for i in 0..headers {
+				b = b.header(format!("x-h{}", i), "v");
+			}
+			b.body(Body::from(body.to_string())).unwrap()
+		};
+		assert_eq!(
+			status(&app, req("/echo", 2, "12345678")).await,
+			StatusCode::OK
+		);
+		assert_eq!(
+			status(&app, req("/echo", 0, "123456789")).await,
+			StatusCode::PAYLOAD_TOO_LARGE
+		);
+		assert_eq!(
+			status(&app, req("/echo?q=1234567890", 0, "")).await,
+			StatusCode::URI_TOO_LONG
+		);
+		assert_eq!(
+			status(&app, req("/echo", 3, "")).await,
+			StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+		);
+	}
+}
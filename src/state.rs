@@ -1,11 +1,20 @@
 use crate::{
 	config,
-	logquery::labels::LabelCacheExpiry,
+	errors::AppError,
+	logquery::{
+		delete::DeleteJobs, label_names::LabelNames, labels::LabelCacheExpiry,
+		warmup::WarmupProgress,
+	},
 	metrics,
 	storage::{log::LogStorage, trace::TraceStorage},
+	tenant::{Tenant, TenantSources},
 };
 use moka::sync::Cache;
-use std::sync::Arc;
+use std::{
+	collections::HashMap,
+	sync::{Arc, Mutex},
+};
+use tokio::sync::watch;
 use tracing::debug;
 
 #[derive(Clone)]
@@ -13,8 +22,142 @@ pub struct AppState {
 	pub config: Arc<config::AppConfig>,
 	pub log_handle: Box<dyn LogStorage>,
 	pub trace_handle: Box<dyn TraceStorage>,
-	pub cache: Cache<String, Arc<Vec<u8>>>,
+	pub cache: TenantCache,
 	pub metrics: Arc<metrics::Instrumentations>,
+	pub tenants: Arc<TenantSources>,
+	pub deletes: DeleteJobs,
+	pub warmup: Arc<WarmupProgress>,
+	pub label_names: LabelNames,
+}
+
+impl AppState {
+	// swaps in the storages of the tenant if it has overrides
+	pub fn for_tenant(mut self, t: &Tenant) -> Self {
+		if let Some(h) = self.tenants.log(t) {
+			self.log_handle = h;
+		}
+		if let Some(h) = self.tenants.trace(t) {
+			self.trace_handle = h;
+		}
+		self.cache = self.cache.for_tenant(t);
+		self.label_names = self.label_names.for_tenant(t);
+		self
+	}
+}
+
+// TenantCache is one cache shared by all tenants, every key is prefixed
+// with the tenant so a tenant never reads what another one cached
+#[derive(Clone)]
+pub struct TenantCache {
+	inner: Cache<String, Arc<Vec<u8>>>,
+	prefix: String,
+	flights: Flights,
+}
+
+impl TenantCache {
+	pub fn new(inner: Cache<String, Arc<Vec<u8>>>, t: &Tenant) -> Self {
+		Self {
+			inner,
+			prefix: Self::prefix(t),
+			flights: Flights::default(),
+		}
+	}
+	// header values can't hold a NUL, so no tenant id is a prefix of another
+	fn prefix(t: &Tenant) -> String {
+		format!("{}\0", t.0)
+	}
+	pub fn for_tenant(&self, t: &Tenant) -> Self {
+		Self {
+			prefix: Self::prefix(t),
+			..self.clone()
+		}
+	}
+	pub fn get(&self, key: &str) -> Option<Arc<Vec<u8>>> {
+		self.inner.get(&format!("{}{}", self.prefix, key))
+	}
+	pub fn insert(&self, key: String, value: Arc<Vec<u8>>) {
+		self.inner.insert(self.prefix.clone() + &key, value);
+	}
+	// join makes the caller the leader of key unless an identical request
+	// is running, then it waits for what that one lands. A value cached
+	// meanwhile, e.g. by the flight before, lands at once
+	pub async fn join(&self, key: &str) -> Joined {
+		let key = format!("{}{}", self.prefix, key);
+		loop {
+			let mut rx = {
+				let mut flights = self.flights.0.lock().unwrap();
+				match flights.get(&key) {
+					Some(rx) => rx.clone(),
+					None => {
+						if let Some(v) = self.inner.get(&key) {
+							return Joined::Landed(Ok(v));
+						}
+						let (tx, rx) = watch::channel(None);
+						flights.insert(key.clone(), rx);
+						return Joined::Leader(Flight {
+							key,
+							tx,
+							flights: self.flights.clone(),
+						});
+					}
+				}
+			};
+			// a leader dropped before landing, e.g. as its client went
+			// away, leaves the key to the next one
+			let landed = rx.wait_for(Option::is_some).await.ok();
+			if let Some(outcome) = landed.and_then(|v| v.clone()) {
+				return Joined::Landed(outcome);
+			}
+		}
+	}
+	// clear drops every entry of this tenant, e.g. once its logs changed
+	pub fn clear(&self) {
+		for (k, _) in self.inner.iter() {
+			if k.starts_with(&self.prefix) {
+				self.inner.invalidate(k.as_str());
+			}
+		}
+	}
+	// iter walks the entries of this tenant only, without the prefix
+	pub fn iter(&self) -> impl Iterator<Item = (String, Arc<Vec<u8>>)> + '_ {
+		self.inner.iter().filter_map(|(k, v)| {
+			k.strip_prefix(&self.prefix).map(|k| (k.to_string(), v))
+		})
+	}
+}
+
+// what the first of identical requests got, the others get it as well
+pub type Outcome = Result<Arc<Vec<u8>>, Arc<AppError>>;
+
+pub enum Joined {
+	// nothing identical is running, run it and land the flight
+	Leader(Flight),
+	Landed(Outcome),
+}
+
+// Flights has the outcome of each key being computed, so identical
+// requests arriving together reach the backend once
+#[derive(Clone, Default)]
+struct Flights(Arc<Mutex<HashMap<String, watch::Receiver<Option<Outcome>>>>>);
+
+pub struct Flight {
+	key: String,
+	tx: watch::Sender<Option<Outcome>>,
+	flights: Flights,
+}
+
+impl Flight {
+	// land hands the outcome to the requests waiting for this one, a
+	// response should be cached before
+	pub fn land(self, outcome: Outcome) {
+		self.tx.send_replace(Some(outcome));
+	}
+}
+
+impl Drop for Flight {
+	fn drop(&mut self) {
+		self.flights.0.lock().unwrap().remove(&self.key);
+	}
 }
 
 pub fn new_cache(cfg: &config::Cache) -> Cache<String, Arc<Vec<u8>>> {
@@ -30,3 +173,99 @@ pub fn new_cache(cfg: &config::Cache) -> Cache<String, Arc<Vec<u8>>> {
 		.time_to_idle(cfg.time_to_idle)
 		.build()
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use pretty_assertions::assert_eq;
+
+	#[test]
+	fn test_tenant_cache() {
+		let cfg = config::Cache {
+			max_capacity: 1024,
+			time_to_live: std::time::Duration::from_secs(60),
+			time_to_idle: std::time::Duration::from_secs(60),
+			refresh_interval: None,
+		};
+		let t = |id: &str| Tenant(id.to_string());
+		let a = TenantCache::new(new_cache(&cfg), &t("a"));
+		let b = a.for_tenant(&t("b"));
+		a.insert("q".to_string(), Arc::new(vec![1]));
+		// key bq of tenant a must not be key q of tenant ab
+		a.insert("bq".to_string(), Arc::new(vec![3]));
+		assert_eq!(a.for_tenant(&t("ab")).get("q"), None);
+		assert_eq!(a.get("q"), Some(Arc::new(vec![1])));
+		assert_eq!(b.get("q"), None);
+		b.insert("q".to_string(), Arc::new(vec![2]));
+		assert_eq!(a.get("q"), Some(Arc::new(vec![1])));
+		assert_eq!(b.get("q"), Some(Arc::new(vec![2])));
+		let mut keys: Vec<_> = a.iter().map(|(k, _)| k).collect();
+		keys.sort();
+		assert_eq!(keys, vec!["bq".to_string(), "q".to_string()]);
+		a.clear();
+		assert_eq!(a.iter().count(), 0);
+		assert_eq!(b.get("q"), Some(Arc::new(vec![2])));
+	}
+
+	#[tokio::test]
+	async fn test_join() {
+		let cfg = config::Cache {
+			max_capacity: 1024,
+			time_to_live: std::time::Duration::from_secs(60),
+			time_to_idle: std::time::Duration::from_secs(60),
+			refresh_interval: None,
+		};
+		let a = TenantCache::new(new_cache(&cfg), &Tenant("a".to_string()));
+		let follow = |key: &'static str| {
+			let a = a.clone();
+			tokio::spawn(async move {
+				match a.join(key).await {
+					Joined::Landed(outcome) => Some(outcome),
+					Joined::Leader(_) => None,
+				}
+			})
+		};
+		let Joined::Leader(first) = a.join("q").await else {
+			unreachable!()
+		};
+		let second = follow("q");
+		// another tenant doesn't wait
+		let b = a.for_tenant(&Tenant("b".to_string()));
+		assert!(matches!(b.join("q").await, Joined::Leader(_)));
+		tokio::task::yield_now().await;
+		a.insert("q".to_string(), Arc::new(vec![1]));
+		first.land(Ok(Arc::new(vec![1])));
+		let landed = second.await.unwrap().unwrap();
+		assert_eq!(landed.unwrap(), Arc::new(vec![1]));
+		assert!(a.flights.0.lock().unwrap().is_empty());
+
+		// the waiters get the error too, no one runs the request again
+		let Joined::Leader(first) = a.join("e").await else {
+			unreachable!()
+		};
+		let second = follow("e");
+		tokio::task::yield_now().await;
+		first.land(Err(Arc::new(AppError::TraceNotFound)));
+		let landed = second.await.unwrap().unwrap();
+		assert!(matches!(
+			landed.unwrap_err().as_ref(),
+			AppError::TraceNotFound
+		));
+
+		// a leader gone without landing hands over to a waiter
+		let Joined::Leader(first) = a.join("d").await else {
+			unreachable!()
+		};
+		let second = follow("d");
+		tokio::task::yield_now().await;
+		drop(first);
+		assert!(second.await.unwrap().is_none());
+
+		// what's cached lands at once
+		a.insert("c".to_string(), Arc::new(vec![2]));
+		let Joined::Landed(Ok(v)) = a.join("c").await else {
+			unreachable!()
+		};
+		assert_eq!(v, Arc::new(vec![2]));
+	}
+}
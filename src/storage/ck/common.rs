@@ -1,61 +1,132 @@
-use crate::config::Clickhouse;
-use crate::storage::Direction;
+use crate::config::{Clickhouse, S3Archive};
+use crate::metrics::HedgeInstrumentations;
+use crate::query_tags;
+use crate::storage::{
+	explain::{self, ExplainKind},
+	stats, Direction,
+};
+use crate::utils::log::redact_sql;
 use anyhow::Result;
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
+use common::TimeRange;
 use http::Extensions;
 use itertools::Itertools;
 use reqwest::{
 	header::{ACCEPT_ENCODING, CONTENT_TYPE},
-	Client,
+	Client, StatusCode,
 };
 use reqwest::{Request, Response};
 use reqwest_middleware::{
-	ClientBuilder, Middleware, Next, Result as ReqResult,
+	ClientBuilder, Middleware, Next, RequestBuilder, Result as ReqResult,
+};
+use serde::{
+	de::{DeserializeSeed, IgnoredAny, MapAccess, SeqAccess, Visitor},
+	Deserialize, Deserializer,
 };
-use serde::Deserialize;
 use serde_json::Value as JSONValue;
-use sqlbuilder::{
-	builder::{SortType, TableSchema},
-	visit::{ATTRIBUTES_PREFIX, RESOURCES_PREFIX},
+use sqlbuilder::builder::{escape_str, SortType, TableSchema};
+use std::{
+	borrow::Cow,
+	collections::HashMap,
+	fmt,
+	future::Future,
+	ops::Deref,
+	sync::OnceLock,
+	time::{Duration, Instant},
 };
-use std::{collections::HashMap, time::Duration};
 use thiserror::Error;
 use tracing::{error, info};
 
-pub fn to_start_interval(step: Duration) -> &'static str {
-	let sec = step.as_secs();
-	if sec < 5 {
-		"toStartOfSecond(Timestamp) as Tts"
-	} else if sec < 10 {
-		"toStartOfInterval(Timestamp, INTERVAL 5 SECOND) as Tts"
-	} else if sec < 15 {
-		"toStartOfInterval(Timestamp, INTERVAL 10 SECOND) as Tts"
-	} else if sec < 60 {
-		"toStartOfInterval(Timestamp, INTERVAL 30 SECOND) as Tts"
-	} else if sec < 5 * 60 {
-		"toStartOfMinute(Timestamp) as Tts"
-	} else if sec < 10 * 60 {
-		"toStartOfFiveMinutes(Timestamp) as Tts"
-	} else if sec < 30 * 60 {
-		"toStartOfTenMinutes(Timestamp) as Tts"
-	} else if sec < 60 * 60 {
-		"toStartOfInterval(Timestamp, INTERVAL 30 MINUTE) as Tts"
-	} else if sec < 2 * 60 * 60 {
-		"toStartOfHour(Timestamp) as Tts"
-	} else if sec < 24 * 60 * 60 {
-		"toStartOfInterval(Timestamp, INTERVAL 2 HOUR) as Tts"
-	} else if sec < 7 * 24 * 60 * 60 {
-		"toStartOfDay(Timestamp) as Tts"
-	} else if sec < 30 * 24 * 60 * 60 {
-		// Set Monday is the first day of a week
-		// https://clickhouse.com/docs/en/sql-reference/functions/date-time-functions#toweek
-		"toStartOfWeek(Timestamp, 1) as Tts"
-	} else if sec < 365 * 24 * 60 * 60 {
-		"toStartOfMonth(Timestamp) as Tts"
-	} else {
-		"toStartOfYear(Timestamp) as Tts"
+// a query never returns more buckets than this, same as loki's
+// limit of points per series. A smaller step is widened to fit
+const MAX_BUCKETS: u32 = 11_000;
+
+const MINUTE: u64 = 60;
+const HOUR: u64 = 60 * MINUTE;
+const DAY: u64 = 24 * HOUR;
+
+// steps from 5s up are rounded to one of these buckets: a step below
+// the first number gets the bucket of the second number of seconds
+const TIERS: [(u64, u64, &str); 13] = [
+	(
+		10,
+		5,
+		"toStartOfInterval(Timestamp, INTERVAL 5 SECOND, 'UTC')",
+	),
+	(
+		15,
+		10,
+		"toStartOfInterval(Timestamp, INTERVAL 10 SECOND, 'UTC')",
+	),
+	(
+		MINUTE,
+		30,
+		"toStartOfInterval(Timestamp, INTERVAL 30 SECOND, 'UTC')",
+	),
+	(5 * MINUTE, MINUTE, "toStartOfMinute(Timestamp, 'UTC')"),
+	(
+		10 * MINUTE,
+		5 * MINUTE,
+		"toStartOfFiveMinutes(Timestamp, 'UTC')",
+	),
+	(
+		30 * MINUTE,
+		10 * MINUTE,
+		"toStartOfTenMinutes(Timestamp, 'UTC')",
+	),
+	(
+		HOUR,
+		30 * MINUTE,
+		"toStartOfInterval(Timestamp, INTERVAL 30 MINUTE, 'UTC')",
+	),
+	(2 * HOUR, HOUR, "toStartOfHour(Timestamp, 'UTC')"),
+	(
+		DAY,
+		2 * HOUR,
+		"toStartOfInterval(Timestamp, INTERVAL 2 HOUR, 'UTC')",
+	),
+	(7 * DAY, DAY, "toStartOfDay(Timestamp, 'UTC')"),
+	// Set Monday is the first day of a week
+	// https://clickhouse.com/docs/en/sql-reference/functions/date-time-functions#toweek
+	(30 * DAY, 7 * DAY, "toStartOfWeek(Timestamp, 1, 'UTC')"),
+	(365 * DAY, 30 * DAY, "toStartOfMonth(Timestamp, 'UTC')"),
+	(u64::MAX, 365 * DAY, "toStartOfYear(Timestamp, 'UTC')"),
+];
+
+// min_bucket is the smallest bucket that keeps range under MAX_BUCKETS
+fn min_bucket(range: &TimeRange) -> Duration {
+	let (Some(start), Some(end)) = (range.start, range.end) else {
+		return Duration::ZERO;
+	};
+	(end - start).to_std().unwrap_or_default() / MAX_BUCKETS
+}
+
+// buckets are aligned in utc, whatever timezone the server runs in.
+// Timestamp is DateTime64, so steps under 5s get buckets of exactly
+// their size, down to milliseconds
+pub fn to_start_interval(step: Duration, range: &TimeRange) -> String {
+	let min = min_bucket(range);
+	let step = step.max(min);
+	if step < Duration::from_secs(5) {
+		let ms = step.as_millis().max(1);
+		let interval = if ms % 1000 == 0 {
+			format!("{} SECOND", ms / 1000)
+		} else {
+			format!("{} MILLISECOND", ms)
+		};
+		return format!(
+			"toStartOfInterval(Timestamp, INTERVAL {}, 'UTC') as Tts",
+			interval
+		);
 	}
+	let sec = step.as_secs();
+	let min = min.as_secs() + u64::from(min.subsec_nanos() > 0);
+	let (_, _, expr) = TIERS
+		.iter()
+		.find(|(below, size, _)| sec < *below && *size >= min)
+		.unwrap_or(&TIERS[TIERS.len() - 1]);
+	format!("{} as Tts", expr)
 }
 
 pub fn direction_to_sorting(
@@ -78,47 +149,522 @@ pub(crate) struct RecordWarpper {
 	pub data: Vec<Vec<JSONValue>>,
 }
 
-static QUERY_PARAMS: [(&str, &str); 7] = [
+// the same envelope with typed rows, which may borrow from the response
+#[derive(Debug, Deserialize)]
+struct Rows<R> {
+	data: Vec<R>,
+}
+
+/// Text is a string column read straight from the response, borrowed
+/// unless it had to be unescaped. As with `as_str().unwrap_or("")`,
+/// anything that isn't a string reads as empty.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Text<'a>(pub Cow<'a, str>);
+
+impl Text<'_> {
+	pub fn into_string(self) -> String {
+		self.0.into_owned()
+	}
+}
+
+impl Deref for Text<'_> {
+	type Target = str;
+	fn deref(&self) -> &str {
+		&self.0
+	}
+}
+
+impl<'de: 'a, 'a> Deserialize<'de> for Text<'a> {
+	fn deserialize<D: Deserializer<'de>>(
+		d: D,
+	) -> std::result::Result<Self, D::Error> {
+		d.deserialize_any(TextVisitor).map(Text)
+	}
+}
+
+struct TextVisitor;
+
+impl<'de> Visitor<'de> for TextVisitor {
+	type Value = Cow<'de, str>;
+	fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str("a string")
+	}
+	fn visit_borrowed_str<E>(self, v: &'de str) -> Result<Self::Value, E> {
+		Ok(Cow::Borrowed(v))
+	}
+	fn visit_str<E>(self, v: &str) -> Result<Self::Value, E> {
+		Ok(Cow::Owned(v.to_owned()))
+	}
+	fn visit_string<E>(self, v: String) -> Result<Self::Value, E> {
+		Ok(Cow::Owned(v))
+	}
+	fn visit_bool<E>(self, _: bool) -> Result<Self::Value, E> {
+		Ok(Cow::Borrowed(""))
+	}
+	fn visit_i64<E>(self, _: i64) -> Result<Self::Value, E> {
+		Ok(Cow::Borrowed(""))
+	}
+	fn visit_u64<E>(self, _: u64) -> Result<Self::Value, E> {
+		Ok(Cow::Borrowed(""))
+	}
+	fn visit_f64<E>(self, _: f64) -> Result<Self::Value, E> {
+		Ok(Cow::Borrowed(""))
+	}
+	fn visit_unit<E>(self) -> Result<Self::Value, E> {
+		Ok(Cow::Borrowed(""))
+	}
+	fn visit_none<E>(self) -> Result<Self::Value, E> {
+		Ok(Cow::Borrowed(""))
+	}
+	fn visit_seq<A: SeqAccess<'de>>(
+		self,
+		mut seq: A,
+	) -> Result<Self::Value, A::Error> {
+		while seq.next_element::<IgnoredAny>()?.is_some() {}
+		Ok(Cow::Borrowed(""))
+	}
+	fn visit_map<A: MapAccess<'de>>(
+		self,
+		mut map: A,
+	) -> Result<Self::Value, A::Error> {
+		while map.next_entry::<IgnoredAny, IgnoredAny>()?.is_some() {}
+		Ok(Cow::Borrowed(""))
+	}
+}
+
+/// Attrs is an attribute column as a flat map of strings. Map columns
+/// already are one, JSON columns come back as nested objects: their paths
+/// are joined with dots and numbers or bools are printed.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Attrs(pub HashMap<String, String>);
+
+impl<'de> Deserialize<'de> for Attrs {
+	fn deserialize<D: Deserializer<'de>>(
+		d: D,
+	) -> std::result::Result<Self, D::Error> {
+		let mut out = HashMap::new();
+		d.deserialize_any(AttrsVisitor {
+			prefix: None,
+			out: &mut out,
+		})?;
+		Ok(Attrs(out))
+	}
+}
+
+struct AttrsVisitor<'m> {
+	prefix: Option<String>,
+	out: &'m mut HashMap<String, String>,
+}
+
+impl<'de> Visitor<'de> for AttrsVisitor<'_> {
+	type Value = ();
+	fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str("a map of attributes")
+	}
+	fn visit_unit<E>(self) -> Result<(), E> {
+		Ok(())
+	}
+	fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+		while let Some(k) = map.next_key::<String>()? {
+			let key = match &self.prefix {
+				Some(p) => format!("{}.{}", p, k),
+				None => k,
+			};
+			map.next_value_seed(AttrValue {
+				key,
+				out: &mut *self.out,
+			})?;
+		}
+		Ok(())
+	}
+}
+
+// AttrValue puts one value of an attribute object into the flat map
+struct AttrValue<'m> {
+	key: String,
+	out: &'m mut HashMap<String, String>,
+}
+
+impl<'de> DeserializeSeed<'de> for AttrValue<'_> {
+	type Value = ();
+	fn deserialize<D: Deserializer<'de>>(
+		self,
+		d: D,
+	) -> std::result::Result<(), D::Error> {
+		d.deserialize_any(self)
+	}
+}
+
+impl AttrValue<'_> {
+	fn set(self, v: String) {
+		self.out.insert(self.key, v);
+	}
+}
+
+impl<'de> Visitor<'de> for AttrValue<'_> {
+	type Value = ();
+	fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str("an attribute value")
+	}
+	fn visit_str<E>(self, v: &str) -> Result<(), E> {
+		self.set(v.to_string());
+		Ok(())
+	}
+	fn visit_string<E>(self, v: String) -> Result<(), E> {
+		self.set(v);
+		Ok(())
+	}
+	fn visit_bool<E>(self, v: bool) -> Result<(), E> {
+		self.set(v.to_string());
+		Ok(())
+	}
+	fn visit_i64<E>(self, v: i64) -> Result<(), E> {
+		self.set(v.to_string());
+		Ok(())
+	}
+	fn visit_u64<E>(self, v: u64) -> Result<(), E> {
+		self.set(v.to_string());
+		Ok(())
+	}
+	fn visit_f64<E>(self, v: f64) -> Result<(), E> {
+		self.set(v.to_string());
+		Ok(())
+	}
+	// a path a row doesn't have
+	fn visit_unit<E>(self) -> Result<(), E> {
+		Ok(())
+	}
+	fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+		let mut items = vec![];
+		while let Some(v) = seq.next_element::<JSONValue>()? {
+			items.push(v);
+		}
+		self.set(JSONValue::Array(items).to_string());
+		Ok(())
+	}
+	fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<(), A::Error> {
+		AttrsVisitor {
+			prefix: Some(self.key),
+			out: self.out,
+		}
+		.visit_map(map)
+	}
+}
+
+// flatten_attrs is Attrs for the columns kept as json values,
+// nested objects of JSON columns become dotted keys
+pub(crate) fn flatten_attrs(
+	m: HashMap<String, JSONValue>,
+) -> HashMap<String, JSONValue> {
+	if !m.values().any(JSONValue::is_object) {
+		return m;
+	}
+	fn walk(key: String, v: JSONValue, out: &mut HashMap<String, JSONValue>) {
+		match v {
+			JSONValue::Object(o) => {
+				for (k, v) in o {
+					walk(format!("{}.{}", key, k), v, out);
+				}
+			}
+			JSONValue::Null => {}
+			v => {
+				out.insert(key, v);
+			}
+		}
+	}
+	let mut out = HashMap::with_capacity(m.len());
+	for (k, v) in m {
+		walk(k, v, &mut out);
+	}
+	out
+}
+
+// parse_rows deserializes JSONCompact rows without building a Value tree
+pub(crate) fn parse_rows<'a, R: Deserialize<'a>>(
+	text: &'a str,
+) -> Result<Vec<R>> {
+	let rows: Rows<R> = serde_json::from_str(text).inspect_err(|_| {
+		error!("fail to parse ck response: {}", redact_sql(text));
+	})?;
+	Ok(rows.data)
+}
+
+pub(crate) fn decode_rows<'a, R, T>(text: &'a str) -> Result<Vec<T>>
+where
+	R: Deserialize<'a>,
+	T: TryFrom<R, Error = CKConvertErr>,
+{
+	parse_rows::<R>(text)?
+		.into_iter()
+		.map(|row| T::try_from(row).map_err(Into::into))
+		.collect()
+}
+
+static QUERY_PARAMS: [(&str, &str); 6] = [
 	("default_format", "JSONCompact"),
 	("date_time_output_format", "unix_timestamp"), // this is required to handle
 	("add_http_cors_header", "1"),
-	("result_overflow_mode", "break"),
-	("max_result_rows", "1000"),
-	("max_result_bytes", "10000000"),
 	("enable_http_compression", "1"), // enable gzip
+	// otherwise X-ClickHouse-Summary is sent before the query finishes
+	("wait_end_of_query", "1"),
+	// a query whose request is dropped, e.g. the loser of a hedge or one
+	// past the timeout, is cancelled instead of running to the end
+	("cancel_http_readonly_queries_on_client_close", "1"),
 ];
 
+pub(crate) const OVERFLOW_MODE: &str = "result_overflow_mode";
+
+const SUMMARY_HEADER: &str = "X-ClickHouse-Summary";
+
+// numbers in the summary header are quoted
+#[derive(Debug, Default, Deserialize)]
+struct Summary {
+	#[serde(default)]
+	read_rows: String,
+	#[serde(default)]
+	read_bytes: String,
+}
+
+fn record_summary(resp: &Response) {
+	let Some(summary) = resp
+		.headers()
+		.get(SUMMARY_HEADER)
+		.and_then(|v| v.to_str().ok())
+		.and_then(|v| serde_json::from_str::<Summary>(v).ok())
+	else {
+		return;
+	};
+	stats::record(
+		summary.read_rows.parse().unwrap_or(0),
+		summary.read_bytes.parse().unwrap_or(0),
+	);
+}
+
+// qualified table name, since we use http the database must be given
+// explicitly
+pub(crate) fn qualified_name(cfg: &Clickhouse, table: &str) -> String {
+	format!("{}.{}", cfg.database, table)
+}
+
+// full table name to read from. FINAL belongs to the FROM clause only,
+// statements like ALTER TABLE take the qualified name
+pub(crate) fn full_table_name(cfg: &Clickhouse, table: &str) -> String {
+	if cfg.use_final {
+		format!("{} FINAL", qualified_name(cfg, table))
+	} else {
+		qualified_name(cfg, table)
+	}
+}
+
+// s3 table function reading archived files, used in place of a table name
+pub(crate) fn s3_table_function(s3: &S3Archive) -> String {
+	let quote = |v: &str| format!("'{}'", escape_str(v));
+	let mut args = vec![quote(&s3.url)];
+	if let (Some(key), Some(secret)) =
+		(&s3.access_key_id, &s3.secret_access_key)
+	{
+		args.push(quote(key));
+		args.push(quote(secret));
+	}
+	args.push(quote(&s3.format));
+	format!("s3({})", args.join(", "))
+}
+
+fn setting_value(v: &JSONValue, quote: bool) -> String {
+	match v {
+		JSONValue::String(s) if quote => format!("'{}'", s),
+		JSONValue::String(s) => s.clone(),
+		v => v.to_string(),
+	}
+}
+
+fn settings_clause(cfg: &Clickhouse) -> String {
+	let kvs = cfg
+		.clickhouse_settings
+		.iter()
+		.map(|(k, v)| format!("{} = {}", k, setting_value(v, true)))
+		.join(", ");
+	format!(" SETTINGS {}", kvs)
+}
+
 pub(crate) async fn send_query(
 	cli: Client,
 	cfg: Clickhouse,
 	sql: String,
+	max_rows: Option<u32>,
 ) -> Result<Vec<Vec<JSONValue>>> {
+	let res = query_text(cli, cfg, sql, max_rows).await?;
+	let resp: RecordWarpper = serde_json::from_str(&res).inspect_err(|_| {
+		error!("fail to parse ck response: {}", redact_sql(&res));
+	})?;
+	Ok(resp.data)
+}
+
+// query_text returns the raw JSONCompact body, for callers that decode
+// it into typed rows. max_rows is the limit of the request if any, the
+// configured max_result_rows is used otherwise
+pub(crate) async fn query_text(
+	cli: Client,
+	mut cfg: Clickhouse,
+	mut sql: String,
+	max_rows: Option<u32>,
+) -> Result<String> {
+	// grafana's query tags take the place of a configured log_comment
+	if let Some(comment) = query_tags::log_comment() {
+		cfg.clickhouse_settings
+			.insert("log_comment".to_string(), comment.into());
+	}
+	let mut settings = vec![
+		(
+			"max_result_rows".to_string(),
+			max_rows.unwrap_or(cfg.max_result_rows).to_string(),
+		),
+		(
+			"max_result_bytes".to_string(),
+			cfg.max_result_bytes.to_string(),
+		),
+	];
+	// a result over the limits is cut short unless the caller asks to fail
+	if !cfg.clickhouse_settings.contains_key(OVERFLOW_MODE) {
+		settings.push((OVERFLOW_MODE.to_string(), "break".to_string()));
+	}
+	if !cfg.clickhouse_settings.is_empty() {
+		if cfg.settings_clause {
+			sql.push_str(&settings_clause(&cfg));
+		} else {
+			settings.extend(
+				cfg.clickhouse_settings
+					.iter()
+					.map(|(k, v)| (k.clone(), setting_value(v, false))),
+			);
+		}
+	}
+	// the plan comes back as rows of a single string column
+	let explained = match explain::current() {
+		Some(ExplainKind::Sql) => {
+			explain::record(&sql, String::new());
+			return Ok(EMPTY_RESULT.to_string());
+		}
+		Some(ExplainKind::Plan) => Some(format!("EXPLAIN indexes = 1 {}", sql)),
+		Some(ExplainKind::Pipeline) => {
+			Some(format!("EXPLAIN PIPELINE {}", sql))
+		}
+		None => None,
+	}
+	.map(|stmt| std::mem::replace(&mut sql, stmt));
 	let c = ClientBuilder::new(cli).with(LoggingMiddlware).build();
-	let req = c
+	let req = |url: &str| {
+		c.post(url)
+			.query(&QUERY_PARAMS)
+			.query(&settings)
+			.header(CONTENT_TYPE, "text/plain;charset=UTF-8")
+			.header(ACCEPT_ENCODING, "gzip")
+			.body(sql.clone())
+			.basic_auth(cfg.username.clone(), Some(cfg.password.clone()))
+	};
+	let start = Instant::now();
+	let res = match &cfg.hedge {
+		None => fetch(req(&cfg.url)).await?,
+		Some(h) => {
+			let url = h.url.as_deref().unwrap_or(&cfg.url);
+			hedged(fetch(req(&cfg.url)), h.delay, || fetch(req(url))).await?
+		}
+	};
+	stats::record_sql(&sql, start.elapsed());
+	if let Some(original) = explained {
+		let resp: RecordWarpper = serde_json::from_str(&res)?;
+		let plan = resp
+			.data
+			.iter()
+			.filter_map(|row| row.first()?.as_str())
+			.join("\n");
+		explain::record(&original, plan);
+		return Ok(EMPTY_RESULT.to_string());
+	}
+	Ok(res)
+}
+
+// what an explained query returns in place of its rows
+const EMPTY_RESULT: &str = r#"{"meta":[],"data":[],"rows":0}"#;
+
+async fn fetch(req: RequestBuilder) -> Result<String> {
+	let res = req.send().await.map_err(|e| {
+		error!("fail to send ck request: {}", e);
+		e
+	})?;
+	record_summary(&res);
+	let status = res.status();
+	let res = res.text().await.map_err(|e| {
+		error!("fail to read ck response: {}", e);
+		e
+	})?;
+	successful(status, res)
+}
+
+// an error response must not win a hedge or be parsed as rows
+fn successful(status: StatusCode, body: String) -> Result<String> {
+	if !status.is_success() {
+		anyhow::bail!("ck responds {}: {}", status, redact_sql(&body));
+	}
+	Ok(body)
+}
+
+static HEDGE_METRICS: OnceLock<HedgeInstrumentations> = OnceLock::new();
+
+// hedged runs first, and when it hasn't finished after delay starts second
+// too. The first success wins, the other future is dropped, which closes
+// its connection and makes ck cancel the query, see QUERY_PARAMS. An error
+// only wins when both fail
+pub(crate) async fn hedged<T, F, G>(
+	first: F,
+	delay: Duration,
+	second: impl FnOnce() -> G,
+) -> Result<T>
+where
+	F: Future<Output = Result<T>>,
+	G: Future<Output = Result<T>>,
+{
+	tokio::pin!(first);
+	tokio::select! {
+		r = &mut first => return r,
+		_ = tokio::time::sleep(delay) => {}
+	}
+	let metrics = HEDGE_METRICS.get_or_init(HedgeInstrumentations::default);
+	let second = second();
+	tokio::pin!(second);
+	let (res, winner) = tokio::select! {
+		r = &mut first => match r {
+			Ok(v) => (Ok(v), "first"),
+			Err(_) => (second.await, "second"),
+		},
+		r = &mut second => match r {
+			Ok(v) => (Ok(v), "second"),
+			Err(_) => (first.await, "first"),
+		},
+	};
+	metrics.record(if res.is_ok() { winner } else { "error" });
+	res
+}
+
+// exec runs a statement that returns no rows, e.g. DDL
+pub(crate) async fn exec(
+	cli: Client,
+	cfg: Clickhouse,
+	sql: String,
+) -> Result<()> {
+	let resp = cli
 		.post(cfg.url.clone())
-		.query(&QUERY_PARAMS)
 		.header(CONTENT_TYPE, "text/plain;charset=UTF-8")
-		.header(ACCEPT_ENCODING, "gzip")
 		.body(sql)
 		.basic_auth(cfg.username.clone(), Some(cfg.password.clone()))
-		.build()?;
-	let res = c
-		.execute(req)
-		.await
-		.map_err(|e| {
-			error!("fail to send ck request: {}", e);
-			e
-		})?
-		.text()
-		.await
-		.map_err(|e| {
-			error!("fail to read ck response: {}", e);
-			e
-		})?;
-	let resp: RecordWarpper = serde_json::from_str(&res).inspect_err(|_| {
-		error!("fail to parse ck response: {}", res);
-	})?;
-	Ok(resp.data)
+		.send()
+		.await?;
+	if !resp.status().is_success() {
+		let status = resp.status();
+		let body = resp.text().await.unwrap_or_default();
+		anyhow::bail!("ck responds {}: {}", status, redact_sql(&body));
+	}
+	Ok(())
 }
 
 #[derive(Debug, Error)]
@@ -127,87 +673,16 @@ pub enum CKConvertErr {
 	Length,
 	#[error("Invalid timestamp")]
 	Timestamp,
-	#[error("Invalid hashmap")]
-	HashMap,
 	#[error("Invalid duration")]
 	Duration,
-	#[error("Invalid array")]
-	Array,
-	#[error("Invalid string")]
-	String,
-}
-
-pub(crate) fn json_object_to_map_s_s(
-	value: &JSONValue,
-) -> std::result::Result<HashMap<String, String>, CKConvertErr> {
-	value
-		.as_object()
-		.map(|o| {
-			o.iter()
-				.map(|(k, v)| (k.clone(), v.as_str().unwrap_or("").to_string()))
-				.collect()
-		})
-		.ok_or(CKConvertErr::HashMap)
-}
-
-pub(crate) fn json_object_to_map_s_jsonv(
-	value: &JSONValue,
-) -> std::result::Result<HashMap<String, JSONValue>, CKConvertErr> {
-	value
-		.as_object()
-		.map(|o| o.iter().map(|(k, v)| (k.clone(), v.clone())).collect())
-		.ok_or(CKConvertErr::HashMap)
-}
-
-pub(crate) fn json_array_to_date(
-	value: &JSONValue,
-) -> std::result::Result<Vec<DateTime<Utc>>, CKConvertErr> {
-	value
-		.as_array()
-		.map(|a| {
-			a.iter()
-				.map(|v| {
-					let ts = v.as_str().ok_or(CKConvertErr::Timestamp)?;
-					DateTime::parse_from_str(ts, "%s%.9f")
-						.map_err(|_| CKConvertErr::Timestamp)
-						.map(|v| v.to_utc())
-				})
-				.collect_vec()
-				.into_iter()
-				.collect::<Result<Vec<_>, _>>()
-		})
-		.ok_or(CKConvertErr::Timestamp)?
-}
-
-pub(crate) fn json_array_string(
-	value: &JSONValue,
-) -> std::result::Result<Vec<String>, CKConvertErr> {
-	value
-		.as_array()
-		.ok_or(CKConvertErr::Array)?
-		.iter()
-		.map(|v| {
-			v.as_str()
-				.ok_or(CKConvertErr::String)
-				.map(|v| v.to_string())
-		})
-		.collect()
-}
-
-pub(crate) fn json_array_hashmap(
-	value: &JSONValue,
-) -> std::result::Result<Vec<HashMap<String, JSONValue>>, CKConvertErr> {
-	value
-		.as_array()
-		.ok_or(CKConvertErr::Array)?
-		.iter()
-		.map(json_object_to_map_s_jsonv)
-		.collect()
 }
 
 static TS_FORMATS: [&str; 4] = ["%s%.9f", "%s", "%s%.6f", "%s%.3f"];
 
 pub(crate) fn parse_timestamp_try_best(ts: &str) -> Result<DateTime<Utc>> {
+	if let Some(v) = parse_unix_fraction(ts) {
+		return Ok(v);
+	}
 	for f in TS_FORMATS.iter() {
 		if let Ok(v) = DateTime::parse_from_str(ts, f) {
 			return Ok(v.to_utc());
@@ -216,6 +691,22 @@ pub(crate) fn parse_timestamp_try_best(ts: &str) -> Result<DateTime<Utc>> {
 	Err(anyhow::anyhow!("Invalid timestamp: {}", ts))
 }
 
+// unix_timestamp output is "secs.fraction", splitting it by hand is much
+// cheaper than going through chrono's format parser for every row
+fn parse_unix_fraction(ts: &str) -> Option<DateTime<Utc>> {
+	let (secs, frac) = ts.split_once('.').unwrap_or((ts, ""));
+	let digits = |s: &str| s.bytes().all(|b| b.is_ascii_digit());
+	if secs.is_empty() || frac.len() > 9 || !digits(secs) || !digits(frac) {
+		return None;
+	}
+	let nanos = if frac.is_empty() {
+		0
+	} else {
+		frac.parse::<u32>().ok()? * 10u32.pow(9 - frac.len() as u32)
+	};
+	DateTime::from_timestamp(secs.parse().ok()?, nanos)
+}
+
 struct LoggingMiddlware;
 
 #[async_trait]
@@ -227,7 +718,8 @@ impl Middleware for LoggingMiddlware {
 		next: Next<'_>,
 	) -> ReqResult<Response> {
 		if let Some(v) = req.body().and_then(|b| b.as_bytes()) {
-			info!("exec sql in ck: {:?}", std::str::from_utf8(v).unwrap());
+			let sql = String::from_utf8_lossy(v);
+			info!("exec sql in ck: {:?}", redact_sql(&sql));
 		};
 		let start = std::time::Instant::now();
 		let res = next.run(req, extensions).await;
@@ -236,41 +728,193 @@ impl Middleware for LoggingMiddlware {
 	}
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
-pub enum LabelType {
-	Raw(String),
-	ServiceName,
-	Level,
-	ResourceAttr(String),
-	LogAttr(String),
-	TraceId,
-}
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use pretty_assertions::assert_eq;
+	use serde_json::json;
+	use std::collections::BTreeMap;
 
-impl From<LabelType> for String {
-	fn from(l: LabelType) -> Self {
-		match l {
-			LabelType::Raw(s) => s,
-			LabelType::ServiceName => "ServiceName".to_string(),
-			LabelType::Level => "SeverityText".to_string(),
-			LabelType::ResourceAttr(s) => format!("{}{}", RESOURCES_PREFIX, s),
-			LabelType::LogAttr(s) => format!("{}{}", ATTRIBUTES_PREFIX, s),
-			LabelType::TraceId => "trace_id".to_string(),
-		}
+	#[test]
+	fn test_successful() {
+		let body = successful(StatusCode::OK, "[]".to_string()).unwrap();
+		assert_eq!(body, "[]");
+		let err = successful(
+			StatusCode::INTERNAL_SERVER_ERROR,
+			"Code: 60. Unknown table".to_string(),
+		)
+		.unwrap_err();
+		assert_eq!(
+			err.to_string(),
+			"ck responds 500 Internal Server Error: Code: 60. Unknown table"
+		);
 	}
-}
 
-impl From<&str> for LabelType {
-	fn from(s: &str) -> Self {
-		if s.starts_with("resource_") {
-			LabelType::ResourceAttr(s.to_string())
-		} else if s.starts_with("log_") {
-			LabelType::LogAttr(s.to_string())
-		} else if s.to_uppercase().eq("SERVICENAME") {
-			LabelType::ServiceName
-		} else if s.to_uppercase().eq("SEVERITYTEXT") {
-			LabelType::Level
-		} else {
-			LabelType::Raw(s.to_string())
+	#[tokio::test]
+	async fn test_hedged() {
+		let ms = Duration::from_millis;
+		let answer = |after, v: Result<&'static str>| async move {
+			tokio::time::sleep(after).await;
+			v
+		};
+		// fast enough, the second query is never sent
+		let got = hedged(answer(ms(0), Ok("first")), ms(50), || async {
+			unreachable!()
+		})
+		.await;
+		assert_eq!(got.unwrap(), "first");
+		let got = hedged(answer(ms(500), Ok("first")), ms(10), || {
+			answer(ms(0), Ok("second"))
+		})
+		.await;
+		assert_eq!(got.unwrap(), "second");
+		// a failing hedge doesn't hide the slow answer
+		let got = hedged(answer(ms(50), Ok("first")), ms(10), || {
+			answer(ms(0), Err(anyhow::anyhow!("replica down")))
+		})
+		.await;
+		assert_eq!(got.unwrap(), "first");
+	}
+
+	#[test]
+	fn test_text() {
+		let row: Vec<Text> =
+			serde_json::from_str(r#"["plain","esc\"aped",1,null,[1],{"a":1}]"#)
+				.unwrap();
+		assert!(matches!(row[0].0, Cow::Borrowed("plain")));
+		assert!(matches!(row[1].0, Cow::Owned(_)));
+		let got: Vec<&str> = row.iter().map(|t| &**t).collect();
+		assert_eq!(got, vec!["plain", "esc\"aped", "", "", "", ""]);
+	}
+
+	#[test]
+	fn test_to_start_interval() {
+		let no_range = TimeRange {
+			start: None,
+			end: None,
+		};
+		let interval = |step| to_start_interval(step, &no_range);
+		assert_eq!(
+			interval(Duration::from_millis(250)),
+			"toStartOfInterval(Timestamp, INTERVAL 250 MILLISECOND, 'UTC') as Tts"
+		);
+		assert_eq!(
+			interval(Duration::from_secs(1)),
+			"toStartOfInterval(Timestamp, INTERVAL 1 SECOND, 'UTC') as Tts"
+		);
+		assert_eq!(
+			interval(Duration::from_millis(1500)),
+			"toStartOfInterval(Timestamp, INTERVAL 1500 MILLISECOND, 'UTC') as Tts"
+		);
+		assert_eq!(
+			interval(Duration::from_secs(60)),
+			"toStartOfMinute(Timestamp, 'UTC') as Tts"
+		);
+		assert_eq!(
+			interval(Duration::from_secs(20)),
+			"toStartOfInterval(Timestamp, INTERVAL 30 SECOND, 'UTC') as Tts"
+		);
+		// a day in 1s steps is too many buckets, 10s ones are the
+		// smallest that keep it under the limit
+		let day = TimeRange {
+			start: Some(DateTime::UNIX_EPOCH.naive_utc()),
+			end: DateTime::from_timestamp(86400, 0).map(|t| t.naive_utc()),
+		};
+		assert_eq!(
+			to_start_interval(Duration::from_secs(1), &day),
+			"toStartOfInterval(Timestamp, INTERVAL 10 SECOND, 'UTC') as Tts"
+		);
+	}
+
+	#[test]
+	fn test_attrs() {
+		let flat = |j: &str| {
+			let mut v: Vec<_> = serde_json::from_str::<Attrs>(j)
+				.unwrap()
+				.0
+				.into_iter()
+				.collect();
+			v.sort();
+			v
+		};
+		let pair = |k: &str, v: &str| (k.to_string(), v.to_string());
+		assert_eq!(
+			flat(r#"{"service.name":"api"}"#),
+			[pair("service.name", "api")]
+		);
+		// what a JSON column looks like
+		assert_eq!(
+			flat(
+				r#"{"http":{"status_code":200,"ok":true},"tags":["a"],"x":null}"#
+			),
+			[
+				pair("http.ok", "true"),
+				pair("http.status_code", "200"),
+				pair("tags", r#"["a"]"#)
+			]
+		);
+		assert_eq!(flat("null"), []);
+		let nested = serde_json::from_str(r#"{"k8s":{"pod":{"name":"p"}}}"#);
+		assert_eq!(
+			flatten_attrs(nested.unwrap()),
+			HashMap::from([("k8s.pod.name".to_string(), json!("p"))])
+		);
+	}
+
+	#[test]
+	fn test_parse_timestamp() {
+		for ts in [
+			"1700000000.123000000",
+			"1700000000.123",
+			"1700000000.123000",
+		] {
+			let v = parse_timestamp_try_best(ts).unwrap();
+			assert_eq!(v.timestamp(), 1700000000);
+			assert_eq!(v.timestamp_subsec_millis(), 123);
 		}
+		let v = parse_timestamp_try_best("1700000000").unwrap();
+		assert_eq!(v.timestamp_subsec_nanos(), 0);
+		assert!(parse_timestamp_try_best("").is_err());
+		assert!(parse_timestamp_try_best("2023-11-14").is_err());
+	}
+
+	#[test]
+	fn test_settings() {
+		let cfg = Clickhouse {
+			database: "otel".to_string(),
+			clickhouse_settings: BTreeMap::from([
+				("max_threads".to_string(), 4.into()),
+				("log_comment".to_string(), "ltbridge".into()),
+			]),
+			use_final: true,
+			..Default::default()
+		};
+		assert_eq!(
+			settings_clause(&cfg),
+			" SETTINGS log_comment = 'ltbridge', max_threads = 4"
+		);
+		assert_eq!(full_table_name(&cfg, "logs"), "otel.logs FINAL");
+		assert_eq!(qualified_name(&cfg, "logs"), "otel.logs");
+	}
+
+	#[test]
+	fn test_s3_table_function() {
+		let mut s3 = S3Archive {
+			url: "https://b.s3.amazonaws.com/logs/*.parquet".to_string(),
+			access_key_id: None,
+			secret_access_key: None,
+			format: "Parquet".to_string(),
+		};
+		assert_eq!(
+			s3_table_function(&s3),
+			"s3('https://b.s3.amazonaws.com/logs/*.parquet', 'Parquet')"
+		);
+		s3.access_key_id = Some("ak".to_string());
+		s3.secret_access_key = Some("s'k".to_string());
+		assert_eq!(
+			s3_table_function(&s3),
+			"s3('https://b.s3.amazonaws.com/logs/*.parquet', 'ak', 's\\'k', \
+			 'Parquet')"
+		);
 	}
 }
@@ -1,12 +1,24 @@
+use super::schema::AttrColumn;
+use crate::config::NonAsciiFilter;
+use crate::storage::timelit::{ck_datetime, ck_datetime64_at};
 use chrono::NaiveDateTime;
+use common::{level::SeverityRange, LogLevel};
 use itertools::Itertools as _;
 use sqlbuilder::builder::*;
 
+// the otel exporter fills it even when SeverityText is left empty
+pub(super) const SEVERITY_NUMBER: &str = "SeverityNumber";
+
 #[derive(Clone)]
 pub struct CKLogConverter<T: TableSchema> {
 	table: T,
 	replace_dash_to_dot: bool,
 	level_insenstive: bool,
+	ts_time: Option<&'static str>,
+	attrs: AttrColumn,
+	// the configured severity ranges, when levels are matched by number
+	level_number: Option<Vec<SeverityRange>>,
+	non_ascii: NonAsciiFilter,
 }
 
 impl<T: TableSchema> CKLogConverter<T> {
@@ -19,8 +31,34 @@ impl<T: TableSchema> CKLogConverter<T> {
 			table,
 			replace_dash_to_dot,
 			level_insenstive,
+			ts_time: None,
+			attrs: AttrColumn::Map,
+			level_number: None,
+			non_ascii: NonAsciiFilter::Position,
 		}
 	}
+	// also bound the second precision timestamp column of the table
+	pub fn with_ts_time(mut self, key: Option<&'static str>) -> Self {
+		self.ts_time = key;
+		self
+	}
+	pub fn with_attrs(mut self, attrs: AttrColumn) -> Self {
+		self.attrs = attrs;
+		self
+	}
+	// level = x and level != x become SeverityNumber ranges, the
+	// configured ones before the otel ones
+	pub fn with_level_number(
+		mut self,
+		ranges: Option<Vec<SeverityRange>>,
+	) -> Self {
+		self.level_number = ranges;
+		self
+	}
+	pub fn with_non_ascii_filter(mut self, f: NonAsciiFilter) -> Self {
+		self.non_ascii = f;
+		self
+	}
 }
 
 impl<T: TableSchema> QueryConverter for CKLogConverter<T> {
@@ -43,16 +81,8 @@ impl<T: TableSchema> QueryConverter for CKLogConverter<T> {
 			Cmp::RegexNotMatch(v) => {
 				format!("NOT match({}, '{}')", col_name, v)
 			}
-			Cmp::Contains(v) => v
-				.split(' ')
-				.map(|s| format!("hasToken({}, '{}')", col_name, s))
-				.collect_vec()
-				.join(" AND "),
-			Cmp::NotContains(v) => v
-				.split(' ')
-				.map(|s| format!("NOT hasToken({}, '{}')", col_name, s))
-				.collect_vec()
-				.join(" AND "),
+			Cmp::Contains(v) => self.contains(&col_name, v, false),
+			Cmp::NotContains(v) => self.contains(&col_name, v, true),
 		}
 	}
 	fn convert_timing(
@@ -61,20 +91,43 @@ impl<T: TableSchema> QueryConverter for CKLogConverter<T> {
 		o: &OrdType,
 		t: &NaiveDateTime,
 	) -> String {
-		let ts = t.and_utc().timestamp();
-		match o {
-			OrdType::LargerEqual => {
-				format!("{}>=toDateTime64({}, 9)", ts_key, ts)
-			}
-			OrdType::SmallerEqual => {
-				format!("{}<=toDateTime64({}, 9)", ts_key, ts)
-			}
+		let secs = t.and_utc().timestamp();
+		let ts = ck_datetime64_at(t);
+		let op = match o {
+			OrdType::LargerEqual => ">=",
+			OrdType::SmallerEqual => "<=",
+		};
+		match self.ts_time {
+			Some(k) => format!(
+				"{}{}{} AND {}{}{}",
+				k,
+				op,
+				ck_datetime(secs),
+				ts_key,
+				op,
+				ts
+			),
+			None => format!("{}{}{}", ts_key, op, ts),
 		}
 	}
 }
 
 impl<T: TableSchema> CKLogConverter<T> {
+	fn contains(&self, col: &str, v: &str, negated: bool) -> String {
+		let f = match self.non_ascii {
+			_ if v.is_ascii() => contains,
+			NonAsciiFilter::Token => contains,
+			NonAsciiFilter::Position => position,
+			NonAsciiFilter::Like => like,
+		};
+		f(col, v, negated)
+	}
 	fn convert_level(&self, cmp: &Cmp) -> Option<String> {
+		if let Some(ranges) = &self.level_number {
+			if let Some(s) = level_number_range(cmp, ranges) {
+				return Some(s);
+			}
+		}
 		let insensitive = self.level_insenstive;
 		let key = self.table.level_key();
 		match cmp {
@@ -99,7 +152,7 @@ impl<T: TableSchema> CKLogConverter<T> {
 			_ => None,
 		}
 	}
-	fn column_name(&self, c: &Column) -> String {
+	pub(crate) fn column_name(&self, c: &Column) -> String {
 		match c {
 			Column::Message => self.table.msg_key().to_string(),
 			Column::Timestamp => self.table.ts_key().to_string(),
@@ -107,27 +160,117 @@ impl<T: TableSchema> CKLogConverter<T> {
 			Column::TraceID => self.table.trace_key().to_string(),
 			Column::Resources(s) => {
 				if self.replace_dash_to_dot {
-					format!(
-						"{}['{}']",
-						self.table.resources_key(),
-						s.replace("_", ".")
-					)
+					self.attrs
+						.get(self.table.resources_key(), &s.replace("_", "."))
 				} else {
-					format!("{}['{}']", self.table.resources_key(), s)
+					self.attrs.get(self.table.resources_key(), s)
 				}
 			}
 			Column::Attributes(s) => {
 				if self.replace_dash_to_dot {
-					format!("{}['{}']", self.table.attributes_key(), s)
+					self.attrs.get(self.table.attributes_key(), s)
 				} else {
-					format!(
-						"{}['{}']",
-						self.table.attributes_key(),
-						s.replace("_", ".")
-					)
+					self.attrs
+						.get(self.table.attributes_key(), &s.replace("_", "."))
 				}
 			}
 			Column::Raw(s) => s.clone(),
+			Column::BodyJson(path) => {
+				let keys = path
+					.split('.')
+					.map(|k| format!("'{}'", escape_str(k)))
+					.collect_vec()
+					.join(",");
+				format!("JSONExtractString({},{})", self.table.msg_key(), keys)
+			}
 		}
 	}
 }
+
+// only exact level names are turned into ranges, anything else
+// (warning, regexes) still compares the text. Like LevelMapping, a
+// number is the level of the configured range it's in, and of its otel
+// range only when no configured range has it
+fn level_number_range(
+	cmp: &Cmp,
+	configured: &[SeverityRange],
+) -> Option<String> {
+	let (v, not) = match cmp {
+		Cmp::Equal(PlaceValue::String(v)) => (v, ""),
+		Cmp::NotEqual(PlaceValue::String(v)) => (v, "NOT "),
+		_ => return None,
+	};
+	let level = LogLevel::try_from(v.as_str())
+		.ok()
+		.filter(|l| String::from(*l).eq_ignore_ascii_case(v))?;
+	let r = level.severity_range();
+	let between = |from, to, not| {
+		format!("{} {}BETWEEN {} AND {}", SEVERITY_NUMBER, not, from, to)
+	};
+	let taken = configured
+		.iter()
+		.filter(|c| c.from <= *r.end() && c.to >= *r.start())
+		.map(|c| between(c.from, c.to, "NOT "))
+		.collect_vec();
+	let own = configured
+		.iter()
+		.filter(|c| c.level == level)
+		.map(|c| between(c.from, c.to, ""))
+		.collect_vec();
+	if taken.is_empty() && own.is_empty() {
+		return Some(between(*r.start(), *r.end(), not));
+	}
+	let otel = std::iter::once(between(*r.start(), *r.end(), ""))
+		.chain(taken)
+		.join(" AND ");
+	let cond = own
+		.into_iter()
+		.chain(std::iter::once(otel))
+		.map(|c| format!("({})", c))
+		.join(" OR ");
+	Some(format!("{}({})", not, cond))
+}
+
+// what hasToken accepts as a token, anything else is a separator and
+// makes it throw
+fn is_token(s: &str) -> bool {
+	!s.is_empty() && s.bytes().all(|b| b.is_ascii_alphanumeric() || b >= 0x80)
+}
+
+// a line filter is a substring match, which position() decides. A word
+// inside a phrase is a whole token of every line containing it, so
+// hasToken on those lets the token bloom filter skip granules first. The
+// first and last words may be part of longer tokens, e.g. "timeouts"
+fn contains(col: &str, v: &str, negated: bool) -> String {
+	let words = v.split(' ').collect_vec();
+	let inner = match words.len() {
+		n if n > 2 => &words[1..n - 1],
+		_ => &[],
+	};
+	let tokens = inner
+		.iter()
+		.filter(|w| is_token(w))
+		.map(|w| format!("hasToken({}, '{}')", col, escape_str(w)))
+		.collect_vec();
+	// a line without the token may still not contain the phrase
+	if negated || tokens.is_empty() {
+		return position(col, v, negated);
+	}
+	format!("{} AND {}", tokens.join(" AND "), position(col, v, negated))
+}
+
+fn position(col: &str, v: &str, negated: bool) -> String {
+	let op = if negated { "=" } else { ">" };
+	format!("position({}, '{}') {} 0", col, escape_str(v), op)
+}
+
+fn like(col: &str, v: &str, negated: bool) -> String {
+	let not = if negated { "NOT " } else { "" };
+	// LIKE escapes its wildcards and backslash, the literal then escapes
+	// those backslashes and the quotes once more
+	let v = v
+		.replace('\\', "\\\\")
+		.replace('%', "\\%")
+		.replace('_', "\\_");
+	format!("{} {}LIKE '%{}%'", col, not, escape_str(&v))
+}
@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS {database}.{rollup_table} (
+	Timestamp DateTime CODEC(Delta, ZSTD(1)),
+	ServiceName LowCardinality(String) CODEC(ZSTD(1)),
+	SeverityText LowCardinality(String) CODEC(ZSTD(1)),
+	Count UInt64 CODEC(ZSTD(1))
+) ENGINE = SummingMergeTree(Count)
+PARTITION BY toDate(Timestamp)
+ORDER BY (ServiceName, SeverityText, Timestamp)
+SETTINGS index_granularity = 8192
@@ -0,0 +1,9 @@
+CREATE MATERIALIZED VIEW IF NOT EXISTS {database}.{rollup_table}_mv
+TO {database}.{rollup_table}
+AS SELECT
+	toStartOfMinute(Timestamp) AS Timestamp,
+	ServiceName,
+	SeverityText,
+	count() AS Count
+FROM {database}.{table}
+GROUP BY Timestamp, ServiceName, SeverityText
@@ -0,0 +1,9 @@
+CREATE MATERIALIZED VIEW IF NOT EXISTS {database}.{trace_ts_table}_mv
+TO {database}.{trace_ts_table}
+AS SELECT
+	TraceId,
+	min(Timestamp) AS Start,
+	max(Timestamp) AS End
+FROM {database}.{table}
+WHERE TraceId != ''
+GROUP BY TraceId
@@ -1,51 +1,73 @@
-use super::{common::*, converter::CKLogConverter, labels::SeriesStore};
-use crate::config::ClickhouseLog;
+use super::{
+	common::*,
+	converter::{CKLogConverter, SEVERITY_NUMBER},
+	schema::{preset, Preset},
+	value_index::{self, ValueBlooms},
+};
+use crate::config::{ClickhouseLog, LabelDiscovery, ValueIndex};
+use crate::storage::labels::{
+	KeyFilter, LabelSet, LabelType, SeriesStore, Snapshot,
+};
 use crate::storage::{log::*, *};
 use async_trait::async_trait;
-use chrono::DateTime;
-use common::LogLevel;
-use logql::parser::{LogQuery, MetricQuery};
+use chrono::{DateTime, TimeDelta, Utc};
+use common::{level::LevelMapping, LogLevel, TimeRange};
+use logql::parser::{LabelPair, LogQuery, MetricQuery, Operator};
 use reqwest::Client;
+use serde::Deserialize;
 use serde_json::Value as JSONValue;
 use sqlbuilder::{
-	builder::{time_range_into_timing, QueryConverter, QueryPlan, TableSchema},
-	visit::{DefaultIRVisitor, LogQLVisitor},
+	builder::{
+		escape_str, time_range_into_timing, Column, QueryConverter, QueryPlan,
+		TableSchema,
+	},
+	partition::TableTemplate,
+	visit::{label_column, DefaultIRVisitor, IRVisitor, LogQLVisitor},
 };
 use std::{
-	collections::{HashMap, HashSet},
-	sync::OnceLock,
+	collections::{BTreeMap, HashMap, HashSet},
+	sync::{Arc, RwLock},
+	time::Duration,
 };
 use tokio::sync::mpsc::Sender;
-use tracing::error;
+use tracing::{error, info, warn};
 
 const TRACE_ID_NAME: &str = "trace_id";
 
-static DEFAULT_LEVEL: OnceLock<String> = OnceLock::new();
-
 #[derive(Clone)]
 pub struct CKLogQuerier {
 	cli: Client,
 	schema: LogTable,
 	ck_cfg: ClickhouseLog,
 	meta: SeriesStore,
-	tx: Sender<(LabelType, String)>,
+	tx: Sender<LabelSet>,
+	// attribute keys found by label discovery
+	discovered: Arc<RwLock<DiscoveredKeys>>,
+	values: ValueBlooms,
+}
+
+#[derive(Debug, Default)]
+struct DiscoveredKeys {
+	resources: Vec<String>,
+	attributes: Vec<String>,
 }
 
 impl CKLogQuerier {
 	pub fn new(cli: Client, table: String, ck_cfg: ClickhouseLog) -> Self {
-		let lvl = ck_cfg.default_log_level.clone();
-		_ = DEFAULT_LEVEL.set(lvl);
-		let (meta, tx) = SeriesStore::new();
+		let retention = ck_cfg.label.discovery.as_ref().map(|d| d.lookback);
+		let (meta, tx) = SeriesStore::new(ck_cfg.label.max_values, retention);
+		let from = match &ck_cfg.s3 {
+			Some(s3) => s3_table_function(s3),
+			None => full_table_name(&ck_cfg.common, &table),
+		};
 		Self {
 			cli,
-			// since we use http, we should use the full table name(database.table)
-			schema: LogTable::new(format!(
-				"{}.{}",
-				ck_cfg.common.database, table
-			)),
+			schema: LogTable::new(from, preset(ck_cfg.common.schema_version)),
 			ck_cfg,
 			meta,
 			tx,
+			discovered: Arc::default(),
+			values: ValueBlooms::default(),
 		}
 	}
 	fn new_converter(&self) -> CKLogConverter<LogTable> {
@@ -54,6 +76,15 @@ impl CKLogQuerier {
 			self.ck_cfg.replace_dash_to_dot.unwrap_or(false),
 			!self.ck_cfg.level_case_sensitive.unwrap_or(false),
 		)
+		.with_ts_time(self.schema.preset.log_ts_time)
+		.with_attrs(self.schema.preset.attrs)
+		.with_level_number(
+			self.ck_cfg
+				.level_by_number
+				.unwrap_or(false)
+				.then(|| self.ck_cfg.levels.numbers.clone()),
+		)
+		.with_non_ascii_filter(self.ck_cfg.non_ascii_filter)
 	}
 }
 
@@ -64,22 +95,33 @@ impl LogStorage for CKLogQuerier {
 		q: &LogQuery,
 		opt: QueryLimits,
 	) -> Result<Vec<LogItem>> {
-		let sql = logql_to_sql(q, opt, &self.schema, self.new_converter());
-		let mut results = vec![];
-		let rows =
-			send_query(self.cli.clone(), self.ck_cfg.common.clone(), sql)
-				.await
-				.map_err(|e| {
-					error!("Query log error: {:?}", e);
-					e
-				})?;
-		for row in rows {
-			let record = LogRecod::try_from(row).map_err(|e| {
-				error!("Convert log record error: {:?}", e);
-				e
-			})?;
-			results.push(record.into());
+		if self.values.surely_empty(q, &opt.range) {
+			return Ok(vec![]);
 		}
+		let limit = opt.limit;
+		let tables = self.read_tables(&opt.range).await?;
+		let sql =
+			logql_to_sql(q, opt, &self.schema, self.new_converter(), tables);
+		let text = query_text(
+			self.cli.clone(),
+			self.ck_cfg.common.clone(),
+			sql,
+			limit,
+		)
+		.await
+		.map_err(|e| {
+			error!("Query log error: {:?}", e);
+			e
+		})?;
+		let results = decode_logs(
+			&text,
+			&self.ck_cfg.levels,
+			&self.ck_cfg.default_log_level,
+		)
+		.map_err(|e| {
+			error!("Convert log record error: {:?}", e);
+			e
+		})?;
 		self.record_label(&results).await;
 		Ok(results)
 	}
@@ -88,50 +130,108 @@ impl LogStorage for CKLogQuerier {
 		q: &MetricQuery,
 		opt: QueryLimits,
 	) -> Result<Vec<MetricItem>> {
-		let sql = new_from_metricquery(
-			q,
-			opt,
-			self.schema.clone(),
-			self.new_converter(),
-		);
-		let mut results = vec![];
-		let rows =
-			send_query(self.cli.clone(), self.ck_cfg.common.clone(), sql)
-				.await?;
+		if self.values.surely_empty(&q.log_query, &opt.range) {
+			return Ok(vec![]);
+		}
+		let step = opt.step.unwrap_or(DEFAULT_STEP);
+		let rollup = self.rollup_table(q, step);
+		// the rollup only keeps the text of the level
+		let level_number = rollup.is_none();
+		let sql = match rollup {
+			Some(rollup) => {
+				let converter = CKLogConverter::new(
+					rollup.clone(),
+					self.ck_cfg.replace_dash_to_dot.unwrap_or(false),
+					!self.ck_cfg.level_case_sensitive.unwrap_or(false),
+				);
+				new_from_metricquery(
+					q,
+					opt,
+					rollup,
+					converter,
+					"sum(Count)",
+					false,
+					vec![],
+				)
+			}
+			None => {
+				let tables = self.read_tables(&opt.range).await?;
+				new_from_metricquery(
+					q,
+					opt,
+					self.schema.clone(),
+					self.new_converter(),
+					"count(*)",
+					true,
+					tables,
+				)
+			}
+		};
+		// a series cut at max_result_rows would be drawn as a drop to zero,
+		// so going over the limit is an error here
+		let mut cfg = self.ck_cfg.common.clone();
+		cfg.clickhouse_settings
+			.insert(OVERFLOW_MODE.to_string(), "throw".into());
+		let levels = &self.ck_cfg.levels;
+		let mut results: BTreeMap<_, u64> = BTreeMap::new();
+		let rows = send_query(self.cli.clone(), cfg, sql, None).await?;
 		for row in rows {
 			let record = MetricRecord::try_from(row)?;
-			results.push(record.into());
+			let item = record.into_item(&q.agg_by, levels, level_number);
+			// several severities may map to the same level
+			*results.entry((item.labels, item.ts)).or_default() += item.total;
 		}
-		Ok(results)
+		Ok(results
+			.into_iter()
+			.map(|((labels, ts), total)| MetricItem { labels, total, ts })
+			.collect())
 	}
-	async fn labels(&self, _: QueryLimits) -> Result<Vec<String>> {
-		let mut arr: Vec<String> =
-			self.meta.labels().into_iter().map(Into::into).collect();
+	async fn labels(&self, opt: QueryLimits) -> Result<Vec<String>> {
+		let mut arr: Vec<String> = self
+			.meta
+			.labels(opt.range.start)
+			.into_iter()
+			.map(Into::into)
+			.collect();
 		arr.push(TRACE_ID_NAME.to_string());
 		Ok(arr)
 	}
 	async fn label_values(
 		&self,
 		label: &str,
-		_: QueryLimits,
+		filter: Option<&ValueFilter>,
+		opt: QueryLimits,
 	) -> Result<Vec<String>> {
 		if matches!(label.to_lowercase().as_str(), TRACE_ID_NAME | "traceid") {
 			return Ok(vec!["your_trace_id".to_string()]);
 		}
-		if let Some(v) = self.meta.get(&label.into()) {
-			Ok(v)
-		} else {
-			Ok(vec![])
-		}
+		Ok(self
+			.meta
+			.get(&label.into(), filter, opt.range.start)
+			.unwrap_or_default())
+	}
+	fn high_cardinality_labels(&self) -> Vec<String> {
+		self.meta
+			.high_cardinality()
+			.into_iter()
+			.map(Into::into)
+			.collect()
+	}
+	fn series_snapshot(&self, label: Option<&str>) -> Option<Snapshot> {
+		Some(self.meta.snapshot(label))
+	}
+	fn restore_series(&self, snapshot: Snapshot) -> Result<()> {
+		self.meta.restore(snapshot);
+		Ok(())
 	}
 	async fn series(
 		&self,
 		_match: Option<LogQuery>,
-		_opt: QueryLimits,
+		opt: QueryLimits,
 	) -> Result<Vec<HashMap<String, String>>> {
 		Ok(self
 			.meta
-			.series()
+			.series(opt.range.start)
 			.into_iter()
 			.map(|v| {
 				v.into_iter()
@@ -144,9 +244,110 @@ impl LogStorage for CKLogQuerier {
 			})
 			.collect())
 	}
+	// the mutation runs in the background, ck answers once it's queued;
+	// a rollup table keeps the counts of the deleted lines
+	async fn delete(&self, q: &LogQuery, range: TimeRange) -> Result<()> {
+		if self.ck_cfg.s3.is_some() {
+			anyhow::bail!("logs archived in s3 are read only");
+		}
+		let cfg = &self.ck_cfg.common;
+		let mut tables = self.partitions(&range).await?;
+		if tables.is_empty() {
+			tables.push(cfg.table.clone());
+		}
+		for table in tables {
+			let table = qualified_name(cfg, &table);
+			let sql = delete_sql(
+				q,
+				&range,
+				&table,
+				&self.schema,
+				self.new_converter(),
+			);
+			exec(self.cli.clone(), cfg.clone(), sql).await?;
+		}
+		Ok(())
+	}
+	fn can_delete(&self) -> bool {
+		self.ck_cfg.s3.is_none()
+	}
+}
+
+fn existing_tables_sql(database: &str, tables: &[String]) -> String {
+	let quote = |v: &str| format!("'{}'", escape_str(v));
+	let names: Vec<String> = tables.iter().map(|t| quote(t)).collect();
+	format!(
+		"SELECT name FROM system.tables WHERE database = {} AND name IN ({})",
+		quote(database),
+		names.join(", ")
+	)
+}
+
+// delete_sql filters with the same conditions a query of q would use
+fn delete_sql(
+	q: &LogQuery,
+	range: &TimeRange,
+	table: &str,
+	schema: &LogTable,
+	converter: impl QueryConverter,
+) -> String {
+	let v = LogQLVisitor::new(DefaultIRVisitor {});
+	let qp = QueryPlan::new(
+		converter,
+		schema.clone(),
+		vec![],
+		v.visit(q),
+		vec![],
+		vec![],
+		time_range_into_timing(range),
+		None,
+	);
+	format!("ALTER TABLE {} DELETE WHERE {}", table, qp.where_sql())
 }
 
 impl CKLogQuerier {
+	// partitions lists the tables a range reads from, the ones not created
+	// yet or already dropped are left out. None means the configured
+	// table, which is also what an open start falls back to
+	async fn partitions(&self, range: &TimeRange) -> Result<Vec<String>> {
+		let (Some(p), None, Some(start)) =
+			(&self.ck_cfg.partitions, &self.ck_cfg.s3, range.start)
+		else {
+			return Ok(vec![]);
+		};
+		let end = range.end.unwrap_or_else(|| Utc::now().naive_utc());
+		let template = TableTemplate::new(p.template.as_str());
+		let tables: Vec<String> =
+			template.tables(start, end).take(p.max_tables + 1).collect();
+		if tables.len() > p.max_tables {
+			anyhow::bail!(
+				"the range spans more than {} tables, narrow it down",
+				p.max_tables
+			);
+		}
+		self.existing(tables).await
+	}
+	async fn read_tables(&self, range: &TimeRange) -> Result<Vec<String>> {
+		let cfg = &self.ck_cfg.common;
+		Ok(self
+			.partitions(range)
+			.await?
+			.iter()
+			.map(|t| full_table_name(cfg, t))
+			.collect())
+	}
+	async fn existing(&self, tables: Vec<String>) -> Result<Vec<String>> {
+		let cfg = &self.ck_cfg.common;
+		let sql = existing_tables_sql(&cfg.database, &tables);
+		let rows = send_query(self.cli.clone(), cfg.clone(), sql, None).await?;
+		let found: HashSet<&str> =
+			rows.iter().filter_map(|r| r.first()?.as_str()).collect();
+		Ok(tables
+			.iter()
+			.filter(|t| found.contains(t.as_str()))
+			.cloned()
+			.collect())
+	}
 	pub async fn init_labels(&self) {
 		let sql = format!(
 			"SELECT {} FROM {} WHERE {} >= now() - INTERVAL 5 MINUTE LIMIT 3000",
@@ -154,160 +355,363 @@ impl CKLogQuerier {
 			self.schema.table(),
 			self.schema.ts_key(),
 		);
-		let rows =
-			send_query(self.cli.clone(), self.ck_cfg.common.clone(), sql)
+		let text =
+			query_text(self.cli.clone(), self.ck_cfg.common.clone(), sql, None)
 				.await
 				.unwrap_or_default();
-		let mut records = vec![];
-		for row in rows {
-			if let Ok(record) = LogRecod::try_from(row) {
-				records.push(record.into());
-			}
-		}
+		// rows that fail to convert are skipped, the rest still seed labels
+		let records: Vec<LogItem> = parse_rows::<LogRecod>(&text)
+			.unwrap_or_default()
+			.into_iter()
+			.filter_map(|r| r.try_into().ok())
+			.collect();
 		self.record_label(&records).await;
 	}
-	async fn record_label(&self, records: &[LogItem]) {
-		let cfg = self.ck_cfg.label.clone();
-		for name in Self::collect_svcname(records) {
-			let _ = self.tx.send((LabelType::ServiceName, name)).await;
-		}
-		for level in Self::collect_level(records) {
-			let _ = self.tx.send((LabelType::Level, level)).await;
-		}
-		if !cfg.resource_attributes.is_empty() {
-			for (k, vs) in Self::collect_attrs(
-				&records
-					.iter()
-					.map(|r| &r.resource_attributes)
-					.collect::<Vec<_>>(),
-				&cfg.resource_attributes,
-			) {
-				for v in vs {
-					let _ = self
-						.tx
-						.send((LabelType::ResourceAttr(k.clone()), v))
-						.await;
-				}
-			}
-		}
-		if !cfg.log_attributes.is_empty() {
-			for (k, vs) in Self::collect_attrs(
-				&records
-					.iter()
-					.map(|r| &r.log_attributes)
-					.collect::<Vec<_>>(),
-				&cfg.log_attributes,
-			) {
-				for v in vs {
-					let _ =
-						self.tx.send((LabelType::LogAttr(k.clone()), v)).await;
+	pub fn spawn_value_index(&self, cfg: ValueIndex) {
+		let q = self.clone();
+		tokio::spawn(async move {
+			let mut ticker = tokio::time::interval(cfg.refresh_interval);
+			loop {
+				ticker.tick().await;
+				if let Err(e) = q.refresh_value_index(&cfg).await {
+					warn!("fail to refresh label value index: {}", e);
 				}
 			}
+		});
+	}
+	// values first seen after a refresh are unknown until the next one,
+	// the snapshot only speaks for logs up to the time it was taken
+	async fn refresh_value_index(&self, cfg: &ValueIndex) -> Result<()> {
+		let to = Utc::now().naive_utc();
+		let from =
+			to - TimeDelta::from_std(cfg.lookback).unwrap_or(TimeDelta::zero());
+		let converter = self.new_converter();
+		let cols = cfg
+			.labels
+			.iter()
+			.map(|label| {
+				let c = DefaultIRVisitor {}.label_pair(&LabelPair {
+					label: label.clone(),
+					op: Operator::Equal,
+					value: String::new(),
+				});
+				format!("groupUniqArray({})", converter.column_name(&c.column))
+			})
+			.collect::<Vec<_>>();
+		let sql = format!(
+			"SELECT {} FROM {} WHERE {} >= {} AND {} <= {}",
+			cols.join(", "),
+			self.schema.table(),
+			self.schema.ts_key(),
+			timelit::ck_datetime64_at(&from),
+			self.schema.ts_key(),
+			timelit::ck_datetime64_at(&to),
+		);
+		let rows =
+			send_query(self.cli.clone(), self.ck_cfg.common.clone(), sql, None)
+				.await?;
+		let row = rows.into_iter().next().unwrap_or_default();
+		let values = cfg
+			.labels
+			.iter()
+			.enumerate()
+			.map(|(i, label)| {
+				let vals = row
+					.get(i)
+					.and_then(|v| v.as_array())
+					.map(|arr| {
+						arr.iter()
+							.filter_map(|v| v.as_str().map(str::to_string))
+							.collect()
+					})
+					.unwrap_or_default();
+				(label.clone(), vals)
+			})
+			.collect();
+		self.values
+			.replace(value_index::Snapshot::new(from, to, values));
+		Ok(())
+	}
+	// the rollup only has minute buckets of service and level
+	fn rollup_table(
+		&self,
+		q: &MetricQuery,
+		step: Duration,
+	) -> Option<LogTable> {
+		let rollup = self.ck_cfg.rollup.as_ref()?;
+		let min_step = rollup.min_step.max(Duration::from_secs(60));
+		if step < min_step || !rollup_covers(q) {
+			return None;
 		}
+		Some(LogTable::new(
+			format!("{}.{}", self.ck_cfg.common.database, rollup.table),
+			self.schema.preset,
+		))
 	}
-	fn collect_svcname(records: &[LogItem]) -> Vec<String> {
-		let set: HashSet<String> =
-			records.iter().map(|r| r.service_name.clone()).collect();
-		set.into_iter().collect()
-	}
-	fn collect_level(records: &[LogItem]) -> Vec<String> {
-		let set: HashSet<String> =
-			records.iter().map(|r| r.level.clone()).collect();
-		set.into_iter().collect()
-	}
-	fn collect_attrs(
-		records: &[&HashMap<String, String>],
-		want_keys: &[String],
-	) -> HashMap<String, Vec<String>> {
-		let mut m = HashMap::new();
-		for r in records {
-			for k in want_keys {
-				if let Some(v) = r.get(k) {
-					m.entry(k).or_insert(HashSet::new()).insert(v);
+	// spawn_discovery periodically looks up the attribute keys in the table,
+	// then resamples the labels with the keys that pass the filter
+	pub fn spawn_discovery(&self, d: LabelDiscovery) {
+		let q = self.clone();
+		tokio::spawn(async move {
+			let filter = KeyFilter::new(&d.include, &d.exclude);
+			let mut ticker = tokio::time::interval(d.interval);
+			loop {
+				ticker.tick().await;
+				if let Err(e) = q.discover_keys(d.lookback, &filter).await {
+					warn!("fail to discover label keys: {}", e);
+					continue;
 				}
+				q.init_labels().await;
 			}
+		});
+	}
+	async fn discover_keys(
+		&self,
+		lookback: Duration,
+		filter: &KeyFilter,
+	) -> Result<()> {
+		let sql = discovery_sql(&self.schema, lookback);
+		let rows =
+			send_query(self.cli.clone(), self.ck_cfg.common.clone(), sql, None)
+				.await?;
+		let row = rows.first();
+		let keys = |idx: usize| -> Vec<String> {
+			let mut keys: Vec<String> = row
+				.and_then(|r| r.get(idx))
+				.and_then(|v| v.as_array())
+				.map(|arr| {
+					arr.iter()
+						.filter_map(|k| k.as_str())
+						.filter(|k| filter.allows(k))
+						.map(str::to_string)
+						.collect()
+				})
+				.unwrap_or_default();
+			keys.sort();
+			keys
+		};
+		let found = DiscoveredKeys {
+			resources: keys(0),
+			attributes: keys(1),
+		};
+		info!(
+			"discovered {} resource keys and {} attribute keys",
+			found.resources.len(),
+			found.attributes.len()
+		);
+		*self.discovered.write().unwrap() = found;
+		Ok(())
+	}
+	// configured keys first, then the discovered ones not listed
+	fn label_keys(&self) -> (Vec<String>, Vec<String>) {
+		let cfg = &self.ck_cfg.label;
+		let found = self.discovered.read().unwrap();
+		let merge = |listed: &[String], found: &[String]| {
+			listed
+				.iter()
+				.chain(found.iter().filter(|k| !listed.contains(k)))
+				.cloned()
+				.collect::<Vec<_>>()
+		};
+		(
+			merge(&cfg.resource_attributes, &found.resources),
+			merge(&cfg.log_attributes, &found.attributes),
+		)
+	}
+	// every distinct label set goes to the series store, which folds
+	// them in the background
+	async fn record_label(&self, records: &[LogItem]) {
+		let (resource_attributes, log_attributes) = self.label_keys();
+		let sets: HashSet<LabelSet> = records
+			.iter()
+			.map(|r| label_set(r, &resource_attributes, &log_attributes))
+			.collect();
+		for set in sets {
+			let _ = self.tx.send(set).await;
 		}
-		m.into_iter()
-			.map(|(k, v)| {
-				(
-					k.clone(),
-					v.iter().cloned().cloned().collect::<Vec<String>>(),
-				)
-			})
-			.collect()
 	}
 }
 
+fn label_set(
+	r: &LogItem,
+	resource_keys: &[String],
+	log_keys: &[String],
+) -> LabelSet {
+	let attrs = |m: &HashMap<String, String>, keys: &[String]| {
+		keys.iter()
+			.filter_map(|k| m.get(k).map(|v| (k.clone(), v.clone())))
+			.collect::<Vec<_>>()
+	};
+	let mut set = vec![
+		(LabelType::ServiceName, r.service_name.clone()),
+		(LabelType::Level, r.level.clone()),
+	];
+	set.extend(
+		attrs(&r.resource_attributes, resource_keys)
+			.into_iter()
+			.map(|(k, v)| (LabelType::ResourceAttr(k), v)),
+	);
+	set.extend(
+		attrs(&r.log_attributes, log_keys)
+			.into_iter()
+			.map(|(k, v)| (LabelType::LogAttr(k), v)),
+	);
+	set.sort();
+	set
+}
+
+// a row is the bucket, the value of each group label and the count
 #[derive(Debug)]
 struct MetricRecord {
 	ts: i64,
-	severity_text: String,
+	labels: Vec<String>,
 	total: u64,
 }
 
 impl TryFrom<Vec<JSONValue>> for MetricRecord {
 	type Error = CKConvertErr;
 	fn try_from(
-		value: Vec<JSONValue>,
+		mut value: Vec<JSONValue>,
 	) -> std::result::Result<Self, Self::Error> {
-		if value.len() != 3 {
+		if value.len() < 2 {
 			return Err(CKConvertErr::Length);
 		}
+		let total = value.pop().unwrap_or_default();
 		let ts = value[0].as_str().ok_or(CKConvertErr::Timestamp)?;
 		let tts = parse_timestamp_try_best(ts)
 			.map_err(|_| CKConvertErr::Timestamp)?;
 
 		let record = Self {
 			ts: tts.timestamp_nanos_opt().ok_or(CKConvertErr::Timestamp)?,
-			severity_text: value[1].as_str().unwrap_or("").to_string(),
-			total: value[2].as_str().unwrap_or("0").parse().unwrap_or(0),
+			labels: value[1..]
+				.iter()
+				.map(|v| match v {
+					JSONValue::String(s) => s.clone(),
+					// SeverityNumber
+					JSONValue::Number(n) => n.to_string(),
+					_ => String::new(),
+				})
+				.collect(),
+			total: total.as_str().unwrap_or("0").parse().unwrap_or(0),
 		};
 		Ok(record)
 	}
 }
 
-impl From<MetricRecord> for MetricItem {
-	fn from(r: MetricRecord) -> Self {
-		Self {
-			level: LogLevel::try_from(r.severity_text)
-				.unwrap_or(LogLevel::Trace),
-			total: r.total,
-			ts: DateTime::from_timestamp_nanos(r.ts),
+impl MetricRecord {
+	// names are the group labels, in the order they were selected. With
+	// level_number the level is followed by its SeverityNumber
+	fn into_item(
+		self,
+		names: &[String],
+		levels: &LevelMapping,
+		level_number: bool,
+	) -> MetricItem {
+		let mut values = self.labels.into_iter();
+		let mut labels = BTreeMap::new();
+		for name in names {
+			let v = values.next().unwrap_or_default();
+			let v = match label_column(name) {
+				Column::Level => {
+					let number = match level_number {
+						true => values.next().and_then(|n| n.parse().ok()),
+						false => None,
+					};
+					levels
+						.level(&v, number.unwrap_or(0))
+						.unwrap_or(LogLevel::Trace)
+						.into()
+				}
+				_ => v,
+			};
+			labels.insert(name.clone(), v);
+		}
+		MetricItem {
+			labels,
+			total: self.total,
+			ts: DateTime::from_timestamp_nanos(self.ts),
 		}
 	}
 }
 
+// the rollup only has the service and level columns
+fn rollup_covers(q: &MetricQuery) -> bool {
+	let rollup_label = |label: &str| {
+		label == "ServiceName"
+			|| matches!(label.to_lowercase().as_str(), "level" | "severitytext")
+	};
+	q.log_query.filters.as_ref().map_or(true, Vec::is_empty)
+		&& q.log_query
+			.selector
+			.label_paris
+			.iter()
+			.all(|p| rollup_label(&p.label))
+		&& q.agg_by.iter().all(|l| rollup_label(l))
+}
+
+// total counts the rows of the raw table, or sums the rollup counts.
+// level_number groups the level by SeverityNumber too, the otel exporter
+// may leave SeverityText empty
 fn new_from_metricquery(
 	q: &MetricQuery,
 	limits: QueryLimits,
 	schema: LogTable,
-	converter: impl QueryConverter,
+	converter: CKLogConverter<LogTable>,
+	total: &str,
+	level_number: bool,
+	tables: Vec<String>,
 ) -> String {
 	let v = LogQLVisitor::new(DefaultIRVisitor {});
 	let selection = v.visit(&q.log_query);
 	let step = limits.step.unwrap_or(DEFAULT_STEP);
+	let mut groups: Vec<String> = vec![];
+	for l in &q.agg_by {
+		let column = label_column(l);
+		groups.push(converter.column_name(&column));
+		if level_number && matches!(column, Column::Level) {
+			groups.push(SEVERITY_NUMBER.to_string());
+		}
+	}
+	let mut projection = vec![to_start_interval(step, &limits.range)];
+	projection.extend(groups.iter().cloned());
+	projection.push(format!("{} as Total", total));
+	let mut grouping = groups;
+	grouping.push("Tts".to_string());
 	let qp = QueryPlan::new(
 		converter,
 		schema.clone(),
-		vec![
-			to_start_interval(step).to_string(),
-			"SeverityText".to_string(),
-			"count(*) as Total".to_string(),
-		],
+		projection,
 		selection,
-		vec!["SeverityText".to_string(), "Tts".to_string()],
+		grouping,
 		vec![],
 		time_range_into_timing(&limits.range),
-		limits.limit,
-	);
+		// aggregated rows are bounded by series * steps, not by limit
+		None,
+	)
+	.with_tables(tables);
 	qp.as_sql()
 }
 
+// at most 1000 keys per column, labels are meant to be low cardinality
+fn discovery_sql(schema: &LogTable, lookback: Duration) -> String {
+	format!(
+		"SELECT groupUniqArrayArray(1000)({}), \
+		 groupUniqArrayArray(1000)({}) \
+		 FROM {} WHERE {} >= now() - INTERVAL {} SECOND",
+		schema.preset.attrs.keys(schema.resources_key()),
+		schema.preset.attrs.keys(schema.attributes_key()),
+		schema.table(),
+		schema.ts_key(),
+		lookback.as_secs(),
+	)
+}
+
 fn logql_to_sql(
 	q: &LogQuery,
 	limits: QueryLimits,
 	schema: &LogTable,
 	converter: impl QueryConverter,
+	tables: Vec<String>,
 ) -> String {
 	let v = LogQLVisitor::new(DefaultIRVisitor {});
 	let selection = v.visit(q);
@@ -320,36 +724,53 @@ fn logql_to_sql(
 		direction_to_sorting(&limits.direction, schema),
 		time_range_into_timing(&limits.range),
 		limits.limit,
-	);
+	)
+	.with_tables(tables);
 	qp.as_sql()
 }
 
 #[derive(Debug, Clone)]
 pub(crate) struct LogTable {
 	table: String,
+	preset: &'static Preset,
 }
 
 impl LogTable {
-	pub fn new(name: String) -> Self {
-		Self { table: name }
+	pub fn new(name: String, preset: &'static Preset) -> Self {
+		Self {
+			table: name,
+			preset,
+		}
 	}
 	fn projection(&self) -> Vec<String> {
-		LOG_TABLE_COLS.iter().map(|s| s.to_string()).collect()
+		self.preset
+			.log_cols
+			.iter()
+			.map(|(c, _)| c.to_string())
+			.collect()
 	}
 }
 
-static LOG_TABLE_COLS: [&str; 11] = [
-	"Timestamp",
-	"TraceId",
-	"SpanId",
-	"SeverityText",
-	"SeverityNumber",
-	"ServiceName",
-	"Body",
-	"ResourceAttributes",
-	"ScopeName",
-	"ScopeAttributes",
-	"LogAttributes",
+// column and its type family, see schema::family
+pub(super) static LOG_TABLE_COLS: [(&str, &str); 11] = [
+	("Timestamp", "DateTime64"),
+	("TraceId", "String"),
+	("SpanId", "String"),
+	("SeverityText", "String"),
+	("SeverityNumber", "Int"),
+	("ServiceName", "String"),
+	("Body", "String"),
+	("ResourceAttributes", "Map"),
+	("ScopeName", "String"),
+	("ScopeAttributes", "Map"),
+	("LogAttributes", "Map"),
+];
+
+pub(super) static ROLLUP_TABLE_COLS: [(&str, &str); 4] = [
+	("Timestamp", "DateTime"),
+	("ServiceName", "String"),
+	("SeverityText", "String"),
+	("Count", "Int"),
 ];
 
 /*
@@ -365,71 +786,70 @@ static LOG_TABLE_COLS: [&str; 11] = [
 	`ScopeAttributes` Map(LowCardinality(String), String) CODEC(ZSTD(1)),
 	`LogAttributes` Map(LowCardinality(String), String) CODEC(ZSTD(1)),
 */
-#[derive(Debug, Clone)]
-struct LogRecod {
-	timestamp: i64,
-	trace_id: String,
-	span_id: String,
-	severity_text: String,
-	service_name: String,
-	body: String,
-	resource_attr: HashMap<String, String>,
-	scope_name: String,
-	scope_attributes: HashMap<String, String>,
-	log_attributes: HashMap<String, String>,
+// a row as it comes back, columns in the order of LOG_TABLE_COLS.
+// strings are borrowed from the response body when possible
+#[derive(Debug, Clone, Deserialize)]
+struct LogRecod<'a> {
+	#[serde(borrow)]
+	timestamp: Text<'a>,
+	#[serde(borrow)]
+	trace_id: Text<'a>,
+	#[serde(borrow)]
+	span_id: Text<'a>,
+	#[serde(borrow)]
+	severity_text: Text<'a>,
+	severity_number: i64,
+	#[serde(borrow)]
+	service_name: Text<'a>,
+	#[serde(borrow)]
+	body: Text<'a>,
+	resource_attr: Attrs,
+	#[serde(borrow)]
+	scope_name: Text<'a>,
+	scope_attributes: Attrs,
+	log_attributes: Attrs,
 }
 
-impl TryFrom<Vec<JSONValue>> for LogRecod {
-	type Error = CKConvertErr;
-	fn try_from(
-		value: Vec<JSONValue>,
-	) -> std::result::Result<Self, Self::Error> {
-		if value.len() != 11 {
-			return Err(CKConvertErr::Length);
-		}
-		let ts = value[0].as_str().ok_or(CKConvertErr::Timestamp)?;
-		let tts = parse_timestamp_try_best(ts)
+impl LogRecod<'_> {
+	// default_level is only used when none of the sources in
+	// levels.detect map to a level
+	fn into_item(
+		self,
+		levels: &LevelMapping,
+		default_level: &str,
+	) -> std::result::Result<LogItem, CKConvertErr> {
+		let ts = parse_timestamp_try_best(&self.timestamp)
 			.map_err(|_| CKConvertErr::Timestamp)?;
-		let record = Self {
-			timestamp: tts
-				.timestamp_nanos_opt()
-				.ok_or(CKConvertErr::Timestamp)?,
-			trace_id: value[1].as_str().unwrap_or("").to_string(),
-			span_id: value[2].as_str().unwrap_or("").to_string(),
-			severity_text: consistent_level(value[3].as_str().unwrap_or("")),
-			service_name: value[5].as_str().unwrap_or("").to_string(),
-			body: value[6].as_str().unwrap_or("").to_string(),
-			resource_attr: json_object_to_map_s_s(&value[7])?,
-			scope_name: value[8].as_str().unwrap_or("").to_string(),
-			scope_attributes: json_object_to_map_s_s(&value[9])?,
-			log_attributes: json_object_to_map_s_s(&value[10])?,
-		};
-		Ok(record)
-	}
-}
-
-fn consistent_level(lvl: &str) -> String {
-	match LogLevel::try_from(lvl) {
-		Ok(l) => l.into(),
-		Err(_) => DEFAULT_LEVEL.get().unwrap().clone(),
+		let number = u32::try_from(self.severity_number).unwrap_or(0);
+		Ok(LogItem {
+			ts,
+			trace_id: self.trace_id.into_string(),
+			span_id: self.span_id.into_string(),
+			level: levels
+				.detect(&self.severity_text, number, &self.body)
+				.map_or_else(|| default_level.to_string(), Into::into),
+			service_name: self.service_name.into_string(),
+			message: self.body.into_string(),
+			resource_attributes: self.resource_attr.0,
+			scope_name: self.scope_name.into_string(),
+			scope_attributes: self.scope_attributes.0,
+			log_attributes: self.log_attributes.0,
+			source: None,
+		})
 	}
 }
 
-impl From<LogRecod> for LogItem {
-	fn from(r: LogRecod) -> Self {
-		Self {
-			ts: DateTime::from_timestamp_nanos(r.timestamp),
-			trace_id: r.trace_id,
-			span_id: r.span_id,
-			level: r.severity_text,
-			service_name: r.service_name,
-			message: r.body,
-			resource_attributes: r.resource_attr,
-			scope_name: r.scope_name,
-			scope_attributes: r.scope_attributes,
-			log_attributes: r.log_attributes,
-		}
-	}
+/// decode_logs turns a JSONCompact response of LOG_TABLE_COLS into items,
+/// the levels are those of the source
+pub fn decode_logs(
+	text: &str,
+	levels: &LevelMapping,
+	default_level: &str,
+) -> Result<Vec<LogItem>> {
+	parse_rows::<LogRecod>(text)?
+		.into_iter()
+		.map(|r| r.into_item(levels, default_level).map_err(Into::into))
+		.collect()
 }
 
 impl TableSchema for LogTable {
@@ -462,19 +882,409 @@ impl TableSchema for LogTable {
 #[cfg(test)]
 mod tests {
 	use super::*;
+	use crate::config::NonAsciiFilter;
 	use anyhow::Result;
 	use pretty_assertions::assert_eq;
+	use sqlbuilder::builder::{Cmp, Condition, OrdType, PlaceValue};
 	#[test]
 	fn test_decode_log_resp() -> Result<()> {
 		// read json file from "./testdata/log.json"
 		use std::fs;
 		let v = fs::read_to_string("./testdata/ck/log_resp.json")?;
-		let resp: RecordWarpper = serde_json::from_str(&v)?;
-		assert_eq!(resp.data.len(), 1);
-		for d in resp.data {
-			let w: LogRecod = d.try_into()?;
+		let items = decode_logs(&v, &LevelMapping::default(), "info")?;
+		assert_eq!(items.len(), 1);
+		for w in items {
 			assert_eq!(w.trace_id, "2a4aa700ea743a8ffb5b1d1dde88fbe8");
+			assert_eq!(w.level, "DEBUG");
 		}
+		// each source decodes with its own mapping
+		let levels = LevelMapping {
+			names: HashMap::from([("debug".to_string(), LogLevel::Info)]),
+			..Default::default()
+		};
+		let items = decode_logs(&v, &levels, "info")?;
+		assert_eq!(items[0].level, "INFO");
 		Ok(())
 	}
+
+	#[test]
+	fn test_json_label_pushdown() {
+		let q = r#"{ServiceName="x"} | json | user_id="42" | req.id!="1""#;
+		let logql::parser::Query::LogQuery(q) =
+			logql::parser::parse_logql_query(q).unwrap()
+		else {
+			unreachable!()
+		};
+		let v = LogQLVisitor::new(DefaultIRVisitor {});
+		let schema = LogTable::new(
+			"logs".to_string(),
+			preset(crate::config::SchemaVersion::V0_90),
+		);
+		let qp = QueryPlan::new(
+			CKLogConverter::new(schema.clone(), false, false),
+			schema,
+			vec!["Body".to_string()],
+			v.visit(&q),
+			vec![],
+			vec![],
+			vec![],
+			None,
+		);
+		assert_eq!(
+			qp.as_sql(),
+			"SELECT Body FROM logs WHERE (ServiceName = 'x' AND \
+			 (JSONExtractString(Body,'user_id') = '42' AND \
+			 JSONExtractString(Body,'req','id') != '1'))"
+		);
+		let cond = CKLogConverter::new(
+			LogTable::new(
+				"logs".to_string(),
+				preset(crate::config::SchemaVersion::V0_90),
+			),
+			false,
+			false,
+		)
+		.convert_condition(&Condition {
+			column: Column::BodyJson(r"it's.a\b".to_string()),
+			cmp: Cmp::Equal(PlaceValue::String("1".to_string())),
+		});
+		assert_eq!(cond, r"JSONExtractString(Body,'it\'s','a\\b') = '1'");
+	}
+
+	#[test]
+	fn test_schema_version_timing() {
+		let schema = LogTable::new(
+			"logs".to_string(),
+			preset(crate::config::SchemaVersion::V0_100),
+		);
+		let converter = CKLogConverter::new(schema.clone(), false, false)
+			.with_ts_time(schema.preset.log_ts_time);
+		let t = DateTime::from_timestamp(1700000000, 0).unwrap().naive_utc();
+		assert_eq!(
+			converter.convert_timing("Timestamp", &OrdType::LargerEqual, &t),
+			"TimestampTime>=toDateTime(1700000000, 'UTC') AND \
+			 Timestamp>=toDateTime64(1700000000, 9, 'UTC')"
+		);
+	}
+
+	#[test]
+	fn test_contains_substring() {
+		let schema = LogTable::new(
+			"logs".to_string(),
+			preset(crate::config::SchemaVersion::V0_90),
+		);
+		let converter = CKLogConverter::new(schema, false, false);
+		let cond = |cmp| {
+			converter.convert_condition(&Condition {
+				column: Column::Message,
+				cmp,
+			})
+		};
+		// "timeouts" and "readtimeout" contain it too
+		assert_eq!(
+			cond(Cmp::Contains("timeout".to_string())),
+			"position(Body, 'timeout') > 0"
+		);
+		assert_eq!(
+			cond(Cmp::Contains("read timeout".to_string())),
+			"position(Body, 'read timeout') > 0"
+		);
+		assert_eq!(
+			cond(Cmp::Contains("dial tcp: i/o timeout".to_string())),
+			"hasToken(Body, 'tcp') AND \
+			 position(Body, 'dial tcp: i/o timeout') > 0"
+		);
+		assert_eq!(
+			cond(Cmp::Contains("status=500".to_string())),
+			"position(Body, 'status=500') > 0"
+		);
+		assert_eq!(
+			cond(Cmp::NotContains("read timeout".to_string())),
+			"position(Body, 'read timeout') = 0"
+		);
+		assert_eq!(
+			cond(Cmp::NotContains("read the timeout".to_string())),
+			"position(Body, 'read the timeout') = 0"
+		);
+		assert_eq!(
+			cond(Cmp::Contains(r"it's C:\tmp".to_string())),
+			r"position(Body, 'it\'s C:\\tmp') > 0"
+		);
+		assert_eq!(
+			cond(Cmp::Contains("x')=0--('".to_string())),
+			r"position(Body, 'x\')=0--(\'') > 0"
+		);
+	}
+
+	#[test]
+	fn test_contains_non_ascii() {
+		let schema = LogTable::new(
+			"logs".to_string(),
+			preset(crate::config::SchemaVersion::V0_90),
+		);
+		let cond = |f, cmp| {
+			CKLogConverter::new(schema.clone(), false, false)
+				.with_non_ascii_filter(f)
+				.convert_condition(&Condition {
+					column: Column::Message,
+					cmp,
+				})
+		};
+		let has = |v: &str| Cmp::Contains(v.to_string());
+		let not = |v: &str| Cmp::NotContains(v.to_string());
+		assert_eq!(
+			cond(NonAsciiFilter::Position, has("错误")),
+			"position(Body, '错误') > 0"
+		);
+		assert_eq!(
+			cond(NonAsciiFilter::Position, not("接続 timeout")),
+			"position(Body, '接続 timeout') = 0"
+		);
+		// ascii filters don't depend on the setting
+		assert_eq!(
+			cond(NonAsciiFilter::Position, has("read the timeout")),
+			"hasToken(Body, 'the') AND position(Body, 'read the timeout') > 0"
+		);
+		assert_eq!(
+			cond(NonAsciiFilter::Like, has("错误_100%")),
+			r"Body LIKE '%错误\\_100\\%%'"
+		);
+		assert_eq!(
+			cond(NonAsciiFilter::Like, not("エラー")),
+			"Body NOT LIKE '%エラー%'"
+		);
+		assert_eq!(
+			cond(NonAsciiFilter::Like, has(r"用户's 目录\")),
+			r"Body LIKE '%用户\'s 目录\\\\%'"
+		);
+		assert_eq!(
+			cond(NonAsciiFilter::Token, has("连接 数据库 错误")),
+			"hasToken(Body, '数据库') AND position(Body, '连接 数据库 错误') > 0"
+		);
+	}
+
+	#[test]
+	fn test_metric_group_by() {
+		let q = r#"sum by (level, resources_host) (count_over_time({ServiceName="api"}[1m]))"#;
+		let logql::parser::Query::MetricQuery(q) =
+			logql::parser::parse_logql_query(q).unwrap()
+		else {
+			unreachable!()
+		};
+		let schema = LogTable::new(
+			"logs".to_string(),
+			preset(crate::config::SchemaVersion::V0_90),
+		);
+		let limits = QueryLimits {
+			limit: None,
+			range: TimeRange {
+				start: None,
+				end: None,
+			},
+			direction: None,
+			step: None,
+			spss: None,
+			unbounded: false,
+		};
+		let sql = new_from_metricquery(
+			&q,
+			limits,
+			schema.clone(),
+			CKLogConverter::new(schema, false, false),
+			"count(*)",
+			true,
+			vec![],
+		);
+		assert_eq!(
+			sql,
+			"SELECT toStartOfMinute(Timestamp, 'UTC') as Tts,SeverityText,\
+			 SeverityNumber,ResourceAttributes['host'],count(*) as Total \
+			 FROM logs WHERE ServiceName = 'api' \
+			 GROUP BY SeverityText,SeverityNumber,ResourceAttributes['host'],Tts"
+		);
+		let levels = LevelMapping::default();
+		let item = |row: &str| {
+			let row: Vec<JSONValue> = serde_json::from_str(row).unwrap();
+			MetricRecord::try_from(row)
+				.unwrap()
+				.into_item(&q.agg_by, &levels, true)
+		};
+		let labels = |level: &str| {
+			BTreeMap::from([
+				("level".to_string(), level.to_string()),
+				("resources_host".to_string(), "a".to_string()),
+			])
+		};
+		let warn = item(r#"["1700000000", "warn", 0, "a", "3"]"#);
+		assert_eq!(warn.labels, labels("WARN"));
+		assert_eq!(warn.total, 3);
+		// the exporter left the text empty
+		let error = item(r#"["1700000000", "", 17, "a", "3"]"#);
+		assert_eq!(error.labels, labels("ERROR"));
+	}
+
+	#[test]
+	fn test_level_by_number() {
+		let schema = LogTable::new(
+			"logs".to_string(),
+			preset(crate::config::SchemaVersion::V0_90),
+		);
+		let converter = CKLogConverter::new(schema.clone(), false, true)
+			.with_level_number(Some(vec![]));
+		let level = |v: &str| PlaceValue::String(v.to_string());
+		let cases = [
+			(
+				Cmp::Equal(level("error")),
+				"SeverityNumber BETWEEN 17 AND 20",
+			),
+			(
+				Cmp::NotEqual(level("INFO")),
+				"SeverityNumber NOT BETWEEN 9 AND 12",
+			),
+			// not a name of ours, the text has to match
+			(Cmp::Equal(level("warning")), "SeverityText ILIKE 'warning'"),
+		];
+		for (cmp, want) in cases {
+			let c = Condition {
+				column: Column::Level,
+				cmp,
+			};
+			assert_eq!(converter.convert_condition(&c), want);
+		}
+		// 13-16 are warn in otel, configured as error here
+		let converter = CKLogConverter::new(schema, false, true)
+			.with_level_number(Some(vec![common::level::SeverityRange {
+				from: 13,
+				to: 16,
+				level: LogLevel::Error,
+			}]));
+		let cases = [
+			(
+				Cmp::Equal(level("error")),
+				"((SeverityNumber BETWEEN 13 AND 16) OR \
+				 (SeverityNumber BETWEEN 17 AND 20))",
+			),
+			(
+				Cmp::NotEqual(level("warn")),
+				"NOT ((SeverityNumber BETWEEN 13 AND 16 AND \
+				 SeverityNumber NOT BETWEEN 13 AND 16))",
+			),
+		];
+		for (cmp, want) in cases {
+			let c = Condition {
+				column: Column::Level,
+				cmp,
+			};
+			assert_eq!(converter.convert_condition(&c), want);
+		}
+	}
+
+	#[test]
+	fn test_rollup_covers() {
+		let covers = |q: &str| match logql::parser::parse_logql_query(q) {
+			Ok(logql::parser::Query::MetricQuery(mq)) => rollup_covers(&mq),
+			_ => unreachable!(),
+		};
+		assert!(covers(
+			r#"sum by (level) (count_over_time({ServiceName="api", level="error"}[1m]))"#
+		));
+		assert!(!covers(
+			r#"sum by (level) (count_over_time({ServiceName="api"} |= `timeout` [1m]))"#
+		));
+		assert!(!covers(
+			r#"sum by (level) (count_over_time({resources_host="a"}[1m]))"#
+		));
+		assert!(!covers(
+			r#"sum by (resources_host) (count_over_time({ServiceName="api"}[1m]))"#
+		));
+	}
+
+	#[test]
+	fn test_delete_sql() {
+		let logql::parser::Query::LogQuery(q) =
+			logql::parser::parse_logql_query(r#"{ServiceName="x"} |= "pwd""#)
+				.unwrap()
+		else {
+			unreachable!()
+		};
+		let schema = LogTable::new(
+			"logs".to_string(),
+			preset(crate::config::SchemaVersion::V0_90),
+		);
+		let range = TimeRange {
+			start: DateTime::from_timestamp(1700000000, 0)
+				.map(|t| t.naive_utc()),
+			end: None,
+		};
+		assert_eq!(
+			delete_sql(
+				&q,
+				&range,
+				"otel.logs",
+				&schema,
+				CKLogConverter::new(schema.clone(), false, false)
+			),
+			"ALTER TABLE otel.logs DELETE WHERE \
+			 (ServiceName = 'x' AND position(Body, 'pwd') > 0) \
+			 AND Timestamp>=toDateTime64(1700000000, 9, 'UTC')"
+		);
+	}
+
+	#[test]
+	fn test_existing_tables_sql() {
+		assert_eq!(
+			existing_tables_sql(
+				"otel",
+				&["logs_2024_06_01".to_string(), "it's".to_string()]
+			),
+			"SELECT name FROM system.tables WHERE database = 'otel' \
+			 AND name IN ('logs_2024_06_01', 'it\\'s')"
+		);
+	}
+
+	#[test]
+	fn test_partitioned_sql() {
+		let logql::parser::Query::LogQuery(q) =
+			logql::parser::parse_logql_query(r#"{ServiceName="x"}"#).unwrap()
+		else {
+			unreachable!()
+		};
+		let schema = LogTable::new(
+			"otel.logs".to_string(),
+			preset(crate::config::SchemaVersion::V0_90),
+		);
+		let t = |secs| DateTime::from_timestamp(secs, 0).map(|t| t.naive_utc());
+		let limits = QueryLimits {
+			limit: Some(10),
+			range: TimeRange {
+				start: t(1717200000),
+				end: t(1717300000),
+			},
+			direction: Some(Direction::Backward),
+			step: None,
+			spss: None,
+			unbounded: false,
+		};
+		let sql = logql_to_sql(
+			&q,
+			limits,
+			&schema,
+			CKLogConverter::new(schema.clone(), false, false),
+			vec!["otel.logs_2024_06_01".into(), "otel.logs_2024_06_02".into()],
+		);
+		let part = |t: &str| {
+			format!(
+				"(SELECT * FROM otel.{} WHERE ServiceName = 'x' \
+				 AND Timestamp>=toDateTime64(1717200000, 9, 'UTC') \
+				 AND Timestamp<=toDateTime64(1717300000, 9, 'UTC') \
+				 ORDER BY Timestamp DESC LIMIT 10)",
+				t
+			)
+		};
+		assert!(sql.ends_with(&format!(
+			" FROM ({} UNION ALL {}) ORDER BY Timestamp DESC LIMIT 10",
+			part("logs_2024_06_01"),
+			part("logs_2024_06_02")
+		)));
+	}
 }
@@ -1,32 +1,108 @@
-use super::{log::LogStorage, trace::TraceStorage};
-use crate::config::{ClickhouseLog, ClickhouseTrace};
-use anyhow::Result;
-use reqwest::Client;
+use super::{
+	http_client,
+	log::LogStorage,
+	registry::{Creating, Registry},
+	trace::TraceStorage,
+};
+use crate::config::{
+	ClickhouseConf, ClickhouseLog, ClickhouseTrace, DataSource,
+};
+use anyhow::{bail, Result};
 use std::time::Duration;
 
 pub(crate) mod common;
 pub(crate) mod converter;
-pub(crate) mod labels;
 pub mod log;
+pub(crate) mod retention;
+pub(crate) mod schema;
 pub mod trace;
+pub(crate) mod value_index;
+
+// only used when the config wasn't loaded through AppConfig::new
+const DEFAULT_LOG_TIMEOUT: Duration = Duration::from_secs(90);
+const DEFAULT_TRACE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// log and trace share the tag, the config under it tells them apart
+pub(super) fn register(r: &mut Registry) {
+	r.log("clickhouse", log_factory);
+	r.trace("clickhouse", trace_factory);
+}
+
+fn log_factory(d: DataSource) -> Creating<dyn LogStorage> {
+	match d {
+		DataSource::Clickhouse(ClickhouseConf::Log(cfg)) => {
+			Box::pin(new_log_source(cfg))
+		}
+		_ => Box::pin(async {
+			bail!("the clickhouse trace config can't be used as the log source")
+		}),
+	}
+}
+
+fn trace_factory(d: DataSource) -> Creating<dyn TraceStorage> {
+	match d {
+		DataSource::Clickhouse(ClickhouseConf::Trace(cfg)) => {
+			Box::pin(new_trace_source(cfg))
+		}
+		_ => Box::pin(async {
+			bail!("the clickhouse log config can't be used as the trace source")
+		}),
+	}
+}
 
 pub async fn new_log_source(cfg: ClickhouseLog) -> Result<Box<dyn LogStorage>> {
-	let cli = Client::builder()
+	let cli = http_client(&cfg.common.http)
 		.gzip(true)
-		.timeout(Duration::from_secs(90))
+		.timeout(cfg.common.query_timeout.unwrap_or(DEFAULT_LOG_TIMEOUT))
 		.build()?;
+	// archived files have no table to create or check, and sampling
+	// labels or values from them would scan the bucket
+	let archive = cfg.s3.is_some();
+	if cfg.common.bootstrap && !archive {
+		schema::bootstrap_log_table(&cli, &cfg).await?;
+	}
+	if !archive {
+		schema::check_log_table(&cli, &cfg).await?;
+	}
+	if let (Some(r), false) = (&cfg.common.retention, archive) {
+		let tables = [(cfg.common.table.as_str(), "Timestamp")];
+		retention::apply(&cli, &cfg.common, r, &tables).await?;
+	}
+	let discovery = cfg.label.discovery.clone();
+	let value_index = cfg.value_index.clone();
 	let q = log::CKLogQuerier::new(cli, cfg.common.table.clone(), cfg);
-	q.init_labels().await;
+	if !archive {
+		q.init_labels().await;
+	}
+	if let (Some(d), false) = (discovery, archive) {
+		q.spawn_discovery(d);
+	}
+	if let (Some(v), false) = (value_index, archive) {
+		q.spawn_value_index(v);
+	}
 	Ok(Box::new(q))
 }
 
 pub async fn new_trace_source(
 	cfg: ClickhouseTrace,
 ) -> Result<Box<dyn TraceStorage>> {
-	let cli = Client::builder()
+	let cli = http_client(&cfg.common.http)
 		.gzip(true)
-		.timeout(Duration::from_secs(60))
+		.timeout(cfg.common.query_timeout.unwrap_or(DEFAULT_TRACE_TIMEOUT))
 		.build()?;
+	if cfg.common.bootstrap {
+		schema::bootstrap_trace_tables(&cli, &cfg.common, &cfg.trace_ts_table)
+			.await?;
+	}
+	schema::check_trace_tables(&cli, &cfg.common, &cfg.trace_ts_table).await?;
+	if let Some(r) = &cfg.common.retention {
+		// the trace id index expires with the last span of the trace
+		let tables = [
+			(cfg.common.table.as_str(), "Timestamp"),
+			(cfg.trace_ts_table.as_str(), "End"),
+		];
+		retention::apply(&cli, &cfg.common, r, &tables).await?;
+	}
 	Ok(Box::new(trace::CKTraceQuerier::new(
 		cli,
 		cfg.common.table.clone(),
@@ -0,0 +1,79 @@
+use super::common::exec;
+use crate::config::{Clickhouse, Retention};
+use anyhow::Result;
+use reqwest::Client;
+use std::time::Duration;
+use tracing::{info, warn};
+
+// ttl_sql makes ck drop rows once ts is older than ttl, expired parts
+// are removed during merges. It runs on every start of every replica, so
+// the ttl isn't materialized, which would rewrite every part each time
+fn ttl_sql(table: &str, ts: &str, ttl: Duration) -> String {
+	format!(
+		"ALTER TABLE {} MODIFY TTL toDateTime({}) + toIntervalSecond({}) \
+		 SETTINGS materialize_ttl_after_modify = 0",
+		table,
+		ts,
+		ttl.as_secs()
+	)
+}
+
+// apply sets the ttl of every (table, timestamp column) pair and keeps
+// optimizing them in the background if an interval is given
+pub(crate) async fn apply(
+	cli: &Client,
+	cfg: &Clickhouse,
+	retention: &Retention,
+	tables: &[(&str, &str)],
+) -> Result<()> {
+	let mut names = vec![];
+	for (table, ts) in tables {
+		let name = format!("{}.{}", cfg.database, table);
+		exec(cli.clone(), cfg.clone(), ttl_sql(&name, ts, retention.ttl))
+			.await?;
+		info!("{} keeps data for {:?}", name, retention.ttl);
+		names.push(name);
+	}
+	if let Some(interval) = retention.maintenance_interval {
+		spawn_optimize(cli.clone(), cfg.clone(), names, interval);
+	}
+	Ok(())
+}
+
+fn spawn_optimize(
+	cli: Client,
+	cfg: Clickhouse,
+	tables: Vec<String>,
+	interval: Duration,
+) {
+	tokio::spawn(async move {
+		let mut ticker = tokio::time::interval(interval);
+		// the first tick fires at once, startup is busy enough
+		ticker.tick().await;
+		loop {
+			ticker.tick().await;
+			for t in &tables {
+				let sql = format!("OPTIMIZE TABLE {}", t);
+				if let Err(e) = exec(cli.clone(), cfg.clone(), sql).await {
+					warn!("fail to optimize {}: {}", t, e);
+				}
+			}
+		}
+	});
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use pretty_assertions::assert_eq;
+
+	#[test]
+	fn test_ttl_sql() {
+		assert_eq!(
+			ttl_sql("otel.otel_logs", "Timestamp", Duration::from_secs(86400)),
+			"ALTER TABLE otel.otel_logs MODIFY TTL \
+			 toDateTime(Timestamp) + toIntervalSecond(86400) \
+			 SETTINGS materialize_ttl_after_modify = 0"
+		);
+	}
+}
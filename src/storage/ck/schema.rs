@@ -0,0 +1,355 @@
+use super::{
+	common::{exec, send_query},
+	log::{LOG_TABLE_COLS, ROLLUP_TABLE_COLS},
+	trace::{TRACE_TABLE_COLS, TRACE_TS_TABLE_COLS},
+};
+use crate::{
+	config::{Clickhouse, ClickhouseLog, SchemaCheck, SchemaVersion},
+	storage::schema_check::{diff, report},
+};
+use anyhow::Result;
+use reqwest::Client;
+use sqlbuilder::builder::escape_str;
+use tracing::warn;
+
+// columns and keys the bridge relies on for one exporter version
+#[derive(Debug)]
+pub(crate) struct Preset {
+	pub log_cols: &'static [(&'static str, &'static str)],
+	// second precision copy of Timestamp in the log table's sorting key,
+	// filtering on it lets ck skip granules
+	pub log_ts_time: Option<&'static str>,
+	pub trace_cols: &'static [(&'static str, &'static str)],
+	pub attrs: AttrColumn,
+}
+
+static V0_90: Preset = Preset {
+	log_cols: &LOG_TABLE_COLS,
+	log_ts_time: None,
+	trace_cols: &TRACE_TABLE_COLS,
+	attrs: AttrColumn::Map,
+};
+
+// the trace table is the same as v0.90
+static V0_100: Preset = Preset {
+	log_cols: &LOG_TABLE_COLS,
+	log_ts_time: Some("TimestampTime"),
+	trace_cols: &TRACE_TABLE_COLS,
+	attrs: AttrColumn::Map,
+};
+
+static JSON: Preset = Preset {
+	log_cols: &LOG_TABLE_COLS,
+	log_ts_time: Some("TimestampTime"),
+	trace_cols: &TRACE_TABLE_COLS,
+	attrs: AttrColumn::Json,
+};
+
+pub(crate) fn preset(v: SchemaVersion) -> &'static Preset {
+	match v {
+		SchemaVersion::V0_90 => &V0_90,
+		SchemaVersion::V0_100 => &V0_100,
+		SchemaVersion::Json => &JSON,
+	}
+}
+
+// the type of the Resource/Scope/Log/SpanAttributes columns
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum AttrColumn {
+	#[default]
+	Map,
+	Json,
+}
+
+impl AttrColumn {
+	// get reads one attribute, a missing one is '' as with Map
+	pub fn get(&self, col: &str, key: &str) -> String {
+		match self {
+			AttrColumn::Map => format!("{}['{}']", col, escape_str(key)),
+			// a path of the JSON type is Dynamic, it has to be cast
+			// before it can be compared to a string
+			AttrColumn::Json if is_plain_path(key) => format!(
+				"ifNull(CAST({}.`{}`, 'Nullable(String)'), '')",
+				col, key
+			),
+			// odd keys can't be written as a path, they are looked up
+			// in the serialized object instead, which is a lot slower
+			AttrColumn::Json => format!(
+				"JSONExtractString(toJSONString({}), {})",
+				col,
+				key.split('.')
+					.map(|k| format!("'{}'", escape_str(k)))
+					.collect::<Vec<_>>()
+					.join(", ")
+			),
+		}
+	}
+	// keys lists the attribute names of every row
+	pub fn keys(&self, col: &str) -> String {
+		match self {
+			AttrColumn::Map => format!("mapKeys({})", col),
+			AttrColumn::Json => format!("JSONAllPaths({})", col),
+		}
+	}
+	fn type_name(&self) -> &'static str {
+		match self {
+			AttrColumn::Map => "Map",
+			AttrColumn::Json => "JSON",
+		}
+	}
+}
+
+fn is_plain_path(key: &str) -> bool {
+	!key.is_empty()
+		&& key.split('.').all(|p| {
+			!p.is_empty()
+				&& p.chars().all(|c| {
+					c.is_ascii_alphanumeric() || matches!(c, '_' | '-')
+				})
+		})
+}
+
+// the attribute columns in the expected columns are Map, swap in the
+// type the preset uses
+fn with_attr_type(
+	cols: &[(&'static str, &'static str)],
+	attrs: AttrColumn,
+) -> Vec<(&'static str, &'static str)> {
+	cols.iter()
+		.map(|&(name, typ)| match (typ, attrs) {
+			("Map", a) => (name, a.type_name()),
+			("Array(Map)", AttrColumn::Json) => (name, "Array(JSON)"),
+			_ => (name, typ),
+		})
+		.collect()
+}
+
+// json_ddl turns a ddl with Map attributes into the one the exporter
+// uses with json: true, the map key/value indexes have no equivalent
+fn json_ddl(ddl: &str) -> String {
+	ddl.lines()
+		.filter(|l| !l.contains("mapKeys(") && !l.contains("mapValues("))
+		.collect::<Vec<_>>()
+		.join("\n")
+		.replace("Map(LowCardinality(String), String)", "JSON")
+}
+
+static LOGS_V0_90_DDL: &str = include_str!("ddl/logs_v0_90.sql");
+static LOGS_V0_100_DDL: &str = include_str!("ddl/logs_v0_100.sql");
+static TRACES_DDL: &str = include_str!("ddl/traces.sql");
+static TRACE_ID_TS_DDL: &str = include_str!("ddl/trace_id_ts.sql");
+static TRACE_ID_TS_MV_DDL: &str = include_str!("ddl/trace_id_ts_mv.sql");
+static LOGS_ROLLUP_DDL: &str = include_str!("ddl/logs_rollup.sql");
+static LOGS_ROLLUP_MV_DDL: &str = include_str!("ddl/logs_rollup_mv.sql");
+
+fn render(ddl: &str, cfg: &Clickhouse, trace_ts_table: &str) -> String {
+	ddl.replace("{database}", &cfg.database)
+		.replace("{table}", &cfg.table)
+		.replace("{trace_ts_table}", trace_ts_table)
+}
+
+// bootstrap creates the tables the exporter would create, if missing
+pub(crate) async fn bootstrap_log_table(
+	cli: &Client,
+	log: &ClickhouseLog,
+) -> Result<()> {
+	let cfg = &log.common;
+	let ddl = match cfg.schema_version {
+		SchemaVersion::V0_90 => LOGS_V0_90_DDL.to_string(),
+		SchemaVersion::V0_100 => LOGS_V0_100_DDL.to_string(),
+		SchemaVersion::Json => json_ddl(LOGS_V0_100_DDL),
+	};
+	exec(cli.clone(), cfg.clone(), render(&ddl, cfg, "")).await?;
+	if let Some(rollup) = &log.rollup {
+		for ddl in [LOGS_ROLLUP_DDL, LOGS_ROLLUP_MV_DDL] {
+			let sql =
+				render(ddl, cfg, "").replace("{rollup_table}", &rollup.table);
+			exec(cli.clone(), cfg.clone(), sql).await?;
+		}
+	}
+	Ok(())
+}
+
+pub(crate) async fn bootstrap_trace_tables(
+	cli: &Client,
+	cfg: &Clickhouse,
+	trace_ts_table: &str,
+) -> Result<()> {
+	let traces = match cfg.schema_version {
+		SchemaVersion::Json => json_ddl(TRACES_DDL),
+		_ => TRACES_DDL.to_string(),
+	};
+	// the view reads from the span table and writes to the trace_ts table
+	for ddl in [&traces, TRACE_ID_TS_DDL, TRACE_ID_TS_MV_DDL] {
+		let sql = render(ddl, cfg, trace_ts_table);
+		exec(cli.clone(), cfg.clone(), sql).await?;
+	}
+	Ok(())
+}
+
+// family groups ck types that decode the same way,
+// e.g. LowCardinality(String) and String, or Int32 and UInt64
+pub(crate) fn family(t: &str) -> String {
+	let t = t.trim();
+	let unwrap = |w: &str| t.strip_prefix(w).and_then(|s| s.strip_suffix(')'));
+	if let Some(inner) = unwrap("LowCardinality(").or(unwrap("Nullable(")) {
+		return family(inner);
+	}
+	if let Some(inner) = unwrap("Array(") {
+		return format!("Array({})", family(inner));
+	}
+	let name = t.split('(').next().unwrap_or(t);
+	match name {
+		"FixedString" => "String",
+		n if n.starts_with("Int") || n.starts_with("UInt") => "Int",
+		n => n,
+	}
+	.to_string()
+}
+
+async fn describe(
+	cli: &Client,
+	cfg: &Clickhouse,
+	table: &str,
+) -> Result<Vec<(String, String)>> {
+	let sql = format!("DESCRIBE TABLE {}.{}", cfg.database, table);
+	let rows = send_query(cli.clone(), cfg.clone(), sql, None).await?;
+	Ok(rows
+		.into_iter()
+		.filter_map(|r| {
+			let name = r.first()?.as_str()?.to_string();
+			let typ = r.get(1)?.as_str()?.to_string();
+			Some((name, typ))
+		})
+		.collect())
+}
+
+async fn check_table(
+	cli: &Client,
+	cfg: &Clickhouse,
+	table: &str,
+	expected: &[(&str, &str)],
+) -> Result<()> {
+	let name = format!("{}.{}", cfg.database, table);
+	let actual = match cfg.schema_check {
+		SchemaCheck::Off => return Ok(()),
+		SchemaCheck::Warn => match describe(cli, cfg, table).await {
+			Ok(a) => a,
+			Err(e) => {
+				warn!("fail to describe {}: {}", name, e);
+				return Ok(());
+			}
+		},
+		SchemaCheck::Fail => describe(cli, cfg, table).await?,
+	};
+	report(&name, &diff(expected, &actual, family), cfg.schema_check)
+}
+
+pub(crate) async fn check_log_table(
+	cli: &Client,
+	log: &ClickhouseLog,
+) -> Result<()> {
+	let cfg = &log.common;
+	let p = preset(cfg.schema_version);
+	let mut expected = with_attr_type(p.log_cols, p.attrs);
+	if let Some(k) = p.log_ts_time {
+		expected.push((k, "DateTime"));
+	}
+	check_table(cli, cfg, &cfg.table, &expected).await?;
+	if let Some(rollup) = &log.rollup {
+		check_table(cli, cfg, &rollup.table, &ROLLUP_TABLE_COLS).await?;
+	}
+	Ok(())
+}
+
+pub(crate) async fn check_trace_tables(
+	cli: &Client,
+	cfg: &Clickhouse,
+	trace_ts_table: &str,
+) -> Result<()> {
+	let p = preset(cfg.schema_version);
+	let expected = with_attr_type(p.trace_cols, p.attrs);
+	check_table(cli, cfg, &cfg.table, &expected).await?;
+	check_table(cli, cfg, trace_ts_table, &TRACE_TS_TABLE_COLS).await
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use pretty_assertions::assert_eq;
+
+	#[test]
+	fn test_render_ddl() {
+		let cfg = Clickhouse {
+			database: "otel".to_string(),
+			table: "otel_traces".to_string(),
+			..Default::default()
+		};
+		let sql = render(TRACE_ID_TS_MV_DDL, &cfg, "otel_traces_trace_id_ts");
+		assert!(sql.starts_with(
+			"CREATE MATERIALIZED VIEW IF NOT EXISTS \
+			 otel.otel_traces_trace_id_ts_mv\nTO otel.otel_traces_trace_id_ts"
+		));
+		assert!(sql.contains("FROM otel.otel_traces\n"));
+	}
+
+	#[test]
+	fn test_family() {
+		let cases = [
+			("LowCardinality(String)", "String"),
+			("DateTime64(9)", "DateTime64"),
+			("DateTime('UTC')", "DateTime"),
+			("UInt64", "Int"),
+			("Map(LowCardinality(String), String)", "Map"),
+			("Array(Map(LowCardinality(String), String))", "Array(Map)"),
+			("Array(DateTime64(9))", "Array(DateTime64)"),
+		];
+		for (t, want) in cases {
+			assert_eq!(family(t), want, "{}", t);
+		}
+	}
+
+	#[test]
+	fn test_attr_column() {
+		let cases = [
+			(
+				AttrColumn::Map,
+				"k8s.pod.name",
+				"ResourceAttributes['k8s.pod.name']",
+			),
+			(
+				AttrColumn::Json,
+				"k8s.pod.name",
+				"ifNull(CAST(ResourceAttributes.`k8s.pod.name`, \
+				 'Nullable(String)'), '')",
+			),
+			(
+				AttrColumn::Json,
+				"it's",
+				"JSONExtractString(toJSONString(ResourceAttributes), 'it\\'s')",
+			),
+		];
+		for (attrs, key, want) in cases {
+			assert_eq!(attrs.get("ResourceAttributes", key), want);
+		}
+		assert_eq!(
+			AttrColumn::Json.keys("LogAttributes"),
+			"JSONAllPaths(LogAttributes)"
+		);
+	}
+
+	#[test]
+	fn test_json_ddl() {
+		let ddl = json_ddl(TRACES_DDL);
+		assert!(!ddl.contains("Map("), "{}", ddl);
+		assert!(!ddl.contains("mapKeys"), "{}", ddl);
+		assert!(ddl.contains("SpanAttributes JSON CODEC(ZSTD(1)),"));
+		assert_eq!(
+			with_attr_type(&TRACE_TABLE_COLS, AttrColumn::Json)
+				.into_iter()
+				.filter(|(_, t)| t.contains("JSON"))
+				.count(),
+			4
+		);
+	}
+}
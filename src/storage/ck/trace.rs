@@ -1,39 +1,223 @@
-use super::{common::*, converter::CKLogConverter};
+use super::{
+	common::*,
+	converter::CKLogConverter,
+	schema::{preset, AttrColumn, Preset},
+};
 use crate::config::ClickhouseTrace;
+use crate::query_tags;
 use crate::storage::trace::{Links, SpanEvent};
 use crate::storage::{trace::*, *};
 use anyhow::Result;
 use async_trait::async_trait;
-use chrono::{DateTime, Utc};
+use chrono::DateTime;
+use common::TimeRange;
 use itertools::izip;
+use moka::sync::Cache;
 use opentelemetry_proto::tonic::trace::v1::{
 	span::SpanKind, status::StatusCode,
 };
 use reqwest::Client;
+use serde::Deserialize;
 use serde_json::Value as JSONValue;
-use sqlbuilder::{builder::TableSchema, trace::single_spanset_query};
-use std::collections::HashMap;
+use sqlbuilder::{
+	builder::{
+		escape_str, time_range_into_timing, Cmp, Column, Condition, PlaceValue,
+		QueryPlan, Selection, TableSchema,
+	},
+	trace::{single_spanset_query, spanset_to_selection, StatusNames},
+};
+use std::{collections::HashMap, time::Duration};
+use tokio::task::JoinSet;
 use traceql::*;
 use tracing::{error, warn};
 
+// spans of a trace may still be arriving, so its window is only kept
+// for a short while before being looked up again
+const TRACE_WINDOW_TTL: Duration = Duration::from_secs(5 * 60);
+const TRACE_WINDOW_CAPACITY: u64 = 10_000;
+const SECONDS_PER_HOUR: i64 = 60 * 60;
+// tag values back autocomplete, which asks again on every keystroke
+const TAG_VALUES_TTL: Duration = Duration::from_secs(30);
+const TAG_VALUES_CAPACITY: u64 = 1_000;
+const TAG_VALUES_LIMIT: u32 = 1000;
+
 #[derive(Clone)]
 pub struct CKTraceQuerier {
 	client: Client,
 	ck_cfg: ClickhouseTrace,
 	schema: TraceTable,
+	// trace_id -> [start, end) in unix seconds
+	windows: Cache<String, (i64, i64)>,
+	// sql -> values
+	tag_values: Cache<String, Vec<String>>,
 }
 
 impl CKTraceQuerier {
 	pub fn new(client: Client, table: String, ck_cfg: ClickhouseTrace) -> Self {
+		let names = &ck_cfg.status_codes;
+		let status = StatusNames {
+			ok: names.ok.clone(),
+			error: names.error.clone(),
+			unset: names.unset.clone(),
+		};
 		Self {
 			client,
 			ck_cfg: ck_cfg.clone(),
-			schema: TraceTable::new(
-				table,
-				ck_cfg.common.database,
-				ck_cfg.trace_ts_table,
-			),
+			schema: TraceTable {
+				status,
+				..TraceTable::new(
+					full_table_name(&ck_cfg.common, &table),
+					ck_cfg.common.database,
+					ck_cfg.trace_ts_table,
+					preset(ck_cfg.common.schema_version),
+				)
+			},
+			windows: Cache::builder()
+				.max_capacity(TRACE_WINDOW_CAPACITY)
+				.time_to_live(TRACE_WINDOW_TTL)
+				.build(),
+			tag_values: Cache::builder()
+				.max_capacity(TAG_VALUES_CAPACITY)
+				.time_to_live(TAG_VALUES_TTL)
+				.build(),
+		}
+	}
+
+	async fn trace_window(&self, trace_id: &str) -> Result<Option<(i64, i64)>> {
+		if let Some(w) = self.windows.get(trace_id) {
+			return Ok(Some(w));
+		}
+		let window = self.window(&trace_id_filter(&[trace_id])).await?;
+		if let Some(w) = window {
+			self.windows.insert(trace_id.to_string(), w);
 		}
+		Ok(window)
+	}
+
+	// window is [start, end) of every trace matching the traces condition
+	async fn window(&self, traces: &str) -> Result<Option<(i64, i64)>> {
+		let rows = send_query(
+			self.client.clone(),
+			self.ck_cfg.common.clone(),
+			trace_window_sql(traces, &self.schema),
+			None,
+		)
+		.await?;
+		Ok(rows.first().and_then(|row| {
+			let start = row.first().and_then(json_as_i64)?;
+			let end = row.get(1).and_then(json_as_i64)?;
+			// min() on an empty set gives the epoch
+			(start > 0).then_some((start, end))
+		}))
+	}
+
+	// spans_between reads the spans of the traces condition in
+	// [start, end), an hour per query when the table is sharded by hour
+	async fn spans_between(
+		&self,
+		traces: &str,
+		start: i64,
+		end: i64,
+	) -> Result<Vec<SpanItem>> {
+		let shards = if self.ck_cfg.shard_by_hour {
+			split_by_hour(start, end)
+		} else {
+			vec![(start, end)]
+		};
+		let mut tasks = JoinSet::new();
+		for (from, to) in shards {
+			let sql = traceid_query_sql(traces, from, to, &self.schema);
+			let cli = self.client.clone();
+			let cfg = self.ck_cfg.common.clone();
+			tasks.spawn(stats::inherit(explain::inherit(query_tags::inherit(
+				query_text(cli, cfg, sql, None),
+			))));
+		}
+		let mut results = vec![];
+		while let Some(res) = tasks.join_next().await {
+			let text = res?.inspect_err(|e| {
+				error!("Query trace error: {:?}", e);
+			})?;
+			let part = decode_spans(&text).inspect_err(|e| {
+				error!("Convert trace record error: {:?}", e);
+			})?;
+			results.extend(part);
+		}
+		if self.ck_cfg.dedup_spans {
+			results = dedup_spans(results);
+		}
+		Ok(results)
+	}
+
+	// the limit of a search is always set, and never above the max. An
+	// unbounded one keeps what it asked for, nothing when unset
+	fn limited(&self, opt: QueryLimits) -> QueryLimits {
+		if opt.unbounded {
+			return opt;
+		}
+		QueryLimits {
+			limit: Some(self.ck_cfg.search_limit(opt.limit)),
+			..opt
+		}
+	}
+
+	// search_spanset picks the traces first, reading only the columns of
+	// the filter, then reads every column of their matching spans. A
+	// filter matching many spans per trace doesn't read all of them
+	async fn search_spanset(
+		&self,
+		expr: &Expression,
+		sp: &SpanSet,
+		opt: QueryLimits,
+	) -> Result<Vec<SpanItem>> {
+		let candidates = candidates_sql(sp, &self.schema, &opt);
+		let ids = self.trace_ids(candidates.clone()).await?;
+		let traces = match ids.is_empty() {
+			// nothing was run, the second statement is explained with the
+			// first one in place of its ids
+			true if explain::current().is_some() => {
+				format!("TraceId IN ({})", candidates)
+			}
+			true => return Ok(vec![]),
+			false => ids_filter(&ids),
+		};
+		match search_in_sql(expr, traces, &self.schema, &opt) {
+			Some(sql) => self.spans(sql).await,
+			None => Ok(vec![]),
+		}
+	}
+
+	async fn trace_ids(&self, sql: String) -> Result<Vec<String>> {
+		let rows = send_query(
+			self.client.clone(),
+			self.ck_cfg.common.clone(),
+			sql,
+			None,
+		)
+		.await?;
+		Ok(rows
+			.into_iter()
+			.filter_map(|row| match row.into_iter().next() {
+				Some(JSONValue::String(s)) => Some(s),
+				_ => None,
+			})
+			.collect())
+	}
+
+	async fn spans(&self, sql: String) -> Result<Vec<SpanItem>> {
+		let text = query_text(
+			self.client.clone(),
+			self.ck_cfg.common.clone(),
+			sql,
+			None,
+		)
+		.await
+		.inspect_err(|e| {
+			error!("Query trace error: {:?}", e);
+		})?;
+		decode_spans(&text).inspect_err(|e| {
+			error!("Convert trace record error: {:?}", e);
+		})
 	}
 }
 
@@ -42,97 +226,536 @@ impl TraceStorage for CKTraceQuerier {
 	async fn query_trace(
 		&self,
 		trace_id: &str,
-		opt: QueryLimits,
+		_: QueryLimits,
 	) -> Result<Vec<SpanItem>> {
-		let sql = traceid_query_sql(trace_id, opt, self.schema.clone());
-		let mut results = vec![];
-		let rows =
-			send_query(self.client.clone(), self.ck_cfg.common.clone(), sql)
-				.await
-				.map_err(|e| {
-					error!("Query trace error: {:?}", e);
-					e
-				})?;
-		for row in rows {
-			let record = TraceRecord::try_from(row).map_err(|e| {
-				error!("Convert trace record error: {:?}", e);
-				e
-			})?;
-			results.push(record.into());
+		let Some((start, end)) =
+			self.trace_window(trace_id).await.inspect_err(|e| {
+				error!("Query trace window error: {:?}", e);
+			})?
+		else {
+			return Ok(vec![]);
+		};
+		self.spans_between(&trace_id_filter(&[trace_id]), start, end)
+			.await
+	}
+	// query_traces looks up the window of all the traces at once and
+	// reads them in the same queries
+	async fn query_traces(
+		&self,
+		trace_ids: &[String],
+		_: QueryLimits,
+	) -> Result<Vec<SpanItem>> {
+		if trace_ids.is_empty() {
+			return Ok(vec![]);
 		}
-		Ok(results)
+		let traces = trace_id_filter(trace_ids);
+		let Some((start, end)) =
+			self.window(&traces).await.inspect_err(|e| {
+				error!("Query traces window error: {:?}", e);
+			})?
+		else {
+			return Ok(vec![]);
+		};
+		self.spans_between(&traces, start, end).await
 	}
 	async fn search_span(
 		&self,
 		expr: &Expression,
 		opt: QueryLimits,
 	) -> Result<Vec<SpanItem>> {
-		match expr {
-			Expression::Logical(_, _, _) => {
-				warn!("Search span does not support logical expression");
-				return Ok(vec![]);
+		let opt = self.limited(opt);
+		if let Expression::SpanSet(sp) = expr {
+			return self.search_spanset(expr, sp, opt).await;
+		}
+		let Some(sql) = search_sql(expr, &self.schema, &opt.range, opt.limit)
+		else {
+			warn!("Search span does not support logical expression");
+			return Ok(vec![]);
+		};
+		self.spans(sql).await
+	}
+	async fn search_trace_ids(
+		&self,
+		expr: &Expression,
+		opt: QueryLimits,
+	) -> Result<Vec<String>> {
+		let opt = self.limited(opt);
+		let Some(sql) = trace_ids_sql(expr, &self.schema, &opt) else {
+			warn!("Search span does not support logical expression");
+			return Ok(vec![]);
+		};
+		self.trace_ids(sql).await
+	}
+	async fn search_span_in(
+		&self,
+		expr: &Expression,
+		trace_ids: &[String],
+		opt: QueryLimits,
+	) -> Result<Vec<SpanItem>> {
+		if trace_ids.is_empty() {
+			return Ok(vec![]);
+		}
+		let traces = ids_filter(trace_ids);
+		match search_in_sql(expr, traces, &self.schema, &opt) {
+			Some(sql) => self.spans(sql).await,
+			None => Ok(vec![]),
+		}
+	}
+	async fn trace_roots(
+		&self,
+		trace_ids: &[String],
+		opt: QueryLimits,
+	) -> Result<Vec<SpanItem>> {
+		if trace_ids.is_empty() {
+			return Ok(vec![]);
+		}
+		self.spans(roots_sql(trace_ids, &self.schema, &opt.range))
+			.await
+	}
+	async fn span_tag_values(
+		&self,
+		tag: &str,
+		scope: Option<&SpanSet>,
+		opt: QueryLimits,
+	) -> Result<Vec<String>> {
+		let Some(col) = tag_column(tag, self.schema.preset.attrs) else {
+			return Ok(vec![]);
+		};
+		let sql = tag_values_sql(&col, scope, &self.schema, &opt.range);
+		if let Some(values) = self.tag_values.get(&sql) {
+			return Ok(values);
+		}
+		let rows = send_query(
+			self.client.clone(),
+			self.ck_cfg.common.clone(),
+			sql.clone(),
+			None,
+		)
+		.await?;
+		let values: Vec<String> = rows
+			.into_iter()
+			.filter_map(|row| match row.into_iter().next() {
+				Some(JSONValue::String(s)) => Some(s),
+				_ => None,
+			})
+			.collect();
+		self.tag_values.insert(sql, values.clone());
+		Ok(values)
+	}
+	fn capabilities(&self) -> Capabilities {
+		Capabilities {
+			logical_spanset: false,
+			trace_ids_first: true,
+			..Default::default()
+		}
+	}
+}
+
+// tag_column maps a tempo tag name to the column holding its values
+fn tag_column(tag: &str, attrs: AttrColumn) -> Option<String> {
+	let col = match tag {
+		"name" => "SpanName".to_string(),
+		"status" => "StatusCode".to_string(),
+		"kind" => "SpanKind".to_string(),
+		"statusMessage" => "StatusMessage".to_string(),
+		"resource.service.name" => "ServiceName".to_string(),
+		_ => {
+			if let Some(k) = tag.strip_prefix("resource.") {
+				attrs.get("ResourceAttributes", k)
+			} else if let Some(k) =
+				tag.strip_prefix("span.").or_else(|| tag.strip_prefix('.'))
+			{
+				attrs.get("SpanAttributes", k)
+			} else {
+				return None;
 			}
-			Expression::SpanSet(sp) => {
-				let converter =
-					CKLogConverter::new(self.schema.clone(), true, true);
-				let sql = single_spanset_query(
-					sp,
-					self.schema.clone(),
-					self.schema.projection(),
-					opt.range,
-					converter,
+		}
+	};
+	Some(col)
+}
+
+fn converter(schema: &TraceTable) -> CKLogConverter<TraceTable> {
+	CKLogConverter::new(schema.clone(), true, true)
+		.with_attrs(schema.preset.attrs)
+}
+
+// the range is widened to whole minutes, so the sql, which is also the
+// cache key, stays the same while the user types
+fn tag_values_sql(
+	col: &str,
+	scope: Option<&SpanSet>,
+	schema: &TraceTable,
+	range: &TimeRange,
+) -> String {
+	let minute = |t: chrono::NaiveDateTime, up: bool| {
+		let secs = t.and_utc().timestamp();
+		let secs = secs - secs.rem_euclid(60) + if up { 60 } else { 0 };
+		DateTime::from_timestamp(secs, 0).map_or(t, |d| d.naive_utc())
+	};
+	let range = TimeRange {
+		start: range.start.map(|t| minute(t, false)),
+		end: range.end.map(|t| minute(t, true)),
+	};
+	let not_empty = Selection::Unit(Condition {
+		column: Column::Raw(col.to_string()),
+		cmp: Cmp::NotEqual(PlaceValue::String(String::new())),
+	});
+	let selection = match scope {
+		Some(sp) => Selection::LogicalAnd(
+			Box::new(spanset_to_selection(sp, &schema.status)),
+			Box::new(not_empty),
+		),
+		None => not_empty,
+	};
+	QueryPlan::new(
+		converter(schema),
+		schema.clone(),
+		vec![format!("DISTINCT {}", col)],
+		Some(selection),
+		vec![],
+		vec![],
+		time_range_into_timing(&range),
+		Some(TAG_VALUES_LIMIT),
+	)
+	.as_sql()
+}
+
+// search_sql renders a spanset, or spansets chained with > and >>,
+// spansets combined with && or || can't be searched. The limit counts
+// spans for a spanset and traces, the newest ones, for a chain
+fn search_sql(
+	expr: &Expression,
+	schema: &TraceTable,
+	range: &TimeRange,
+	limit: Option<u32>,
+) -> Option<String> {
+	match expr {
+		Expression::SpanSet(sp) => Some(single_spanset_query(
+			sp,
+			schema.clone(),
+			schema.projection(),
+			range.clone(),
+			converter(schema),
+			&schema.status,
+			limit,
+		)),
+		Expression::Structural(l, op, r) => {
+			let mut q = StructuralQuery {
+				schema,
+				range,
+				ctes: vec![],
+				traces: None,
+			};
+			let spans = q.below(l, *op, r, schema.projection())?;
+			let Some(limit) = limit else {
+				return Some(format!("WITH {} {}", q.ctes.join(", "), spans));
+			};
+			let matched = q.cte(spans);
+			Some(format!(
+				"WITH {} SELECT * FROM {} WHERE TraceId IN ({})",
+				q.ctes.join(", "),
+				matched,
+				newest_traces(&matched, Some(limit)),
+			))
+		}
+		Expression::Logical(..) => None,
+	}
+}
+
+// candidates_sql picks the traces with a span matching sp, newest first
+// like trace_ids_sql
+fn candidates_sql(
+	sp: &SpanSet,
+	schema: &TraceTable,
+	opt: &QueryLimits,
+) -> String {
+	let q = StructuralQuery {
+		schema,
+		range: &opt.range,
+		ctes: vec![],
+		traces: None,
+	};
+	let mut sql = q.select(
+		vec!["TraceId".to_string()],
+		Some(spanset_to_selection(sp, &schema.status)),
+		vec![],
+	);
+	sql.push_str(" GROUP BY TraceId ORDER BY max(Timestamp) DESC");
+	if let Some(limit) = opt.limit {
+		sql.push_str(&format!(" LIMIT {}", limit));
+	}
+	sql
+}
+
+// trace_ids_sql picks the traces of what search_sql finds, newest first
+fn trace_ids_sql(
+	expr: &Expression,
+	schema: &TraceTable,
+	opt: &QueryLimits,
+) -> Option<String> {
+	let mut q = StructuralQuery {
+		schema,
+		range: &opt.range,
+		ctes: vec![],
+		traces: None,
+	};
+	let ids = vec!["TraceId".to_string(), "Timestamp".to_string()];
+	let spans = match expr {
+		Expression::SpanSet(sp) => q.select(
+			ids,
+			Some(spanset_to_selection(sp, &schema.status)),
+			vec![],
+		),
+		Expression::Structural(l, op, r) => q.below(l, *op, r, ids)?,
+		Expression::Logical(..) => return None,
+	};
+	let sql = newest_traces(&format!("({})", spans), opt.limit);
+	if q.ctes.is_empty() {
+		return Some(sql);
+	}
+	Some(format!("WITH {} {}", q.ctes.join(", "), sql))
+}
+
+// newest_traces selects the distinct TraceId of spans, a table or a
+// subquery having TraceId and Timestamp, the latest started first
+fn newest_traces(spans: &str, limit: Option<u32>) -> String {
+	let mut sql = format!(
+		"SELECT TraceId FROM {} GROUP BY TraceId \
+		 ORDER BY max(Timestamp) DESC",
+		spans
+	);
+	if let Some(limit) = limit {
+		sql.push_str(&format!(" LIMIT {}", limit));
+	}
+	sql
+}
+
+fn ids_filter(trace_ids: &[String]) -> String {
+	let ids = trace_ids
+		.iter()
+		.map(|id| format!("'{}'", escape_str(id)))
+		.collect::<Vec<_>>();
+	format!("TraceId IN ({})", ids.join(", "))
+}
+
+// search_in_sql is search_sql for the traces matching the traces
+// condition, every statement only reads their spans so the only limit is
+// the earliest spss spans of each trace, the ones shown in the spanset
+fn search_in_sql(
+	expr: &Expression,
+	traces: String,
+	schema: &TraceTable,
+	opt: &QueryLimits,
+) -> Option<String> {
+	let mut q = StructuralQuery {
+		schema,
+		range: &opt.range,
+		ctes: vec![],
+		traces: Some(traces),
+	};
+	let spans = match expr {
+		Expression::SpanSet(sp) => q.select(
+			schema.projection(),
+			Some(spanset_to_selection(sp, &schema.status)),
+			vec![],
+		),
+		Expression::Structural(l, op, r) => {
+			q.below(l, *op, r, schema.projection())?
+		}
+		Expression::Logical(..) => return None,
+	};
+	let mut sql = match q.ctes.is_empty() {
+		true => spans,
+		false => format!("WITH {} {}", q.ctes.join(", "), spans),
+	};
+	if let Some(spss) = opt.spss {
+		sql.push_str(&format!(" ORDER BY Timestamp LIMIT {} BY TraceId", spss));
+	}
+	Some(sql)
+}
+
+// roots_sql selects the spans without a parent of the traces
+fn roots_sql(
+	trace_ids: &[String],
+	schema: &TraceTable,
+	range: &TimeRange,
+) -> String {
+	let q = StructuralQuery {
+		schema,
+		range,
+		ctes: vec![],
+		traces: Some(ids_filter(trace_ids)),
+	};
+	q.select(
+		schema.projection(),
+		None,
+		vec!["ParentSpanId = ''".to_string()],
+	)
+}
+
+// descendants further down than this are missed by >>,
+// each level is one more lookup on ParentSpanId
+const MAX_DESCENDANT_DEPTH: usize = 8;
+
+// StructuralQuery joins the span table with itself on ParentSpanId,
+// every set of spans it needs more than once is kept in a CTE
+struct StructuralQuery<'a> {
+	schema: &'a TraceTable,
+	range: &'a TimeRange,
+	ctes: Vec<String>,
+	// added to every statement, when only some traces are searched
+	traces: Option<String>,
+}
+
+impl StructuralQuery<'_> {
+	fn cte(&mut self, sql: String) -> String {
+		let name = format!("s{}", self.ctes.len());
+		self.ctes.push(format!("{} AS ({})", name, sql));
+		name
+	}
+	fn select(
+		&self,
+		projection: Vec<String>,
+		selection: Option<Selection>,
+		conds: Vec<String>,
+	) -> String {
+		let qp = QueryPlan::new(
+			converter(self.schema),
+			self.schema.clone(),
+			projection,
+			selection,
+			vec![],
+			vec![],
+			time_range_into_timing(self.range),
+			None,
+		);
+		let mut sep = match qp.selection.is_none() && qp.timing.is_empty() {
+			true => " WHERE ",
+			false => " AND ",
+		};
+		let mut sql = qp.as_sql();
+		for c in conds.into_iter().chain(self.traces.clone()) {
+			sql.push_str(sep);
+			sql.push_str(&c);
+			sep = " AND ";
+		}
+		sql
+	}
+	// matched returns the CTE holding TraceId and SpanId of what expr matches
+	fn matched(&mut self, expr: &Expression) -> Option<String> {
+		let ids = vec!["TraceId".to_string(), "SpanId".to_string()];
+		let sql = match expr {
+			Expression::SpanSet(sp) => self.select(
+				ids,
+				Some(spanset_to_selection(sp, &self.schema.status)),
+				vec![],
+			),
+			Expression::Structural(l, op, r) => self.below(l, *op, r, ids)?,
+			Expression::Logical(..) => return None,
+		};
+		Some(self.cte(sql))
+	}
+	// below selects the spans of r that are children or descendants of l,
+	// only traces that have a span matching l are looked at
+	fn below(
+		&mut self,
+		l: &Expression,
+		op: StructuralOperator,
+		r: &Expression,
+		projection: Vec<String>,
+	) -> Option<String> {
+		let Expression::SpanSet(sp) = r else {
+			return None;
+		};
+		let parents = self.matched(l)?;
+		let candidates =
+			format!("TraceId IN (SELECT TraceId FROM {})", parents);
+		let mut above = format!("SELECT TraceId, SpanId FROM {}", parents);
+		if op == StructuralOperator::Descendant {
+			// every round adds the children of the spans found so far
+			for _ in 1..MAX_DESCENDANT_DEPTH {
+				let children = self.select(
+					vec!["TraceId".to_string(), "SpanId".to_string()],
+					None,
+					vec![
+						candidates.clone(),
+						format!("(TraceId, ParentSpanId) IN ({})", above),
+					],
 				);
-				let mut results = vec![];
-				let rows = send_query(
-					self.client.clone(),
-					self.ck_cfg.common.clone(),
-					sql,
-				)
-				.await
-				.map_err(|e| {
-					error!("Query trace error: {:?}", e);
-					e
-				})?;
-				for row in rows {
-					let record = TraceRecord::try_from(row).map_err(|e| {
-						error!("Convert trace record error: {:?}", e);
-						e
-					})?;
-					results.push(record.into());
-				}
-				Ok(results)
+				let name =
+					self.cte(format!("{} UNION ALL {}", above, children));
+				above = format!("SELECT TraceId, SpanId FROM {}", name);
 			}
 		}
+		Some(self.select(
+			projection,
+			Some(spanset_to_selection(sp, &self.schema.status)),
+			vec![
+				candidates,
+				format!("(TraceId, ParentSpanId) IN ({})", above),
+			],
+		))
+	}
+}
+
+// the short form of a padded id is matched too
+fn trace_id_filter<S: AsRef<str>>(trace_ids: &[S]) -> String {
+	let ids = trace_ids
+		.iter()
+		.flat_map(|id| trace_id_forms(id.as_ref()))
+		.map(|id| format!("'{}'", escape_str(&id)))
+		.collect::<Vec<_>>();
+	match ids.as_slice() {
+		[id] => format!("TraceId = {}", id),
+		_ => format!("TraceId IN ({})", ids.join(", ")),
 	}
 }
 
+fn trace_window_sql(traces: &str, schema: &TraceTable) -> String {
+	format!(
+		"SELECT toUnixTimestamp(min(Start)), toUnixTimestamp(max(End)) + 1 \
+		 FROM {}.{} WHERE {}",
+		schema.database(),
+		schema.trace_ts_table(),
+		traces,
+	)
+}
+
 fn traceid_query_sql(
-	trace_id: &str,
-	_: QueryLimits,
-	schema: TraceTable,
+	traces: &str,
+	start: i64,
+	end: i64,
+	schema: &TraceTable,
 ) -> String {
-	let db = schema.database();
-	let trace_ts_table = schema.trace_ts_table();
-	let sql = format!(
-		r#"
-WITH
-	'{}' as trace_id,
-	(SELECT min(Start) FROM {}.{} WHERE TraceId = trace_id) as start,
-	(SELECT max(End) + 1 FROM {}.{} WHERE TraceId = trace_id) as end
-SELECT {} FROM {}
-WHERE TraceId = trace_id
-AND Timestamp >= start
-AND Timestamp <= end
-"#,
-		trace_id,
-		db,
-		trace_ts_table,
-		db,
-		trace_ts_table,
+	format!(
+		"SELECT {} FROM {} WHERE {} \
+		 AND Timestamp >= {} AND Timestamp < {}",
 		schema.projection().join(","),
 		schema.table,
-	);
-	sql.replace("\n", " ").replace("\t", " ")
+		traces,
+		timelit::ck_datetime64(start),
+		timelit::ck_datetime64(end),
+	)
+}
+
+// split [start, end) at hour boundaries
+fn split_by_hour(start: i64, end: i64) -> Vec<(i64, i64)> {
+	let mut shards = vec![];
+	let mut from = start;
+	while from < end {
+		let to = ((from / SECONDS_PER_HOUR + 1) * SECONDS_PER_HOUR).min(end);
+		shards.push((from, to));
+		from = to;
+	}
+	shards
+}
+
+fn json_as_i64(v: &JSONValue) -> Option<i64> {
+	match v {
+		JSONValue::Number(n) => n.as_i64(),
+		JSONValue::String(s) => s.parse().ok(),
+		_ => None,
+	}
 }
 
 #[derive(Clone)]
@@ -140,6 +763,8 @@ struct TraceTable {
 	table: String,
 	database: String,
 	trace_ts_table: String,
+	preset: &'static Preset,
+	status: StatusNames,
 }
 
 impl TraceTable {
@@ -147,15 +772,22 @@ impl TraceTable {
 		table: String,
 		database: String,
 		trace_ts_table: String,
+		preset: &'static Preset,
 	) -> Self {
 		Self {
-			table: format!("{}.{}", database, table),
+			table,
 			database,
 			trace_ts_table,
+			preset,
+			status: StatusNames::default(),
 		}
 	}
 	fn projection(&self) -> Vec<String> {
-		TRACE_TABLE_COLS.iter().map(|s| s.to_string()).collect()
+		self.preset
+			.trace_cols
+			.iter()
+			.map(|(c, _)| c.to_string())
+			.collect()
 	}
 	fn database(&self) -> &str {
 		self.database.as_str()
@@ -192,49 +824,73 @@ impl TraceTable {
 		 Attributes Map(LowCardinality(String), String)
 	 ) CODEC(ZSTD(1))
 */
-static TRACE_TABLE_COLS: [&str; 22] = [
-	"Timestamp",
-	"TraceId",
-	"SpanId",
-	"ParentSpanId",
-	"TraceState",
-	"SpanName",
-	"SpanKind",
-	"ServiceName",
-	"ResourceAttributes",
-	"ScopeName",
-	"ScopeVersion",
-	"SpanAttributes",
-	"Duration",
-	"StatusCode",
-	"StatusMessage",
-	"Events.Timestamp",
-	"Events.Name",
-	"Events.Attributes",
-	"Links.TraceId",
-	"Links.SpanId",
-	"Links.TraceState",
-	"Links.Attributes",
+pub(super) static TRACE_TABLE_COLS: [(&str, &str); 22] = [
+	("Timestamp", "DateTime64"),
+	("TraceId", "String"),
+	("SpanId", "String"),
+	("ParentSpanId", "String"),
+	("TraceState", "String"),
+	("SpanName", "String"),
+	("SpanKind", "String"),
+	("ServiceName", "String"),
+	("ResourceAttributes", "Map"),
+	("ScopeName", "String"),
+	("ScopeVersion", "String"),
+	("SpanAttributes", "Map"),
+	("Duration", "Int"),
+	("StatusCode", "String"),
+	("StatusMessage", "String"),
+	("Events.Timestamp", "Array(DateTime64)"),
+	("Events.Name", "Array(String)"),
+	("Events.Attributes", "Array(Map)"),
+	("Links.TraceId", "Array(String)"),
+	("Links.SpanId", "Array(String)"),
+	("Links.TraceState", "Array(String)"),
+	("Links.Attributes", "Array(Map)"),
 ];
 
-#[derive(Debug)]
-struct TraceRecord {
-	timestamp: i64,
-	trace_id: String,
-	span_id: String,
-	parent_span_id: String,
-	trace_state: String,
-	span_name: String,
-	span_kind: String,
-	service_name: String,
+// the table kept by the exporter to look up the time range of a trace
+pub(super) static TRACE_TS_TABLE_COLS: [(&str, &str); 3] = [
+	("TraceId", "String"),
+	("Start", "DateTime64"),
+	("End", "DateTime64"),
+];
+
+// a row in the order of TRACE_TABLE_COLS. attribute maps keep their
+// values as json, everything else is borrowed from the response if it can
+#[derive(Debug, Deserialize)]
+struct TraceRecord<'a> {
+	#[serde(borrow)]
+	timestamp: Text<'a>,
+	#[serde(borrow)]
+	trace_id: Text<'a>,
+	#[serde(borrow)]
+	span_id: Text<'a>,
+	#[serde(borrow)]
+	parent_span_id: Text<'a>,
+	#[serde(borrow)]
+	trace_state: Text<'a>,
+	#[serde(borrow)]
+	span_name: Text<'a>,
+	#[serde(borrow)]
+	span_kind: Text<'a>,
+	#[serde(borrow)]
+	service_name: Text<'a>,
 	resource_attributes: HashMap<String, JSONValue>,
-	scope_name: String,
-	scope_version: String,
+	#[serde(borrow)]
+	scope_name: Text<'a>,
+	#[serde(borrow)]
+	scope_version: Text<'a>,
 	span_attributes: HashMap<String, JSONValue>,
-	duration: i64,
-	status_code: String,
-	status_message: String,
-	events_ts: Vec<DateTime<Utc>>,
+	// UInt64 is quoted in JSONCompact
+	#[serde(borrow)]
+	duration: Text<'a>,
+	#[serde(borrow)]
+	status_code: Text<'a>,
+	#[serde(borrow)]
+	status_message: Text<'a>,
+	#[serde(borrow)]
+	events_ts: Vec<Text<'a>>,
 	events_name: Vec<String>,
 	events_attrs: Vec<HashMap<String, JSONValue>>,
 	links_trace_id: Vec<String>,
@@ -243,75 +899,47 @@ struct TraceRecord {
 	links_attrs: Vec<HashMap<String, JSONValue>>,
 }
 
-impl TryFrom<Vec<JSONValue>> for TraceRecord {
+impl TryFrom<TraceRecord<'_>> for SpanItem {
 	type Error = CKConvertErr;
 	fn try_from(
-		value: Vec<JSONValue>,
+		value: TraceRecord<'_>,
 	) -> std::result::Result<Self, Self::Error> {
-		if value.len() != 22 {
-			return Err(CKConvertErr::Length);
-		}
-		let ts = value[0].as_str().ok_or(CKConvertErr::Timestamp)?;
-		let tts = parse_timestamp_try_best(ts)
+		let ts = parse_timestamp_try_best(&value.timestamp)
 			.map_err(|_| CKConvertErr::Timestamp)?;
-		let record = Self {
-			timestamp: tts
-				.timestamp_nanos_opt()
-				.ok_or(CKConvertErr::Timestamp)?,
-			trace_id: value[1].as_str().unwrap_or("").to_string(),
-			span_id: value[2].as_str().unwrap_or("").to_string(),
-			parent_span_id: value[3].as_str().unwrap_or("").to_string(),
-			trace_state: value[4].as_str().unwrap_or("").to_string(),
-			span_name: value[5].as_str().unwrap_or("").to_string(),
-			span_kind: value[6].as_str().unwrap_or("").to_string(),
-			service_name: value[7].as_str().unwrap_or("").to_string(),
-			resource_attributes: json_object_to_map_s_jsonv(&value[8])?,
-			scope_name: value[9].as_str().unwrap_or("").to_string(),
-			scope_version: value[10].as_str().unwrap_or("").to_string(),
-			span_attributes: json_object_to_map_s_jsonv(&value[11])?,
-			duration: value[12]
-				.as_str()
-				.unwrap_or("0")
-				.parse()
-				.map_err(|_| CKConvertErr::Duration)?,
-			status_code: value[13].as_str().unwrap_or("").to_string(),
-			status_message: value[14].as_str().unwrap_or("").to_string(),
-			events_ts: json_array_to_date(&value[15])?,
-			events_name: json_array_string(&value[16])?,
-			events_attrs: json_array_hashmap(&value[17])?,
-			links_trace_id: json_array_string(&value[18])?,
-			links_span_id: json_array_string(&value[19])?,
-			links_trace_state: json_array_string(&value[20])?,
-			links_attrs: json_array_hashmap(&value[21])?,
+		let duration = match &*value.duration {
+			"" => 0,
+			d => d.parse().map_err(|_| CKConvertErr::Duration)?,
 		};
-		Ok(record)
-	}
-}
-
-impl From<TraceRecord> for SpanItem {
-	fn from(value: TraceRecord) -> Self {
-		Self {
-			ts: DateTime::from_timestamp_nanos(value.timestamp),
-			trace_id: value.trace_id.clone(),
-			span_id: value.span_id.clone(),
-			parent_span_id: value.parent_span_id.clone(),
-			trace_state: value.trace_state.clone(),
-			span_name: value.span_name.clone(),
+		let events_ts = value
+			.events_ts
+			.iter()
+			.map(|ts| {
+				parse_timestamp_try_best(ts)
+					.map_err(|_| CKConvertErr::Timestamp)
+			})
+			.collect::<std::result::Result<Vec<_>, _>>()?;
+		Ok(Self {
+			ts,
+			trace_id: value.trace_id.into_string(),
+			span_id: value.span_id.into_string(),
+			parent_span_id: value.parent_span_id.into_string(),
+			trace_state: value.trace_state.into_string(),
+			span_name: value.span_name.into_string(),
 			span_kind: SpanKind::from_str_name(&value.span_kind)
 				.unwrap_or(SpanKind::Unspecified)
 				.into(),
-			service_name: value.service_name.clone(),
-			resource_attributes: value.resource_attributes,
+			service_name: value.service_name.into_string(),
+			resource_attributes: flatten_attrs(value.resource_attributes),
 			scope_name: str_2_opt_str(&value.scope_name),
 			scope_version: str_2_opt_str(&value.scope_version),
-			span_attributes: value.span_attributes,
-			duration: value.duration,
+			span_attributes: flatten_attrs(value.span_attributes),
+			duration,
 			// https://github.com/open-telemetry/opentelemetry-collector-contrib/blob/main/internal/coreinternal/traceutil/traceutil.go#L37
 			// collector sets status_code as "STATUS_CODE_OK" rather than its corresponding number
 			status_code: parse_status_code(&value.status_code),
 			status_message: str_2_opt_str(&value.status_message),
 			span_events: izip!(
-				value.events_ts,
+				events_ts,
 				value.events_name,
 				value.events_attrs
 			)
@@ -319,7 +947,7 @@ impl From<TraceRecord> for SpanItem {
 				ts,
 				dropped_attributes_count: 0,
 				name,
-				attributes,
+				attributes: flatten_attrs(attributes),
 			})
 			.collect(),
 			link: izip!(
@@ -332,22 +960,26 @@ impl From<TraceRecord> for SpanItem {
 				trace_id,
 				span_id,
 				trace_state,
-				attributes,
+				attributes: flatten_attrs(attributes),
 			})
 			.collect(),
-		}
+		})
 	}
 }
 
-static STATUS_CODE_STR: [StatusCode; 3] =
-	[StatusCode::Unset, StatusCode::Ok, StatusCode::Error];
+/// decode_spans turns a JSONCompact response of TRACE_TABLE_COLS into spans
+pub fn decode_spans(text: &str) -> Result<Vec<SpanItem>> {
+	decode_rows::<TraceRecord, _>(text)
+}
 
+// both the proto names and the short ones newer exporters write are known
 fn parse_status_code(s: &str) -> Option<i32> {
-	STATUS_CODE_STR
-		.iter()
-		.find(|k| (*k).as_str_name() == s)
-		.map(|k| (*k).into())
-		.or(Some(StatusCode::Unset.into()))
+	let code = match s {
+		"STATUS_CODE_OK" | "Ok" => StatusCode::Ok,
+		"STATUS_CODE_ERROR" | "Error" => StatusCode::Error,
+		_ => StatusCode::Unset,
+	};
+	Some(code.into())
 }
 
 fn str_2_opt_str(s: &str) -> Option<String> {
@@ -393,6 +1025,62 @@ mod tests {
 	use std::{fs, path::PathBuf};
 	use traceql::parse_traceql;
 
+	#[test]
+	fn test_decode_spans() {
+		let text = r#"{"meta":[],"data":[[
+			"1700000000.123456789","t1","s2","s1","","GET \"/\"",
+			"SPAN_KIND_SERVER","api",{"host.name":"a"},"","",
+			{"http.status_code":"200"},"1500","STATUS_CODE_ERROR","boom",
+			["1700000000.200000000"],["retry"],[{"n":"1"}],
+			[],[],[],[]
+		]],"rows":1}"#;
+		let spans = decode_spans(text).unwrap();
+		assert_eq!(spans.len(), 1);
+		let span = &spans[0];
+		assert_eq!(span.span_name, r#"GET "/""#);
+		assert_eq!(span.ts.timestamp_subsec_nanos(), 123456789);
+		assert_eq!(span.duration, 1500);
+		assert_eq!(span.span_kind, SpanKind::Server as i32);
+		assert_eq!(span.status_code, Some(StatusCode::Error.into()));
+		assert_eq!(span.scope_name, None);
+		assert_eq!(span.span_events.len(), 1);
+		assert_eq!(span.span_events[0].name, "retry");
+		assert!(span.link.is_empty());
+		// a row with the wrong number of columns is rejected as a whole
+		let short = r#"{"data":[["1700000000","t1"]]}"#;
+		assert!(decode_spans(short).is_err());
+	}
+
+	#[test]
+	fn test_split_by_hour() {
+		let h = SECONDS_PER_HOUR;
+		assert_eq!(split_by_hour(10, 20), vec![(10, 20)]);
+		assert_eq!(
+			split_by_hour(h - 5, 2 * h + 5),
+			vec![(h - 5, h), (h, 2 * h), (2 * h, 2 * h + 5)]
+		);
+		assert!(split_by_hour(20, 20).is_empty());
+	}
+
+	#[test]
+	fn test_trace_id_filter() {
+		let full = "4bf92f3577b34da6a3ce929d0e0e4736";
+		assert_eq!(trace_id_filter(&[full]), format!("TraceId = '{}'", full));
+		assert_eq!(
+			trace_id_filter(&["0000000000000000a3ce929d0e0e4736"]),
+			"TraceId IN ('0000000000000000a3ce929d0e0e4736', \
+			 'a3ce929d0e0e4736')"
+		);
+		assert_eq!(
+			trace_id_filter(&[full, "0000000000000000a3ce929d0e0e4736"]),
+			format!(
+				"TraceId IN ('{}', '0000000000000000a3ce929d0e0e4736', \
+				 'a3ce929d0e0e4736')",
+				full
+			)
+		);
+	}
+
 	#[test]
 	fn expand_complex_traceql() {
 		let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
@@ -407,21 +1095,16 @@ mod tests {
 		let cases: HashMap<String, TestCase> =
 			serde_yaml::from_str(&test_cases).unwrap();
 		let schema = TraceTable::new(
-			"otel_traces".to_string(),
+			"otlp.otel_traces".to_string(),
 			"otlp".to_string(),
 			"xx".to_string(),
+			preset(crate::config::SchemaVersion::V0_90),
 		);
 		for (name, tc) in cases {
 			let expr = parse_traceql(&tc.input).unwrap();
-			if let Expression::SpanSet(sp) = expr {
-				let converter = CKLogConverter::new(schema.clone(), true, true);
-				let sql = single_spanset_query(
-					&sp,
-					schema.clone(),
-					schema.projection(),
-					common::TimeRange::default(),
-					converter,
-				);
+			if let Some(sql) =
+				search_sql(&expr, &schema, &TimeRange::default(), Some(500))
+			{
 				let actual_ast =
 					Parser::parse_sql(&ClickHouseDialect {}, &sql).unwrap();
 				let expect_ast =
@@ -436,4 +1119,249 @@ mod tests {
 			}
 		}
 	}
+
+	#[test]
+	fn test_descendant_sql() {
+		let schema = TraceTable::new(
+			"otlp.otel_traces".to_string(),
+			"otlp".to_string(),
+			"xx".to_string(),
+			preset(crate::config::SchemaVersion::V0_90),
+		);
+		let expr =
+			parse_traceql(r#"{serviceName="gateway"} >> {name="query"}"#)
+				.unwrap();
+		let sql =
+			search_sql(&expr, &schema, &TimeRange::default(), None).unwrap();
+		// the matched parents plus one CTE per extra level
+		let last = format!("s{} AS (", MAX_DESCENDANT_DEPTH - 1);
+		assert!(sql.contains(&last));
+		assert!(!sql.contains(&format!("s{} AS (", MAX_DESCENDANT_DEPTH)));
+		assert!(sql.ends_with(&format!(
+			"(TraceId, ParentSpanId) IN (SELECT TraceId, SpanId FROM s{})",
+			MAX_DESCENDANT_DEPTH - 1
+		)));
+		Parser::parse_sql(&ClickHouseDialect {}, &sql).unwrap();
+		let expr = parse_traceql(r#"{name="a"} > ({name="b"} || {name="c"})"#)
+			.unwrap();
+		assert_eq!(
+			search_sql(&expr, &schema, &TimeRange::default(), Some(500)),
+			None
+		);
+	}
+
+	#[test]
+	fn test_search_limit() {
+		let schema = TraceTable::new(
+			"otlp.otel_traces".to_string(),
+			"otlp".to_string(),
+			"xx".to_string(),
+			preset(crate::config::SchemaVersion::V0_90),
+		);
+		let range = TimeRange::default();
+		let expr = parse_traceql(r#"{name="query"}"#).unwrap();
+		let sql = search_sql(&expr, &schema, &range, Some(20)).unwrap();
+		assert!(sql.ends_with(" LIMIT 20"), "{}", sql);
+		// a chain limits the traces, not their spans
+		let expr = parse_traceql(r#"{name="a"} >> {name="b"}"#).unwrap();
+		let sql = search_sql(&expr, &schema, &range, Some(20)).unwrap();
+		let last = format!("s{}", MAX_DESCENDANT_DEPTH);
+		assert!(
+			sql.ends_with(&format!(
+				"SELECT * FROM {0} WHERE TraceId IN (SELECT TraceId FROM {0} \
+				 GROUP BY TraceId ORDER BY max(Timestamp) DESC LIMIT 20)",
+				last
+			)),
+			"{}",
+			sql
+		);
+		Parser::parse_sql(&ClickHouseDialect {}, &sql).unwrap();
+		let sql = search_sql(&expr, &schema, &range, None).unwrap();
+		assert!(!sql.contains("LIMIT"));
+	}
+
+	#[test]
+	fn test_candidates_sql() {
+		let schema = TraceTable::new(
+			"otlp.otel_traces".to_string(),
+			"otlp".to_string(),
+			"xx".to_string(),
+			preset(crate::config::SchemaVersion::V0_90),
+		);
+		let expr = parse_traceql(r#"{name="query"}"#).unwrap();
+		let Expression::SpanSet(sp) = &expr else {
+			unreachable!()
+		};
+		let opt = QueryLimits {
+			limit: Some(20),
+			..Default::default()
+		};
+		let sql = candidates_sql(sp, &schema, &opt);
+		assert!(sql.starts_with("SELECT TraceId FROM otlp.otel_traces"));
+		assert!(sql.ends_with(
+			" GROUP BY TraceId ORDER BY max(Timestamp) DESC LIMIT 20"
+		));
+		Parser::parse_sql(&ClickHouseDialect {}, &sql).unwrap();
+		let sql = trace_ids_sql(&expr, &schema, &opt).unwrap();
+		assert!(sql.starts_with("SELECT TraceId FROM (SELECT TraceId,"));
+		assert!(!sql.contains("LIMIT 500"));
+		Parser::parse_sql(&ClickHouseDialect {}, &sql).unwrap();
+	}
+
+	#[test]
+	fn test_hoisted_candidates_sql() {
+		let schema = TraceTable::new(
+			"otlp.otel_traces".to_string(),
+			"otlp".to_string(),
+			"xx".to_string(),
+			preset(crate::config::SchemaVersion::V0_90),
+		);
+		let q =
+			r#"{duration > 1s && name = "a" || duration > 1s && name = "b"}"#;
+		let expr = traceql::hoist::expression(parse_traceql(q).unwrap());
+		let Expression::SpanSet(sp) = &expr else {
+			unreachable!()
+		};
+		let sql = candidates_sql(sp, &schema, &QueryLimits::default());
+		let expect = "SELECT TraceId FROM otlp.otel_traces \
+		 WHERE (Duration > 1000000000 AND \
+		 ((Duration > 1000000000 AND SpanName = 'a') OR \
+		 (Duration > 1000000000 AND SpanName = 'b'))) \
+		 GROUP BY TraceId ORDER BY max(Timestamp) DESC";
+		let parse = |sql: &str| {
+			Parser::parse_sql(&ClickHouseDialect {}, sql).unwrap()[0]
+				.to_string()
+		};
+		assert_eq!(parse(&sql), parse(expect));
+	}
+
+	#[test]
+	fn test_search_in_sql() {
+		let schema = TraceTable::new(
+			"otlp.otel_traces".to_string(),
+			"otlp".to_string(),
+			"xx".to_string(),
+			preset(crate::config::SchemaVersion::V0_90),
+		);
+		let ids = ids_filter(&["a".to_string(), "b'c".to_string()]);
+		let opt = QueryLimits::default();
+		let expr = parse_traceql(r#"{name="query"}"#).unwrap();
+		let sql = search_in_sql(&expr, ids.clone(), &schema, &opt).unwrap();
+		assert!(sql.ends_with(r"AND TraceId IN ('a', 'b\'c')"), "{}", sql);
+		Parser::parse_sql(&ClickHouseDialect {}, &sql).unwrap();
+		// the parents are only looked up in the same traces
+		let expr = parse_traceql(r#"{serviceName="gateway"} > {name="query"}"#)
+			.unwrap();
+		let sql = search_in_sql(&expr, ids.clone(), &schema, &opt).unwrap();
+		assert_eq!(sql.matches("TraceId IN ('a'").count(), 2);
+		assert!(!sql.contains("LIMIT"));
+		Parser::parse_sql(&ClickHouseDialect {}, &sql).unwrap();
+		// only the spans a spanset shows are read
+		let opt = QueryLimits {
+			spss: Some(3),
+			..Default::default()
+		};
+		let sql = search_in_sql(&expr, ids, &schema, &opt).unwrap();
+		assert!(
+			sql.ends_with(" ORDER BY Timestamp LIMIT 3 BY TraceId"),
+			"{}",
+			sql
+		);
+	}
+
+	#[test]
+	fn test_roots_sql() {
+		let schema = TraceTable::new(
+			"otlp.otel_traces".to_string(),
+			"otlp".to_string(),
+			"xx".to_string(),
+			preset(crate::config::SchemaVersion::V0_90),
+		);
+		let range = TimeRange {
+			start: DateTime::from_timestamp(1700000000, 0)
+				.map(|d| d.naive_utc()),
+			end: None,
+		};
+		let sql = roots_sql(&["a".to_string()], &schema, &range);
+		assert!(
+			sql.ends_with("AND ParentSpanId = '' AND TraceId IN ('a')"),
+			"{}",
+			sql
+		);
+		assert!(sql.contains("Timestamp>="), "{}", sql);
+		Parser::parse_sql(&ClickHouseDialect {}, &sql).unwrap();
+	}
+
+	#[test]
+	fn test_tag_values_sql() {
+		let schema = TraceTable::new(
+			"otlp.otel_traces".to_string(),
+			"otlp".to_string(),
+			"xx".to_string(),
+			preset(crate::config::SchemaVersion::V0_90),
+		);
+		let attrs = AttrColumn::Map;
+		assert_eq!(
+			tag_column("resource.service.name", attrs).unwrap(),
+			"ServiceName"
+		);
+		assert_eq!(tag_column("foo", attrs), None);
+		let col = tag_column("span.http.method", attrs).unwrap();
+		let Expression::SpanSet(scope) =
+			parse_traceql(r#"{resource.service.name="checkout"}"#).unwrap()
+		else {
+			panic!("not a spanset");
+		};
+		let range = TimeRange {
+			start: DateTime::from_timestamp(90, 0).map(|d| d.naive_utc()),
+			end: DateTime::from_timestamp(150, 0).map(|d| d.naive_utc()),
+		};
+		let sql = tag_values_sql(&col, Some(&scope), &schema, &range);
+		assert!(
+			sql.starts_with(
+				"SELECT DISTINCT SpanAttributes['http.method'] FROM \
+				 otlp.otel_traces WHERE (ResourceAttributes['service.name'] \
+				 = 'checkout' AND SpanAttributes['http.method'] != '')"
+			),
+			"{}",
+			sql
+		);
+		assert!(
+			sql.contains("Timestamp>=toDateTime64(60, 9, 'UTC')"),
+			"{}",
+			sql
+		);
+		assert!(
+			sql.contains("Timestamp<=toDateTime64(180, 9, 'UTC')"),
+			"{}",
+			sql
+		);
+		assert!(sql.ends_with("LIMIT 1000"), "{}", sql);
+	}
+
+	#[test]
+	fn test_status_names() {
+		let schema = TraceTable {
+			status: StatusNames {
+				ok: "Ok".to_string(),
+				error: "Error".to_string(),
+				unset: "Unset".to_string(),
+			},
+			..TraceTable::new(
+				"otlp.otel_traces".to_string(),
+				"otlp".to_string(),
+				"xx".to_string(),
+				preset(crate::config::SchemaVersion::V0_90),
+			)
+		};
+		let expr = parse_traceql("{status != error}").unwrap();
+		let sql = search_sql(&expr, &schema, &TimeRange::default(), Some(500))
+			.unwrap();
+		assert!(sql.contains("StatusCode != 'Error'"), "{}", sql);
+		assert_eq!(parse_status_code("Error"), Some(StatusCode::Error.into()));
+		assert_eq!(
+			parse_status_code("STATUS_CODE_OK"),
+			Some(StatusCode::Ok.into())
+		);
+	}
 }
@@ -0,0 +1,149 @@
+use chrono::NaiveDateTime;
+use common::TimeRange;
+use logql::parser::{LogQuery, Operator};
+use std::{
+	collections::HashMap,
+	hash::{DefaultHasher, Hash, Hasher},
+	sync::{Arc, RwLock},
+};
+
+// BloomFilter answers whether a value may have been inserted,
+// a negative answer is always right
+#[derive(Debug, Clone)]
+pub struct BloomFilter {
+	bits: Vec<u64>,
+	hashes: u64,
+}
+
+impl BloomFilter {
+	// about 10 bits and 7 hashes per item gives 1% false positives
+	pub fn new(items: usize) -> Self {
+		Self {
+			bits: vec![0; (items.max(1) * 10).div_ceil(64)],
+			hashes: 7,
+		}
+	}
+	fn positions(&self, v: &str) -> Vec<usize> {
+		let (h1, h2) = (hash(v, 0), hash(v, 1));
+		let m = self.bits.len() as u64 * 64;
+		(0..self.hashes)
+			.map(|i| (h1.wrapping_add(i.wrapping_mul(h2)) % m) as usize)
+			.collect()
+	}
+	pub fn insert(&mut self, v: &str) {
+		for p in self.positions(v) {
+			self.bits[p / 64] |= 1 << (p % 64);
+		}
+	}
+	pub fn contains(&self, v: &str) -> bool {
+		self.positions(v)
+			.into_iter()
+			.all(|p| self.bits[p / 64] & (1 << (p % 64)) != 0)
+	}
+}
+
+fn hash(v: &str, seed: u64) -> u64 {
+	let mut h = DefaultHasher::new();
+	seed.hash(&mut h);
+	v.hash(&mut h);
+	h.finish()
+}
+
+// values each indexed label had between `from` and `to`
+#[derive(Debug)]
+pub struct Snapshot {
+	pub from: NaiveDateTime,
+	pub to: NaiveDateTime,
+	pub filters: HashMap<String, BloomFilter>,
+}
+
+impl Snapshot {
+	pub fn new(
+		from: NaiveDateTime,
+		to: NaiveDateTime,
+		values: Vec<(String, Vec<String>)>,
+	) -> Self {
+		let filters = values
+			.into_iter()
+			.map(|(label, vals)| {
+				let mut f = BloomFilter::new(vals.len());
+				vals.iter().for_each(|v| f.insert(v));
+				(label, f)
+			})
+			.collect();
+		Self { from, to, filters }
+	}
+}
+
+// ValueBlooms is shared by the clones of a querier and
+// replaced as a whole on every refresh
+#[derive(Debug, Clone, Default)]
+pub struct ValueBlooms {
+	snapshot: Arc<RwLock<Option<Snapshot>>>,
+}
+
+impl ValueBlooms {
+	pub fn replace(&self, s: Snapshot) {
+		*self.snapshot.write().unwrap() = Some(s);
+	}
+	// surely_empty tells whether an equality matcher of q asks for a value
+	// that no log in the index window had. Only a range within the window
+	// qualifies, logs after it may have values the index hasn't seen
+	pub fn surely_empty(&self, q: &LogQuery, range: &TimeRange) -> bool {
+		let guard = self.snapshot.read().unwrap();
+		let Some(s) = guard.as_ref() else {
+			return false;
+		};
+		let covered = range.start.is_some_and(|start| start >= s.from)
+			&& range.end.is_some_and(|end| end <= s.to);
+		if !covered {
+			return false;
+		}
+		q.selector.label_paris.iter().any(|p| {
+			p.op == Operator::Equal
+				&& s.filters
+					.get(&p.label)
+					.is_some_and(|f| !f.contains(&p.value))
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use chrono::DateTime;
+
+	#[test]
+	fn test_surely_empty() {
+		let t = |secs| DateTime::from_timestamp(secs, 0).unwrap().naive_utc();
+		let blooms = ValueBlooms::default();
+		let pods = (0..1000).map(|i| format!("api-{}", i)).collect();
+		blooms.replace(Snapshot::new(
+			t(100),
+			t(300),
+			vec![("resources_k8s.pod.name".to_string(), pods)],
+		));
+		let q = |pod: &str| match logql::parser::parse_logql_query(&format!(
+			r#"{{ServiceName="api", resources_k8s.pod.name="{}"}}"#,
+			pod
+		)) {
+			Ok(logql::parser::Query::LogQuery(q)) => q,
+			_ => unreachable!(),
+		};
+		let range = |start, end| TimeRange {
+			start: Some(t(start)),
+			end: Some(t(end)),
+		};
+		assert!(!blooms.surely_empty(&q("api-42"), &range(200, 250)));
+		assert!(blooms.surely_empty(&q("web-1"), &range(200, 250)));
+		// older than what the index has seen
+		assert!(!blooms.surely_empty(&q("web-1"), &range(50, 250)));
+		// newer, or open ended
+		assert!(!blooms.surely_empty(&q("web-1"), &range(200, 400)));
+		let open = TimeRange {
+			start: Some(t(200)),
+			end: None,
+		};
+		assert!(!blooms.surely_empty(&q("web-1"), &open));
+	}
+}
@@ -1,6 +1,9 @@
 use super::{log::LogTable, trace::TraceTable};
+use crate::storage::{stats, timelit::databend_timestamp};
 use chrono::NaiveDateTime;
+use chrono_tz::Tz;
 use sqlbuilder::builder::*;
+use std::fmt;
 
 #[derive(Clone)]
 pub struct DatabendLogConverter {
@@ -11,17 +14,79 @@ impl DatabendLogConverter {
 	pub fn new(table: LogTable) -> Self {
 		Self { table }
 	}
+
+	// MATCH only finds whole tokens of the index, so a filter the
+	// tokenizer would split, or a token too short to be indexed, can't use
+	// it. Several plain words still can, as a phrase through query()
+	fn contains_path(
+		&self,
+		col: &Column,
+		v: &str,
+		negated: bool,
+	) -> ContainsPath {
+		if !self.table.use_inverted_index || *col != Column::Message {
+			return ContainsPath::Like;
+		}
+		let tokens: Vec<_> = v.split(' ').collect();
+		let indexed = tokens.iter().all(|t| {
+			t.len() >= self.table.min_token_len
+				&& t.bytes().all(|b| b.is_ascii_alphanumeric())
+		});
+		let path = match (indexed, tokens.len()) {
+			(false, _) => ContainsPath::Like,
+			(true, 1) => ContainsPath::Match,
+			// query() can't be negated
+			(true, _) if negated => ContainsPath::Like,
+			(true, _) => ContainsPath::Phrase,
+		};
+		stats::note(format!(
+			"contains {:?} on {}: {}",
+			v,
+			self.table.msg_key(),
+			path
+		));
+		path
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ContainsPath {
+	Match,
+	Phrase,
+	Like,
+}
+
+impl fmt::Display for ContainsPath {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let s = match self {
+			Self::Match => "match",
+			Self::Phrase => "query",
+			Self::Like => "like",
+		};
+		f.write_str(s)
+	}
 }
 
-fn column_name(obj: &impl TableSchema, c: &Column) -> String {
+pub(super) fn column_name(obj: &impl TableSchema, c: &Column) -> String {
 	match c {
 		Column::Message => obj.msg_key().to_string(),
 		Column::Timestamp => obj.ts_key().to_string(),
 		Column::Level => obj.level_key().to_string(),
 		Column::TraceID => obj.trace_key().to_string(),
-		Column::Resources(s) => format!("{}['{}']", obj.resources_key(), s),
-		Column::Attributes(s) => format!("{}['{}']", obj.attributes_key(), s),
+		Column::Resources(s) => {
+			format!("{}['{}']", obj.resources_key(), escape_str(s))
+		}
+		Column::Attributes(s) => {
+			format!("{}['{}']", obj.attributes_key(), escape_str(s))
+		}
 		Column::Raw(s) => s.clone(),
+		Column::BodyJson(path) => {
+			format!(
+				"JSON_EXTRACT_PATH_TEXT({},'{}')",
+				obj.msg_key(),
+				escape_str(path)
+			)
+		}
 	}
 }
 
@@ -37,20 +102,20 @@ impl QueryConverter for DatabendLogConverter {
 			Cmp::LessEqual(v) => format!("{} <= {}", col_name, v),
 			Cmp::RegexMatch(v) => format!("{} REGEXP '{}'", col_name, v),
 			Cmp::RegexNotMatch(v) => format!("{} NOT REGEXP '{}'", col_name, v),
-			Cmp::Contains(v) => {
-				if self.table.use_inverted_index {
-					format!("MATCH({},'{}')", col_name, v)
-				} else {
-					format!("{} LIKE '%{}%'", col_name, v)
+			Cmp::Contains(v) => match self.contains_path(&c.column, v, false) {
+				ContainsPath::Match => format!("MATCH({},'{}')", col_name, v),
+				ContainsPath::Phrase => {
+					format!("query('{}:\"{}\"')", col_name, v)
 				}
-			}
-			Cmp::NotContains(v) => {
-				if self.table.use_inverted_index {
+				ContainsPath::Like => format!("{} LIKE '%{}%'", col_name, v),
+			},
+			Cmp::NotContains(v) => match self.contains_path(&c.column, v, true)
+			{
+				ContainsPath::Match => {
 					format!("NOT MATCH({},'{}')", col_name, v)
-				} else {
-					format!("{} NOT LIKE '%{}%'", col_name, v)
 				}
-			}
+				_ => format!("{} NOT LIKE '%{}%'", col_name, v),
+			},
 		}
 	}
 
@@ -60,12 +125,132 @@ impl QueryConverter for DatabendLogConverter {
 		o: &OrdType,
 		t: &NaiveDateTime,
 	) -> String {
-		convert_timing(ts_key, o, t)
+		convert_timing(ts_key, o, t, self.table.tz)
+	}
+
+	fn convert_condition_params(
+		&self,
+		c: &Condition,
+		p: &mut Params,
+	) -> String {
+		let col_name = column_name(&self.table, &c.column);
+		let s = |v: &str| PlaceValue::String(v.to_string());
+		match &c.cmp {
+			Cmp::Equal(v) => format!("{} = {}", col_name, p.bind(v.clone())),
+			Cmp::NotEqual(v) => {
+				format!("{} != {}", col_name, p.bind(v.clone()))
+			}
+			Cmp::Larger(v) => format!("{} > {}", col_name, p.bind(v.clone())),
+			Cmp::LargerEqual(v) => {
+				format!("{} >= {}", col_name, p.bind(v.clone()))
+			}
+			Cmp::Less(v) => format!("{} < {}", col_name, p.bind(v.clone())),
+			Cmp::LessEqual(v) => {
+				format!("{} <= {}", col_name, p.bind(v.clone()))
+			}
+			Cmp::RegexMatch(v) => {
+				format!("{} REGEXP {}", col_name, p.bind(s(v)))
+			}
+			Cmp::RegexNotMatch(v) => {
+				format!("{} NOT REGEXP {}", col_name, p.bind(s(v)))
+			}
+			Cmp::Contains(v) => match self.contains_path(&c.column, v, false) {
+				ContainsPath::Match => {
+					format!("MATCH({},{})", col_name, p.bind(s(v)))
+				}
+				ContainsPath::Phrase => {
+					let v = format!("{}:\"{}\"", col_name, v);
+					format!("query({})", p.bind(s(&v)))
+				}
+				ContainsPath::Like => {
+					let v = format!("%{}%", v);
+					format!("{} LIKE {}", col_name, p.bind(s(&v)))
+				}
+			},
+			Cmp::NotContains(v) => match self.contains_path(&c.column, v, true)
+			{
+				ContainsPath::Match => {
+					format!("NOT MATCH({},{})", col_name, p.bind(s(v)))
+				}
+				_ => {
+					let v = format!("%{}%", v);
+					format!("{} NOT LIKE {}", col_name, p.bind(s(&v)))
+				}
+			},
+		}
+	}
+
+	fn convert_timing_params(
+		&self,
+		ts_key: &str,
+		o: &OrdType,
+		t: &NaiveDateTime,
+		p: &mut Params,
+	) -> String {
+		let ts =
+			p.bind(PlaceValue::String(databend_timestamp(t, self.table.tz)));
+		match o {
+			OrdType::LargerEqual => format!("{}>={}", ts_key, ts),
+			OrdType::SmallerEqual => format!("{}<={}", ts_key, ts),
+		}
 	}
 }
 
-fn convert_timing(ts_key: &str, o: &OrdType, t: &NaiveDateTime) -> String {
-	let ts = micro_time(t);
+// the driver we depend on can't send bind values to the server yet, so the
+// placeholders are filled in here. Literals are quoted properly, which is
+// the part string interpolation in convert_condition gets wrong
+pub fn bind_params(sql: &str, params: &Params) -> String {
+	let mut values = params.values().iter();
+	let mut out = String::with_capacity(sql.len());
+	let mut in_quote = false;
+	let mut escaped = false;
+	for c in sql.chars() {
+		match c {
+			// a backslash escapes the next char inside a literal, so \' in
+			// e.g. a quoted attribute key doesn't end it
+			_ if escaped => escaped = false,
+			'\\' if in_quote => escaped = true,
+			'\'' => in_quote = !in_quote,
+			'?' if !in_quote => {
+				if let Some(v) = values.next() {
+					out.push_str(&quote_literal(v));
+					continue;
+				}
+			}
+			_ => {}
+		}
+		out.push(c);
+	}
+	out
+}
+
+fn quote_literal(v: &PlaceValue) -> String {
+	match v {
+		PlaceValue::String(s) => format!("'{}'", escape_str(s)),
+		_ => v.to_string(),
+	}
+}
+
+// attribute values are VARIANT, which doesn't compare with a number
+// the way one expects, so the value is cast to the type of the literal.
+// TRY_CAST gives NULL for values of another type, and those don't match
+fn cast_variant(col: String, v: &PlaceValue) -> String {
+	let ty = match v {
+		PlaceValue::String(_) => return col,
+		PlaceValue::Integer(_) => "BIGINT",
+		PlaceValue::Float(_) => "DOUBLE",
+		PlaceValue::Bool(_) => "BOOLEAN",
+	};
+	format!("TRY_CAST({} AS {})", col, ty)
+}
+
+fn convert_timing(
+	ts_key: &str,
+	o: &OrdType,
+	t: &NaiveDateTime,
+	tz: Tz,
+) -> String {
+	let ts = databend_timestamp(t, tz);
 	match o {
 		OrdType::LargerEqual => {
 			format!("{}>='{}'", ts_key, ts)
@@ -76,10 +261,6 @@ fn convert_timing(ts_key: &str, o: &OrdType, t: &NaiveDateTime) -> String {
 	}
 }
 
-pub fn micro_time(t: &NaiveDateTime) -> String {
-	t.format("%Y-%m-%d %H:%M:%S%.6f").to_string()
-}
-
 #[derive(Clone)]
 pub struct DatabendTraceConverter {
 	table: TraceTable,
@@ -94,6 +275,18 @@ impl DatabendTraceConverter {
 impl QueryConverter for DatabendTraceConverter {
 	fn convert_condition(&self, c: &Condition) -> String {
 		let col_name = column_name(&self.table, &c.column);
+		let col_name = match (&c.column, &c.cmp) {
+			(
+				Column::Attributes(_) | Column::Resources(_),
+				Cmp::Equal(v)
+				| Cmp::NotEqual(v)
+				| Cmp::Larger(v)
+				| Cmp::LargerEqual(v)
+				| Cmp::Less(v)
+				| Cmp::LessEqual(v),
+			) => cast_variant(col_name, v),
+			_ => col_name,
+		};
 		match &c.cmp {
 			Cmp::Equal(v) => format!("{} = {}", col_name, v),
 			Cmp::NotEqual(v) => format!("{} != {}", col_name, v),
@@ -114,6 +307,143 @@ impl QueryConverter for DatabendTraceConverter {
 		o: &OrdType,
 		t: &NaiveDateTime,
 	) -> String {
-		convert_timing(ts_key, o, t)
+		convert_timing(ts_key, o, t, self.table.tz)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use pretty_assertions::assert_eq;
+
+	#[test]
+	fn test_bind_params() {
+		let mut p = Params::default();
+		let sql = format!(
+			"SELECT * FROM logs WHERE resources['a?'] = {} AND level = {}",
+			p.bind(PlaceValue::String(r"it's \d".to_string())),
+			p.bind(PlaceValue::Integer(3)),
+		);
+		assert_eq!(
+			bind_params(&sql, &p),
+			r"SELECT * FROM logs WHERE resources['a?'] = 'it\'s \\d' AND level = 3"
+		);
+		let mut p = Params::default();
+		let sql = format!(
+			r"SELECT * FROM logs WHERE attributes['it\'s?\\'] = {}",
+			p.bind(PlaceValue::Integer(1)),
+		);
+		assert_eq!(
+			bind_params(&sql, &p),
+			r"SELECT * FROM logs WHERE attributes['it\'s?\\'] = 1"
+		);
+	}
+
+	#[test]
+	fn test_params_only_depend_on_shape() {
+		let conv = DatabendLogConverter::new(LogTable::default());
+		let cond = |v: &str| Condition {
+			column: Column::Message,
+			cmp: Cmp::Contains(v.to_string()),
+		};
+		let (mut p1, mut p2) = (Params::default(), Params::default());
+		let a = conv.convert_condition_params(&cond("foo"), &mut p1);
+		let b = conv.convert_condition_params(&cond("bar"), &mut p2);
+		assert_eq!(a, b);
+		assert_eq!(a, "message LIKE ?");
+		assert_eq!(p1.values(), &[PlaceValue::String("%foo%".to_string())]);
+	}
+
+	#[test]
+	fn test_body_json_path() {
+		let path = Column::BodyJson(r"it's.a\b".to_string());
+		assert_eq!(
+			column_name(&LogTable::default(), &path),
+			r"JSON_EXTRACT_PATH_TEXT(message,'it\'s.a\\b')"
+		);
+	}
+
+	#[test]
+	fn test_contains_path() {
+		let conv = DatabendLogConverter::new(LogTable {
+			use_inverted_index: true,
+			min_token_len: 3,
+			..Default::default()
+		});
+		let cond = |column, v: &str| {
+			conv.convert_condition(&Condition {
+				column,
+				cmp: Cmp::Contains(v.to_string()),
+			})
+		};
+		assert_eq!(
+			cond(Column::Message, "timeout"),
+			"MATCH(message,'timeout')"
+		);
+		assert_eq!(
+			cond(Column::Message, "read timeout"),
+			r#"query('message:"read timeout"')"#
+		);
+		// too short, punctuation and not the indexed column
+		assert_eq!(cond(Column::Message, "io"), "message LIKE '%io%'");
+		assert_eq!(
+			cond(Column::Message, "status=500"),
+			"message LIKE '%status=500%'"
+		);
+		assert_eq!(
+			cond(Column::Raw("app".to_string()), "camp"),
+			"app LIKE '%camp%'"
+		);
+		let mut p = Params::default();
+		let got = conv.convert_condition_params(
+			&Condition {
+				column: Column::Message,
+				cmp: Cmp::NotContains("read timeout".to_string()),
+			},
+			&mut p,
+		);
+		assert_eq!(got, "message NOT LIKE ?");
+	}
+
+	#[test]
+	fn test_quoted_attribute_key() {
+		let conv = DatabendTraceConverter::new(TraceTable::default());
+		let cond = Condition {
+			column: Column::Attributes("it's".to_string()),
+			cmp: Cmp::Equal(PlaceValue::String("1".to_string())),
+		};
+		assert_eq!(
+			conv.convert_condition(&cond),
+			r"span_attributes['it\'s'] = '1'"
+		);
+	}
+
+	#[test]
+	fn test_cast_attribute_value() {
+		let conv = DatabendTraceConverter::new(TraceTable::default());
+		let cond =
+			|column, cmp| conv.convert_condition(&Condition { column, cmp });
+		assert_eq!(
+			cond(
+				Column::Resources("ratio".to_string()),
+				Cmp::LessEqual(PlaceValue::Float(0.5.into()))
+			),
+			"TRY_CAST(resource_attributes['ratio'] AS DOUBLE) <= 0.5"
+		);
+		assert_eq!(
+			cond(
+				Column::Attributes("cached".to_string()),
+				Cmp::Equal(PlaceValue::Bool(true))
+			),
+			"TRY_CAST(span_attributes['cached'] AS BOOLEAN) = true"
+		);
+		// intrinsic columns have a type already
+		assert_eq!(
+			cond(
+				Column::Raw("duration".to_string()),
+				Cmp::Larger(PlaceValue::Integer(3))
+			),
+			"duration > 3"
+		);
 	}
 }
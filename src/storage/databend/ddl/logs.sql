@@ -0,0 +1,12 @@
+CREATE TABLE IF NOT EXISTS {table} (
+	service_name STRING NOT NULL,
+	trace_id STRING,
+	span_id STRING,
+	level TINYINT,
+	resource_attributes MAP(STRING, STRING) NOT NULL,
+	scope_name STRING,
+	scope_attributes MAP(STRING, STRING),
+	log_attributes MAP(STRING, STRING) NOT NULL,
+	message STRING NOT NULL,
+	ts TIMESTAMP NOT NULL
+) ENGINE = FUSE CLUSTER BY (TO_YYYYMMDDHH(ts), service_name)
@@ -0,0 +1,19 @@
+CREATE TABLE IF NOT EXISTS {table} (
+	ts TIMESTAMP NOT NULL,
+	trace_id STRING NOT NULL,
+	span_id STRING NOT NULL,
+	parent_span_id STRING,
+	trace_state STRING NOT NULL,
+	span_name STRING NOT NULL,
+	span_kind TINYINT,
+	service_name STRING DEFAULT 'unknown',
+	resource_attributes MAP(STRING, VARIANT) NOT NULL,
+	scope_name STRING,
+	scope_version STRING,
+	span_attributes MAP(STRING, VARIANT),
+	duration BIGINT,
+	status_code INT32,
+	status_message STRING,
+	span_events VARIANT,
+	links VARIANT
+) ENGINE = FUSE CLUSTER BY (TO_YYYYMMDDHH(ts))
@@ -1,18 +1,26 @@
-use super::converter::DatabendLogConverter;
-use crate::storage::{log::*, *};
+use super::{
+	converter::{bind_params, column_name, DatabendLogConverter},
+	query_rows,
+};
+use crate::{
+	metrics::RowsInstrumentations,
+	storage::{log::*, *},
+};
 use anyhow::Result;
 use async_trait::async_trait;
 use chrono::NaiveDateTime;
-use common::LogLevel;
+use chrono_tz::Tz;
+use common::{level::LevelMapping, LogLevel, TimeRange};
 use databend_driver::{Connection, Row, TryFromRow};
-use logql::parser::{LogQuery, MetricQuery};
+use logql::parser::{LabelPair, LogQuery, MetricQuery, Operator};
 use sqlbuilder::builder::*;
 use sqlbuilder::{
 	builder::QueryPlan,
-	visit::{DefaultIRVisitor, LogQLVisitor},
+	visit::{DefaultIRVisitor, IRVisitor, LogQLVisitor},
 };
 use std::{collections::HashMap, time::Duration};
 use tokio_stream::StreamExt;
+use tracing::warn;
 
 const DEFAULT_STEP: Duration = Duration::from_secs(60);
 
@@ -20,6 +28,9 @@ const DEFAULT_STEP: Duration = Duration::from_secs(60);
 pub struct BendLogQuerier {
 	cli: Box<dyn Connection>,
 	schema: LogTable,
+	max_result_bytes: usize,
+	rows_metrics: RowsInstrumentations,
+	levels: LevelMapping,
 }
 
 impl BendLogQuerier {
@@ -27,10 +38,23 @@ impl BendLogQuerier {
 		Self {
 			cli,
 			schema: LogTable::default(),
+			max_result_bytes: usize::MAX,
+			rows_metrics: RowsInstrumentations::new("databend"),
+			levels: LevelMapping::default(),
 		}
 	}
-	pub fn with_inverted_index(&mut self, open: bool) {
+	pub fn with_inverted_index(&mut self, open: bool, min_token_len: usize) {
 		self.schema.use_inverted_index = open;
+		self.schema.min_token_len = min_token_len;
+	}
+	pub fn with_timezone(&mut self, tz: Tz) {
+		self.schema.tz = tz;
+	}
+	pub fn with_max_result_bytes(&mut self, max: usize) {
+		self.max_result_bytes = max;
+	}
+	pub fn with_levels(&mut self, levels: LevelMapping) {
+		self.levels = levels;
 	}
 }
 
@@ -41,14 +65,28 @@ impl LogStorage for BendLogQuerier {
 		q: &LogQuery,
 		opt: QueryLimits,
 	) -> Result<Vec<LogItem>> {
+		let max_rows = opt.limit.map_or(usize::MAX, |l| l as usize);
 		let sql = logql_to_sql(q, opt, &self.schema);
 		let mut logs = vec![];
-		let mut stream = self.cli.query_iter(&sql).await?;
+		let mut budget = RowBudget::new(max_rows, self.max_result_bytes);
+		let mut stream = query_rows(self.cli.as_ref(), &sql).await?;
+		// dropping the stream early stops fetching the remaining pages
 		while let Some(row) = stream.next().await {
-			let row = row?;
-			let item = row_into_logitem(row)?;
+			let item = row_into_logitem(row?, self.schema.tz, &self.levels)?;
+			if !budget.take(logitem_size(&item)) {
+				warn!(
+					"databend result exceeds {} bytes, truncated at {} rows",
+					self.max_result_bytes,
+					logs.len()
+				);
+				break;
+			}
 			logs.push(item);
+			if budget.rows_full() {
+				break;
+			}
 		}
+		self.rows_metrics.record(budget.scanned, logs.len() as u64);
 		Ok(logs)
 	}
 	async fn query_metrics(
@@ -59,31 +97,104 @@ impl LogStorage for BendLogQuerier {
 		let v = LogQLVisitor::new(DefaultIRVisitor {});
 		let selection = v.visit(&q.log_query);
 		let qp = new_from_metricquery(opt, self.schema.clone(), selection);
-		let sql = qp.as_sql();
-		let mut stream = self.cli.query_iter(&sql).await?;
+		let (sql, params) = qp.as_sql_with_params();
+		let sql = bind_params(&sql, &params);
+		let mut stream = query_rows(self.cli.as_ref(), &sql).await?;
 		let mut metrics = vec![];
 		while let Some(row) = stream.next().await {
 			let row = row?;
 			let (level, nts, total): (u32, NaiveDateTime, u64) =
 				row.try_into().map_err(|e: String| anyhow::anyhow!(e))?;
-			metrics.push(MetricItem {
-				level: level.into(),
+			metrics.push(MetricItem::by_level(
+				level.into(),
 				total,
-				ts: nts.and_utc(),
-			});
+				timelit::databend_to_utc(nts, self.schema.tz),
+			));
 		}
 		Ok(metrics)
 	}
 	async fn labels(&self, _: QueryLimits) -> Result<Vec<String>> {
 		Ok(vec![])
 	}
+	// there's no label store for databend, values are read from the table
 	async fn label_values(
 		&self,
-		_: &str,
-		_: QueryLimits,
+		label: &str,
+		filter: Option<&ValueFilter>,
+		opt: QueryLimits,
 	) -> Result<Vec<String>> {
-		Ok(vec![])
+		let sql = label_values_sql(label, filter, &opt.range, &self.schema);
+		let mut stream = query_rows(self.cli.as_ref(), &sql).await?;
+		let mut values = vec![];
+		while let Some(row) = stream.next().await {
+			let (v,): (String,) =
+				row?.try_into().map_err(|e: String| anyhow::anyhow!(e))?;
+			values.push(v);
+		}
+		Ok(values)
+	}
+	async fn delete(&self, q: &LogQuery, range: TimeRange) -> Result<()> {
+		self.cli.exec(&delete_sql(q, &range, &self.schema)).await?;
+		Ok(())
 	}
+	fn can_delete(&self) -> bool {
+		true
+	}
+	// query_metrics counts by level only
+	fn capabilities(&self) -> Capabilities {
+		Capabilities {
+			group_by_labels: false,
+			..Default::default()
+		}
+	}
+}
+
+const MAX_LABEL_VALUES: u32 = 1000;
+
+fn label_values_sql(
+	label: &str,
+	filter: Option<&ValueFilter>,
+	range: &TimeRange,
+	schema: &LogTable,
+) -> String {
+	let c = DefaultIRVisitor {}.label_pair(&LabelPair {
+		label: label.to_string(),
+		op: Operator::Equal,
+		value: String::new(),
+	});
+	let col = column_name(schema, &c.column);
+	let converter = DatabendLogConverter::new(schema.clone());
+	let mut conds: Vec<String> = time_range_into_timing(range)
+		.iter()
+		.map(|(o, t)| converter.convert_timing(schema.ts_key(), o, t))
+		.collect();
+	if let Some(f) = filter {
+		conds.push(format!("{} LIKE '{}'", col, escape_str(&f.like_pattern())));
+	}
+	let mut sql = format!("SELECT DISTINCT {} FROM {}", col, schema.table());
+	if !conds.is_empty() {
+		sql.push_str(&format!(" WHERE {}", conds.join(" AND ")));
+	}
+	format!("{} LIMIT {}", sql, MAX_LABEL_VALUES)
+}
+
+fn delete_sql(q: &LogQuery, range: &TimeRange, schema: &LogTable) -> String {
+	let v = LogQLVisitor::new(DefaultIRVisitor {});
+	let qp = QueryPlan::new(
+		DatabendLogConverter::new(schema.clone()),
+		schema.clone(),
+		vec![],
+		v.visit(q),
+		vec![],
+		vec![],
+		time_range_into_timing(range),
+		None,
+	);
+	let (filter, params) = qp.where_sql_with_params();
+	bind_params(
+		&format!("DELETE FROM {} WHERE {}", schema.table(), filter),
+		&params,
+	)
 }
 
 fn logql_to_sql(
@@ -103,7 +214,8 @@ fn logql_to_sql(
 		time_range_into_timing(&limits.range),
 		limits.limit,
 	);
-	qp.as_sql()
+	let (sql, params) = qp.as_sql_with_params();
+	bind_params(&sql, &params)
 }
 
 #[derive(Debug, Default, Clone, TryFromRow)]
@@ -120,22 +232,84 @@ struct LogRaw {
 	pub log_attributes: HashMap<String, String>,
 }
 
-fn row_into_logitem(row: Row) -> Result<LogItem> {
+fn row_into_logitem(
+	row: Row,
+	tz: Tz,
+	levels: &LevelMapping,
+) -> Result<LogItem> {
 	let row: LogRaw = row.try_into().map_err(|e: String| anyhow::anyhow!(e))?;
+	let level = levels
+		.detect("", row.level, &row.message)
+		.unwrap_or(LogLevel::Trace);
 	Ok(LogItem {
-		ts: row.ts.and_utc(),
+		ts: timelit::databend_to_utc(row.ts, tz),
 		trace_id: row.trace_id,
 		span_id: row.span_id,
-		level: LogLevel::from(row.level).into(),
+		level: level.into(),
 		service_name: row.service_name,
 		message: row.message,
 		resource_attributes: row.resource_attributes,
 		scope_name: row.scope_name,
 		scope_attributes: row.scope_attributes,
 		log_attributes: row.log_attributes,
+		source: None,
 	})
 }
 
+// RowBudget tracks how many rows and bytes have been decoded
+// so that query_stream can stop pulling from the driver early
+struct RowBudget {
+	max_rows: usize,
+	max_bytes: usize,
+	rows: usize,
+	bytes: usize,
+	scanned: u64,
+}
+
+impl RowBudget {
+	fn new(max_rows: usize, max_bytes: usize) -> Self {
+		Self {
+			max_rows,
+			max_bytes,
+			rows: 0,
+			bytes: 0,
+			scanned: 0,
+		}
+	}
+	// take returns false if accepting a row of `size` bytes would
+	// exceed the byte budget, the first row is always accepted
+	fn take(&mut self, size: usize) -> bool {
+		self.scanned += 1;
+		let bytes = self.bytes.saturating_add(size);
+		if self.rows > 0 && bytes > self.max_bytes {
+			return false;
+		}
+		self.rows += 1;
+		self.bytes = bytes;
+		true
+	}
+	fn rows_full(&self) -> bool {
+		self.rows >= self.max_rows
+	}
+}
+
+fn map_size(m: &HashMap<String, String>) -> usize {
+	m.iter().map(|(k, v)| k.len() + v.len()).sum()
+}
+
+// approximate heap size of a decoded row
+fn logitem_size(item: &LogItem) -> usize {
+	item.trace_id.len()
+		+ item.span_id.len()
+		+ item.level.len()
+		+ item.service_name.len()
+		+ item.message.len()
+		+ item.scope_name.len()
+		+ map_size(&item.resource_attributes)
+		+ map_size(&item.scope_attributes)
+		+ map_size(&item.log_attributes)
+}
+
 /*
 	CREATE TABLE logs (
 		service_name STRING NOT NULL,
@@ -151,9 +325,26 @@ fn row_into_logitem(row: Row) -> Result<LogItem> {
 	) ENGINE=FUSE CLUSTER BY(TO_YYYYMMDDHH(ts), server);
 	CREATE INVERTED INDEX message_idx ON logs(message);
 */
+// column and its type family, checked at startup
+pub(super) static LOG_TABLE_COLS: [(&str, &str); 10] = [
+	("service_name", "string"),
+	("trace_id", "string"),
+	("span_id", "string"),
+	("level", "int"),
+	("resource_attributes", "map"),
+	("scope_name", "string"),
+	("scope_attributes", "map"),
+	("log_attributes", "map"),
+	("message", "string"),
+	("ts", "timestamp"),
+];
+
 #[derive(Debug, Clone)]
 pub(crate) struct LogTable {
 	pub use_inverted_index: bool,
+	// shorter filter tokens aren't in the index, they go through LIKE
+	pub min_token_len: usize,
+	pub tz: Tz,
 	msg_key: &'static str,
 	ts_key: &'static str,
 	table: &'static str,
@@ -165,6 +356,8 @@ impl Default for LogTable {
 	fn default() -> Self {
 		Self {
 			use_inverted_index: false,
+			min_token_len: 1,
+			tz: Tz::UTC,
 			msg_key: "message",
 			ts_key: "timestamp",
 			table: "logs",
@@ -239,7 +432,7 @@ fn new_from_metricquery(
 		grouping,
 		direction_to_sorting(&limits.direction, &schema, true),
 		time_range_into_timing(&limits.range),
-		limits.limit,
+		None,
 	)
 }
 
@@ -326,12 +519,46 @@ fn get_round_func(d: Duration) -> &'static str {
 
 #[cfg(test)]
 mod tests {
-	use super::{super::converter::micro_time, *};
+	use super::*;
+	use crate::storage::timelit::databend_timestamp;
 	use chrono::Local;
 	use pretty_assertions::assert_eq;
 	use sqlparser::{dialect::AnsiDialect, parser::Parser};
 	use std::{fs, path::PathBuf};
 
+	#[test]
+	fn test_label_values_sql() {
+		let filter = ValueFilter::parse("api_*");
+		assert_eq!(
+			label_values_sql(
+				"resources_host.name",
+				filter.as_ref(),
+				&TimeRange::default(),
+				&LogTable::default()
+			),
+			"SELECT DISTINCT resources['host.name'] FROM logs \
+			 WHERE resources['host.name'] LIKE 'api\\\\_%' LIMIT 1000"
+		);
+	}
+
+	#[test]
+	fn test_row_budget() {
+		let mut b = RowBudget::new(3, 10);
+		assert!(b.take(4));
+		assert!(b.take(4));
+		assert!(!b.take(4));
+		assert_eq!(2, b.rows);
+		assert_eq!(3, b.scanned);
+
+		let mut b = RowBudget::new(2, 10);
+		// a single oversized row is still returned
+		assert!(b.take(100));
+		assert!(!b.rows_full());
+		let mut b = RowBudget::new(1, 10);
+		assert!(b.take(1));
+		assert!(b.rows_full());
+	}
+
 	#[test]
 	fn test_truncate_ts() {
 		let test_cases = [
@@ -393,6 +620,8 @@ mod tests {
 		let now = Local::now().naive_local();
 		let tb = LogTable {
 			use_inverted_index: false,
+			min_token_len: 1,
+			tz: Tz::UTC,
 			msg_key: "message",
 			ts_key: "ts",
 			table: "logs",
@@ -420,7 +649,7 @@ mod tests {
 		);
 		assert_eq!(
 			plan.as_sql(),
-			format!("SELECT msg,ts FROM logs WHERE (app = 'camp' AND message LIKE '%error%') AND ts>='{}' GROUP BY app,server ORDER BY ts ASC LIMIT 10", micro_time(&now))
+			format!("SELECT msg,ts FROM logs WHERE (app = 'camp' AND message LIKE '%error%') AND ts>='{}' GROUP BY app,server ORDER BY ts ASC LIMIT 10", databend_timestamp(&now, Tz::UTC))
 		);
 	}
 	#[test]
@@ -429,6 +658,8 @@ mod tests {
 		let end = now + Duration::from_secs(3600);
 		let tb = LogTable {
 			use_inverted_index: true,
+			min_token_len: 1,
+			tz: Tz::UTC,
 			msg_key: "message",
 			ts_key: "ts",
 			table: "log",
@@ -460,7 +691,7 @@ mod tests {
 		);
 		assert_eq!(
 			plan.as_sql(),
-			format!("SELECT level,TO_START_OF_HOUR(ts) as nts,count(*) as total FROM log WHERE (app != 'camp' AND MATCH(message,'error')) AND ts>='{}' AND ts<='{}' GROUP BY level,nts ORDER BY nts DESC LIMIT 1000",micro_time(&now), micro_time(&end))
+			format!("SELECT level,TO_START_OF_HOUR(ts) as nts,count(*) as total FROM log WHERE (app != 'camp' AND MATCH(message,'error')) AND ts>='{}' AND ts<='{}' GROUP BY level,nts ORDER BY nts DESC LIMIT 1000",databend_timestamp(&now, Tz::UTC), databend_timestamp(&end, Tz::UTC))
 		);
 	}
 
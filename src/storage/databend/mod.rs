@@ -1,32 +1,301 @@
-use super::{log::LogStorage, trace::TraceStorage};
-use crate::config::Databend;
+use super::{
+	explain,
+	log::LogStorage,
+	registry::{mismatch, Creating, Registry},
+	schema_check::{diff, report},
+	stats,
+	trace::TraceStorage,
+};
+use crate::config::{DataSource, Databend, Retention, SchemaCheck};
 use anyhow::Result;
-use databend_driver::{Client, Connection};
+use chrono_tz::Tz;
+use databend_driver::{Client, Connection, Row, RowWithStats};
+use sqlbuilder::builder::TableSchema;
+use std::{
+	pin::Pin,
+	time::{Duration, Instant},
+};
+use tokio_stream::{Stream, StreamExt};
+use tracing::warn;
 
 pub(crate) mod converter;
 pub mod log;
 pub mod trace;
 
+static LOGS_DDL: &str = include_str!("ddl/logs.sql");
+static SPANS_DDL: &str = include_str!("ddl/spans.sql");
+
+pub(super) fn register(r: &mut Registry) {
+	r.log("databend", log_factory);
+	r.trace("databend", trace_factory);
+}
+
+fn log_factory(d: DataSource) -> Creating<dyn LogStorage> {
+	match d {
+		DataSource::Databend(cfg) => Box::pin(new_log_source(cfg)),
+		d => mismatch("databend", &d),
+	}
+}
+
+fn trace_factory(d: DataSource) -> Creating<dyn TraceStorage> {
+	match d {
+		DataSource::Databend(cfg) => Box::pin(new_trace_source(cfg)),
+		d => mismatch("databend", &d),
+	}
+}
+
 pub async fn new_log_source(cfg: Databend) -> Result<Box<dyn LogStorage>> {
 	let use_inv_idx = cfg.inverted_index;
+	let min_token_len = cfg.inverted_index_min_token_len;
+	let max_result_bytes = cfg.max_result_bytes;
+	let schema_check = cfg.schema_check;
+	let bootstrap = cfg.bootstrap;
+	let retention = cfg.retention.clone();
+	let tz = cfg.timezone;
+	let timeout = cfg.query_timeout;
+	let levels = cfg.levels.clone();
 	let cli = Client::try_from(cfg)?;
 	let conn = cli.get_conn().await?;
-	init_log_source(conn.clone()).await?;
+	init_log_source(conn.clone(), tz).await?;
+	set_query_timeout(conn.as_ref(), timeout).await?;
+	let table = log::LogTable::default();
+	if bootstrap {
+		conn.exec(&LOGS_DDL.replace("{table}", table.table()))
+			.await?;
+		if use_inv_idx {
+			conn.exec(&format!(
+				"CREATE INVERTED INDEX IF NOT EXISTS message_idx ON {}(message)",
+				table.table()
+			))
+			.await?;
+		}
+	}
+	check_table(
+		conn.as_ref(),
+		table.table(),
+		&log::LOG_TABLE_COLS,
+		schema_check,
+	)
+	.await?;
+	if let Some(r) = retention {
+		spawn_retention(conn.clone(), table.table(), table.ts_key(), r);
+	}
 	let mut q = log::BendLogQuerier::new(conn);
-	q.with_inverted_index(use_inv_idx);
+	q.with_inverted_index(use_inv_idx, min_token_len);
+	q.with_max_result_bytes(max_result_bytes);
+	q.with_timezone(tz);
+	q.with_levels(levels);
 	Ok(Box::new(q))
 }
 
 // when query volume of logs overtime, set numeric_cast_option = 'truncating'
 // to ensure time column is truncated to integer(floor)
-async fn init_log_source(conn: Box<dyn Connection>) -> Result<()> {
+async fn init_log_source(conn: Box<dyn Connection>, tz: Tz) -> Result<()> {
 	conn.exec("SET numeric_cast_option = 'truncating';").await?;
+	set_timezone(conn.as_ref(), tz).await
+}
+
+// pin the session timezone instead of relying on the server default, the
+// converters write and read timestamps in it
+async fn set_timezone(conn: &dyn Connection, tz: Tz) -> Result<()> {
+	conn.exec(&format!("SET timezone = '{}';", tz.name()))
+		.await?;
+	Ok(())
+}
+
+// databend stops a query running longer than max_execution_time itself,
+// so one the server already gave up on doesn't keep running
+async fn set_query_timeout(
+	conn: &dyn Connection,
+	timeout: Option<Duration>,
+) -> Result<()> {
+	let Some(t) = timeout else {
+		return Ok(());
+	};
+	conn.exec(&format!("SET max_execution_time = {};", t.as_secs().max(1)))
+		.await?;
 	Ok(())
 }
 
 pub async fn new_trace_source(cfg: Databend) -> Result<Box<dyn TraceStorage>> {
+	let dedup_spans = cfg.dedup_spans;
+	let schema_check = cfg.schema_check;
+	let bootstrap = cfg.bootstrap;
+	let retention = cfg.retention.clone();
+	let tz = cfg.timezone;
+	let timeout = cfg.query_timeout;
 	let cli = Client::try_from(cfg)?;
 	let conn = cli.get_conn().await?;
-	let q = trace::BendTraceQuerier::new(conn);
+	set_timezone(conn.as_ref(), tz).await?;
+	set_query_timeout(conn.as_ref(), timeout).await?;
+	let table = trace::TraceTable::default();
+	if bootstrap {
+		conn.exec(&SPANS_DDL.replace("{table}", table.table()))
+			.await?;
+	}
+	check_table(
+		conn.as_ref(),
+		table.table(),
+		&trace::TRACE_TABLE_COLS,
+		schema_check,
+	)
+	.await?;
+	if let Some(r) = retention {
+		spawn_retention(conn.clone(), table.table(), table.ts_key(), r);
+	}
+	let mut q = trace::BendTraceQuerier::new(conn);
+	q.with_timezone(tz);
+	q.with_dedup_spans(dedup_spans);
 	Ok(Box::new(q))
 }
+
+const DEFAULT_MAINTENANCE_INTERVAL: Duration = Duration::from_secs(3600);
+
+// databend has no ttl, expired rows are deleted on a timer and the
+// files they lived in purged afterwards
+fn expire_sql(table: &str, ts: &str, ttl: Duration) -> String {
+	format!(
+		"DELETE FROM {} WHERE {} < SUBTRACT_SECONDS(NOW(), {})",
+		table,
+		ts,
+		ttl.as_secs()
+	)
+}
+
+fn spawn_retention(
+	conn: Box<dyn Connection>,
+	table: &str,
+	ts: &str,
+	r: Retention,
+) {
+	let expire = expire_sql(table, ts, r.ttl);
+	let purge = format!("OPTIMIZE TABLE {} PURGE", table);
+	let interval = r
+		.maintenance_interval
+		.unwrap_or(DEFAULT_MAINTENANCE_INTERVAL);
+	tokio::spawn(async move {
+		let mut ticker = tokio::time::interval(interval);
+		loop {
+			ticker.tick().await;
+			for sql in [&expire, &purge] {
+				if let Err(e) = conn.exec(sql).await {
+					warn!("fail to apply retention: {}: {}", sql, e);
+					break;
+				}
+			}
+		}
+	});
+}
+
+// family groups databend types that decode the same way
+fn family(t: &str) -> String {
+	let t = t.trim().to_lowercase();
+	let name = t.split(['(', ' ']).next().unwrap_or_default();
+	match name {
+		"varchar" | "string" => "string",
+		n if n.contains("int") => "int",
+		n => n,
+	}
+	.to_string()
+}
+
+async fn check_table(
+	conn: &dyn Connection,
+	table: &str,
+	expected: &[(&str, &str)],
+	mode: SchemaCheck,
+) -> Result<()> {
+	if mode == SchemaCheck::Off {
+		return Ok(());
+	}
+	let rows = match conn.query_all(&format!("DESC {}", table)).await {
+		Ok(rows) => rows,
+		Err(e) if mode == SchemaCheck::Warn => {
+			warn!("fail to describe {}: {}", table, e);
+			return Ok(());
+		}
+		Err(e) => return Err(e.into()),
+	};
+	// Field, Type, Null, Default, Extra
+	let mut actual = vec![];
+	for row in rows {
+		let (field, typ, _, _, _): (String, String, String, String, String) =
+			row.try_into().map_err(|e: String| anyhow::anyhow!(e))?;
+		actual.push((field, typ));
+	}
+	report(table, &diff(expected, &actual, family), mode)
+}
+
+// query_rows hides the progress of the query from the caller, progress is
+// cumulative so only the growth since the last one is recorded
+// An explained query only records the plan and yields no rows
+async fn query_rows(
+	cli: &dyn Connection,
+	sql: &str,
+) -> databend_driver::Result<RowStream> {
+	if explain::current() == Some(explain::ExplainKind::Sql) {
+		explain::record(sql, String::new());
+		return Ok(Box::pin(tokio_stream::empty()));
+	}
+	if explain::current().is_some() {
+		let rows = cli.query_all(&format!("EXPLAIN {}", sql)).await?;
+		let plan = rows
+			.into_iter()
+			.filter_map(|r| <(String,)>::try_from(r).ok())
+			.map(|(line,)| line)
+			.collect::<Vec<_>>()
+			.join("\n");
+		explain::record(sql, plan);
+		return Ok(Box::pin(tokio_stream::empty()));
+	}
+	let mut seen = (0, 0);
+	let start = Instant::now();
+	let stream = cli.query_iter_ext(sql).await?;
+	// rows are paged in lazily, so this is the time to the first page
+	stats::record_sql(sql, start.elapsed());
+	Ok(Box::pin(stream.filter_map(move |r| match r {
+		Ok(RowWithStats::Row(row)) => Some(Ok(row)),
+		Ok(RowWithStats::Stats(s)) => {
+			let (rows, bytes) = (s.read_rows as u64, s.read_bytes as u64);
+			stats::record(
+				rows.saturating_sub(seen.0),
+				bytes.saturating_sub(seen.1),
+			);
+			seen = (rows.max(seen.0), bytes.max(seen.1));
+			None
+		}
+		Err(e) => Some(Err(e)),
+	})))
+}
+
+type RowStream =
+	Pin<Box<dyn Stream<Item = databend_driver::Result<Row>> + Send>>;
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use pretty_assertions::assert_eq;
+
+	#[test]
+	fn test_expire_sql() {
+		assert_eq!(
+			expire_sql("logs", "timestamp", Duration::from_secs(7 * 86400)),
+			"DELETE FROM logs WHERE timestamp < SUBTRACT_SECONDS(NOW(), 604800)"
+		);
+	}
+
+	#[test]
+	fn test_family() {
+		let cases = [
+			("VARCHAR", "string"),
+			("TINYINT UNSIGNED", "int"),
+			("BIGINT", "int"),
+			("MAP(STRING, STRING)", "map"),
+			("TIMESTAMP", "timestamp"),
+			("VARIANT", "variant"),
+		];
+		for (t, want) in cases {
+			assert_eq!(family(t), want, "{}", t);
+		}
+	}
+}
@@ -1,7 +1,8 @@
 use crate::storage::{trace::*, *};
-use anyhow::Result;
+use anyhow::{bail, Result};
 use async_trait::async_trait;
-use databend::converter::DatabendTraceConverter;
+use chrono_tz::Tz;
+use databend::{converter::DatabendTraceConverter, query_rows};
 use databend_driver::{Connection, Row, TryFromRow};
 use itertools::Itertools;
 use sqlbuilder::builder::*;
@@ -13,6 +14,7 @@ use traceql::*;
 pub struct BendTraceQuerier {
 	cli: Box<dyn Connection>,
 	schema: TraceTable,
+	dedup_spans: bool,
 }
 
 impl BendTraceQuerier {
@@ -20,8 +22,28 @@ impl BendTraceQuerier {
 		Self {
 			cli,
 			schema: TraceTable::default(),
+			dedup_spans: false,
 		}
 	}
+	pub fn with_timezone(&mut self, tz: Tz) {
+		self.schema.tz = tz;
+	}
+	pub fn with_dedup_spans(&mut self, dedup: bool) {
+		self.dedup_spans = dedup;
+	}
+	async fn spans(&self, sql: &str) -> Result<Vec<SpanItem>> {
+		let mut spans = vec![];
+		let mut stream = query_rows(self.cli.as_ref(), sql).await?;
+		while let Some(row) = stream.next().await {
+			let row = row?;
+			let item = row_into_spanitem(row, self.schema.tz)?;
+			spans.push(item);
+		}
+		if self.dedup_spans {
+			spans = dedup_spans(spans);
+		}
+		Ok(spans)
+	}
 }
 
 #[async_trait]
@@ -31,55 +53,180 @@ impl TraceStorage for BendTraceQuerier {
 		trace_id: &str,
 		opt: QueryLimits,
 	) -> Result<Vec<SpanItem>> {
-		let mut qp = new_qp(&opt, self.schema.clone());
-		let conds = vec![Condition {
-			column: Column::TraceID,
-			cmp: Cmp::Equal(PlaceValue::String(trace_id.to_string())),
-		}];
-		let selection = Some(conditions_into_selection(conds.as_slice()));
-		qp.selection = selection;
-		let sql = qp.as_sql();
+		self.query_traces(&[trace_id.to_string()], opt).await
+	}
+
+	async fn query_traces(
+		&self,
+		trace_ids: &[String],
+		opt: QueryLimits,
+	) -> Result<Vec<SpanItem>> {
+		if trace_ids.is_empty() {
+			return Ok(vec![]);
+		}
+		let sql = traces_sql(trace_ids, &opt, &self.schema, false);
+		self.spans(&sql).await
+	}
+	async fn trace_roots(
+		&self,
+		trace_ids: &[String],
+		opt: QueryLimits,
+	) -> Result<Vec<SpanItem>> {
+		if trace_ids.is_empty() {
+			return Ok(vec![]);
+		}
+		let sql = traces_sql(trace_ids, &opt, &self.schema, true);
+		self.spans(&sql).await
+	}
+
+	async fn search_span(
+		&self,
+		expr: &Expression,
+		opt: QueryLimits,
+	) -> Result<Vec<SpanItem>> {
+		let sql = search_span_sql(expr, &opt, &self.schema)?;
 		let mut spans = vec![];
-		let mut stream = self.cli.query_iter(&sql).await?;
+		let mut stream = query_rows(self.cli.as_ref(), &sql).await?;
 		while let Some(row) = stream.next().await {
 			let row = row?;
-			let item = row_into_spanitem(row)?;
+			let item = row_into_spanitem(row, self.schema.tz)?;
 			spans.push(item);
 		}
 		Ok(spans)
 	}
-
-	async fn search_span(
+	async fn search_trace_ids(
 		&self,
 		expr: &Expression,
 		opt: QueryLimits,
+	) -> Result<Vec<String>> {
+		let sql = trace_ids_sql(expr, &opt, &self.schema)?;
+		let mut ids = vec![];
+		let mut stream = query_rows(self.cli.as_ref(), &sql).await?;
+		while let Some(row) = stream.next().await {
+			let (id,): (String,) =
+				row?.try_into().map_err(|e: String| anyhow::anyhow!(e))?;
+			ids.push(id);
+		}
+		Ok(ids)
+	}
+	async fn search_span_in(
+		&self,
+		expr: &Expression,
+		trace_ids: &[String],
+		opt: QueryLimits,
 	) -> Result<Vec<SpanItem>> {
-		let sql = search_span_sql(expr, &opt, &self.schema);
+		if trace_ids.is_empty() {
+			return Ok(vec![]);
+		}
+		let sql = search_span_in_sql(expr, trace_ids, &opt, &self.schema)?;
 		let mut spans = vec![];
-		let mut stream = self.cli.query_iter(&sql).await?;
+		let mut stream = query_rows(self.cli.as_ref(), &sql).await?;
 		while let Some(row) = stream.next().await {
 			let row = row?;
-			let item = row_into_spanitem(row)?;
+			let item = row_into_spanitem(row, self.schema.tz)?;
 			spans.push(item);
 		}
 		Ok(spans)
 	}
+	fn capabilities(&self) -> Capabilities {
+		Capabilities {
+			structural: false,
+			trace_ids_first: true,
+			..Default::default()
+		}
+	}
+}
+
+// traces_sql reads the spans of trace_ids, or only their roots
+fn traces_sql(
+	trace_ids: &[String],
+	opt: &QueryLimits,
+	schema: &TraceTable,
+	roots: bool,
+) -> String {
+	let mut qp = new_qp(opt, schema.clone());
+	// any of the forms the ids may be stored in
+	let traces = trace_ids
+		.iter()
+		.flat_map(|id| trace_id_forms(id))
+		.map(|id| {
+			Selection::Unit(Condition {
+				column: Column::TraceID,
+				cmp: Cmp::Equal(PlaceValue::String(id)),
+			})
+		})
+		.reduce(|l, r| Selection::LogicalOr(Box::new(l), Box::new(r)));
+	qp.selection = match (traces, roots) {
+		(Some(traces), true) => Some(Selection::LogicalAnd(
+			Box::new(traces),
+			Box::new(Selection::Unit(Condition {
+				column: Column::Raw("parent_span_id".to_string()),
+				cmp: Cmp::Equal(PlaceValue::String("".to_string())),
+			})),
+		)),
+		(traces, _) => traces,
+	};
+	qp.as_sql()
 }
 
 fn search_span_sql(
 	expr: &Expression,
 	opt: &QueryLimits,
 	schema: &TraceTable,
-) -> String {
+) -> Result<String> {
 	let mut spans = vec![];
-	let subq = new_from_expression(expr, opt, schema, &mut spans);
+	let subq = new_from_expression(expr, opt, schema, &mut spans)?;
 	let complex = ComplexQuery {
 		schema: schema.clone(),
 		span_selections: spans,
 		trace_selections: subq,
 		limits: opt.clone(),
+		trace_ids: vec![],
+	};
+	Ok(complex.as_sql())
+}
+
+// trace_ids_sql picks the traces of every span the search finds, newest
+// first, the limit is on the traces instead of the spans
+fn trace_ids_sql(
+	expr: &Expression,
+	opt: &QueryLimits,
+	schema: &TraceTable,
+) -> Result<String> {
+	let all = QueryLimits {
+		limit: None,
+		..opt.clone()
+	};
+	let mut sql = format!(
+		"SELECT trace_id FROM ({}) GROUP BY trace_id ORDER BY max(ts) DESC",
+		search_span_sql(expr, &all, schema)?
+	);
+	if let Some(limit) = opt.limit {
+		sql.push_str(&format!(" LIMIT {}", limit));
+	}
+	Ok(sql)
+}
+
+// every span of the given traces the search finds
+fn search_span_in_sql(
+	expr: &Expression,
+	trace_ids: &[String],
+	opt: &QueryLimits,
+	schema: &TraceTable,
+) -> Result<String> {
+	let mut spans = vec![];
+	let subq = new_from_expression(expr, opt, schema, &mut spans)?;
+	let complex = ComplexQuery {
+		schema: schema.clone(),
+		span_selections: spans,
+		trace_selections: subq,
+		limits: QueryLimits {
+			limit: None,
+			..opt.clone()
+		},
+		trace_ids: trace_ids.to_vec(),
 	};
-	complex.as_sql()
+	Ok(complex.as_sql())
 }
 
 /*
@@ -103,15 +250,37 @@ CREATE TABLE spans (
 	links Variant
 ) ENGINE = FUSE CLUSTER BY (TO_YYYYMMDDHH(ts));
 */
+pub(super) static TRACE_TABLE_COLS: [(&str, &str); 17] = [
+	("ts", "timestamp"),
+	("trace_id", "string"),
+	("span_id", "string"),
+	("parent_span_id", "string"),
+	("trace_state", "string"),
+	("span_name", "string"),
+	("span_kind", "int"),
+	("service_name", "string"),
+	("resource_attributes", "map"),
+	("scope_name", "string"),
+	("scope_version", "string"),
+	("span_attributes", "map"),
+	("duration", "int"),
+	("status_code", "int"),
+	("status_message", "string"),
+	("span_events", "variant"),
+	("links", "variant"),
+];
+
 #[derive(Debug, Clone)]
 pub struct TraceTable {
 	t: String,
+	pub tz: Tz,
 }
 
 impl Default for TraceTable {
 	fn default() -> Self {
 		Self {
 			t: "spans".to_string(),
+			tz: Tz::UTC,
 		}
 	}
 }
@@ -237,6 +406,8 @@ struct ComplexQuery {
 	span_selections: Vec<QueryPlan<TraceTable, DatabendTraceConverter>>,
 	trace_selections: SubQuery,
 	limits: QueryLimits,
+	// only these traces are searched, all of them when empty
+	trace_ids: Vec<String>,
 }
 
 impl ComplexQuery {
@@ -260,6 +431,14 @@ impl ComplexQuery {
 		sql.push_str(") AS sub WHERE ");
 		sql.push_str(self.trace_selections.as_sql().as_ref());
 		sql.push(')');
+		if !self.trace_ids.is_empty() {
+			let ids = self
+				.trace_ids
+				.iter()
+				.map(|id| format!("'{}'", escape_str(id)))
+				.join(", ");
+			sql.push_str(&format!(" AND sp.trace_id IN ({})", ids));
+		}
 		if let Some(limit) = self.limits.limit {
 			sql.push_str(&format!(" LIMIT {}", limit));
 		}
@@ -298,6 +477,7 @@ fn field_value_to_place_value(f: &FieldValue) -> PlaceValue {
 		FieldValue::String(s) => PlaceValue::String(s.clone()),
 		FieldValue::Integer(i) => PlaceValue::Integer(*i),
 		FieldValue::Float(f) => PlaceValue::Float(*f),
+		FieldValue::Bool(b) => PlaceValue::Bool(*b),
 		_ => unimplemented!("field value to place value"),
 	}
 }
@@ -440,8 +620,8 @@ fn new_from_expression(
 	opt: &QueryLimits,
 	schema: &TraceTable,
 	spans: &mut Vec<QueryPlan<TraceTable, DatabendTraceConverter>>,
-) -> SubQuery {
-	match expr {
+) -> Result<SubQuery> {
+	let q = match expr {
 		Expression::SpanSet(spanset) => {
 			let selection = spanset_to_qp(spanset);
 			let mut qp = new_qp(opt, schema.clone());
@@ -453,14 +633,19 @@ fn new_from_expression(
 			SubQuery::Basic(qp)
 		}
 		Expression::Logical(left, op, right) => {
-			let l = new_from_expression(left, opt, schema, spans);
-			let r = new_from_expression(right, opt, schema, spans);
+			let l = new_from_expression(left, opt, schema, spans)?;
+			let r = new_from_expression(right, opt, schema, spans)?;
 			match op {
 				LogicalOperator::And => SubQuery::And(Box::new(l), Box::new(r)),
 				LogicalOperator::Or => SubQuery::Or(Box::new(l), Box::new(r)),
 			}
 		}
-	}
+		// rejected up front by capabilities, this is for any other caller
+		Expression::Structural(_, _, _) => {
+			bail!("structural operators are not supported by databend")
+		}
+	};
+	Ok(q)
 }
 
 #[derive(Debug, Default, Clone, TryFromRow)]
@@ -484,7 +669,7 @@ struct TraceRaw {
 	link: String,
 }
 
-fn row_into_spanitem(row: Row) -> Result<SpanItem> {
+fn row_into_spanitem(row: Row, tz: Tz) -> Result<SpanItem> {
 	let raw =
 		TraceRaw::try_from(row).map_err(|e: String| anyhow::anyhow!(e))?;
 	let attr_json: HashMap<String, serde_json::Value> = raw
@@ -500,7 +685,7 @@ fn row_into_spanitem(row: Row) -> Result<SpanItem> {
 	let events: Vec<SpanEvent> = serde_json::from_str(&raw.span_events)?;
 	let links: Vec<Links> = serde_json::from_str(&raw.link)?;
 	Ok(SpanItem {
-		ts: raw.ts.and_utc(),
+		ts: timelit::databend_to_utc(raw.ts, tz),
 		trace_id: raw.trace_id,
 		span_id: raw.span_id,
 		parent_span_id: raw.parent_span_id,
@@ -529,6 +714,24 @@ mod tests {
 	use std::{fs, path::PathBuf};
 	use traceql::parse_traceql;
 
+	#[test]
+	fn test_traces_sql() {
+		let ids = vec!["a".to_string(), "b".to_string()];
+		let opt = QueryLimits::default();
+		let tb = TraceTable::default();
+		let where_part =
+			|sql: String| sql.split(" WHERE ").nth(1).unwrap().to_string();
+		assert_eq!(
+			where_part(traces_sql(&ids, &opt, &tb, false)),
+			"(trace_id = 'a' OR trace_id = 'b')"
+		);
+		// every root in one statement
+		assert_eq!(
+			where_part(traces_sql(&ids, &opt, &tb, true)),
+			"((trace_id = 'a' OR trace_id = 'b') AND parent_span_id = '')"
+		);
+	}
+
 	#[test]
 	fn expand_complex_traceql() {
 		let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
@@ -553,9 +756,11 @@ mod tests {
 				},
 				direction: None,
 				step: None,
+				spss: None,
+				unbounded: false,
 			};
 			let tb = TraceTable::default();
-			let sql = search_span_sql(&expr, &opt, &tb);
+			let sql = search_span_sql(&expr, &opt, &tb).unwrap();
 			let actual_ast = Parser::parse_sql(&AnsiDialect {}, &sql).unwrap();
 			let expect_ast =
 				Parser::parse_sql(&AnsiDialect {}, &tc.expect).unwrap();
@@ -0,0 +1,103 @@
+use crate::utils::log::redact_sql;
+use serde::{Deserialize, Serialize};
+use std::{
+	future::Future,
+	sync::{Arc, Mutex},
+};
+
+// while a request is explained the backends answer every statement with
+// its EXPLAIN instead of running it, and return no rows
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExplainKind {
+	#[default]
+	Plan,
+	// ck only, databend has a single kind of EXPLAIN
+	Pipeline,
+	// only the statements, nothing is sent to the backend
+	Sql,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Plan {
+	pub sql: String,
+	pub plan: String,
+}
+
+#[derive(Debug)]
+struct Explainer {
+	kind: ExplainKind,
+	plans: Mutex<Vec<Plan>>,
+}
+
+tokio::task_local! {
+	static EXPLAINER: Arc<Explainer>;
+}
+
+// collect runs f in explain mode and returns the plans of the statements
+// it would have sent
+pub async fn collect<F: Future>(
+	kind: ExplainKind,
+	f: F,
+) -> (F::Output, Vec<Plan>) {
+	let e = Arc::new(Explainer {
+		kind,
+		plans: Mutex::default(),
+	});
+	let out = EXPLAINER.scope(e.clone(), f).await;
+	let plans = std::mem::take(&mut *e.plans.lock().unwrap());
+	(out, plans)
+}
+
+// current is None unless the request is explained
+pub fn current() -> Option<ExplainKind> {
+	EXPLAINER.try_with(|e| e.kind).ok()
+}
+
+pub fn record(sql: &str, plan: String) {
+	let _ = EXPLAINER.try_with(|e| {
+		e.plans.lock().unwrap().push(Plan {
+			sql: redact_sql(sql).into_owned(),
+			plan,
+		})
+	});
+}
+
+// same as stats::inherit
+pub fn inherit<F: Future>(f: F) -> impl Future<Output = F::Output> {
+	let e = EXPLAINER.try_with(Arc::clone).ok();
+	async move {
+		match e {
+			Some(e) => EXPLAINER.scope(e, f).await,
+			None => f.await,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use pretty_assertions::assert_eq;
+
+	#[tokio::test]
+	async fn test_collect() {
+		assert_eq!(current(), None);
+		let (kind, plans) = collect(ExplainKind::Pipeline, async {
+			tokio::spawn(inherit(async {
+				record("SELECT 1", "(Expression)".to_string());
+			}))
+			.await
+			.unwrap();
+			current()
+		})
+		.await;
+		assert_eq!(kind, Some(ExplainKind::Pipeline));
+		assert_eq!(
+			plans,
+			[Plan {
+				sql: "SELECT 1".to_string(),
+				plan: "(Expression)".to_string(),
+			}]
+		);
+	}
+}
@@ -0,0 +1,342 @@
+use super::{
+	explain,
+	log::{pair_matches, LogItem, LogStorage, MetricItem, ValueFilter},
+	merge::merge_sorted,
+	registry::{mismatch, Creating, Registry},
+	stats, Capabilities, Direction, QueryLimits,
+};
+use crate::config::{DataSource, Fanout, MergeStrategy};
+use crate::query_tags;
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use common::TimeRange;
+use logql::parser::{LogQuery, MetricQuery};
+use std::{
+	collections::{HashMap, HashSet},
+	future::Future,
+};
+use tokio::task::JoinSet;
+
+// label added to every stream, telling which source it came from
+pub const SOURCE_LABEL: &str = "__source__";
+
+type Source = (String, Box<dyn LogStorage>);
+
+// FanoutLog sends each query to all its sources and merges the results
+#[derive(Clone)]
+pub struct FanoutLog {
+	sources: Vec<Source>,
+	merge: MergeStrategy,
+}
+
+pub(super) fn register(r: &mut Registry) {
+	r.log("fanout", log_factory);
+}
+
+fn log_factory(d: DataSource) -> Creating<dyn LogStorage> {
+	match d {
+		DataSource::Fanout(cfg) => Box::pin(new_log_source(cfg)),
+		d => mismatch("fanout", &d),
+	}
+}
+
+pub async fn new_log_source(cfg: Fanout) -> Result<Box<dyn LogStorage>> {
+	if cfg.sources.is_empty() {
+		bail!("fanout needs at least one source");
+	}
+	let mut sources = vec![];
+	for s in cfg.sources {
+		let h = Box::pin(super::new_log_source(s.source)).await?;
+		sources.push((s.name, h));
+	}
+	Ok(Box::new(FanoutLog {
+		sources,
+		merge: cfg.merge,
+	}))
+}
+
+impl FanoutLog {
+	// the backends know nothing about __source__, so its matchers are
+	// removed from the query and used to pick the sources instead
+	fn route(&self, q: &LogQuery) -> Result<(LogQuery, Vec<Source>)> {
+		let mut q = q.clone();
+		let (matchers, rest): (Vec<_>, Vec<_>) = q
+			.selector
+			.label_paris
+			.into_iter()
+			.partition(|p| p.label == SOURCE_LABEL);
+		q.selector.label_paris = rest;
+		let mut picked = vec![];
+		for (name, h) in &self.sources {
+			let mut keep = true;
+			for m in &matchers {
+				keep = keep && pair_matches(m, name)?;
+			}
+			if keep {
+				picked.push((name.clone(), h.clone()));
+			}
+		}
+		Ok((q, picked))
+	}
+}
+
+// fan runs f against every source in parallel,
+// the results keep the order of the sources
+async fn fan<T, F, Fut>(sources: Vec<Source>, f: F) -> Result<Vec<(String, T)>>
+where
+	T: Send + 'static,
+	F: Fn(Box<dyn LogStorage>) -> Fut,
+	Fut: Future<Output = Result<T>> + Send + 'static,
+{
+	let mut tasks = JoinSet::new();
+	for (i, (name, h)) in sources.into_iter().enumerate() {
+		let fut = f(h);
+		tasks.spawn(stats::inherit(explain::inherit(query_tags::inherit(
+			async move { (i, name, fut.await) },
+		))));
+	}
+	let mut out = vec![];
+	while let Some(res) = tasks.join_next().await {
+		let (i, name, r) = res?;
+		let v = r.with_context(|| format!("query source {}", name))?;
+		out.push((i, name, v));
+	}
+	out.sort_by_key(|(i, ..)| *i);
+	Ok(out.into_iter().map(|(_, name, v)| (name, v)).collect())
+}
+
+#[async_trait]
+impl LogStorage for FanoutLog {
+	async fn query_stream(
+		&self,
+		q: &LogQuery,
+		opt: QueryLimits,
+	) -> Result<Vec<LogItem>> {
+		let (q, sources) = self.route(q)?;
+		let (direction, limit) = (opt.direction.clone(), opt.limit);
+		let parts = fan(sources, move |h| {
+			let (q, opt) = (q.clone(), opt.clone());
+			async move { h.query_stream(&q, opt).await }
+		})
+		.await?;
+		Ok(merge_logs(parts, self.merge, direction, limit))
+	}
+	async fn query_metrics(
+		&self,
+		q: &MetricQuery,
+		opt: QueryLimits,
+	) -> Result<Vec<MetricItem>> {
+		let (log_query, sources) = self.route(&q.log_query)?;
+		let q = MetricQuery {
+			log_query,
+			..q.clone()
+		};
+		let parts = fan(sources, move |h| {
+			let (q, opt) = (q.clone(), opt.clone());
+			async move { h.query_metrics(&q, opt).await }
+		})
+		.await?;
+		let parts = parts.into_iter().map(|(_, v)| v).collect();
+		Ok(merge_metrics(parts, self.merge))
+	}
+	async fn labels(&self, opt: QueryLimits) -> Result<Vec<String>> {
+		let parts = fan(self.sources.clone(), move |h| {
+			let opt = opt.clone();
+			async move { h.labels(opt).await }
+		})
+		.await?;
+		let mut labels: Vec<String> =
+			parts.into_iter().flat_map(|(_, v)| v).collect();
+		labels.push(SOURCE_LABEL.to_string());
+		labels.sort();
+		labels.dedup();
+		Ok(labels)
+	}
+	async fn label_values(
+		&self,
+		label: &str,
+		filter: Option<&ValueFilter>,
+		opt: QueryLimits,
+	) -> Result<Vec<String>> {
+		if label == SOURCE_LABEL {
+			return Ok(self
+				.sources
+				.iter()
+				.map(|(n, _)| n.clone())
+				.filter(|n| filter.map_or(true, |f| f.matches(n)))
+				.collect());
+		}
+		let (label, filter) = (label.to_string(), filter.cloned());
+		let parts = fan(self.sources.clone(), move |h| {
+			let (label, filter, opt) =
+				(label.clone(), filter.clone(), opt.clone());
+			async move { h.label_values(&label, filter.as_ref(), opt).await }
+		})
+		.await?;
+		let mut values: Vec<String> =
+			parts.into_iter().flat_map(|(_, v)| v).collect();
+		values.sort();
+		values.dedup();
+		Ok(values)
+	}
+	async fn series(
+		&self,
+		matches: Option<LogQuery>,
+		opt: QueryLimits,
+	) -> Result<Vec<HashMap<String, String>>> {
+		let (matches, sources) = match matches {
+			Some(q) => {
+				let (q, sources) = self.route(&q)?;
+				(Some(q), sources)
+			}
+			None => (None, self.sources.clone()),
+		};
+		let parts = fan(sources, move |h| {
+			let (matches, opt) = (matches.clone(), opt.clone());
+			async move { h.series(matches, opt).await }
+		})
+		.await?;
+		Ok(parts
+			.into_iter()
+			.flat_map(|(name, series)| {
+				series.into_iter().map(move |mut s| {
+					s.insert(SOURCE_LABEL.to_string(), name.clone());
+					s
+				})
+			})
+			.collect())
+	}
+	async fn delete(&self, q: &LogQuery, range: TimeRange) -> Result<()> {
+		let (q, sources) = self.route(q)?;
+		fan(sources, move |h| {
+			let (q, range) = (q.clone(), range.clone());
+			async move { h.delete(&q, range).await }
+		})
+		.await?;
+		Ok(())
+	}
+	fn can_delete(&self) -> bool {
+		self.sources.iter().all(|(_, h)| h.can_delete())
+	}
+	fn high_cardinality_labels(&self) -> Vec<String> {
+		let mut labels: Vec<String> = self
+			.sources
+			.iter()
+			.flat_map(|(_, h)| h.high_cardinality_labels())
+			.collect();
+		labels.sort();
+		labels.dedup();
+		labels
+	}
+	// only what every source supports can be pushed down
+	fn capabilities(&self) -> Capabilities {
+		self.sources
+			.iter()
+			.map(|(_, h)| h.capabilities())
+			.fold(Capabilities::default(), Capabilities::and)
+	}
+}
+
+fn merge_logs(
+	parts: Vec<(String, Vec<LogItem>)>,
+	strategy: MergeStrategy,
+	direction: Option<Direction>,
+	limit: Option<u32>,
+) -> Vec<LogItem> {
+	let mut seen = HashSet::new();
+	let parts = parts
+		.into_iter()
+		.map(|(name, items)| {
+			items
+				.into_iter()
+				.filter(|item| {
+					strategy != MergeStrategy::Dedup
+						|| seen.insert((
+							item.ts,
+							item.service_name.clone(),
+							item.message.clone(),
+						))
+				})
+				.map(|mut item| {
+					item.source = Some(name.clone());
+					item
+				})
+				.collect()
+		})
+		.collect();
+	// each source applied the limit on its own, the merged rows need it again
+	merge_sorted(parts, |r| r.ts, &direction, limit)
+}
+
+pub(super) fn merge_metrics(
+	parts: Vec<Vec<MetricItem>>,
+	strategy: MergeStrategy,
+) -> Vec<MetricItem> {
+	let mut buckets: HashMap<_, u64> = HashMap::new();
+	for item in parts.into_iter().flatten() {
+		let total = buckets.entry((item.labels, item.ts)).or_default();
+		match strategy {
+			MergeStrategy::Union => *total += item.total,
+			// copies of the same logs, counting both would double them
+			MergeStrategy::Dedup => *total = (*total).max(item.total),
+		}
+	}
+	let mut items: Vec<MetricItem> = buckets
+		.into_iter()
+		.map(|((labels, ts), total)| MetricItem { labels, total, ts })
+		.collect();
+	items.sort_by_key(|i| i.ts);
+	items
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use chrono::DateTime;
+	use pretty_assertions::assert_eq;
+
+	fn item(ts: i64, message: &str) -> LogItem {
+		LogItem {
+			ts: DateTime::from_timestamp(ts, 0).unwrap(),
+			trace_id: String::new(),
+			span_id: String::new(),
+			level: "info".to_string(),
+			service_name: "svc".to_string(),
+			message: message.to_string(),
+			resource_attributes: Default::default(),
+			scope_name: String::new(),
+			scope_attributes: Default::default(),
+			log_attributes: Default::default(),
+			source: None,
+		}
+	}
+
+	#[test]
+	fn test_merge_logs() {
+		let parts = || {
+			vec![
+				("databend".to_string(), vec![item(3, "c"), item(1, "a")]),
+				("ck".to_string(), vec![item(3, "c"), item(2, "b")]),
+			]
+		};
+		let got = |strategy, direction, limit| {
+			merge_logs(parts(), strategy, direction, limit)
+				.into_iter()
+				.map(|r| (r.message, r.source.unwrap()))
+				.collect::<Vec<_>>()
+		};
+		let pair = |m: &str, s: &str| (m.to_string(), s.to_string());
+		assert_eq!(
+			got(MergeStrategy::Union, None, Some(3)),
+			vec![pair("c", "databend"), pair("c", "ck"), pair("b", "ck")]
+		);
+		assert_eq!(
+			got(MergeStrategy::Dedup, Some(Direction::Forward), None),
+			vec![
+				pair("a", "databend"),
+				pair("b", "ck"),
+				pair("c", "databend")
+			]
+		);
+	}
+}
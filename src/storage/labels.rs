@@ -0,0 +1,512 @@
+use std::{
+	collections::{BTreeMap, HashMap, HashSet},
+	sync::Arc,
+	time::{Duration, Instant},
+};
+
+use crate::{storage::log::ValueFilter, utils::glob::glob};
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use dashmap::{DashMap, DashSet};
+use itertools::Itertools;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use sqlbuilder::visit::{ATTRIBUTES_PREFIX, RESOURCES_PREFIX};
+use tokio::sync::mpsc::{self, Sender};
+
+#[derive(
+	Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize,
+)]
+pub enum LabelType {
+	Raw(String),
+	ServiceName,
+	Level,
+	ResourceAttr(String),
+	LogAttr(String),
+	TraceId,
+}
+
+impl From<LabelType> for String {
+	fn from(l: LabelType) -> Self {
+		match l {
+			LabelType::Raw(s) => s,
+			LabelType::ServiceName => "ServiceName".to_string(),
+			LabelType::Level => "SeverityText".to_string(),
+			LabelType::ResourceAttr(s) => format!("{}{}", RESOURCES_PREFIX, s),
+			LabelType::LogAttr(s) => format!("{}{}", ATTRIBUTES_PREFIX, s),
+			LabelType::TraceId => "trace_id".to_string(),
+		}
+	}
+}
+
+impl From<&str> for LabelType {
+	fn from(s: &str) -> Self {
+		if s.starts_with("resource_") {
+			LabelType::ResourceAttr(s.to_string())
+		} else if s.starts_with("log_") {
+			LabelType::LogAttr(s.to_string())
+		} else if s.to_uppercase().eq("SERVICENAME") {
+			LabelType::ServiceName
+		} else if s.to_uppercase().eq("SEVERITYTEXT") {
+			LabelType::Level
+		} else {
+			LabelType::Raw(s.to_string())
+		}
+	}
+}
+
+// the labels of one log line, sorted by label
+pub type LabelSet = Vec<(LabelType, String)>;
+
+// Snapshot is what a SeriesStore knows, as json. A user's snapshot loaded
+// into another store reproduces their label issues without their ck
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+	// label -> value -> the last time it was seen
+	pub values: Vec<(LabelType, BTreeMap<String, NaiveDateTime>)>,
+	pub series: Vec<(LabelSet, NaiveDateTime)>,
+	pub high_cardinality: Vec<LabelType>,
+}
+
+// value -> the last time it was seen, sharded by value so observing one
+// value of a busy label doesn't lock out the others
+type Values = Arc<DashMap<String, NaiveDateTime>>;
+
+// SeriesStore is sharded by label and then by value. Readers clone the
+// values of a label out of the outer map and walk them without holding
+// its lock, so lookups and recording rarely wait on each other
+#[derive(Debug, Clone)]
+pub struct SeriesStore {
+	m: Arc<DashMap<LabelType, Values>>,
+	// label sets that were actually seen together, series are built
+	// from them rather than from every combination of values
+	combos: Arc<DashMap<LabelSet, NaiveDateTime>>,
+	// labels that hit max_values, their values are only partly known
+	// and they're left out of series
+	high_cardinality: Arc<DashSet<LabelType>>,
+	max_values: usize,
+	// values not seen for this long are dropped, so only the recent ones
+	// count towards max_values
+	retention: Option<TimeDelta>,
+}
+
+impl SeriesStore {
+	fn inner_new(max_values: usize, retention: Option<Duration>) -> Self {
+		Self {
+			m: Arc::new(DashMap::new()),
+			combos: Arc::new(DashMap::new()),
+			high_cardinality: Arc::new(DashSet::new()),
+			max_values,
+			retention: retention.and_then(|r| TimeDelta::from_std(r).ok()),
+		}
+	}
+	// label sets are folded in by a background task so recording them
+	// never holds up a query, it also drops what expired now and then
+	pub fn new(
+		max_values: usize,
+		retention: Option<Duration>,
+	) -> (Self, Sender<LabelSet>) {
+		let (tx, mut rx) = mpsc::channel(100_000);
+		let ss = Self::inner_new(max_values, retention);
+		let m = ss.clone();
+		tokio::spawn(async move {
+			let mut expired_at = Instant::now();
+			while let Some(set) = rx.recv().await {
+				m.observe(set);
+				if expired_at.elapsed() >= EXPIRE_EVERY {
+					m.expire(Utc::now().naive_utc());
+					expired_at = Instant::now();
+				}
+			}
+		});
+		(ss, tx)
+	}
+	pub fn observe(&self, set: LabelSet) {
+		self.observe_at(set, Utc::now().naive_utc());
+	}
+	fn observe_at(&self, mut set: LabelSet, ts: NaiveDateTime) {
+		for (k, v) in &set {
+			self.insert_at(k.clone(), v.clone(), ts);
+		}
+		// keeps the number of combinations bounded
+		set.retain(|(k, _)| !self.high_cardinality.contains(k));
+		if set.is_empty() {
+			return;
+		}
+		set.sort();
+		self.combos.insert(set, ts);
+	}
+	fn values(&self, key: &LabelType) -> Option<Values> {
+		self.m.get(key).map(|v| v.value().clone())
+	}
+	// all values of every label, cloned out so no shard stays locked
+	fn all_values(&self) -> Vec<(LabelType, Values)> {
+		self.m
+			.iter()
+			.map(|ent| (ent.key().clone(), ent.value().clone()))
+			.collect()
+	}
+	// max_values may be overshot by a few when several writers add a
+	// value at once, the background task is the only regular writer
+	fn insert_at(&self, key: LabelType, value: String, ts: NaiveDateTime) {
+		let values = match self.values(&key) {
+			Some(values) => values,
+			None => self.m.entry(key.clone()).or_default().value().clone(),
+		};
+		if let Some(mut seen) = values.get_mut(&value) {
+			*seen = ts;
+			return;
+		}
+		if values.len() >= self.max_values {
+			// expired values make room first
+			if let Some(cutoff) = self.cutoff(ts) {
+				values.retain(|_, seen| *seen >= cutoff);
+			}
+			if values.len() >= self.max_values {
+				self.high_cardinality.insert(key);
+				return;
+			}
+		}
+		values.insert(value, ts);
+	}
+	fn cutoff(&self, now: NaiveDateTime) -> Option<NaiveDateTime> {
+		self.retention.and_then(|r| now.checked_sub_signed(r))
+	}
+	// expire drops the values and label sets not seen within the
+	// retention, a label left with fewer than max_values isn't high
+	// cardinality anymore
+	fn expire(&self, now: NaiveDateTime) {
+		let Some(cutoff) = self.cutoff(now) else {
+			return;
+		};
+		for (key, values) in self.all_values() {
+			values.retain(|_, seen| *seen >= cutoff);
+			if values.len() < self.max_values {
+				self.high_cardinality.remove(&key);
+			}
+			self.m.remove_if(&key, |_, v| v.is_empty());
+		}
+		self.combos.retain(|_, seen| *seen >= cutoff);
+	}
+	// values not seen since `since` are left out
+	pub fn get(
+		&self,
+		key: &LabelType,
+		filter: Option<&ValueFilter>,
+		since: Option<NaiveDateTime>,
+	) -> Option<Vec<String>> {
+		self.values(key).map(|v| {
+			v.iter()
+				.filter(|ent| fresh(ent.value(), since))
+				.map(|ent| ent.key().clone())
+				.filter(|v| filter.map_or(true, |f| f.matches(v)))
+				.collect_vec()
+		})
+	}
+	pub fn high_cardinality(&self) -> Vec<LabelType> {
+		let mut keys = self
+			.high_cardinality
+			.iter()
+			.map(|k| k.key().clone())
+			.collect_vec();
+		keys.sort();
+		keys
+	}
+
+	pub fn labels(&self, since: Option<NaiveDateTime>) -> Vec<LabelType> {
+		let mut keys = self
+			.all_values()
+			.into_iter()
+			.filter(|(_, v)| v.iter().any(|ent| fresh(ent.value(), since)))
+			.map(|(k, _)| k)
+			.collect_vec();
+		keys.sort();
+		keys
+	}
+	pub fn series(
+		&self,
+		since: Option<NaiveDateTime>,
+	) -> Vec<HashMap<LabelType, String>> {
+		let sets: HashSet<LabelSet> = self
+			.combos
+			.iter()
+			.filter(|ent| fresh(ent.value(), since))
+			.map(|ent| {
+				ent.key()
+					.iter()
+					.filter(|(k, _)| !self.high_cardinality.contains(k))
+					.cloned()
+					.collect_vec()
+			})
+			.filter(|set| !set.is_empty())
+			.collect();
+		sets.into_iter()
+			.map(|set| set.into_iter().collect())
+			.collect()
+	}
+
+	// snapshot keeps only what involves label if there is one, named the
+	// way the api names it
+	pub fn snapshot(&self, label: Option<&str>) -> Snapshot {
+		let wanted = |k: &LabelType| {
+			label.map_or(true, |l| String::from(k.clone()) == l)
+		};
+		let mut values = self
+			.all_values()
+			.into_iter()
+			.filter(|(k, _)| wanted(k))
+			.map(|(k, v)| {
+				let values =
+					v.iter().map(|ent| (ent.key().clone(), *ent.value()));
+				(k, values.collect())
+			})
+			.collect_vec();
+		values.sort();
+		let mut series = self
+			.combos
+			.iter()
+			.filter(|ent| ent.key().iter().any(|(k, _)| wanted(k)))
+			.map(|ent| (ent.key().clone(), *ent.value()))
+			.collect_vec();
+		series.sort();
+		Snapshot {
+			values,
+			series,
+			high_cardinality: self
+				.high_cardinality()
+				.into_iter()
+				.filter(|k| wanted(k))
+				.collect(),
+		}
+	}
+	// restore adds a snapshot to what the store already knows
+	pub fn restore(&self, s: Snapshot) {
+		for k in s.high_cardinality {
+			self.high_cardinality.insert(k);
+		}
+		for (k, values) in s.values {
+			for (v, ts) in values {
+				self.insert_at(k.clone(), v, ts);
+			}
+		}
+		for (set, ts) in s.series {
+			self.combos.insert(set, ts);
+		}
+	}
+}
+
+const EXPIRE_EVERY: Duration = Duration::from_secs(60);
+
+fn fresh(ts: &NaiveDateTime, since: Option<NaiveDateTime>) -> bool {
+	since.map_or(true, |s| *ts >= s)
+}
+
+// KeyFilter decides which discovered attribute keys become labels
+#[derive(Debug, Clone)]
+pub struct KeyFilter {
+	include: Vec<Regex>,
+	exclude: Vec<Regex>,
+}
+
+impl KeyFilter {
+	pub fn new(include: &[String], exclude: &[String]) -> Self {
+		Self {
+			include: include.iter().map(|g| glob(g)).collect(),
+			exclude: exclude.iter().map(|g| glob(g)).collect(),
+		}
+	}
+	pub fn allows(&self, key: &str) -> bool {
+		(self.include.is_empty()
+			|| self.include.iter().any(|r| r.is_match(key)))
+			&& !self.exclude.iter().any(|r| r.is_match(key))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use super::*;
+	use pretty_assertions::assert_eq;
+
+	fn set(pairs: &[(&str, &str)]) -> LabelSet {
+		pairs
+			.iter()
+			.map(|(k, v)| ((*k).into(), v.to_string()))
+			.collect()
+	}
+
+	#[test]
+	fn test_observed_series() {
+		let m = SeriesStore::inner_new(usize::MAX, None);
+		m.observe(set(&[("a", "a1"), ("b", "b1")]));
+		m.observe(set(&[("b", "b2"), ("a", "a2")]));
+		m.observe(set(&[("a", "a1"), ("b", "b1")]));
+		let mut actual = m.series(None);
+		actual.sort_by_key(|m| m[&"a".into()].clone());
+		let expect: Vec<HashMap<LabelType, String>> = vec![
+			set(&[("a", "a1"), ("b", "b1")]).into_iter().collect(),
+			set(&[("a", "a2"), ("b", "b2")]).into_iter().collect(),
+		];
+		// a1 was never seen with b2
+		assert_eq!(actual, expect);
+		assert_eq!(m.get(&"b".into(), None, None).map(|v| v.len()), Some(2));
+	}
+
+	#[test]
+	fn test_labels() {
+		let m = SeriesStore::inner_new(usize::MAX, None);
+		m.observe(set(&[("b", "b1"), ("a", "a1"), ("c", "c1")]));
+		m.observe(set(&[("b", "b2"), ("a", "a2"), ("c", "c2")]));
+		let expect = vec!["a".into(), "b".into(), "c".into()];
+		for _ in 1..10 {
+			let actual = m.labels(None);
+			assert_eq!(actual, expect);
+		}
+	}
+
+	#[test]
+	fn test_high_cardinality() {
+		let m = SeriesStore::inner_new(2, None);
+		for v in ["u1", "u2", "u3", "u1"] {
+			m.observe(set(&[("user_id", v), ("a", "a1")]));
+		}
+		assert_eq!(
+			m.get(&"user_id".into(), None, None).map(|v| v.len()),
+			Some(2)
+		);
+		assert_eq!(m.high_cardinality(), vec!["user_id".into()]);
+		let f = ValueFilter::parse("a*");
+		assert_eq!(
+			m.get(&"a".into(), f.as_ref(), None),
+			Some(vec!["a1".to_string()])
+		);
+		assert_eq!(
+			m.series(None),
+			vec![[("a".into(), "a1".to_string())].into()]
+		);
+	}
+
+	#[test]
+	fn test_since() {
+		let m = SeriesStore::inner_new(usize::MAX, None);
+		let now = Utc::now().naive_utc();
+		let old = now - chrono::Duration::hours(3);
+		m.observe_at(set(&[("a", "a1"), ("b", "b1")]), old);
+		m.observe_at(set(&[("a", "a2")]), now);
+		let since = Some(now - chrono::Duration::hours(1));
+		assert_eq!(m.labels(since), vec!["a".into()]);
+		assert_eq!(
+			m.get(&"a".into(), None, since),
+			Some(vec!["a2".to_string()])
+		);
+		assert_eq!(
+			m.series(since),
+			vec![[("a".into(), "a2".to_string())].into()]
+		);
+		assert_eq!(m.labels(None).len(), 2);
+	}
+
+	#[test]
+	fn test_retention() {
+		let m = SeriesStore::inner_new(2, Some(Duration::from_secs(3600)));
+		let now = Utc::now().naive_utc();
+		let old = now - chrono::Duration::hours(3);
+		m.observe_at(set(&[("user_id", "u1")]), old);
+		m.observe_at(set(&[("user_id", "u2")]), old);
+		// u1 and u2 expired, so u3 still fits
+		m.observe_at(set(&[("user_id", "u3")]), now);
+		assert!(m.high_cardinality().is_empty());
+		assert_eq!(
+			m.get(&"user_id".into(), None, None),
+			Some(vec!["u3".to_string()])
+		);
+		m.observe_at(set(&[("user_id", "u4")]), now);
+		m.observe_at(set(&[("user_id", "u5")]), now);
+		assert_eq!(m.high_cardinality(), vec!["user_id".into()]);
+		m.expire(now + chrono::Duration::hours(2));
+		assert!(m.high_cardinality().is_empty());
+		assert!(m.labels(None).is_empty());
+	}
+
+	#[test]
+	fn test_combos_expire() {
+		let m =
+			SeriesStore::inner_new(usize::MAX, Some(Duration::from_secs(3600)));
+		let now = Utc::now().naive_utc();
+		m.observe_at(set(&[("a", "a1"), ("b", "b1")]), now);
+		m.observe_at(set(&[("a", "a2")]), now + chrono::Duration::hours(2));
+		m.expire(now + chrono::Duration::hours(2));
+		assert_eq!(
+			m.series(None),
+			vec![[("a".into(), "a2".to_string())].into()]
+		);
+		assert_eq!(m.snapshot(None).series.len(), 1);
+	}
+
+	#[test]
+	fn test_snapshot() {
+		let m = SeriesStore::inner_new(2, None);
+		for v in ["u1", "u2", "u3"] {
+			m.observe(set(&[("user_id", v), ("a", "a1")]));
+		}
+		m.observe(set(&[("b", "b1")]));
+		let snap = m.snapshot(None);
+		assert_eq!(snap.values.len(), 3);
+		assert_eq!(snap.high_cardinality, vec!["user_id".into()]);
+		let json = serde_json::to_string(&snap).unwrap();
+		let restored = SeriesStore::inner_new(2, None);
+		restored.restore(serde_json::from_str(&json).unwrap());
+		assert_eq!(restored.snapshot(None), snap);
+		assert_eq!(restored.series(None).len(), 2);
+
+		let only_b = m.snapshot(Some("b"));
+		assert_eq!(only_b.values.len(), 1);
+		assert_eq!(only_b.series.len(), 1);
+		assert!(only_b.high_cardinality.is_empty());
+	}
+
+	#[test]
+	fn test_read_while_recording() {
+		let m = SeriesStore::inner_new(usize::MAX, None);
+		m.observe(set(&[("a", "a1")]));
+		// walking the values of a label keeps no lock on the store, only
+		// on the values being walked
+		let a = m.values(&"a".into()).unwrap();
+		let held = a.iter().next().unwrap();
+		m.observe(set(&[("b", "b1"), ("c", "c1")]));
+		assert_eq!(m.labels(None).len(), 3);
+		assert_eq!(held.key(), "a1");
+		drop(held);
+		m.observe(set(&[("a", "a2")]));
+		let mut values = m.get(&"a".into(), None, None).unwrap();
+		values.sort();
+		assert_eq!(values, vec!["a1", "a2"]);
+	}
+
+	#[test]
+	fn test_key_filter() {
+		let f = KeyFilter::new(
+			&["k8s.*".to_string(), "host.?rch".to_string()],
+			&["k8s.pod.uid".to_string()],
+		);
+		assert!(f.allows("k8s.namespace.name"));
+		assert!(f.allows("host.arch"));
+		assert!(!f.allows("k8s.pod.uid"));
+		assert!(!f.allows("k8s"));
+		assert!(!f.allows("hostXarch"));
+		assert!(KeyFilter::new(&[], &[]).allows("anything"));
+	}
+
+	#[tokio::test]
+	async fn test_async_observe() -> anyhow::Result<()> {
+		use tokio::time;
+		let (m, tx) = SeriesStore::new(usize::MAX, None);
+		tx.send(set(&[("a", "a1"), ("b", "b1")])).await?;
+		tx.send(set(&[("a", "a2"), ("b", "b2")])).await?;
+		// wait for the consumer to finish
+		time::sleep(Duration::from_millis(200)).await;
+		assert_eq!(m.series(None).len(), 2);
+		assert_eq!(m.labels(None), vec!["a".into(), "b".into()]);
+		Ok(())
+	}
+}
@@ -1,11 +1,13 @@
-use super::QueryLimits;
-use anyhow::Result;
+use super::{labels::Snapshot, Capabilities, QueryLimits};
+use anyhow::{bail, Result};
 use async_trait::async_trait;
 use chrono::{offset::Utc, DateTime};
-use common::LogLevel;
+use common::{LogLevel, TimeRange};
 use dyn_clone::DynClone;
-use logql::parser::{LogQuery, MetricQuery};
-use std::collections::HashMap;
+use itertools::Itertools;
+use logql::parser::{LabelPair, LogQuery, MetricQuery, Operator};
+use regex::Regex;
+use std::collections::{BTreeMap, HashMap};
 
 #[async_trait]
 pub trait LogStorage: DynClone + Send + Sync {
@@ -25,21 +27,119 @@ pub trait LogStorage: DynClone + Send + Sync {
 	async fn label_values(
 		&self,
 		_label: &str,
+		_filter: Option<&ValueFilter>,
 		_opt: QueryLimits,
 	) -> Result<Vec<String>> {
 		Ok(vec![])
 	}
+	// without knowing which values go together every label value is a
+	// series of its own, backends that do know return whole label sets.
+	// So a selector that needs two labels matches nothing and one that
+	// needs a single label only asks for the values of that label
 	async fn series(
 		&self,
-		_match: Option<LogQuery>,
-		_opt: QueryLimits,
+		matches: Option<LogQuery>,
+		opt: QueryLimits,
 	) -> Result<Vec<HashMap<String, String>>> {
-		Ok(vec![])
+		let pairs = matches.map(|q| q.selector.label_paris).unwrap_or_default();
+		let needed = pairs
+			.iter()
+			.filter(|p| matches!(p.op, Operator::Equal | Operator::RegexMatch))
+			.map(|p| p.label.clone())
+			.unique()
+			.collect_vec();
+		let labels = match needed.len() {
+			0 => self.labels(opt.clone()).await?,
+			1 => needed,
+			_ => return Ok(vec![]),
+		};
+		let mut series = vec![];
+		for label in labels {
+			for v in self.label_values(&label, None, opt.clone()).await? {
+				let mut keep = true;
+				for p in pairs.iter().filter(|p| p.label == label) {
+					keep = keep && pair_matches(p, &v)?;
+				}
+				if keep {
+					series.push(HashMap::from([(label.clone(), v)]));
+				}
+			}
+		}
+		Ok(series)
+	}
+	// labels whose values are only partly known, e.g. capped in a store
+	fn high_cardinality_labels(&self) -> Vec<String> {
+		vec![]
+	}
+	// the label index of backends that keep one, for debugging
+	fn series_snapshot(&self, _label: Option<&str>) -> Option<Snapshot> {
+		None
+	}
+	fn restore_series(&self, _snapshot: Snapshot) -> Result<()> {
+		bail!("this backend keeps no label index")
+	}
+	// delete removes the lines matched by the selector and line filters
+	// of q, backends that can't delete refuse
+	async fn delete(&self, _q: &LogQuery, _range: TimeRange) -> Result<()> {
+		bail!("deleting logs is not supported by this backend")
+	}
+	fn can_delete(&self) -> bool {
+		false
+	}
+	fn capabilities(&self) -> Capabilities {
+		Capabilities::default()
 	}
 }
 
 dyn_clone::clone_trait_object!(LogStorage);
 
+// whether a label with the value v satisfies the matcher p
+pub(crate) fn pair_matches(p: &LabelPair, v: &str) -> Result<bool> {
+	let re = || Regex::new(&format!("^(?:{})$", p.value));
+	Ok(match p.op {
+		Operator::Equal => p.value == v,
+		Operator::NotEqual => p.value != v,
+		Operator::RegexMatch => re()?.is_match(v),
+		Operator::RegexNotMatch => !re()?.is_match(v),
+	})
+}
+
+// ValueFilter narrows label values down while typing,
+// `api*` asks for a prefix and anything else for a substring
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValueFilter {
+	Prefix(String),
+	Contains(String),
+}
+
+impl ValueFilter {
+	// a stream selector, which loki takes here, isn't a filter
+	pub fn parse(q: &str) -> Option<Self> {
+		let q = q.trim();
+		if q.is_empty() || q.starts_with('{') {
+			return None;
+		}
+		Some(match q.strip_suffix('*') {
+			Some(prefix) => Self::Prefix(prefix.to_string()),
+			None => Self::Contains(q.to_string()),
+		})
+	}
+	pub fn matches(&self, v: &str) -> bool {
+		match self {
+			Self::Prefix(p) => v.starts_with(p.as_str()),
+			Self::Contains(s) => v.contains(s.as_str()),
+		}
+	}
+	// LIKE pattern with the wildcards of the input escaped
+	pub fn like_pattern(&self) -> String {
+		let esc = |s: &str| s.replace('%', "\\%").replace('_', "\\_");
+		match self {
+			Self::Prefix(p) => format!("{}%", esc(p)),
+			Self::Contains(s) => format!("%{}%", esc(s)),
+		}
+	}
+}
+
 #[derive(Debug, Clone)]
 pub struct LogItem {
 	pub ts: DateTime<Utc>,
@@ -52,11 +152,92 @@ pub struct LogItem {
 	pub scope_name: String,
 	pub scope_attributes: HashMap<String, String>,
 	pub log_attributes: HashMap<String, String>,
+	// name of the fanout source the line was read from
+	pub source: Option<String>,
 }
 
 #[derive(Debug, Clone)]
 pub struct MetricItem {
-	pub level: LogLevel,
+	// the series the row belongs to, the labels the query sums by
+	pub labels: BTreeMap<String, String>,
 	pub total: u64,
 	pub ts: DateTime<Utc>,
 }
+
+impl MetricItem {
+	// by_level is the row of a query grouped by level only
+	pub fn by_level(level: LogLevel, total: u64, ts: DateTime<Utc>) -> Self {
+		Self {
+			labels: BTreeMap::from([(LEVEL_LABEL.to_string(), level.into())]),
+			total,
+			ts,
+		}
+	}
+}
+
+pub const LEVEL_LABEL: &str = "level";
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use logql::parser::{parse_logql_query, Query};
+
+	// labels without any knowledge of which values go together
+	#[derive(Clone)]
+	struct Labels;
+
+	#[async_trait]
+	impl LogStorage for Labels {
+		async fn query_stream(
+			&self,
+			_q: &LogQuery,
+			_opt: QueryLimits,
+		) -> Result<Vec<LogItem>> {
+			Ok(vec![])
+		}
+		async fn query_metrics(
+			&self,
+			_q: &MetricQuery,
+			_opt: QueryLimits,
+		) -> Result<Vec<MetricItem>> {
+			Ok(vec![])
+		}
+		async fn labels(&self, _opt: QueryLimits) -> Result<Vec<String>> {
+			Ok(vec!["app".to_string(), "env".to_string()])
+		}
+		async fn label_values(
+			&self,
+			label: &str,
+			_filter: Option<&ValueFilter>,
+			_opt: QueryLimits,
+		) -> Result<Vec<String>> {
+			let values = match label {
+				"app" => vec!["a1", "a2"],
+				_ => vec!["prod"],
+			};
+			Ok(values.into_iter().map(String::from).collect())
+		}
+	}
+
+	async fn series(selector: Option<&str>) -> Vec<HashMap<String, String>> {
+		let matches = selector.map(|s| match parse_logql_query(s).unwrap() {
+			Query::LogQuery(q) => q,
+			_ => unreachable!(),
+		});
+		Labels
+			.series(matches, QueryLimits::default())
+			.await
+			.unwrap()
+	}
+
+	#[tokio::test]
+	async fn test_default_series() {
+		let pair = |k: &str, v: &str| HashMap::from([(k.into(), v.into())]);
+		assert_eq!(series(None).await.len(), 3);
+		assert_eq!(
+			series(Some(r#"{app=~"a1|a3", env!="dev"}"#)).await,
+			vec![pair("app", "a1")]
+		);
+		assert!(series(Some(r#"{app="a1", env="prod"}"#)).await.is_empty());
+	}
+}
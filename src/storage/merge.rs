@@ -0,0 +1,174 @@
+use super::Direction;
+use std::{
+	cmp::{Ordering, Reverse},
+	collections::BinaryHeap,
+};
+
+// merge_sorted merges the results of several sources or shards into one,
+// ordered by key in the given direction and cut at limit. Rows with the
+// same key keep the order of their parts.
+// Each part is expected to be sorted already, it's sorted again anyway,
+// which costs a single pass when it is
+pub fn merge_sorted<T, K: Ord>(
+	parts: Vec<Vec<T>>,
+	key: impl Fn(&T) -> K,
+	direction: &Option<Direction>,
+	limit: Option<u32>,
+) -> Vec<T> {
+	let forward = matches!(direction, Some(Direction::Forward));
+	let limit = limit.map_or(usize::MAX, |l| l as usize);
+	let total: usize = parts.iter().map(Vec::len).sum();
+	let mut parts: Vec<_> = parts
+		.into_iter()
+		.map(|mut p| {
+			if forward {
+				p.sort_by_key(&key);
+			} else {
+				p.sort_by_key(|r| Reverse(key(r)));
+			}
+			p.into_iter()
+		})
+		.collect();
+	let mut heap = BinaryHeap::with_capacity(parts.len());
+	for (part, rows) in parts.iter_mut().enumerate() {
+		if let Some(item) = rows.next() {
+			heap.push(Head::new(item, part, &key, forward));
+		}
+	}
+	let mut out = Vec::with_capacity(total.min(limit));
+	while out.len() < limit {
+		let Some(head) = heap.pop() else {
+			break;
+		};
+		if let Some(item) = parts[head.part].next() {
+			heap.push(Head::new(item, head.part, &key, forward));
+		}
+		out.push(head.item);
+	}
+	out
+}
+
+// Head is the next row of a part, the heap pops the one that comes first
+struct Head<T, K> {
+	key: K,
+	part: usize,
+	forward: bool,
+	item: T,
+}
+
+impl<T, K: Ord> Head<T, K> {
+	fn new(item: T, part: usize, key: impl Fn(&T) -> K, forward: bool) -> Self {
+		Self {
+			key: key(&item),
+			part,
+			forward,
+			item,
+		}
+	}
+}
+
+impl<T, K: Ord> Ord for Head<T, K> {
+	fn cmp(&self, other: &Self) -> Ordering {
+		// BinaryHeap is a max heap, so "first" has to compare greatest
+		let by_key = if self.forward {
+			other.key.cmp(&self.key)
+		} else {
+			self.key.cmp(&other.key)
+		};
+		by_key.then_with(|| other.part.cmp(&self.part))
+	}
+}
+
+impl<T, K: Ord> PartialOrd for Head<T, K> {
+	fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+		Some(self.cmp(other))
+	}
+}
+
+impl<T, K: Ord> PartialEq for Head<T, K> {
+	fn eq(&self, other: &Self) -> bool {
+		self.cmp(other) == Ordering::Equal
+	}
+}
+
+impl<T, K: Ord> Eq for Head<T, K> {}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use pretty_assertions::assert_eq;
+	use proptest::prelude::*;
+
+	// the same result a plain sort of all rows gives
+	fn model(
+		parts: &[Vec<(i64, usize, usize)>],
+		forward: bool,
+		limit: Option<u32>,
+	) -> Vec<(i64, usize, usize)> {
+		let mut rows: Vec<_> = parts.iter().flatten().cloned().collect();
+		if forward {
+			rows.sort_by_key(|r| r.0);
+		} else {
+			rows.sort_by_key(|r| Reverse(r.0));
+		}
+		rows.truncate(limit.map_or(usize::MAX, |l| l as usize));
+		rows
+	}
+
+	fn parts_strategy() -> impl Strategy<Value = Vec<Vec<(i64, usize, usize)>>>
+	{
+		// few distinct keys, so that ties are common
+		prop::collection::vec(prop::collection::vec(0i64..20, 0..30), 0..6)
+			.prop_map(|parts| {
+				parts
+					.into_iter()
+					.enumerate()
+					.map(|(p, keys)| {
+						keys.into_iter()
+							.enumerate()
+							.map(|(i, k)| (k, p, i))
+							.collect()
+					})
+					.collect()
+			})
+	}
+
+	proptest! {
+		#[test]
+		fn prop_merge_sorted(
+			parts in parts_strategy(),
+			forward in any::<bool>(),
+			limit in prop::option::of(0u32..100),
+		) {
+			let direction = if forward {
+				Some(Direction::Forward)
+			} else {
+				Some(Direction::Backward)
+			};
+			// parts come in sorted, like they do from a database
+			let sorted: Vec<_> = parts
+				.iter()
+				.map(|p| model(&[p.clone()], forward, None))
+				.collect();
+			let want = model(&sorted, forward, limit);
+			let got = merge_sorted(sorted.clone(), |r| r.0, &direction, limit);
+			prop_assert_eq!(&got, &want);
+			// and unsorted parts end up the same
+			let got = merge_sorted(parts.clone(), |r| r.0, &direction, limit);
+			prop_assert_eq!(got, model(&sorted, forward, limit));
+		}
+	}
+
+	#[test]
+	fn test_merge_sorted() {
+		let parts = vec![vec![5, 3, 1], vec![4, 3], vec![]];
+		assert_eq!(
+			merge_sorted(parts.clone(), |v| *v, &None, Some(3)),
+			[5, 4, 3]
+		);
+		assert_eq!(
+			merge_sorted(parts, |v| *v, &Some(Direction::Forward), None),
+			[1, 3, 3, 4, 5]
+		);
+	}
+}
@@ -1,22 +1,129 @@
-use crate::config::{ClickhouseConf, DataSource};
+#[cfg(any(feature = "clickhouse", feature = "quickwit"))]
+use crate::config::HttpClient;
+use crate::config::{DataSource, HttpPool};
 use anyhow::Result;
 use chrono::NaiveDateTime;
-use std::time::Duration;
+use std::{sync::OnceLock, time::Duration};
 
+#[cfg(feature = "clickhouse")]
 pub mod ck;
+#[cfg(feature = "databend")]
 pub mod databend;
+pub mod explain;
+pub mod fanout;
+pub mod labels;
 pub mod log;
+pub mod merge;
+#[cfg(feature = "quickwit")]
 pub mod quickwit;
+pub(crate) mod registry;
+pub mod schema_check;
+pub mod shadow;
+pub mod stats;
+pub mod tiered;
+pub mod timelit;
 pub mod trace;
 
+#[cfg(not(any(
+	feature = "clickhouse",
+	feature = "databend",
+	feature = "quickwit"
+)))]
+compile_error!("at least one of clickhouse, databend and quickwit is needed");
+
 const DEFAULT_STEP: Duration = Duration::from_secs(60);
 
+static HTTP_POOL: OnceLock<HttpPool> = OnceLock::new();
+
+// set once at startup, before any source is created
+pub fn set_http_pool(pool: HttpPool) {
+	let _ = HTTP_POOL.set(pool);
+}
+
+// http_client is the builder every backend client starts from, the
+// source's own settings win over the shared pool ones
+#[cfg(any(feature = "clickhouse", feature = "quickwit"))]
+pub(crate) fn http_client(cfg: &HttpClient) -> reqwest::ClientBuilder {
+	let pool = HTTP_POOL.get();
+	let mut b = reqwest::Client::builder().tcp_keepalive(cfg.tcp_keepalive);
+	if let Some(n) = cfg
+		.pool_max_idle_per_host
+		.or(pool.and_then(|p| p.max_idle_per_host))
+	{
+		b = b.pool_max_idle_per_host(n);
+	}
+	if let Some(d) = cfg.pool_idle_timeout.or(pool.and_then(|p| p.idle_timeout))
+	{
+		b = b.pool_idle_timeout(d);
+	}
+	if cfg.http2_prior_knowledge {
+		b = b.http2_prior_knowledge();
+	}
+	b
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct QueryLimits {
 	pub limit: Option<u32>,
 	pub range: common::TimeRange,
 	pub direction: Option<Direction>,
 	pub step: Option<Duration>,
+	// spans per trace a trace search returns, all of them when unset
+	pub spss: Option<u32>,
+	// the max a backend puts on searches doesn't apply, an export reads
+	// every match
+	pub unbounded: bool,
+}
+
+// Capabilities tells the http layer which query features a backend
+// can actually push down, so unsupported queries are rejected up front
+// instead of panicking or silently returning nothing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+	// regex label matchers and line filters
+	pub regex: bool,
+	// traceql search, `{...}`
+	pub span_search: bool,
+	// spansets joined by `&&` or `||`
+	pub logical_spanset: bool,
+	// spansets joined by `>` or `>>`
+	pub structural: bool,
+	// `| json` followed by label filters on the extracted fields
+	pub json_stage: bool,
+	// search_trace_ids and search_span_in are cheaper than search_span,
+	// the default ones run the whole search
+	pub trace_ids_first: bool,
+	// metric queries summed by labels other than level
+	pub group_by_labels: bool,
+}
+
+impl Default for Capabilities {
+	fn default() -> Self {
+		Self {
+			regex: true,
+			span_search: true,
+			logical_spanset: true,
+			structural: true,
+			json_stage: true,
+			trace_ids_first: false,
+			group_by_labels: true,
+		}
+	}
+}
+
+impl Capabilities {
+	// what both can push down, for sources that combine several backends
+	pub fn and(self, o: Self) -> Self {
+		Self {
+			regex: self.regex && o.regex,
+			span_search: self.span_search && o.span_search,
+			logical_spanset: self.logical_spanset && o.logical_spanset,
+			structural: self.structural && o.structural,
+			json_stage: self.json_stage && o.json_stage,
+			trace_ids_first: self.trace_ids_first && o.trace_ids_first,
+			group_by_labels: self.group_by_labels && o.group_by_labels,
+		}
+	}
 }
 
 #[derive(Debug, Clone, Default)]
@@ -29,27 +136,9 @@ pub enum Direction {
 pub async fn new_trace_source(
 	d: DataSource,
 ) -> Result<Box<dyn trace::TraceStorage>> {
-	match d {
-		DataSource::Databend(cfg) => databend::new_trace_source(cfg).await,
-		DataSource::Quickwit(cfg) => quickwit::new_trace_source(cfg).await,
-		DataSource::Clickhouse(cfg) => match cfg {
-			ClickhouseConf::Trace(cfg) => ck::new_trace_source(cfg).await,
-			ClickhouseConf::Log(_) => {
-				panic!("cannot use ck log config for trace source")
-			}
-		},
-	}
+	registry::registry().new_trace_source(d).await
 }
 
 pub async fn new_log_source(d: DataSource) -> Result<Box<dyn log::LogStorage>> {
-	match d {
-		DataSource::Databend(cfg) => databend::new_log_source(cfg).await,
-		DataSource::Quickwit(cfg) => quickwit::new_log_source(cfg).await,
-		DataSource::Clickhouse(cfg) => match cfg {
-			ClickhouseConf::Log(cfg) => ck::new_log_source(cfg).await,
-			ClickhouseConf::Trace(_) => {
-				panic!("cannot use ck trace config for log source")
-			}
-		},
-	}
+	registry::registry().new_log_source(d).await
 }
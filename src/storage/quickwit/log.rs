@@ -7,7 +7,7 @@ use crate::storage::{log::*, *};
 use anyhow::Result;
 use async_trait::async_trait;
 use chrono::DateTime;
-use common::LogLevel;
+use common::{level::LevelMapping, LogLevel};
 use itertools::Itertools;
 use lazy_static::lazy_static;
 use logql::parser::{
@@ -30,14 +30,22 @@ lazy_static! {
 pub struct QuickwitLog {
 	schema: LogIndexMapping,
 	cli: QuickwitSdk,
+	series_labels: Vec<String>,
+	levels: LevelMapping,
 }
 
 impl QuickwitLog {
-	pub fn new(cfg: QuickwitServerConfig) -> Self {
+	pub fn new(
+		cfg: QuickwitServerConfig,
+		series_labels: Vec<String>,
+		levels: LevelMapping,
+	) -> Self {
 		let cli = QuickwitSdk::new(cfg);
 		QuickwitLog {
 			schema: LogIndexMapping::default(),
 			cli,
+			series_labels,
+			levels,
 		}
 	}
 	fn log_query_to_dsl(&self, q: &LogQuery) -> Option<Query> {
@@ -57,13 +65,16 @@ impl QuickwitLog {
 			Some(filters) => filters
 				.iter()
 				.filter_map(|f| match f {
-					Filter::Drop => None,
-					Filter::LogLine(l) => Some(l),
+					Filter::LogLine(l) => Some(loglinefilter_to_unary(l)),
+					// json fields are filtered in the bridge,
+					// see Capabilities::json_stage
+					Filter::Label(p) => Some(label_pair_to_unary(p)),
+					Filter::Drop(_) | Filter::Json => None,
 				})
-				.fold(query, |acc, p| match acc {
-					None => Some(Query::C(loglinefilter_to_unary(p))),
+				.fold(query, |acc, u| match acc {
+					None => Some(Query::C(u)),
 					Some(l) => {
-						let r = Query::C(loglinefilter_to_unary(p));
+						let r = Query::C(u);
 						Some(Query::And(Box::new(l), Box::new(r)))
 					}
 				}),
@@ -88,7 +99,7 @@ impl LogStorage for QuickwitLog {
 			.hits
 			.iter()
 			.filter_map(|h| serde_json::from_value::<LogRecord>(h.clone()).ok())
-			.map(record_to_logitem)
+			.map(|r| record_to_logitem(r, &self.levels))
 			.collect::<Vec<LogItem>>();
 		Ok(records)
 	}
@@ -104,7 +115,7 @@ impl LogStorage for QuickwitLog {
 			.cli
 			.level_aggregation(query, self.schema.ts_key(), interval)
 			.await?;
-		Ok(flatten_volume_agg_response(resp))
+		Ok(flatten_volume_agg_response(resp, &self.levels))
 	}
 	async fn labels(&self, opt: QueryLimits) -> Result<Vec<String>> {
 		self.cli
@@ -123,23 +134,74 @@ impl LogStorage for QuickwitLog {
 	async fn label_values(
 		&self,
 		label: &str,
+		filter: Option<&ValueFilter>,
 		opt: QueryLimits,
 	) -> Result<Vec<String>> {
 		let aliased_label = field_alias_v_2_k(label);
-		self.cli
+		// quickwit only does prefix queries, substrings are filtered here
+		let query = match filter {
+			Some(ValueFilter::Prefix(p)) => {
+				format!("{}:{}*", aliased_label, qwdsl::escape(p))
+			}
+			_ => "*".to_string(),
+		};
+		let mut values = self
+			.cli
 			.field_terms(
 				&aliased_label,
+				&query,
 				sdk::TimeRange {
 					start: opt.range.start,
 					end: opt.range.end,
 				},
 			)
-			.await
+			.await?;
+		if let Some(f) = filter {
+			values.retain(|v| f.matches(v));
+		}
+		Ok(values)
+	}
+	// one nested terms aggregation over series_labels, each path down
+	// to a leaf bucket is a combination that exists in the index
+	async fn series(
+		&self,
+		matches: Option<LogQuery>,
+		opt: QueryLimits,
+	) -> Result<Vec<HashMap<String, String>>> {
+		if self.series_labels.is_empty() {
+			return Ok(vec![]);
+		}
+		let query = matches.and_then(|q| self.log_query_to_dsl(&q));
+		let fields = self
+			.series_labels
+			.iter()
+			.map(|l| field_alias_v_2_k(l))
+			.collect_vec();
+		let combos = self
+			.cli
+			.multi_terms(build_metric_query(query, opt), &fields)
+			.await?;
+		Ok(combos
+			.into_iter()
+			.map(|values| {
+				self.series_labels.iter().cloned().zip(values).collect()
+			})
+			.collect())
+	}
+	// the metric aggregation only terms by level
+	fn capabilities(&self) -> Capabilities {
+		Capabilities {
+			regex: false,
+			json_stage: false,
+			group_by_labels: false,
+			..Default::default()
+		}
 	}
 }
 
 fn flatten_volume_agg_response(
 	resp: sdk::VolumeAggrResponse,
+	levels: &LevelMapping,
 ) -> Vec<MetricItem> {
 	resp.aggregations
 		.volume
@@ -151,10 +213,12 @@ fn flatten_volume_agg_response(
 			b.levels
 				.buckets
 				.into_iter()
-				.map(|ib| MetricItem {
-					ts,
-					level: ib.key.try_into().unwrap_or(LogLevel::Trace),
-					total: ib.doc_count as u64,
+				.map(|ib| {
+					MetricItem::by_level(
+						levels.level(&ib.key, 0).unwrap_or(LogLevel::Trace),
+						ib.doc_count as u64,
+						ts,
+					)
 				})
 				.collect_vec()
 		})
@@ -244,8 +308,19 @@ fn build_search_query(
 	}
 }
 
-fn record_to_logitem(r: LogRecord) -> LogItem {
-	let level = get_level(&r);
+fn record_to_logitem(r: LogRecord, levels: &LevelMapping) -> LogItem {
+	let body = r.body.as_ref().and_then(|v| v.get("message"));
+	let message = body.map(|v| v.to_string()).unwrap_or_default();
+	// the sniffer reads a json string unquoted, or {"level":"warn"} in it
+	// would be escaped and never match
+	let text = body.and_then(|v| v.as_str()).unwrap_or(&message);
+	let level = levels
+		.detect(
+			r.severity_text.as_deref().unwrap_or_default(),
+			u32::try_from(r.severity_number).unwrap_or(0),
+			text,
+		)
+		.unwrap_or(LogLevel::Trace);
 	LogItem {
 		ts: DateTime::from_timestamp_nanos(r.timestamp_nanos as i64),
 		trace_id: r.trace_id.unwrap_or("".to_string()),
@@ -254,28 +329,13 @@ fn record_to_logitem(r: LogRecord) -> LogItem {
 		service_name: r.service_name,
 		resource_attributes: jsonmap_to_stringmap(r.resource_attributes),
 		log_attributes: jsonmap_to_stringmap(r.attributes),
-		message: r
-			.body
-			.map(|v| {
-				v.get("message")
-					.map(|v| v.to_string())
-					.unwrap_or("".to_string())
-			})
-			.unwrap_or_default(),
+		message,
 		scope_name: r.scope_name.unwrap_or("".to_string()),
 		scope_attributes: jsonmap_to_stringmap(r.scope_attributes),
+		source: None,
 	}
 }
 
-fn get_level(r: &LogRecord) -> LogLevel {
-	if let Some(level_text) = &r.severity_text {
-		if !level_text.is_empty() {
-			return level_text.clone().try_into().unwrap_or(LogLevel::Trace);
-		}
-	}
-	(r.severity_number as u32).into()
-}
-
 fn jsonmap_to_stringmap(
 	m: HashMap<String, JSONValue>,
 ) -> HashMap<String, String> {
@@ -323,3 +383,25 @@ fn loglinefilter_to_unary(p: &LogLineFilter) -> Unary {
 		_ => unimplemented!("regexp is not supported yet"),
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use common::level::LevelSource;
+
+	#[test]
+	fn test_record_level_from_body() {
+		let levels = LevelMapping {
+			detect: vec![LevelSource::Body],
+			..Default::default()
+		};
+		let r: LogRecord = serde_json::from_value(serde_json::json!({
+			"timestamp_nanos": 1716190734199699272_u64,
+			"severity_number": 0,
+			"body": {"message": r#"{"level":"warn","msg":"slow"}"#},
+		}))
+		.unwrap();
+		let item = record_to_logitem(r, &levels);
+		assert_eq!(item.level, String::from(LogLevel::Warn));
+	}
+}
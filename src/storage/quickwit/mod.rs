@@ -1,5 +1,9 @@
-use super::{log::LogStorage, trace::TraceStorage};
-use crate::config::Quickwit;
+use super::{
+	log::LogStorage,
+	registry::{mismatch, Creating, Registry},
+	trace::TraceStorage,
+};
+use crate::config::{DataSource, HttpClient, Quickwit};
 use anyhow::Result;
 use std::{path::Path, time::Duration};
 use url::Url;
@@ -10,11 +14,15 @@ pub mod qwdsl;
 pub mod sdk;
 pub mod trace;
 
+// only used when the config wasn't loaded through AppConfig::new
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
 #[derive(Clone, Debug)]
 pub struct QuickwitServerConfig {
 	pub qw_endpoint: url::Url,
 	pub es_endpoint: url::Url,
 	pub timeout: Duration,
+	pub http: HttpClient,
 }
 
 impl QuickwitServerConfig {
@@ -28,13 +36,39 @@ impl QuickwitServerConfig {
 		Ok(QuickwitServerConfig {
 			qw_endpoint,
 			es_endpoint,
-			timeout: cfg.timeout,
+			timeout: cfg.timeout.unwrap_or(DEFAULT_TIMEOUT),
+			http: cfg.http,
 		})
 	}
 }
 
+pub(super) fn register(r: &mut Registry) {
+	r.log("quickwit", log_factory);
+	r.trace("quickwit", trace_factory);
+}
+
+fn log_factory(d: DataSource) -> Creating<dyn LogStorage> {
+	match d {
+		DataSource::Quickwit(cfg) => Box::pin(new_log_source(cfg)),
+		d => mismatch("quickwit", &d),
+	}
+}
+
+fn trace_factory(d: DataSource) -> Creating<dyn TraceStorage> {
+	match d {
+		DataSource::Quickwit(cfg) => Box::pin(new_trace_source(cfg)),
+		d => mismatch("quickwit", &d),
+	}
+}
+
 pub async fn new_log_source(cfg: Quickwit) -> Result<Box<dyn LogStorage>> {
-	let inner = log::QuickwitLog::new(QuickwitServerConfig::new(cfg)?);
+	let series_labels = cfg.series_labels.clone();
+	let levels = cfg.levels.clone();
+	let inner = log::QuickwitLog::new(
+		QuickwitServerConfig::new(cfg)?,
+		series_labels,
+		levels,
+	);
 	Ok(Box::new(inner))
 }
 
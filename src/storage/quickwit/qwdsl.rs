@@ -47,6 +47,19 @@ fn is_valid_string(input: &str) -> bool {
 		.all(|c| c.is_ascii_alphanumeric() || ".-_/@$".contains(c))
 }
 
+// escape the query syntax characters of a value that can't be quoted,
+// e.g. the start of a prefix query
+pub fn escape(input: &str) -> String {
+	let mut out = String::with_capacity(input.len());
+	for c in input.chars() {
+		if c.is_whitespace() || "+-&|!(){}[]^\"~*?:\\/<>=".contains(c) {
+			out.push('\\');
+		}
+		out.push(c);
+	}
+	out
+}
+
 pub enum Unary {
 	Pos(Clause),
 	Neg(Clause),
@@ -148,4 +161,12 @@ mod tests {
 			assert_eq!(expected, actual);
 		}
 	}
+
+	#[test]
+	fn test_escape() {
+		assert_eq!(escape("api-v1"), r"api\-v1");
+		assert_eq!(escape("a b:c*"), r"a\ b\:c\*");
+		assert_eq!(escape(r#"x") OR (y"#), r#"x\"\)\ OR\ \(y"#);
+		assert_eq!(escape("plain.value_1"), "plain.value_1");
+	}
 }
@@ -1,9 +1,9 @@
 use super::QuickwitServerConfig;
-use crate::utils::log::ResultLogger;
-use anyhow::{anyhow, Result};
+use crate::{storage::http_client, utils::log::ResultLogger};
+use anyhow::{anyhow, bail, Result};
 use chrono::NaiveDateTime;
 use itertools::Itertools;
-use reqwest::Client;
+use reqwest::{Client, StatusCode};
 use serde::{Deserialize, Serialize, Serializer};
 use serde_json::Value as JsonValue;
 use std::collections::HashMap;
@@ -16,7 +16,8 @@ pub struct QuickwitSdk {
 
 impl QuickwitSdk {
 	pub fn new(cfg: QuickwitServerConfig) -> Self {
-		let client = Client::builder().timeout(cfg.timeout).build().unwrap();
+		let client =
+			http_client(&cfg.http).timeout(cfg.timeout).build().unwrap();
 		Self { client, cfg }
 	}
 	pub async fn search_records<I>(
@@ -105,10 +106,11 @@ impl QuickwitSdk {
 	pub async fn field_terms(
 		&self,
 		key: &str,
+		query: &str,
 		ts: TimeRange,
 	) -> Result<Vec<String>> {
 		let mut body = serde_json::json!({
-			"query": "*",
+			"query": query,
 			"max_hits": 0,
 			"aggs": {
 				"field_vals": {
@@ -151,6 +153,79 @@ impl QuickwitSdk {
 				agg.buckets.iter().map(|b| b.key.clone()).collect()
 			}))
 	}
+	// the combinations of values of fields that occur together
+	pub async fn multi_terms(
+		&self,
+		mut query: SearcgRequest,
+		fields: &[String],
+	) -> Result<Vec<Vec<String>>> {
+		query.aggs = Some(nested_terms(fields));
+		let mut p = self.cfg.qw_endpoint.clone();
+		p.path_segments_mut().unwrap().push("search");
+		let resp = self.client.post(p).json(&query).send().await?;
+		let status = resp.status();
+		let body = resp.text().await?;
+		combinations(status, &body).log_e()
+	}
+}
+
+// a failed search must not read as an index without series
+fn combinations(status: StatusCode, body: &str) -> Result<Vec<Vec<String>>> {
+	if !status.is_success() {
+		bail!("quickwit responded {}: {}", status, body);
+	}
+	let res: SearchResponseRest = serde_json::from_str(body)?;
+	if !res.errors.is_empty() {
+		bail!("quickwit search failed: {}", res.errors.join("; "));
+	}
+	Ok(res
+		.aggregations
+		.map_or_else(Vec::new, |aggs| flatten_nested_terms(&aggs)))
+}
+
+const NESTED_TERMS_AGG: &str = "series";
+const NESTED_TERMS_SIZE: usize = 1000;
+
+fn nested_terms(fields: &[String]) -> JsonValue {
+	fields.iter().rev().fold(JsonValue::Null, |inner, f| {
+		let mut agg = serde_json::json!({
+			"terms": {
+				"field": f,
+				"size": NESTED_TERMS_SIZE,
+			}
+		});
+		if !inner.is_null() {
+			agg["aggs"] = inner;
+		}
+		serde_json::json!({ NESTED_TERMS_AGG: agg })
+	})
+}
+
+// walks the buckets depth first, a bucket without a nested aggregation
+// ends a combination
+fn flatten_nested_terms(aggs: &JsonValue) -> Vec<Vec<String>> {
+	let Some(buckets) = aggs[NESTED_TERMS_AGG]["buckets"].as_array() else {
+		return vec![];
+	};
+	buckets
+		.iter()
+		.flat_map(|b| {
+			let key = match &b["key"] {
+				JsonValue::String(s) => s.clone(),
+				v => v.to_string(),
+			};
+			if b.get(NESTED_TERMS_AGG).is_none() {
+				return vec![vec![key]];
+			}
+			flatten_nested_terms(b)
+				.into_iter()
+				.map(|mut rest| {
+					rest.insert(0, key.clone());
+					rest
+				})
+				.collect()
+		})
+		.collect()
 }
 
 fn append_key_to_object(
@@ -358,6 +433,50 @@ fn is_zero(count: &u32) -> bool {
 mod tests {
 	use super::*;
 
+	#[test]
+	fn test_nested_terms() {
+		let fields =
+			vec!["service_name".to_string(), "severity_text".to_string()];
+		let aggs = nested_terms(&fields);
+		assert_eq!(aggs["series"]["terms"]["field"], "service_name");
+		assert_eq!(
+			aggs["series"]["aggs"]["series"]["terms"]["field"],
+			"severity_text"
+		);
+		let resp = serde_json::json!({
+			"series": {"buckets": [
+				{"key": "api", "doc_count": 3, "series": {"buckets": [
+					{"key": "INFO", "doc_count": 2},
+					{"key": "ERROR", "doc_count": 1},
+				]}},
+				{"key": "db", "doc_count": 1, "series": {"buckets": []}},
+			]}
+		});
+		assert_eq!(
+			flatten_nested_terms(&resp),
+			vec![vec!["api", "INFO"], vec!["api", "ERROR"]]
+		);
+	}
+
+	#[test]
+	fn test_combinations() {
+		let ok = r#"{"num_hits": 1, "elapsed_time_micros": 5,
+			"aggregations": {"series": {"buckets": [{"key": "api"}]}}}"#;
+		assert_eq!(
+			combinations(StatusCode::OK, ok).unwrap(),
+			vec![vec!["api"]]
+		);
+		let failed = r#"{"num_hits": 0, "elapsed_time_micros": 5,
+			"errors": ["split 1 timed out"]}"#;
+		assert!(combinations(StatusCode::OK, failed).is_err());
+		let err = combinations(
+			StatusCode::BAD_REQUEST,
+			r#"{"message": "unknown field"}"#,
+		)
+		.unwrap_err();
+		assert!(err.to_string().contains("unknown field"));
+	}
+
 	#[test]
 	fn test_de_aggs() {
 		let j = r#"{
@@ -19,6 +19,36 @@ impl QuickwitTrace {
 		let cli = sdk::QuickwitSdk::new(cfg);
 		QuickwitTrace { cli }
 	}
+	async fn spans(&self, query: &sdk::SearcgRequest) -> Result<Vec<SpanItem>> {
+		let sps: Vec<SpanItem> = self
+			.cli
+			.search_records(query)
+			.await?
+			.hits
+			.into_iter()
+			.filter_map(|v| {
+				let sp: Option<QuickwitSpan> =
+					serde_json::from_value(v).map_err(|e| anyhow!(e)).ok();
+				sp
+			})
+			.map(Into::into)
+			.collect_vec();
+		Ok(sps)
+	}
+}
+
+// traces_query matches the spans of trace_ids, or only their roots, which
+// the otel index flags with is_root
+fn traces_query(trace_ids: &[String], roots: bool) -> String {
+	let traces = trace_ids
+		.iter()
+		.flat_map(|id| trace_id_forms(id))
+		.map(|id| format!("trace_id:{}", id))
+		.join(" OR ");
+	match roots {
+		true => format!("({}) AND is_root:true", traces),
+		false => traces,
+	}
 }
 
 #[async_trait]
@@ -29,25 +59,32 @@ impl TraceStorage for QuickwitTrace {
 		opt: QueryLimits,
 	) -> Result<Vec<SpanItem>> {
 		let query = sdk::SearcgRequest {
-			query: format!("trace_id:{}", trace_id),
+			query: traces_query(&[trace_id.to_string()], false),
 			start_timestamp: opt.range.start.map(|v| v.and_utc().timestamp()),
 			end_timestamp: opt.range.end.map(|v| v.and_utc().timestamp()),
 			..Default::default()
 		};
-		let sps: Vec<SpanItem> = self
-			.cli
-			.search_records(&query)
-			.await?
-			.hits
-			.into_iter()
-			.filter_map(|v| {
-				let sp: Option<QuickwitSpan> =
-					serde_json::from_value(v).map_err(|e| anyhow!(e)).ok();
-				sp
-			})
-			.map(Into::into)
-			.collect_vec();
-		Ok(sps)
+		self.spans(&query).await
+	}
+	async fn trace_roots(
+		&self,
+		trace_ids: &[String],
+		opt: QueryLimits,
+	) -> Result<Vec<SpanItem>> {
+		if trace_ids.is_empty() {
+			return Ok(vec![]);
+		}
+		// one root a trace, or one for each form a trace is stored in
+		let forms: usize =
+			trace_ids.iter().map(|id| trace_id_forms(id).len()).sum();
+		let query = sdk::SearcgRequest {
+			query: traces_query(trace_ids, true),
+			max_hits: Some(forms as u64),
+			start_timestamp: opt.range.start.map(|v| v.and_utc().timestamp()),
+			end_timestamp: opt.range.end.map(|v| v.and_utc().timestamp()),
+			..Default::default()
+		};
+		self.spans(&query).await
 	}
 	async fn search_span(
 		&self,
@@ -56,6 +93,12 @@ impl TraceStorage for QuickwitTrace {
 	) -> Result<Vec<SpanItem>> {
 		Ok(vec![])
 	}
+	fn capabilities(&self) -> Capabilities {
+		Capabilities {
+			span_search: false,
+			..Default::default()
+		}
+	}
 }
 
 #[derive(Debug, Default, Clone, Deserialize)]
@@ -213,6 +256,19 @@ where
 mod tests {
 	use super::*;
 
+	#[test]
+	fn test_traces_query() {
+		let ids = vec!["0000000000000000abc".to_string(), "def".to_string()];
+		assert_eq!(
+			traces_query(&ids, false),
+			"trace_id:0000000000000000abc OR trace_id:abc OR trace_id:def"
+		);
+		assert_eq!(
+			traces_query(&ids, true),
+			"(trace_id:0000000000000000abc OR trace_id:abc OR trace_id:def) AND is_root:true"
+		);
+	}
+
 	#[test]
 	fn test_der_qw_trace_json() {
 		let j = serde_json::json!(        {
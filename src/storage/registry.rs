@@ -0,0 +1,104 @@
+use super::{log::LogStorage, trace::TraceStorage};
+use crate::config::DataSource;
+use anyhow::{bail, Result};
+use std::{collections::HashMap, future::Future, pin::Pin, sync::OnceLock};
+
+pub(crate) type Creating<T> =
+	Pin<Box<dyn Future<Output = Result<Box<T>>> + Send>>;
+
+pub(crate) type LogFactory = fn(DataSource) -> Creating<dyn LogStorage>;
+pub(crate) type TraceFactory = fn(DataSource) -> Creating<dyn TraceStorage>;
+
+static REGISTRY: OnceLock<Registry> = OnceLock::new();
+
+// Registry maps the tag of a source in the config to what creates it. A
+// backend left out of the build has no config either, so a missing
+// factory means the source isn't supported there, e.g. fanout for traces
+#[derive(Default)]
+pub(crate) struct Registry {
+	logs: HashMap<&'static str, LogFactory>,
+	traces: HashMap<&'static str, TraceFactory>,
+}
+
+impl Registry {
+	pub(crate) fn log(&mut self, tag: &'static str, f: LogFactory) {
+		self.logs.insert(tag, f);
+	}
+
+	pub(crate) fn trace(&mut self, tag: &'static str, f: TraceFactory) {
+		self.traces.insert(tag, f);
+	}
+
+	fn builtin() -> Self {
+		let mut r = Self::default();
+		#[cfg(feature = "clickhouse")]
+		super::ck::register(&mut r);
+		#[cfg(feature = "databend")]
+		super::databend::register(&mut r);
+		#[cfg(feature = "quickwit")]
+		super::quickwit::register(&mut r);
+		super::fanout::register(&mut r);
+		super::tiered::register(&mut r);
+		super::shadow::register(&mut r);
+		r
+	}
+
+	// the factory is copied out so nothing is borrowed while it runs
+	pub(crate) async fn new_log_source(
+		&self,
+		d: DataSource,
+	) -> Result<Box<dyn LogStorage>> {
+		match self.logs.get(d.tag()).copied() {
+			Some(f) => f(d).await,
+			None => bail!("{} can't be used as the log source", d.tag()),
+		}
+	}
+
+	pub(crate) async fn new_trace_source(
+		&self,
+		d: DataSource,
+	) -> Result<Box<dyn TraceStorage>> {
+		match self.traces.get(d.tag()).copied() {
+			Some(f) => f(d).await,
+			None => bail!("{} can't be used as the trace source", d.tag()),
+		}
+	}
+}
+
+pub(crate) fn registry() -> &'static Registry {
+	REGISTRY.get_or_init(Registry::builtin)
+}
+
+// what a factory returns for the config of another backend, the registry
+// only hands a factory the sources registered under its tag
+pub(crate) fn mismatch<T: ?Sized + 'static>(
+	tag: &'static str,
+	d: &DataSource,
+) -> Creating<T> {
+	let got = d.tag();
+	Box::pin(async move { bail!("the {} factory got a {} source", tag, got) })
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::config::{Fanout, MergeStrategy};
+
+	#[tokio::test]
+	async fn test_unregistered() {
+		let fanout = DataSource::Fanout(Fanout {
+			sources: vec![],
+			merge: MergeStrategy::default(),
+		});
+		let err = registry()
+			.new_trace_source(fanout.clone())
+			.await
+			.err()
+			.unwrap()
+			.to_string();
+		assert_eq!(err, "fanout can't be used as the trace source");
+		// registered, fails in the factory itself
+		let err = registry().new_log_source(fanout).await.err().unwrap();
+		assert_eq!(err.to_string(), "fanout needs at least one source");
+	}
+}
@@ -0,0 +1,109 @@
+use crate::config::SchemaCheck;
+use anyhow::{bail, Result};
+use itertools::Itertools;
+use std::{collections::HashMap, fmt};
+use tracing::{info, warn};
+
+// SchemaDiff is what a table lacks compared to the columns the bridge reads
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SchemaDiff {
+	pub missing: Vec<String>,
+	// column, expected type family, actual type
+	pub mismatched: Vec<(String, String, String)>,
+}
+
+impl SchemaDiff {
+	pub fn is_empty(&self) -> bool {
+		self.missing.is_empty() && self.mismatched.is_empty()
+	}
+}
+
+impl fmt::Display for SchemaDiff {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let mut parts = vec![];
+		if !self.missing.is_empty() {
+			parts.push(format!("missing columns: {}", self.missing.join(", ")));
+		}
+		if !self.mismatched.is_empty() {
+			parts.push(format!(
+				"incompatible columns: {}",
+				self.mismatched
+					.iter()
+					.map(|(c, want, got)| format!(
+						"{} (want {}, got {})",
+						c, want, got
+					))
+					.join(", ")
+			));
+		}
+		write!(f, "{}", parts.join("; "))
+	}
+}
+
+// diff compares the described columns against the expected ones,
+// family maps a backend type to the name used in expected
+pub fn diff(
+	expected: &[(&str, &str)],
+	actual: &[(String, String)],
+	family: impl Fn(&str) -> String,
+) -> SchemaDiff {
+	let actual: HashMap<&str, &str> = actual
+		.iter()
+		.map(|(c, t)| (c.as_str(), t.as_str()))
+		.collect();
+	let mut d = SchemaDiff::default();
+	for (col, want) in expected {
+		match actual.get(col) {
+			None => d.missing.push(col.to_string()),
+			Some(got) if family(got) != *want => d.mismatched.push((
+				col.to_string(),
+				want.to_string(),
+				got.to_string(),
+			)),
+			_ => {}
+		}
+	}
+	d
+}
+
+pub fn report(table: &str, d: &SchemaDiff, mode: SchemaCheck) -> Result<()> {
+	if d.is_empty() {
+		info!("schema of {} checked", table);
+		return Ok(());
+	}
+	match mode {
+		SchemaCheck::Off => Ok(()),
+		SchemaCheck::Warn => {
+			warn!("table {} doesn't match: {}", table, d);
+			Ok(())
+		}
+		SchemaCheck::Fail => bail!("table {} doesn't match: {}", table, d),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use pretty_assertions::assert_eq;
+
+	#[test]
+	fn test_diff() {
+		let actual = vec![
+			("ts".to_string(), "TIMESTAMP".to_string()),
+			("level".to_string(), "VARCHAR".to_string()),
+		];
+		let d = diff(
+			&[("ts", "timestamp"), ("level", "int"), ("message", "string")],
+			&actual,
+			|t| match t {
+				"TIMESTAMP" => "timestamp".to_string(),
+				_ => "string".to_string(),
+			},
+		);
+		assert_eq!(
+			d.to_string(),
+			"missing columns: message; \
+			 incompatible columns: level (want int, got VARCHAR)"
+		);
+	}
+}
@@ -0,0 +1,228 @@
+use super::{
+	labels::Snapshot,
+	log::{LogItem, LogStorage, MetricItem, ValueFilter},
+	registry::{mismatch, Creating, Registry},
+	Capabilities, QueryLimits,
+};
+use crate::{
+	config::{DataSource, Shadow},
+	metrics::ShadowInstrumentations,
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use common::TimeRange;
+use logql::parser::{LogQuery, MetricQuery};
+use std::{
+	collections::HashMap,
+	future::Future,
+	hash::{DefaultHasher, Hash, Hasher},
+	sync::Arc,
+};
+use tokio::sync::Semaphore;
+use tracing::warn;
+
+// ShadowLog answers every read from primary, then replays it against
+// shadow in the background and compares what the two returned
+#[derive(Clone)]
+pub struct ShadowLog {
+	primary: Box<dyn LogStorage>,
+	shadow: Box<dyn LogStorage>,
+	inflight: Arc<Semaphore>,
+	metrics: ShadowInstrumentations,
+}
+
+pub(super) fn register(r: &mut Registry) {
+	r.log("shadow", log_factory);
+}
+
+fn log_factory(d: DataSource) -> Creating<dyn LogStorage> {
+	match d {
+		DataSource::Shadow(cfg) => Box::pin(new_log_source(cfg)),
+		d => mismatch("shadow", &d),
+	}
+}
+
+pub async fn new_log_source(cfg: Shadow) -> Result<Box<dyn LogStorage>> {
+	let primary = Box::pin(super::new_log_source(*cfg.primary)).await?;
+	let shadow = Box::pin(super::new_log_source(*cfg.shadow)).await?;
+	Ok(Box::new(ShadowLog {
+		primary,
+		shadow,
+		inflight: Arc::new(Semaphore::new(cfg.max_inflight)),
+		metrics: ShadowInstrumentations::default(),
+	}))
+}
+
+// Digest sums the hashes of the rows, so the order they come in
+// doesn't matter, e.g. lines with the same timestamp
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Digest {
+	rows: usize,
+	hash: u64,
+}
+
+fn digest<T: Hash>(items: impl IntoIterator<Item = T>) -> Digest {
+	items
+		.into_iter()
+		.fold(Digest { rows: 0, hash: 0 }, |d, item| {
+			let mut h = DefaultHasher::new();
+			item.hash(&mut h);
+			Digest {
+				rows: d.rows + 1,
+				hash: d.hash.wrapping_add(h.finish()),
+			}
+		})
+}
+
+fn log_digest(rows: &[LogItem]) -> Digest {
+	digest(rows.iter().map(|r| (r.ts, &r.service_name, &r.message)))
+}
+
+fn metric_digest(items: &[MetricItem]) -> Digest {
+	digest(items.iter().map(|i| (&i.labels, i.ts, i.total)))
+}
+
+fn series_digest(series: &[HashMap<String, String>]) -> Digest {
+	digest(series.iter().map(|s| {
+		let mut pairs: Vec<_> = s.iter().collect();
+		pairs.sort();
+		pairs
+	}))
+}
+
+impl ShadowLog {
+	// compare runs the shadow read if there's room for it, otherwise it's
+	// skipped. The primary result is returned without waiting for it
+	fn compare<F, Fut>(&self, op: &'static str, want: Digest, f: F)
+	where
+		F: FnOnce(Box<dyn LogStorage>) -> Fut,
+		Fut: Future<Output = Result<Digest>> + Send + 'static,
+	{
+		let Ok(permit) = self.inflight.clone().try_acquire_owned() else {
+			self.metrics.record(op, "skipped");
+			return;
+		};
+		let fut = f(self.shadow.clone());
+		let metrics = self.metrics.clone();
+		tokio::spawn(async move {
+			let result = match fut.await {
+				Ok(got) if got == want => "match",
+				Ok(got) => {
+					warn!(
+						"shadow {} differs: primary has {} rows, shadow has {}",
+						op, want.rows, got.rows
+					);
+					"mismatch"
+				}
+				Err(e) => {
+					warn!("shadow {} fails: {}", op, e);
+					"error"
+				}
+			};
+			metrics.record(op, result);
+			drop(permit);
+		});
+	}
+}
+
+#[async_trait]
+impl LogStorage for ShadowLog {
+	async fn query_stream(
+		&self,
+		q: &LogQuery,
+		opt: QueryLimits,
+	) -> Result<Vec<LogItem>> {
+		let rows = self.primary.query_stream(q, opt.clone()).await?;
+		let q = q.clone();
+		self.compare("query_stream", log_digest(&rows), move |h| async move {
+			h.query_stream(&q, opt).await.map(|r| log_digest(&r))
+		});
+		Ok(rows)
+	}
+	async fn query_metrics(
+		&self,
+		q: &MetricQuery,
+		opt: QueryLimits,
+	) -> Result<Vec<MetricItem>> {
+		let items = self.primary.query_metrics(q, opt.clone()).await?;
+		let q = q.clone();
+		self.compare(
+			"query_metrics",
+			metric_digest(&items),
+			move |h| async move {
+				h.query_metrics(&q, opt).await.map(|r| metric_digest(&r))
+			},
+		);
+		Ok(items)
+	}
+	async fn labels(&self, opt: QueryLimits) -> Result<Vec<String>> {
+		let labels = self.primary.labels(opt.clone()).await?;
+		self.compare("labels", digest(&labels), move |h| async move {
+			h.labels(opt).await.map(digest)
+		});
+		Ok(labels)
+	}
+	async fn label_values(
+		&self,
+		label: &str,
+		filter: Option<&ValueFilter>,
+		opt: QueryLimits,
+	) -> Result<Vec<String>> {
+		let values = self
+			.primary
+			.label_values(label, filter, opt.clone())
+			.await?;
+		let (label, filter) = (label.to_string(), filter.cloned());
+		self.compare("label_values", digest(&values), move |h| async move {
+			h.label_values(&label, filter.as_ref(), opt)
+				.await
+				.map(digest)
+		});
+		Ok(values)
+	}
+	async fn series(
+		&self,
+		matches: Option<LogQuery>,
+		opt: QueryLimits,
+	) -> Result<Vec<HashMap<String, String>>> {
+		let series = self.primary.series(matches.clone(), opt.clone()).await?;
+		self.compare("series", series_digest(&series), move |h| async move {
+			h.series(matches, opt).await.map(|s| series_digest(&s))
+		});
+		Ok(series)
+	}
+	fn high_cardinality_labels(&self) -> Vec<String> {
+		self.primary.high_cardinality_labels()
+	}
+	fn series_snapshot(&self, label: Option<&str>) -> Option<Snapshot> {
+		self.primary.series_snapshot(label)
+	}
+	fn restore_series(&self, snapshot: Snapshot) -> Result<()> {
+		self.primary.restore_series(snapshot)
+	}
+	// only reads are mirrored
+	async fn delete(&self, q: &LogQuery, range: TimeRange) -> Result<()> {
+		self.primary.delete(q, range).await
+	}
+	fn can_delete(&self) -> bool {
+		self.primary.can_delete()
+	}
+	fn capabilities(&self) -> Capabilities {
+		self.primary.capabilities()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use pretty_assertions::assert_eq;
+
+	#[test]
+	fn test_digest() {
+		let a = digest(["x", "y", "z"]);
+		assert_eq!(a, digest(["z", "x", "y"]));
+		assert_eq!(a.rows, 3);
+		assert!(a != digest(["x", "y"]));
+		assert!(a != digest(["x", "y", "w"]));
+	}
+}
@@ -0,0 +1,131 @@
+use crate::utils::log::redact_sql;
+use std::{
+	future::Future,
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Arc, Mutex,
+	},
+	time::Duration,
+};
+
+// what the backend reported for a single request, it's collected
+// through a task local so the storage traits don't have to return it
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct QueryStats {
+	pub rows_processed: u64,
+	pub bytes_processed: u64,
+	// every statement sent to the backend, in the order they finished
+	pub statements: Vec<Statement>,
+	// decisions worth knowing when reading the sql, e.g. which lookup a
+	// line filter got
+	pub notes: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Statement {
+	pub sql: String,
+	pub elapsed: Duration,
+}
+
+#[derive(Debug, Default)]
+pub struct StatsCollector {
+	rows: AtomicU64,
+	bytes: AtomicU64,
+	statements: Mutex<Vec<Statement>>,
+	notes: Mutex<Vec<String>>,
+}
+
+impl StatsCollector {
+	fn add(&self, rows: u64, bytes: u64) {
+		self.rows.fetch_add(rows, Ordering::Relaxed);
+		self.bytes.fetch_add(bytes, Ordering::Relaxed);
+	}
+	fn snapshot(&self) -> QueryStats {
+		QueryStats {
+			rows_processed: self.rows.load(Ordering::Relaxed),
+			bytes_processed: self.bytes.load(Ordering::Relaxed),
+			statements: self.statements.lock().unwrap().clone(),
+			notes: self.notes.lock().unwrap().clone(),
+		}
+	}
+}
+
+tokio::task_local! {
+	static COLLECTOR: Arc<StatsCollector>;
+}
+
+// collect runs f and sums up everything recorded while it runs
+pub async fn collect<F: Future>(f: F) -> (F::Output, QueryStats) {
+	let c = Arc::new(StatsCollector::default());
+	let out = COLLECTOR.scope(c.clone(), f).await;
+	(out, c.snapshot())
+}
+
+// record is a noop outside of collect
+pub fn record(rows: u64, bytes: u64) {
+	let _ = COLLECTOR.try_with(|c| c.add(rows, bytes));
+}
+
+pub fn record_sql(sql: &str, elapsed: Duration) {
+	let _ = COLLECTOR.try_with(|c| {
+		c.statements.lock().unwrap().push(Statement {
+			sql: redact_sql(sql).into_owned(),
+			elapsed,
+		})
+	});
+}
+
+// note keeps one copy of each message, a query may be converted more
+// than once
+pub fn note(msg: String) {
+	let _ = COLLECTOR.try_with(|c| {
+		let mut notes = c.notes.lock().unwrap();
+		if !notes.contains(&msg) {
+			notes.push(msg);
+		}
+	});
+}
+
+// spawned tasks don't inherit task locals, so the collector of the
+// caller is captured here and restored inside the task
+pub fn inherit<F: Future>(f: F) -> impl Future<Output = F::Output> {
+	let c = COLLECTOR.try_with(Arc::clone).ok();
+	async move {
+		match c {
+			Some(c) => COLLECTOR.scope(c, f).await,
+			None => f.await,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use pretty_assertions::assert_eq;
+
+	#[tokio::test]
+	async fn test_collect() {
+		let (_, stats) = collect(async {
+			record(10, 100);
+			record_sql("SELECT 1", Duration::from_millis(3));
+			note("a".to_string());
+			note("a".to_string());
+			tokio::spawn(inherit(async { record(1, 1) })).await.unwrap();
+			// not inherited, lost
+			tokio::spawn(async { record(1, 1) }).await.unwrap();
+		})
+		.await;
+		assert_eq!(
+			stats,
+			QueryStats {
+				rows_processed: 11,
+				bytes_processed: 101,
+				statements: vec![Statement {
+					sql: "SELECT 1".to_string(),
+					elapsed: Duration::from_millis(3),
+				}],
+				notes: vec!["a".to_string()],
+			}
+		);
+	}
+}
@@ -0,0 +1,229 @@
+use super::{
+	fanout::merge_metrics,
+	labels::Snapshot,
+	log::{LogItem, LogStorage, MetricItem, ValueFilter},
+	registry::{mismatch, Creating, Registry},
+	Capabilities, Direction, QueryLimits,
+};
+use crate::config::{DataSource, MergeStrategy, Tiered};
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{NaiveDateTime, TimeDelta, Timelike, Utc};
+use common::TimeRange;
+use logql::parser::{LogQuery, MetricQuery};
+use std::{collections::HashMap, time::Duration};
+
+// TieredLog reads recent logs from hot and older ones from archive,
+// a query spanning both is split at now - hot_retention
+#[derive(Clone)]
+pub struct TieredLog {
+	hot: Box<dyn LogStorage>,
+	archive: Box<dyn LogStorage>,
+	hot_retention: Duration,
+}
+
+pub(super) fn register(r: &mut Registry) {
+	r.log("tiered", log_factory);
+}
+
+fn log_factory(d: DataSource) -> Creating<dyn LogStorage> {
+	match d {
+		DataSource::Tiered(cfg) => Box::pin(new_log_source(cfg)),
+		d => mismatch("tiered", &d),
+	}
+}
+
+pub async fn new_log_source(cfg: Tiered) -> Result<Box<dyn LogStorage>> {
+	let hot = Box::pin(super::new_log_source(*cfg.hot)).await?;
+	let archive = Box::pin(super::new_log_source(*cfg.archive)).await?;
+	Ok(Box::new(TieredLog {
+		hot,
+		archive,
+		hot_retention: cfg.hot_retention,
+	}))
+}
+
+impl TieredLog {
+	fn cutoff(&self) -> NaiveDateTime {
+		let retention =
+			TimeDelta::from_std(self.hot_retention).unwrap_or(TimeDelta::MAX);
+		let now = Utc::now().naive_utc();
+		now.checked_sub_signed(retention)
+			.unwrap_or(NaiveDateTime::MIN)
+			.with_nanosecond(0)
+			.unwrap()
+	}
+}
+
+// split_range returns the parts of range before and after cutoff. Bounds
+// are inclusive, so the old part ends 1ns before cutoff to make it
+// [start, cutoff) without losing the rows in the last second
+fn split_range(
+	range: &TimeRange,
+	cutoff: NaiveDateTime,
+) -> (Option<TimeRange>, Option<TimeRange>) {
+	if range.end.is_some_and(|end| end < cutoff) {
+		return (Some(range.clone()), None);
+	}
+	if range.start.is_some_and(|start| start >= cutoff) {
+		return (None, Some(range.clone()));
+	}
+	let old = TimeRange {
+		start: range.start,
+		end: Some(cutoff - TimeDelta::nanoseconds(1)),
+	};
+	let recent = TimeRange {
+		start: Some(cutoff),
+		end: range.end,
+	};
+	(Some(old), Some(recent))
+}
+
+async fn metrics_in(
+	h: &dyn LogStorage,
+	q: &MetricQuery,
+	opt: &QueryLimits,
+	range: Option<TimeRange>,
+) -> Result<Vec<MetricItem>> {
+	match range {
+		Some(range) => {
+			let opt = QueryLimits {
+				range,
+				..opt.clone()
+			};
+			h.query_metrics(q, opt).await
+		}
+		None => Ok(vec![]),
+	}
+}
+
+#[async_trait]
+impl LogStorage for TieredLog {
+	async fn query_stream(
+		&self,
+		q: &LogQuery,
+		opt: QueryLimits,
+	) -> Result<Vec<LogItem>> {
+		let (old, recent) = split_range(&opt.range, self.cutoff());
+		// read the part the results start with first,
+		// the other one is only queried if the limit isn't reached yet
+		let parts = match opt.direction {
+			Some(Direction::Forward) => {
+				[(old, &self.archive), (recent, &self.hot)]
+			}
+			_ => [(recent, &self.hot), (old, &self.archive)],
+		};
+		let mut rows = vec![];
+		for (range, h) in parts {
+			let Some(range) = range else {
+				continue;
+			};
+			let mut opt = QueryLimits {
+				range,
+				..opt.clone()
+			};
+			if let Some(limit) = opt.limit {
+				let left = limit.saturating_sub(rows.len() as u32);
+				if left == 0 {
+					break;
+				}
+				opt.limit = Some(left);
+			}
+			rows.extend(h.query_stream(q, opt).await?);
+		}
+		Ok(rows)
+	}
+	async fn query_metrics(
+		&self,
+		q: &MetricQuery,
+		opt: QueryLimits,
+	) -> Result<Vec<MetricItem>> {
+		let (old, recent) = split_range(&opt.range, self.cutoff());
+		let (old, recent) = tokio::try_join!(
+			metrics_in(self.archive.as_ref(), q, &opt, old),
+			metrics_in(self.hot.as_ref(), q, &opt, recent),
+		)?;
+		// the step at the cutoff is counted by both, summing fixes it up
+		Ok(merge_metrics(vec![old, recent], MergeStrategy::Union))
+	}
+	// labels are sampled from recent logs only
+	async fn labels(&self, opt: QueryLimits) -> Result<Vec<String>> {
+		self.hot.labels(opt).await
+	}
+	async fn label_values(
+		&self,
+		label: &str,
+		filter: Option<&ValueFilter>,
+		opt: QueryLimits,
+	) -> Result<Vec<String>> {
+		self.hot.label_values(label, filter, opt).await
+	}
+	fn high_cardinality_labels(&self) -> Vec<String> {
+		self.hot.high_cardinality_labels()
+	}
+	fn series_snapshot(&self, label: Option<&str>) -> Option<Snapshot> {
+		self.hot.series_snapshot(label)
+	}
+	fn restore_series(&self, snapshot: Snapshot) -> Result<()> {
+		self.hot.restore_series(snapshot)
+	}
+	async fn series(
+		&self,
+		matches: Option<LogQuery>,
+		opt: QueryLimits,
+	) -> Result<Vec<HashMap<String, String>>> {
+		self.hot.series(matches, opt).await
+	}
+	// lines outside of a tier's range are simply not there to delete,
+	// and a read only archive, e.g. files in s3, keeps them
+	async fn delete(&self, q: &LogQuery, range: TimeRange) -> Result<()> {
+		self.hot.delete(q, range.clone()).await?;
+		match self.archive.can_delete() {
+			true => self.archive.delete(q, range).await,
+			false => Ok(()),
+		}
+	}
+	fn can_delete(&self) -> bool {
+		self.hot.can_delete()
+	}
+	fn capabilities(&self) -> Capabilities {
+		self.hot.capabilities().and(self.archive.capabilities())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use pretty_assertions::assert_eq;
+
+	#[test]
+	fn test_split_range() {
+		let t = |secs| {
+			chrono::DateTime::from_timestamp(secs, 0)
+				.unwrap()
+				.naive_utc()
+		};
+		let range = |start, end| TimeRange {
+			start: Some(t(start)),
+			end: Some(t(end)),
+		};
+		assert_eq!(
+			split_range(&range(10, 50), t(100)),
+			(Some(range(10, 50)), None)
+		);
+		assert_eq!(
+			split_range(&range(100, 150), t(100)),
+			(None, Some(range(100, 150)))
+		);
+		assert_eq!(
+			split_range(&range(10, 150), t(100)),
+			(
+				Some(TimeRange {
+					start: Some(t(10)),
+					end: Some(t(100) - TimeDelta::nanoseconds(1)),
+				}),
+				Some(range(100, 150))
+			)
+		);
+	}
+}
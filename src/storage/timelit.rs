@@ -0,0 +1,81 @@
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+
+// every timestamp literal sent to a database is built here, so none of
+// them depends on the timezone the server happens to run in.
+// NaiveDateTime throughout the query builder is always utc
+
+// ck_datetime is for DateTime columns, ck_datetime64 for DateTime64(9)
+pub fn ck_datetime(secs: i64) -> String {
+	format!("toDateTime({}, 'UTC')", secs)
+}
+
+pub fn ck_datetime64(secs: i64) -> String {
+	format!("toDateTime64({}, 9, 'UTC')", secs)
+}
+
+// ck_datetime64_at keeps the fraction of t, e.g. for a bound just before
+// a whole second
+pub fn ck_datetime64_at(t: &NaiveDateTime) -> String {
+	let t = t.and_utc();
+	match t.timestamp_nanos_opt() {
+		Some(nanos) if t.timestamp_subsec_nanos() != 0 => {
+			format!("fromUnixTimestamp64Nano(toInt64({}), 'UTC')", nanos)
+		}
+		_ => ck_datetime64(t.timestamp()),
+	}
+}
+
+// databend reads a timestamp string in the timezone of the session, so
+// the utc instant is written as the wall clock time of that zone
+pub fn databend_timestamp(t: &NaiveDateTime, tz: Tz) -> String {
+	tz.from_utc_datetime(t)
+		.format("%Y-%m-%d %H:%M:%S%.6f")
+		.to_string()
+}
+
+// databend_to_utc is the reverse, rows come back in the session timezone.
+// The earlier instant wins when a dst change makes the time ambiguous
+pub fn databend_to_utc(t: NaiveDateTime, tz: Tz) -> DateTime<Utc> {
+	tz.from_local_datetime(&t)
+		.earliest()
+		.map_or_else(|| t.and_utc(), |t| t.with_timezone(&Utc))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use chrono::Timelike;
+	use pretty_assertions::assert_eq;
+
+	#[test]
+	fn test_literals() {
+		let t = DateTime::from_timestamp(1717200000, 500_000_000)
+			.unwrap()
+			.naive_utc();
+		let secs = t.and_utc().timestamp();
+		assert_eq!(ck_datetime(secs), "toDateTime(1717200000, 'UTC')");
+		assert_eq!(ck_datetime64(secs), "toDateTime64(1717200000, 9, 'UTC')");
+		assert_eq!(
+			ck_datetime64_at(&t),
+			"fromUnixTimestamp64Nano(toInt64(1717200000500000000), 'UTC')"
+		);
+		assert_eq!(
+			ck_datetime64_at(&t.with_nanosecond(0).unwrap()),
+			"toDateTime64(1717200000, 9, 'UTC')"
+		);
+		assert_eq!(
+			databend_timestamp(&t, Tz::UTC),
+			"2024-06-01 00:00:00.500000"
+		);
+		let tz: Tz = "Asia/Shanghai".parse().unwrap();
+		assert_eq!(databend_timestamp(&t, tz), "2024-06-01 08:00:00.500000");
+		let local = NaiveDateTime::parse_from_str(
+			"2024-06-01 08:00:00.5",
+			"%Y-%m-%d %H:%M:%S%.f",
+		)
+		.unwrap();
+		assert_eq!(databend_to_utc(local, tz).naive_utc(), t);
+		assert_eq!(databend_to_utc(t, Tz::UTC).naive_utc(), t);
+	}
+}
@@ -1,11 +1,11 @@
-use super::QueryLimits;
+use super::{Capabilities, QueryLimits};
 use anyhow::Result;
 use async_trait::async_trait;
 use chrono::{offset::Utc, DateTime};
 use dyn_clone::DynClone;
 use serde::{Deserialize, Deserializer, Serialize};
 use std::collections::HashMap;
-use traceql::Expression;
+use traceql::{Expression, SpanSet};
 
 #[async_trait]
 pub trait TraceStorage: DynClone + Send + Sync {
@@ -14,25 +14,136 @@ pub trait TraceStorage: DynClone + Send + Sync {
 		trace_id: &str,
 		opt: QueryLimits,
 	) -> Result<Vec<SpanItem>>;
+	// query_traces returns the spans of all of trace_ids, backends that
+	// can't read several traces in one query read them one by one
+	async fn query_traces(
+		&self,
+		trace_ids: &[String],
+		opt: QueryLimits,
+	) -> Result<Vec<SpanItem>> {
+		let mut spans = vec![];
+		for id in trace_ids {
+			spans.extend(self.query_trace(id, opt.clone()).await?);
+		}
+		Ok(spans)
+	}
 	async fn search_span(
 		&self,
 		expr: &Expression,
 		opt: QueryLimits,
 	) -> Result<Vec<SpanItem>>;
+	// search_trace_ids lists the traces with a span matching expr, newest
+	// first, at most opt.limit of them
+	async fn search_trace_ids(
+		&self,
+		expr: &Expression,
+		opt: QueryLimits,
+	) -> Result<Vec<String>> {
+		let limit = opt.limit.map_or(usize::MAX, |n| n as usize);
+		let spans = self.search_span(expr, opt).await?;
+		Ok(newest_traces(&spans, limit))
+	}
+	// search_span_in is search_span for the given traces only, the spans
+	// are what the search returns for them. Without the trace_ids_first
+	// capability every call runs the whole search
+	async fn search_span_in(
+		&self,
+		expr: &Expression,
+		trace_ids: &[String],
+		opt: QueryLimits,
+	) -> Result<Vec<SpanItem>> {
+		let mut spans = self.search_span(expr, opt).await?;
+		spans.retain(|sp| trace_ids.contains(&sp.trace_id));
+		Ok(spans)
+	}
+	// trace_roots returns the root spans of trace_ids that start in
+	// opt.range, the backends read them in one query. The default reads
+	// the traces one by one
+	async fn trace_roots(
+		&self,
+		trace_ids: &[String],
+		opt: QueryLimits,
+	) -> Result<Vec<SpanItem>> {
+		let mut roots = vec![];
+		for id in trace_ids {
+			let spans = self.query_trace(id, opt.clone()).await?;
+			roots.extend(
+				spans.into_iter().find(|sp| sp.parent_span_id.is_empty()),
+			);
+		}
+		Ok(roots)
+	}
 	async fn span_tags(&self, _opt: QueryLimits) -> Result<Vec<String>> {
 		Ok(vec![])
 	}
+	// values of tag among the spans matching scope, if any
 	async fn span_tag_values(
 		&self,
 		_tag: &str,
+		_scope: Option<&SpanSet>,
 		_opt: QueryLimits,
 	) -> Result<Vec<String>> {
 		Ok(vec![])
 	}
+	fn capabilities(&self) -> Capabilities {
+		Capabilities::default()
+	}
 }
 
 dyn_clone::clone_trait_object!(TraceStorage);
 
+// newest_traces orders the traces of spans by their latest span
+fn newest_traces(spans: &[SpanItem], limit: usize) -> Vec<String> {
+	let mut latest: HashMap<&str, DateTime<Utc>> = HashMap::new();
+	for sp in spans {
+		let ts = latest.entry(&sp.trace_id).or_insert(sp.ts);
+		*ts = (*ts).max(sp.ts);
+	}
+	let mut traces: Vec<_> = latest.into_iter().collect();
+	traces.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+	traces
+		.into_iter()
+		.take(limit)
+		.map(|(id, _)| id.to_string())
+		.collect()
+}
+
+// dedup_spans drops the rows of a span stored more than once, e.g. by a
+// collector retry, which grafana would render as twins. The row ending
+// last is kept, the later one of equal rows, in the place of the first
+pub fn dedup_spans(spans: Vec<SpanItem>) -> Vec<SpanItem> {
+	let mut seen: HashMap<(String, String), usize> = HashMap::new();
+	let mut out: Vec<SpanItem> = Vec::with_capacity(spans.len());
+	for sp in spans {
+		let key = (sp.trace_id.clone(), sp.span_id.clone());
+		match seen.get(&key) {
+			Some(&i) => {
+				let end = |s: &SpanItem| {
+					s.ts + chrono::Duration::nanoseconds(s.duration)
+				};
+				if end(&sp) >= end(&out[i]) {
+					out[i] = sp;
+				}
+			}
+			None => {
+				seen.insert(key, out.len());
+				out.push(sp);
+			}
+		}
+	}
+	out
+}
+
+// trace_id_forms lists the ways a normalized id may be stored, a 64 bit id
+// padded to 128 bits can also be found in its short form, e.g. from jaeger
+pub fn trace_id_forms(trace_id: &str) -> Vec<String> {
+	let mut forms = vec![trace_id.to_string()];
+	if let Some(short) = trace_id.strip_prefix("0000000000000000") {
+		forms.push(short.to_string());
+	}
+	forms
+}
+
 #[derive(Debug, Default, Clone)]
 pub struct SpanItem {
 	pub ts: DateTime<Utc>,
@@ -97,7 +208,10 @@ fn parse_datetime(s: &str) -> Result<DateTime<Utc>> {
 
 #[cfg(test)]
 mod tests {
-	use crate::storage::trace::parse_datetime;
+	use crate::storage::trace::{
+		dedup_spans, newest_traces, parse_datetime, SpanItem,
+	};
+	use chrono::DateTime;
 	use itertools::Itertools;
 	use std::time::Duration;
 
@@ -115,6 +229,43 @@ mod tests {
 		assert!(actual.iter().all_equal());
 	}
 	#[test]
+	fn test_newest_traces() {
+		let span = |trace_id: &str, secs: i64| SpanItem {
+			trace_id: trace_id.to_string(),
+			ts: DateTime::from_timestamp(secs, 0).unwrap(),
+			..Default::default()
+		};
+		let spans = [span("a", 10), span("b", 5), span("b", 20), span("c", 1)];
+		assert_eq!(newest_traces(&spans, 2), vec!["b", "a"]);
+		assert_eq!(newest_traces(&spans, usize::MAX), vec!["b", "a", "c"]);
+	}
+	#[test]
+	fn test_dedup_spans() {
+		let span =
+			|span_id: &str, start: i64, duration: i64, name: &str| SpanItem {
+				trace_id: "t".to_string(),
+				span_id: span_id.to_string(),
+				ts: DateTime::from_timestamp_nanos(start),
+				duration,
+				span_name: name.to_string(),
+				..Default::default()
+			};
+		let spans = vec![
+			span("1", 0, 10, "first"),
+			span("2", 0, 5, "other"),
+			span("1", 0, 20, "longer"),
+			span("1", 5, 15, "retried"),
+			span("1", 0, 15, "shorter"),
+			// starts later but ends first
+			span("1", 8, 5, "later"),
+		];
+		let names = dedup_spans(spans)
+			.into_iter()
+			.map(|s| s.span_name)
+			.collect_vec();
+		assert_eq!(names, vec!["retried", "other"]);
+	}
+	#[test]
 	fn test_parse_naivedatetime_v2() {
 		let test_cases = vec!["2024-05-04T17:38:07Z", "1714815487"];
 		let actual = test_cases
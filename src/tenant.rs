@@ -0,0 +1,106 @@
+use crate::{
+	config::{AppConfig, DefaultTenantPolicy, Tenant as TenantConfig},
+	errors::AppError,
+	state::AppState,
+	storage::{
+		log::LogStorage, new_log_source, new_trace_source, trace::TraceStorage,
+	},
+};
+use anyhow::Result;
+use axum::{async_trait, extract::FromRequestParts, http::request::Parts};
+use http::HeaderMap;
+use std::collections::HashMap;
+
+// Tenant is the org id of a request, resolved from the configured headers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tenant(pub String);
+
+pub fn resolve(
+	headers: &HeaderMap,
+	cfg: &TenantConfig,
+) -> Result<Tenant, AppError> {
+	let found = cfg.headers.iter().find_map(|h| {
+		headers
+			.get(h.as_str())
+			.and_then(|v| v.to_str().ok())
+			.map(str::trim)
+			.filter(|v| !v.is_empty())
+	});
+	match (found, cfg.default_policy) {
+		(Some(id), _) => Ok(Tenant(id.to_string())),
+		(None, DefaultTenantPolicy::Fallback) => {
+			Ok(Tenant(cfg.default_tenant.clone()))
+		}
+		(None, DefaultTenantPolicy::Reject) => Err(AppError::MissingTenant),
+	}
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for Tenant {
+	type Rejection = AppError;
+
+	async fn from_request_parts(
+		parts: &mut Parts,
+		state: &AppState,
+	) -> Result<Self, Self::Rejection> {
+		resolve(&parts.headers, &state.config.tenant)
+	}
+}
+
+// storages of the tenants with overrides, others use the default ones
+#[derive(Clone, Default)]
+pub struct TenantSources {
+	log: HashMap<String, Box<dyn LogStorage>>,
+	trace: HashMap<String, Box<dyn TraceStorage>>,
+}
+
+impl TenantSources {
+	pub async fn new(cfg: &AppConfig) -> Result<Self> {
+		let mut sources = Self::default();
+		for (id, o) in &cfg.tenant.overrides {
+			let log = cfg
+				.log_source
+				.with_override(o.database.as_ref(), o.log_table.as_ref());
+			let trace = cfg
+				.trace_source
+				.with_override(o.database.as_ref(), o.trace_table.as_ref());
+			sources.log.insert(id.clone(), new_log_source(log).await?);
+			sources
+				.trace
+				.insert(id.clone(), new_trace_source(trace).await?);
+		}
+		Ok(sources)
+	}
+	pub fn log(&self, t: &Tenant) -> Option<Box<dyn LogStorage>> {
+		self.log.get(&t.0).cloned()
+	}
+	pub fn trace(&self, t: &Tenant) -> Option<Box<dyn TraceStorage>> {
+		self.trace.get(&t.0).cloned()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use pretty_assertions::assert_eq;
+
+	#[test]
+	fn test_resolve() {
+		let mut cfg = TenantConfig {
+			headers: vec!["X-Tenant".to_string(), "X-Scope-OrgID".to_string()],
+			..Default::default()
+		};
+		let mut headers = HeaderMap::new();
+		assert_eq!(resolve(&headers, &cfg).unwrap(), Tenant("fake".into()));
+		headers.insert("x-scope-orgid", "team-b".parse().unwrap());
+		assert_eq!(resolve(&headers, &cfg).unwrap(), Tenant("team-b".into()));
+		headers.insert("x-tenant", "team-a".parse().unwrap());
+		assert_eq!(resolve(&headers, &cfg).unwrap(), Tenant("team-a".into()));
+
+		cfg.default_policy = DefaultTenantPolicy::Reject;
+		assert!(matches!(
+			resolve(&HeaderMap::new(), &cfg),
+			Err(AppError::MissingTenant)
+		));
+	}
+}
@@ -0,0 +1,153 @@
+use crate::config::Tls;
+use anyhow::{anyhow, bail, Context, Result};
+use std::{fs::File, io::BufReader, path::Path, sync::Arc, time::SystemTime};
+use tokio::{net::TcpStream, sync::watch};
+use tokio_rustls::{
+	rustls::{
+		crypto::{ring, CryptoProvider},
+		pki_types::CertificateDer,
+		server::WebPkiClientVerifier,
+		RootCertStore, ServerConfig,
+	},
+	server::TlsStream,
+	TlsAcceptor,
+};
+use tracing::{info, warn};
+
+// Acceptor does every handshake with the files loaded last, connections
+// established before a reload keep what they started with
+#[derive(Clone)]
+pub struct Acceptor(watch::Receiver<Arc<ServerConfig>>);
+
+impl Acceptor {
+	pub async fn accept(
+		&self,
+		stream: TcpStream,
+	) -> std::io::Result<TlsStream<TcpStream>> {
+		let config = self.0.borrow().clone();
+		TlsAcceptor::from(config).accept(stream).await
+	}
+}
+
+// spawn fails when the files can't be used, so a bad config stops the
+// start. A bad update later is only logged, the previous files stay in use
+pub fn spawn(cfg: Tls) -> Result<Acceptor> {
+	let (tx, rx) = watch::channel(Arc::new(load(&cfg)?));
+	tokio::spawn(reload_loop(cfg, tx));
+	Ok(Acceptor(rx))
+}
+
+async fn reload_loop(cfg: Tls, tx: watch::Sender<Arc<ServerConfig>>) {
+	let mut seen = modified(&cfg);
+	let mut ticker = tokio::time::interval(cfg.reload_interval);
+	// the first tick is immediate
+	ticker.tick().await;
+	loop {
+		ticker.tick().await;
+		if tx.is_closed() {
+			return;
+		}
+		let now = modified(&cfg);
+		if now == seen {
+			continue;
+		}
+		// a cert and key replaced one after the other don't match until
+		// both are written, the next check tries again
+		match load(&cfg) {
+			Ok(c) => {
+				seen = now;
+				tx.send_replace(Arc::new(c));
+				info!("tls certificate reloaded");
+			}
+			Err(e) => warn!("tls reload fails: {:#}", e),
+		}
+	}
+}
+
+// metadata follows symlinks, so a kubernetes secret swapped by its
+// ..data link counts as modified too
+fn modified(cfg: &Tls) -> Vec<Option<SystemTime>> {
+	[&cfg.cert, &cfg.key]
+		.into_iter()
+		.chain(&cfg.client_ca)
+		.map(|p| std::fs::metadata(p).and_then(|m| m.modified()).ok())
+		.collect()
+}
+
+fn load(cfg: &Tls) -> Result<ServerConfig> {
+	let provider: Arc<CryptoProvider> = Arc::new(ring::default_provider());
+	let builder = ServerConfig::builder_with_provider(provider.clone())
+		.with_safe_default_protocol_versions()?;
+	let builder = match &cfg.client_ca {
+		Some(ca) => {
+			let mut roots = RootCertStore::empty();
+			for c in certs(ca)? {
+				roots.add(c)?;
+			}
+			let verifier = WebPkiClientVerifier::builder_with_provider(
+				Arc::new(roots),
+				provider,
+			)
+			.build()?;
+			builder.with_client_cert_verifier(verifier)
+		}
+		None => builder.with_no_client_auth(),
+	};
+	let key = rustls_pemfile::private_key(&mut reader(&cfg.key)?)?
+		.ok_or_else(|| anyhow!("no private key in {}", cfg.key.display()))?;
+	let mut config = builder.with_single_cert(certs(&cfg.cert)?, key)?;
+	config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+	Ok(config)
+}
+
+fn certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+	let certs = rustls_pemfile::certs(&mut reader(path)?)
+		.collect::<Result<Vec<_>, _>>()?;
+	if certs.is_empty() {
+		bail!("no certificate in {}", path.display());
+	}
+	Ok(certs)
+}
+
+fn reader(path: &Path) -> Result<BufReader<File>> {
+	let f = File::open(path)
+		.with_context(|| format!("can't open {}", path.display()))?;
+	Ok(BufReader::new(f))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::{path::PathBuf, time::Duration};
+
+	#[test]
+	fn test_load() {
+		let cfg = Tls {
+			cert: PathBuf::from("./testdata/tls/server.crt"),
+			key: PathBuf::from("./testdata/tls/server.key"),
+			client_ca: None,
+			reload_interval: Duration::from_secs(60),
+		};
+		let config = load(&cfg).unwrap();
+		assert_eq!(config.alpn_protocols[0], b"h2");
+		// the self-signed cert is its own CA
+		let mtls = Tls {
+			client_ca: Some(cfg.cert.clone()),
+			..cfg.clone()
+		};
+		assert!(load(&mtls).is_ok());
+		assert_eq!(modified(&mtls).len(), 3);
+		// a cert where the key should be
+		let swapped = Tls {
+			key: cfg.cert.clone(),
+			..cfg.clone()
+		};
+		let err = load(&swapped).unwrap_err().to_string();
+		assert!(err.starts_with("no private key in"), "{}", err);
+		let missing = Tls {
+			cert: PathBuf::from("./testdata/tls/missing.crt"),
+			..cfg
+		};
+		assert!(load(&missing).is_err());
+	}
+}
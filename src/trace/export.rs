@@ -0,0 +1,98 @@
+use super::{search::SearchTraceRequest, spans_into_resourcespans};
+use crate::{
+	errors::AppError,
+	proto::tempopb::Trace,
+	state::AppState,
+	storage::{trace::trace_id_forms, QueryLimits},
+	tenant::Tenant,
+	utils::{
+		spill::SpillBuffer,
+		validate::{params, ValidQuery},
+	},
+};
+use anyhow::anyhow;
+use axum::{
+	extract::State,
+	http::header,
+	response::{IntoResponse, Response},
+};
+use itertools::Itertools;
+use tracing::info;
+
+// traces read from the backend per query
+const EXPORT_BATCH: usize = 100;
+
+// export_traces returns every trace matching q, one json encoded trace
+// per line. The whole export is built before anything is sent, so a
+// failure halfway is still an error response rather than a cut body;
+// what doesn't fit in limits.export_memory_bytes goes to a temp file
+pub async fn export_traces(
+	req: ValidQuery<SearchTraceRequest>,
+	State(state): State<AppState>,
+	tenant: Tenant,
+) -> Result<Response, AppError> {
+	let req = params(req).map_err(AppError::InvalidQueryString)?;
+	let state = state.for_tenant(&tenant);
+	let expr =
+		traceql::parse_traceql(&req.q).map_err(AppError::InvalidTraceQL)?;
+	let expr = super::prepare(expr, &state.config.server);
+	let handle = state.trace_handle.clone();
+	super::search::check_capabilities(&expr, handle.capabilities())?;
+	let limit = req.limit.map_or(usize::MAX, |n| n as usize);
+	let limits = QueryLimits {
+		unbounded: true,
+		..req.into()
+	};
+	let spans = handle.search_span(&expr, limits.clone()).await?;
+	// newest first, like search
+	let trace_ids: Vec<_> = spans
+		.into_iter()
+		.into_group_map_by(|sp| sp.trace_id.clone())
+		.into_iter()
+		.map(|(id, sps)| (sps.iter().map(|sp| sp.ts).min(), id))
+		.sorted_by(|a, b| b.cmp(a))
+		.take(limit)
+		.map(|(_, id)| id)
+		.collect();
+	let cfg = &state.config.limits;
+	let mut buf = SpillBuffer::new(
+		cfg.export_memory_bytes,
+		cfg.export_spill_dir.as_deref(),
+	);
+	for ids in trace_ids.chunks(EXPORT_BATCH) {
+		let mut traces = handle
+			.query_traces(ids, limits.clone())
+			.await?
+			.into_iter()
+			.into_group_map_by(|sp| sp.trace_id.clone());
+		for id in ids {
+			// the spans carry the form the id is stored in
+			let spans: Vec<_> = trace_id_forms(id)
+				.iter()
+				.filter_map(|form| traces.remove(form))
+				.flatten()
+				.collect();
+			if spans.is_empty() {
+				continue;
+			}
+			let trace = Trace {
+				batches: spans_into_resourcespans(spans),
+			};
+			let mut line =
+				serde_json::to_vec(&trace).map_err(|e| anyhow!(e))?;
+			line.push(b'\n');
+			buf.write(&line).await.map_err(|e| anyhow!(e))?;
+		}
+	}
+	info!(
+		traces = trace_ids.len(),
+		bytes = buf.len(),
+		spilled = buf.spilled(),
+		"traces exported"
+	);
+	let body = buf.into_body().await.map_err(|e| anyhow!(e))?;
+	Ok(
+		([(header::CONTENT_TYPE, "application/x-ndjson")], body)
+			.into_response(),
+	)
+}
@@ -0,0 +1,44 @@
+use super::search::check_capabilities;
+use crate::{
+	errors::AppError, logquery::ResponseStatus, state::AppState, tenant::Tenant,
+};
+use axum::{
+	extract::{Form, State},
+	Json,
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Deserialize)]
+pub struct FormatTraceQLRequest {
+	pub q: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FormatTraceQLResponse {
+	pub status: ResponseStatus,
+	pub data: String,
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	pub warnings: Vec<String>,
+}
+
+// tempo has no such endpoint, it mirrors loki's format_query
+// so a traceql query can be checked without running it
+pub async fn format_traceql(
+	State(state): State<AppState>,
+	tenant: Tenant,
+	Form(req): Form<FormatTraceQLRequest>,
+) -> Result<Json<FormatTraceQLResponse>, AppError> {
+	let state = state.for_tenant(&tenant);
+	let expr =
+		traceql::parse_traceql(&req.q).map_err(AppError::InvalidTraceQL)?;
+	let warnings =
+		match check_capabilities(&expr, state.trace_handle.capabilities()) {
+			Ok(()) => vec![],
+			Err(e) => vec![e.to_string()],
+		};
+	Ok(Json(FormatTraceQLResponse {
+		status: ResponseStatus::Success,
+		data: traceql::format::format_query(&expr),
+		warnings,
+	}))
+}
@@ -0,0 +1,101 @@
+use super::traceid::{find_trace, normalize_trace_id};
+use crate::{
+	errors::AppError,
+	proto::tempopb::{
+		querier_server::{Querier, QuerierServer},
+		Trace, TraceByIdMetrics, TraceByIdRequest, TraceByIdResponse,
+	},
+	state::AppState,
+	storage::QueryLimits,
+	tenant,
+};
+use prost::Message;
+use tonic::{Request, Response, Status};
+
+// GrpcQuerier serves tempo's Querier service, so clients that talk grpc
+// to tempo can fetch traces from here too
+pub(crate) struct GrpcQuerier {
+	state: AppState,
+}
+
+pub(crate) fn new_querier_server(
+	state: AppState,
+) -> QuerierServer<GrpcQuerier> {
+	QuerierServer::new(GrpcQuerier { state })
+}
+
+#[tonic::async_trait]
+impl Querier for GrpcQuerier {
+	async fn find_trace_by_id(
+		&self,
+		request: Request<TraceByIdRequest>,
+	) -> Result<Response<TraceByIdResponse>, Status> {
+		// the org id comes as metadata, same names as the http headers
+		let headers = request.metadata().clone().into_headers();
+		let tenant = tenant::resolve(&headers, &self.state.config.tenant)?;
+		let state = self.state.clone().for_tenant(&tenant);
+		// unlike the http path, the id is sent as raw bytes
+		let req = request.get_ref();
+		let raw = hex::encode(&req.trace_id);
+		let trace_id =
+			normalize_trace_id(&raw).ok_or(AppError::InvalidTraceID(raw))?;
+		if !in_blocks(&trace_id, &req.block_start, &req.block_end)? {
+			return Ok(Response::new(TraceByIdResponse {
+				trace: None,
+				metrics: Some(TraceByIdMetrics {}),
+			}));
+		}
+		let (trace, encoded) =
+			find_trace(&state, &trace_id, QueryLimits::default(), false)
+				.await?;
+		let trace = match trace {
+			Some(t) => t,
+			None => Trace::decode(encoded.as_slice())
+				.map_err(|e| Status::internal(e.to_string()))?,
+		};
+		Ok(Response::new(TraceByIdResponse {
+			trace: Some(trace),
+			metrics: Some(TraceByIdMetrics {}),
+		}))
+	}
+}
+
+// tempo's frontend shards a lookup by ranges of block ids and merges what
+// the queriers return. There are no blocks here, so the trace id stands in
+// for the id of the one block holding the trace and only the shard whose
+// range covers it answers. No range means the whole of it
+fn in_blocks(trace_id: &str, start: &str, end: &str) -> Result<bool, Status> {
+	let bound = |b: &str, open: &str| -> Result<String, Status> {
+		if b.is_empty() {
+			return Ok(open.to_string());
+		}
+		normalize_trace_id(b)
+			.filter(|_| b.replace('-', "").len() == 32)
+			.ok_or_else(|| {
+				Status::invalid_argument(format!("bad block id {}", b))
+			})
+	};
+	let start = bound(start, &"0".repeat(32))?;
+	let end = bound(end, &"f".repeat(32))?;
+	// same length lowercase hex compares like the numbers
+	Ok(start.as_str() <= trace_id && trace_id <= end.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_in_blocks() {
+		let id = "7f000000000000000000000000000001";
+		assert!(in_blocks(id, "", "").unwrap());
+		assert!(in_blocks(
+			id,
+			"00000000000000000000000000000000",
+			"7FFFFFFF-FFFF-FFFF-FFFF-FFFFFFFFFFFF"
+		)
+		.unwrap());
+		assert!(!in_blocks(id, "80000000000000000000000000000000", "").unwrap());
+		assert!(in_blocks(id, "xyz", "").is_err());
+	}
+}
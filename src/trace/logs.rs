@@ -0,0 +1,114 @@
+use super::traceid::normalize_trace_id;
+use crate::{
+	errors::AppError,
+	logquery::{
+		query_range::{to_log_query_range_response, truncated_warning},
+		trace_context::TraceContext,
+		QueryRangeResponse,
+	},
+	state::AppState,
+	storage::{trace::SpanItem, Direction, QueryLimits},
+	tenant::Tenant,
+};
+use axum::extract::{Path, Query, State};
+use chrono::{DateTime, TimeDelta, Utc};
+use common::TimeRange;
+use logql::parser::{parse_logql_query, LogQuery, Query as LogQL};
+use serde::Deserialize;
+
+const DEFAULT_LIMIT: u32 = 1000;
+
+// the clocks of the services drift, lines logged just around the spans
+// are kept too
+const WINDOW_SLACK: TimeDelta = TimeDelta::seconds(5);
+
+#[derive(Deserialize, Debug)]
+pub struct TraceLogsRequest {
+	limit: Option<u32>,
+}
+
+// trace_logs returns the lines logged with trace_id while the trace ran,
+// oldest first, as loki streams. A grafana data link on a trace opens
+// them as a log panel
+pub async fn trace_logs(
+	Path(trace_id): Path<String>,
+	State(state): State<AppState>,
+	tenant: Tenant,
+	Query(req): Query<TraceLogsRequest>,
+) -> Result<QueryRangeResponse, AppError> {
+	let state = state.for_tenant(&tenant);
+	let trace_id = normalize_trace_id(&trace_id)
+		.ok_or(AppError::InvalidTraceID(trace_id))?;
+	let spans = state
+		.trace_handle
+		.query_trace(&trace_id, QueryLimits::default())
+		.await?;
+	let (start, end) = window(&spans).ok_or(AppError::TraceNotFound)?;
+	let limit = req.limit.unwrap_or(DEFAULT_LIMIT);
+	let opt = QueryLimits {
+		limit: Some(limit),
+		range: TimeRange {
+			start: Some((start - WINDOW_SLACK).naive_utc()),
+			end: Some((end + WINDOW_SLACK).naive_utc()),
+		},
+		direction: Some(Direction::Forward),
+		step: None,
+		spss: None,
+		unbounded: false,
+	};
+	let rows = state
+		.log_handle
+		.query_stream(&trace_id_query(&trace_id)?, opt)
+		.await?;
+	let limits = &state.config.limits;
+	let (mut resp, _) = to_log_query_range_response(
+		&rows,
+		&state.label_names,
+		&TraceContext::default(),
+		limits.max_response_bytes,
+		limits.max_attribute_length,
+	);
+	if rows.len() >= limit as usize {
+		resp.warnings.push(truncated_warning(limit));
+	}
+	Ok(resp)
+}
+
+// window is from the first span's start to the last span's end
+fn window(spans: &[SpanItem]) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+	let start = spans.iter().map(|sp| sp.ts).min()?;
+	let end = spans
+		.iter()
+		.map(|sp| sp.ts + TimeDelta::nanoseconds(sp.duration))
+		.max()?;
+	Some((start, end))
+}
+
+// the id is normalized hex, nothing in it needs escaping
+fn trace_id_query(trace_id: &str) -> Result<LogQuery, AppError> {
+	match parse_logql_query(&format!(r#"{{TraceId="{}"}}"#, trace_id))? {
+		LogQL::LogQuery(q) => Ok(q),
+		_ => unreachable!("a selector is a log query"),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_window() {
+		let ts = |s: i64| DateTime::from_timestamp(s, 0).unwrap();
+		let span = |start: i64, secs: i64| SpanItem {
+			ts: ts(start),
+			duration: secs * 1_000_000_000,
+			..Default::default()
+		};
+		assert_eq!(window(&[]), None);
+		// the root ends after its children
+		let spans = [span(10, 2), span(9, 8), span(11, 1)];
+		assert_eq!(window(&spans), Some((ts(9), ts(17))));
+		let q = trace_id_query("4bf92f3577b34da6a3ce929d0e0e4736").unwrap();
+		assert_eq!(q.selector.label_paris[0].label, "TraceId");
+	}
+}
@@ -1,6 +1,8 @@
 use crate::storage::trace::{
 	Links as BLinks, SpanEvent as BSpanEvent, SpanItem,
 };
+use crate::{config::Server, utils::truncate::truncate_json};
+use itertools::Itertools;
 use opentelemetry_proto::tonic::{
 	common::v1::{
 		any_value::Value, AnyValue, ArrayValue, InstrumentationScope, KeyValue,
@@ -13,102 +15,179 @@ use opentelemetry_proto::tonic::{
 	},
 };
 use opentelemetry_semantic_conventions::SCHEMA_URL;
-use std::{collections::HashMap, time::Duration};
+use std::time::Duration;
 
+mod export;
+mod format;
+pub(crate) mod grpc;
+mod logs;
 mod search;
 mod traceid;
+mod unscoped;
 
-pub(crate) use search::{search_tag_values, search_tags, search_trace_v2};
+pub(crate) use export::export_traces;
+pub(crate) use format::format_traceql;
+pub(crate) use logs::trace_logs;
+pub(crate) use search::{
+	explain_search, search_tag_values, search_tags, search_trace_v2,
+	SearchTraceRequest,
+};
 pub(crate) use traceid::get_trace_by_id;
+pub(crate) use unscoped::Resolver;
+
+// prepare rewrites a parsed search the way every backend gets it
+pub(crate) fn prepare(
+	expr: traceql::Expression,
+	server: &Server,
+) -> traceql::Expression {
+	let expr = Resolver::new(&server.unscoped_attributes).expression(expr);
+	match server.hoist_span_conditions {
+		true => traceql::hoist::expression(expr),
+		false => expr,
+	}
+}
 
-fn spanevent_into_otlp_event(value: &BSpanEvent) -> Event {
+// truncate_attributes cuts every string attribute of sp to max chars,
+// those of its events and links included. 0 keeps them whole
+pub(crate) fn truncate_attributes(sp: &mut SpanItem, max: usize) {
+	if max == 0 {
+		return;
+	}
+	let maps = [&mut sp.resource_attributes, &mut sp.span_attributes]
+		.into_iter()
+		.chain(sp.span_events.iter_mut().map(|e| &mut e.attributes))
+		.chain(sp.link.iter_mut().map(|l| &mut l.attributes));
+	for m in maps {
+		m.values_mut().for_each(|v| truncate_json(v, max));
+	}
+}
+
+// the conversions below consume the storage rows, attribute maps and
+// strings are moved into the otlp structures instead of cloned
+
+fn spanevent_into_otlp_event(value: BSpanEvent) -> Event {
 	Event {
 		time_unix_nano: value.ts.timestamp_nanos_opt().unwrap() as u64,
-		name: value.name.clone(),
-		attributes: hash_into_kv_pairs(value.attributes.clone()),
+		name: value.name,
+		attributes: hash_into_kv_pairs(value.attributes),
 		dropped_attributes_count: 0,
 	}
 }
 
-fn links_into_otlp_link(value: &BLinks) -> Link {
+fn links_into_otlp_link(value: BLinks) -> Link {
 	Link {
 		trace_id: hex::decode(&value.trace_id).unwrap_or_default(),
 		span_id: hex::decode(&value.span_id).unwrap_or_default(),
-		trace_state: value.trace_state.clone(),
-		attributes: hash_into_kv_pairs(value.attributes.clone()),
+		trace_state: value.trace_state,
+		attributes: hash_into_kv_pairs(value.attributes),
 		dropped_attributes_count: 0,
 		flags: 0,
 	}
 }
 
-fn spanitem_into_resourcespans(value: &SpanItem) -> ResourceSpans {
-	ResourceSpans {
-		scope_spans: vec![trace_2_scope_span(value)],
-		resource: Some(Resource {
-			attributes: hash_into_kv_pairs(value.resource_attributes.clone()),
-			dropped_attributes_count: 0,
-		}),
-		schema_url: SCHEMA_URL.to_string(),
+type Attributes = Vec<(String, serde_json::Value)>;
+// name and version
+type Scope = (Option<String>, Option<String>);
+
+// spans_into_resourcespans groups the spans by resource and then by scope,
+// so every resource is built once rather than once per span. A trace has
+// few resources, comparing against each is cheaper than hashing every map
+pub fn spans_into_resourcespans(spans: Vec<SpanItem>) -> Vec<ResourceSpans> {
+	let mut groups: Vec<(Attributes, Vec<SpanItem>)> = vec![];
+	for mut sp in spans {
+		let resource = std::mem::take(&mut sp.resource_attributes)
+			.into_iter()
+			.sorted_by(|a, b| a.0.cmp(&b.0))
+			.collect_vec();
+		match groups.iter_mut().find(|(r, _)| *r == resource) {
+			Some((_, group)) => group.push(sp),
+			None => groups.push((resource, vec![sp])),
+		}
 	}
+	groups
+		.into_iter()
+		.map(|(resource, spans)| ResourceSpans {
+			scope_spans: into_scope_spans(spans),
+			resource: Some(Resource {
+				attributes: hash_into_kv_pairs(resource),
+				dropped_attributes_count: 0,
+			}),
+			schema_url: SCHEMA_URL.to_string(),
+		})
+		.collect()
 }
 
-fn trace_2_scope_span(value: &SpanItem) -> ScopeSpans {
-	ScopeSpans {
-		scope: spanitem_into_instrumentation_scope(value),
-		spans: vec![spanitem_to_otlp_span(value)],
-		schema_url: SCHEMA_URL.to_string(),
+fn into_scope_spans(spans: Vec<SpanItem>) -> Vec<ScopeSpans> {
+	let mut groups: Vec<(Scope, Vec<Span>)> = vec![];
+	for mut sp in spans {
+		let scope = (sp.scope_name.take(), sp.scope_version.take());
+		let span = spanitem_to_otlp_span(sp);
+		match groups.iter_mut().find(|(s, _)| *s == scope) {
+			Some((_, group)) => group.push(span),
+			None => groups.push((scope, vec![span])),
+		}
 	}
+	groups
+		.into_iter()
+		.map(|((name, version), spans)| ScopeSpans {
+			scope: into_instrumentation_scope(name, version),
+			spans,
+			schema_url: SCHEMA_URL.to_string(),
+		})
+		.collect()
 }
 
-fn spanitem_to_otlp_span(value: &SpanItem) -> Span {
+fn spanitem_to_otlp_span(value: SpanItem) -> Span {
 	Span {
 		trace_id: hex::decode(&value.trace_id).unwrap_or_default(),
 		span_id: hex::decode(&value.span_id).unwrap_or_default(),
-		trace_state: value.trace_state.clone(),
+		trace_state: value.trace_state,
 		parent_span_id: hex::decode(&value.parent_span_id).unwrap_or_default(),
 		flags: 0,
-		name: value.span_name.clone(),
+		name: value.span_name,
 		kind: SpanKind::try_from(value.span_kind).unwrap().into(),
 		start_time_unix_nano: value.ts.timestamp_nanos_opt().unwrap() as u64,
 		end_time_unix_nano: (value.ts
 			+ Duration::from_nanos(value.duration as u64))
 		.timestamp_nanos_opt()
 		.unwrap() as u64,
-		attributes: hash_into_kv_pairs(value.span_attributes.clone()),
+		attributes: hash_into_kv_pairs(value.span_attributes),
 		dropped_attributes_count: 0,
 		events: value
 			.span_events
-			.iter()
+			.into_iter()
 			.map(spanevent_into_otlp_event)
 			.collect(),
 		dropped_events_count: 0,
-		links: value.link.iter().map(links_into_otlp_link).collect(),
+		links: value.link.into_iter().map(links_into_otlp_link).collect(),
 		dropped_links_count: 0,
 		status: Some(Status {
 			code: value.status_code.unwrap_or_default(),
-			message: value.status_message.clone().unwrap_or_default(),
+			message: value.status_message.unwrap_or_default(),
 		}),
 	}
 }
 
-fn spanitem_into_instrumentation_scope(
-	value: &SpanItem,
+fn into_instrumentation_scope(
+	name: Option<String>,
+	version: Option<String>,
 ) -> Option<InstrumentationScope> {
-	if value.scope_name.is_none() && value.scope_version.is_none() {
+	if name.is_none() && version.is_none() {
 		return None;
 	}
 	Some(InstrumentationScope {
-		name: value.scope_name.clone().unwrap_or_default(),
-		version: value.scope_version.clone().unwrap_or_default(),
+		name: name.unwrap_or_default(),
+		version: version.unwrap_or_default(),
 		attributes: vec![],
 		dropped_attributes_count: 0,
 	})
 }
 
 fn hash_into_kv_pairs(
-	hash: HashMap<String, serde_json::Value>,
+	attrs: impl IntoIterator<Item = (String, serde_json::Value)>,
 ) -> Vec<KeyValue> {
-	hash.into_iter()
+	attrs
+		.into_iter()
 		.map(|(k, v)| KeyValue {
 			key: k,
 			value: match v {
@@ -178,8 +257,67 @@ pub fn json_value_to_opt_pb_any_value(
 
 #[cfg(test)]
 mod tests {
+	use super::*;
 	use crate::storage::trace::SpanEvent;
 
+	#[test]
+	fn test_spans_into_resourcespans() {
+		let span = |id: &str, svc: &str, scope: &str| SpanItem {
+			span_id: id.to_string(),
+			resource_attributes: [(
+				"service.name".to_string(),
+				serde_json::json!(svc),
+			)]
+			.into(),
+			scope_name: Some(scope.to_string()),
+			..Default::default()
+		};
+		let batches = spans_into_resourcespans(vec![
+			span("01", "a", "http"),
+			span("02", "b", "http"),
+			span("03", "a", "grpc"),
+			span("04", "a", "http"),
+		]);
+		assert_eq!(batches.len(), 2);
+		let mut counts = batches
+			.iter()
+			.map(|rs| {
+				let scopes = rs.scope_spans.len();
+				let spans: usize =
+					rs.scope_spans.iter().map(|ss| ss.spans.len()).sum();
+				(scopes, spans)
+			})
+			.collect::<Vec<_>>();
+		counts.sort();
+		assert_eq!(counts, vec![(1, 1), (2, 3)]);
+	}
+
+	#[test]
+	fn test_truncate_attributes() {
+		let mut sp = SpanItem {
+			span_attributes: [(
+				"db.statement".to_string(),
+				serde_json::json!("select * from orders"),
+			)]
+			.into(),
+			span_events: vec![SpanEvent {
+				attributes: [(
+					"exception.stacktrace".to_string(),
+					serde_json::json!("at main"),
+				)]
+				.into(),
+				..Default::default()
+			}],
+			..Default::default()
+		};
+		truncate_attributes(&mut sp, 0);
+		assert_eq!(sp.span_attributes["db.statement"], "select * from orders");
+		truncate_attributes(&mut sp, 7);
+		assert_eq!(sp.span_attributes["db.statement"], "select…");
+		let event = &sp.span_events[0].attributes;
+		assert_eq!(event["exception.stacktrace"], "at main");
+	}
+
 	#[test]
 	fn deser_span_events() {
 		let json = r#"{"attributes":{"ctx.deadline":"999.918375ms","message.detail":"{\"msg\":\"caibirdme\"}","message.uncompressed_size":19},"dropped_attributes_count":0,"name":"SENT","time_unix_nano":"2024-04-21T09:20:12.167916Z"}
@@ -1,24 +1,49 @@
-use std::collections::HashMap;
+use std::{
+	collections::{HashMap, VecDeque},
+	time::Instant,
+};
 
-use super::json_value_to_opt_pb_any_value;
+use super::{json_value_to_opt_pb_any_value, unscoped::Resolver};
 use crate::{
+	debug::{with_debug_headers, DebugRequest},
 	errors::AppError,
 	proto::tempopb::{
-		SearchResponse, Span as TempoSpan, SpanSet, TraceSearchMetadata,
+		SearchMetrics, Span as TempoSpan, SpanSet, TraceSearchMetadata,
 	},
+	query_tags,
 	state::AppState,
-	storage::{trace::SpanItem, QueryLimits},
+	storage::{
+		explain::{self, ExplainKind, Plan},
+		stats::{self, QueryStats},
+		trace::{SpanItem, TraceStorage},
+		Capabilities, QueryLimits,
+	},
+	tenant::Tenant,
+	utils::{
+		time::unix_seconds,
+		truncate::truncate_json,
+		validate::{params, ValidQuery},
+	},
 };
 use axum::{
-	extract::{Query, State},
+	body::Body,
+	extract::{Path, Query, State},
+	http::header::CONTENT_TYPE,
+	response::{IntoResponse, Response},
 	Json,
 };
-use axum_valid::Valid;
+use bytes::Bytes;
 use chrono::DateTime;
 use common::TimeRange;
 use itertools::Itertools;
 use opentelemetry_proto::tonic::common::v1::KeyValue;
 use serde::{Deserialize, Serialize};
+use tokio::{
+	sync::mpsc::{self, Sender},
+	task::JoinHandle,
+};
+use tokio_stream::wrappers::ReceiverStream;
+use traceql::{Expression, FieldType, LogicalOperator};
 use validator::Validate;
 
 #[derive(Deserialize, Debug, Validate)]
@@ -26,12 +51,23 @@ pub struct SearchTraceRequest {
 	#[validate(length(min = 1))]
 	pub q: String,
 	pub limit: Option<u32>,
+	// unix seconds, though any time query_range takes is accepted
+	#[serde(default, deserialize_with = "unix_seconds")]
 	#[validate(custom(function = "crate::utils::validate::unix_timestamp"))]
 	pub start: Option<u64>,
+	#[serde(default, deserialize_with = "unix_seconds")]
 	#[validate(custom(function = "crate::utils::validate::unix_timestamp"))]
 	pub end: Option<u64>,
+	// spans per spanset
+	pub spss: Option<u32>,
+	// skips limits.max_attribute_length
+	#[serde(default)]
+	pub full_attributes: bool,
 }
 
+// same as tempo
+const DEFAULT_SPSS: usize = 3;
+
 impl From<SearchTraceRequest> for QueryLimits {
 	fn from(value: SearchTraceRequest) -> Self {
 		Self {
@@ -50,6 +86,8 @@ impl From<SearchTraceRequest> for QueryLimits {
 			},
 			direction: None,
 			step: None,
+			spss: value.spss.or(Some(DEFAULT_SPSS as u32)),
+			unbounded: false,
 		}
 	}
 }
@@ -95,39 +133,253 @@ pub async fn search_tags(
 }
 
 pub async fn search_trace_v2(
-	Valid(Query(req)): Valid<Query<SearchTraceRequest>>,
+	req: ValidQuery<SearchTraceRequest>,
 	State(state): State<AppState>,
-) -> Result<Json<SearchResponse>, AppError> {
-	let expr = traceql::parse_traceql(&req.q)?;
+	tenant: Tenant,
+	DebugRequest(debug): DebugRequest,
+) -> Result<Response, AppError> {
+	let req = params(req).map_err(AppError::InvalidQueryString)?;
+	let state = state.for_tenant(&tenant);
+	let start = Instant::now();
+	let expr =
+		traceql::parse_traceql(&req.q).map_err(AppError::InvalidTraceQL)?;
+	let expr = super::prepare(expr, &state.config.server);
 	let handle = state.trace_handle;
-	let spans = handle.search_span(&expr, req.into()).await?;
+	let caps = handle.capabilities();
+	check_capabilities(&expr, caps)?;
+	let parsed = start.elapsed();
+	let spss = req.spss.map_or(DEFAULT_SPSS, |n| n as usize);
+	let limit = req.limit.map(|n| n as usize);
+	let keep = &state.config.server.search_result_attributes;
+	let max_bytes = state.config.limits.max_response_bytes;
+	let max_attr = match req.full_attributes {
+		true => 0,
+		false => state.config.limits.max_attribute_length,
+	};
+	// the debug headers need the stats of the whole search, before the body
+	if caps.trace_ids_first && !debug {
+		let search = StreamSearch {
+			handle,
+			expr,
+			opt: req.into(),
+			spss,
+			keep: keep.clone(),
+			max_attr,
+			max_bytes,
+		};
+		return search.run().await;
+	}
+	let (spans, stats) =
+		stats::collect(handle.search_span(&expr, req.into())).await;
+	let spans = spans?;
+	let queried = start.elapsed() - parsed;
+
+	let traces = trace_metadata(&spans, spss, keep, max_attr)
+		.into_iter()
+		// newest first, like tempo
+		.sorted_by(|a, b| b.start_time_unix_nano.cmp(&a.start_time_unix_nano))
+		.take(limit.unwrap_or(usize::MAX));
+	let body = search_body(traces, &stats, max_bytes)?;
+	let resp = ([(CONTENT_TYPE, "application/json")], body).into_response();
+	if !debug {
+		return Ok(resp);
+	}
+	let stages = [
+		("parse", parsed),
+		("query", queried),
+		("convert", start.elapsed() - parsed - queried),
+		("total", start.elapsed()),
+	];
+	Ok(with_debug_headers(resp, &stats, &stages))
+}
+
+// traces are added until the body would exceed max_bytes, the rest are
+// left out like the traces beyond the limit. The body is written here
+// rather than by Json so every trace is serialized once
+fn search_body(
+	traces: impl Iterator<Item = TraceSearchMetadata>,
+	stats: &QueryStats,
+	max_bytes: usize,
+) -> serde_json::Result<Vec<u8>> {
+	let mut b = br#"{"traces":["#.to_vec();
+	let mut written = 0;
+	for t in traces {
+		let start = b.len();
+		if written > 0 {
+			b.push(b',');
+		}
+		serde_json::to_writer(&mut b, &t)?;
+		if max_bytes > 0 && b.len() > max_bytes {
+			b.truncate(start);
+			break;
+		}
+		written += 1;
+	}
+	b.extend_from_slice(br#"],"metrics":"#);
+	serde_json::to_writer(&mut b, &search_metrics(written, stats))?;
+	b.push(b'}');
+	Ok(b)
+}
+
+// the first batch is small so the first traces are sent quickly, every
+// next one is twice as big up to MAX_BATCH
+const FIRST_BATCH: usize = 5;
+const MAX_BATCH: usize = 40;
+// batches whose spans are fetched at the same time
+const PARALLEL_BATCHES: usize = 4;
+
+// StreamSearch looks up the traces first and then their spans, batch by
+// batch. A trace is written as soon as its batch is back, in the order
+// of the lookup, which is newest first
+struct StreamSearch {
+	handle: Box<dyn TraceStorage>,
+	expr: Expression,
+	opt: QueryLimits,
+	spss: usize,
+	keep: Vec<String>,
+	// string attributes are cut to it, 0 for no limit
+	max_attr: usize,
+	// the traces sent stop short of it, 0 for no limit
+	max_bytes: usize,
+}
+
+type Chunk = Result<Bytes, std::io::Error>;
+
+impl StreamSearch {
+	// errors of the lookup are returned as usual, once the body has
+	// started an error can only cut it short
+	async fn run(self) -> Result<Response, AppError> {
+		let (ids, stats) = stats::collect(
+			self.handle.search_trace_ids(&self.expr, self.opt.clone()),
+		)
+		.await;
+		let ids = ids?;
+		let (tx, rx) = mpsc::channel(PARALLEL_BATCHES);
+		tokio::spawn(query_tags::inherit(async move {
+			if let Err(e) = self.write(&tx, ids, stats).await {
+				let _ = tx.send(Err(std::io::Error::other(e))).await;
+			}
+		}));
+		let body = Body::from_stream(ReceiverStream::new(rx));
+		Ok(([(CONTENT_TYPE, "application/json")], body).into_response())
+	}
+
+	async fn write(
+		&self,
+		tx: &Sender<Chunk>,
+		ids: Vec<String>,
+		mut stats: QueryStats,
+	) -> anyhow::Result<()> {
+		let send = |b: Vec<u8>| tx.send(Ok(Bytes::from(b)));
+		send(br#"{"traces":["#.to_vec()).await?;
+		let mut batches = batches(ids).into_iter();
+		let mut pending = Pending(VecDeque::new());
+		let mut written = 0;
+		let mut size = 0;
+		'batches: loop {
+			while pending.0.len() < PARALLEL_BATCHES {
+				let Some(batch) = batches.next() else {
+					break;
+				};
+				pending.0.push_back(self.fetch(batch));
+			}
+			let Some(task) = pending.0.pop_front() else {
+				break;
+			};
+			let (batch, spans, s) = task.await?;
+			stats.bytes_processed += s.bytes_processed;
+			stats.statements.extend(s.statements);
+			let mut traces: HashMap<_, _> =
+				trace_metadata(&spans?, self.spss, &self.keep, self.max_attr)
+					.into_iter()
+					.map(|t| (t.trace_id.clone(), t))
+					.collect();
+			// a trace whose spans are gone by now is skipped
+			for t in batch.iter().filter_map(|id| traces.remove(id)) {
+				let mut b = if written == 0 { vec![] } else { vec![b','] };
+				serde_json::to_writer(&mut b, &t)?;
+				size += b.len();
+				if self.max_bytes > 0 && size > self.max_bytes {
+					break 'batches;
+				}
+				send(b).await?;
+				written += 1;
+			}
+		}
+		let mut b = br#"],"metrics":"#.to_vec();
+		serde_json::to_writer(&mut b, &search_metrics(written, &stats))?;
+		b.push(b'}');
+		send(b).await?;
+		Ok(())
+	}
+
+	fn fetch(&self, batch: Vec<String>) -> JoinHandle<Fetch> {
+		let handle = self.handle.clone();
+		let expr = self.expr.clone();
+		let opt = self.opt.clone();
+		tokio::spawn(query_tags::inherit(async move {
+			let search = handle.search_span_in(&expr, &batch, opt);
+			let (spans, stats) = stats::collect(search).await;
+			(batch, spans, stats)
+		}))
+	}
+}
+
+type Fetch = (Vec<String>, anyhow::Result<Vec<SpanItem>>, QueryStats);
+
+// Pending aborts the batches still running when the client is gone or a
+// batch failed, their spans aren't needed anymore
+struct Pending(VecDeque<JoinHandle<Fetch>>);
+
+impl Drop for Pending {
+	fn drop(&mut self) {
+		self.0.iter().for_each(|t| t.abort());
+	}
+}
+
+fn batches(mut ids: Vec<String>) -> Vec<Vec<String>> {
+	let mut out = vec![];
+	let mut size = FIRST_BATCH;
+	while !ids.is_empty() {
+		let rest = ids.split_off(size.min(ids.len()));
+		out.push(std::mem::replace(&mut ids, rest));
+		size = (size * 2).min(MAX_BATCH);
+	}
+	out
+}
 
-	// convert to tempo required format
-	let root_name = get_root_name_map(&spans);
-	let traces = spans
+// trace_metadata groups spans by trace, in no particular order. The root
+// span is only known if it's one of spans
+fn trace_metadata(
+	spans: &[SpanItem],
+	spss: usize,
+	keep: &[String],
+	max_attr: usize,
+) -> Vec<TraceSearchMetadata> {
+	let root_name = get_root_name_map(spans);
+	spans
 		.iter()
 		.into_group_map_by(|sp| &sp.trace_id)
 		.into_iter()
 		.map(|(trace_id, sps)| {
+			let matched = sps.len() as u32;
 			let spsset: Vec<TempoSpan> = sps
 				.iter()
+				.sorted_by_key(|v| v.ts)
+				.take(spss)
 				.map(|v| TempoSpan {
 					span_id: v.span_id.clone(),
 					name: v.span_name.clone(),
 					start_time_unix_nano: v.ts.timestamp_nanos_opt().unwrap()
 						as u64,
 					duration_nanos: v.duration as u64,
-					attributes: v
-						.span_attributes
-						.iter()
-						.map(|(k, v)| KeyValue {
-							key: k.clone(),
-							value: json_value_to_opt_pb_any_value(v.clone()),
-						})
-						.collect(),
+					attributes: project_attributes(
+						&v.span_attributes,
+						keep,
+						max_attr,
+					),
 				})
 				.collect();
-			let matched = spsset.len() as u32;
 			let start_time_nano = root_name
 				.get(trace_id)
 				.map(|(_, _, start, _)| *start)
@@ -154,12 +406,80 @@ pub async fn search_trace_v2(
 				}],
 			}
 		})
-		.collect::<Vec<TraceSearchMetadata>>();
-	let resp = SearchResponse {
-		traces,
-		metrics: None,
+		.collect()
+}
+
+// only the attributes in keep are returned, in its order, their strings
+// cut to max_attr chars
+fn project_attributes(
+	attrs: &HashMap<String, serde_json::Value>,
+	keep: &[String],
+	max_attr: usize,
+) -> Vec<KeyValue> {
+	let kv = |k: &String, v: &serde_json::Value| {
+		let mut v = v.clone();
+		truncate_json(&mut v, max_attr);
+		KeyValue {
+			key: k.clone(),
+			value: json_value_to_opt_pb_any_value(v),
+		}
 	};
-	Ok(Json(resp))
+	if keep.iter().any(|k| k == "*") {
+		return attrs.iter().map(|(k, v)| kv(k, v)).collect();
+	}
+	keep.iter()
+		.filter_map(|k| attrs.get_key_value(k))
+		.map(|(k, v)| kv(k, v))
+		.collect()
+}
+
+// the backends have no blocks, what they read is reported as one block
+// and every statement sent to them as a finished job
+fn search_metrics(traces: usize, stats: &QueryStats) -> SearchMetrics {
+	let jobs = stats.statements.len() as u32;
+	SearchMetrics {
+		inspected_traces: traces as u32,
+		inspected_bytes: stats.bytes_processed,
+		total_blocks: u32::from(stats.bytes_processed > 0),
+		completed_jobs: jobs,
+		total_jobs: jobs,
+		total_block_bytes: stats.bytes_processed,
+	}
+}
+
+pub(super) fn check_capabilities(
+	expr: &traceql::Expression,
+	caps: Capabilities,
+) -> Result<(), AppError> {
+	if !caps.span_search {
+		return Err(AppError::UnsupportedFeature("traceql search".to_string()));
+	}
+	check_operators(expr, caps)
+}
+
+// && binds looser than >>, so an operator may sit anywhere in the tree
+fn check_operators(
+	expr: &traceql::Expression,
+	caps: Capabilities,
+) -> Result<(), AppError> {
+	use traceql::Expression::*;
+	match expr {
+		SpanSet(_) => Ok(()),
+		Logical(..) if !caps.logical_spanset => {
+			Err(AppError::UnsupportedFeature(
+				"combining spansets with && or ||".to_string(),
+			))
+		}
+		Structural(..) if !caps.structural => {
+			Err(AppError::UnsupportedFeature(
+				"structural operators > and >>".to_string(),
+			))
+		}
+		Logical(l, _, r) | Structural(l, _, r) => {
+			check_operators(l, caps)?;
+			check_operators(r, caps)
+		}
+	}
 }
 
 // get all root span's name,service name, start_unix_nano and duration
@@ -203,12 +523,285 @@ fn get_root_name_map(
 	root_name
 }
 
+#[derive(Deserialize, Debug, Default)]
+pub struct SearchTagValuesRequest {
+	// the query being typed, its conditions scope the values
+	pub q: Option<String>,
+	pub start: Option<u64>,
+	pub end: Option<u64>,
+}
+
 #[derive(Serialize, Debug)]
 pub struct TagValuesResponse {
 	#[serde(rename = "tagValues")]
-	pub tag_values: Vec<String>,
+	pub tag_values: Vec<TagValue>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct TagValue {
+	#[serde(rename = "type")]
+	pub typ: &'static str,
+	pub value: String,
+}
+
+// explain_search returns the plans of the statements a search for req
+// would send, only the span search is explained, not fetching the traces
+pub(crate) async fn explain_search(
+	req: SearchTraceRequest,
+	state: AppState,
+	kind: ExplainKind,
+) -> Result<Vec<Plan>, AppError> {
+	let expr =
+		traceql::parse_traceql(&req.q).map_err(AppError::InvalidTraceQL)?;
+	let expr = super::prepare(expr, &state.config.server);
+	let handle = state.trace_handle;
+	check_capabilities(&expr, handle.capabilities())?;
+	let (spans, plans) =
+		explain::collect(kind, handle.search_span(&expr, req.into())).await;
+	spans?;
+	Ok(plans)
+}
+
+pub async fn search_tag_values(
+	State(state): State<AppState>,
+	tenant: Tenant,
+	Path(tag): Path<String>,
+	Query(req): Query<SearchTagValuesRequest>,
+) -> Result<Json<TagValuesResponse>, AppError> {
+	let state = state.for_tenant(&tenant);
+	let resolver = Resolver::new(&state.config.server.unscoped_attributes);
+	let scope = req
+		.q
+		.as_deref()
+		.and_then(|q| scope_spanset(q, &tag))
+		.map(|s| resolver.spanset(s));
+	let limits = SearchTraceRequest {
+		q: String::new(),
+		limit: None,
+		start: req.start,
+		end: req.end,
+		spss: None,
+		full_attributes: false,
+	};
+	let values = state
+		.trace_handle
+		.span_tag_values(&tag, scope.as_ref(), limits.into())
+		.await?;
+	Ok(Json(TagValuesResponse {
+		tag_values: values
+			.into_iter()
+			.map(|value| TagValue {
+				typ: "string",
+				value,
+			})
+			.collect(),
+	}))
+}
+
+// scope_spanset keeps the complete conditions of a query that is still
+// being typed, e.g. `{resource.service.name="checkout" && span.http.method=}`
+// gives `{resource.service.name="checkout"}`. Conditions on tag itself are
+// left out, they would only narrow the values down to what's typed
+fn scope_spanset(q: &str, tag: &str) -> Option<traceql::SpanSet> {
+	let inner = q.trim().strip_prefix('{')?;
+	let mut inner = inner.strip_suffix('}').unwrap_or(inner);
+	// the condition being typed doesn't parse yet, conditions are dropped
+	// from the end until the rest does
+	let spanset = loop {
+		match traceql::parse_traceql(&format!("{{{}}}", inner)) {
+			Ok(Expression::SpanSet(s)) => break s,
+			_ => {
+				let cut = [inner.rfind("&&"), inner.rfind("||")]
+					.into_iter()
+					.flatten()
+					.max()?;
+				inner = &inner[..cut];
+			}
+		}
+	};
+	without_tag(spanset, tag)
+}
+
+// None when nothing is left that narrows the spans down
+fn without_tag(s: traceql::SpanSet, tag: &str) -> Option<traceql::SpanSet> {
+	use traceql::SpanSet::{Expr, Logical};
+	match s {
+		Expr(e) if is_tag(&e.kv, tag) => None,
+		Expr(e) => Some(Expr(e)),
+		Logical(l, op, r) => {
+			match (without_tag(*l, tag), op, without_tag(*r, tag)) {
+				(Some(l), op, Some(r)) => {
+					Some(Logical(Box::new(l), op, Box::new(r)))
+				}
+				(l, LogicalOperator::And, r) => l.or(r),
+				// a side without conditions matches any span, so does the or
+				_ => None,
+			}
+		}
+	}
 }
 
-pub async fn search_tag_values() -> Result<Json<TagValuesResponse>, AppError> {
-	Ok(Json(TagValuesResponse { tag_values: vec![] }))
+fn is_tag(kv: &FieldType, tag: &str) -> bool {
+	match kv {
+		FieldType::Span(k, _) => tag.strip_prefix("span.") == Some(k),
+		FieldType::Resource(k, _) => tag.strip_prefix("resource.") == Some(k),
+		FieldType::Unscoped(k, _) => {
+			tag.split_once('.').map(|(_, rest)| rest) == Some(k)
+		}
+		FieldType::Intrinsic(_) => false,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		proto::tempopb::SearchResponse, storage::stats::Statement,
+		utils::serde::json_size,
+	};
+	use pretty_assertions::assert_eq;
+	use std::time::Duration;
+
+	#[test]
+	fn test_batches() {
+		let ids: Vec<String> = (0..100).map(|i| i.to_string()).collect();
+		let sizes: Vec<_> = batches(ids).iter().map(Vec::len).collect();
+		assert_eq!(sizes, vec![5, 10, 20, 40, 25]);
+		let ids = vec!["a".to_string()];
+		assert_eq!(batches(ids), vec![vec!["a".to_string()]]);
+		assert!(batches(vec![]).is_empty());
+	}
+
+	#[test]
+	fn test_check_capabilities() {
+		let databend = Capabilities {
+			structural: false,
+			..Default::default()
+		};
+		let q = |s| traceql::parse_traceql(s).unwrap();
+		assert!(
+			check_capabilities(&q(r#"{a="1"} && {b="2"}"#), databend).is_ok()
+		);
+		for s in [r#"{a="1"} >> {b="2"}"#, r#"{a="1"} >> {b="2"} && {c="3"}"#] {
+			assert!(
+				matches!(
+					check_capabilities(&q(s), databend),
+					Err(AppError::UnsupportedFeature(_))
+				),
+				"{}",
+				s
+			);
+			assert!(check_capabilities(&q(s), Capabilities::default()).is_ok());
+		}
+	}
+
+	#[test]
+	fn test_project_attributes() {
+		let attrs: HashMap<String, serde_json::Value> = [
+			("http.method", "GET"),
+			("http.route", "/a"),
+			("user.id", "42"),
+		]
+		.into_iter()
+		.map(|(k, v)| (k.to_string(), serde_json::json!(v)))
+		.collect();
+		let keys = |keep: &[&str]| {
+			let keep = keep.iter().map(|s| s.to_string()).collect_vec();
+			project_attributes(&attrs, &keep, 0)
+				.into_iter()
+				.map(|kv| kv.key)
+				.sorted()
+				.collect_vec()
+		};
+		assert_eq!(
+			keys(&["http.route", "http.method", "db.system"]),
+			vec!["http.method", "http.route"]
+		);
+		assert_eq!(keys(&["*"]).len(), 3);
+		assert!(keys(&[]).is_empty());
+		let route = project_attributes(&attrs, &["http.route".to_string()], 1);
+		assert_eq!(
+			route[0].value,
+			json_value_to_opt_pb_any_value(serde_json::json!("…"))
+		);
+	}
+
+	#[test]
+	fn test_scope_spanset() {
+		let expect = traceql::parse_traceql(r#"{resource.service.name="a"}"#);
+		let Ok(Expression::SpanSet(expect)) = expect else {
+			panic!("bad traceql");
+		};
+		for q in [
+			r#"{resource.service.name="a" && span.http.method=}"#,
+			r#"{resource.service.name="a" && span.http.method="GE"}"#,
+			r#"{ resource.service.name="a" && "#,
+		] {
+			assert_eq!(
+				scope_spanset(q, "span.http.method"),
+				Some(expect.clone()),
+				"{}",
+				q
+			);
+		}
+		assert_eq!(scope_spanset("{}", "span.http.method"), None);
+		assert_eq!(scope_spanset("", "span.http.method"), None);
+
+		// an or keeps both sides, or nothing when one side is the tag
+		let expect = traceql::parse_traceql(
+			r#"{resource.service.name="a" || resource.service.name="b"}"#,
+		);
+		let Ok(Expression::SpanSet(expect)) = expect else {
+			panic!("bad traceql");
+		};
+		let q = r#"{resource.service.name="a" || resource.service.name="b"
+			&& span.http.method=}"#;
+		assert_eq!(scope_spanset(q, "span.http.method"), Some(expect));
+		let q = r#"{resource.service.name="a" || span.http.method="GET"}"#;
+		assert_eq!(scope_spanset(q, "span.http.method"), None);
+	}
+
+	#[test]
+	fn test_search_body() {
+		let trace = |id: &str| TraceSearchMetadata {
+			trace_id: id.to_string(),
+			..Default::default()
+		};
+		let traces = || ["a", "b", "c"].into_iter().map(trace);
+		let stats = QueryStats::default();
+		let body = search_body(traces(), &stats, 0).unwrap();
+		let all: SearchResponse = serde_json::from_slice(&body).unwrap();
+		assert_eq!(all.traces.len(), 3);
+		// room for the first two traces only
+		let two = r#"{"traces":[,"#.len() + 2 * json_size(&trace("a"));
+		let body = search_body(traces(), &stats, two).unwrap();
+		let some: SearchResponse = serde_json::from_slice(&body).unwrap();
+		assert_eq!(some.traces.len(), 2);
+		assert_eq!(some.metrics.unwrap().inspected_traces, 2);
+	}
+
+	#[test]
+	fn test_search_metrics() {
+		let stmt = Statement {
+			sql: "SELECT 1".to_string(),
+			elapsed: Duration::from_millis(3),
+		};
+		let stats = QueryStats {
+			rows_processed: 10,
+			bytes_processed: 2048,
+			statements: vec![stmt.clone(), stmt],
+			..Default::default()
+		};
+		assert_eq!(
+			search_metrics(3, &stats),
+			SearchMetrics {
+				inspected_traces: 3,
+				inspected_bytes: 2048,
+				total_blocks: 1,
+				completed_jobs: 2,
+				total_jobs: 2,
+				total_block_bytes: 2048,
+			}
+		);
+	}
 }
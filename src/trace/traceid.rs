@@ -3,7 +3,7 @@ use std::sync::Arc;
 use super::*;
 use crate::{
 	errors::AppError, proto::tempopb::Trace, state::AppState,
-	storage::QueryLimits,
+	storage::QueryLimits, tenant::Tenant,
 };
 use anyhow::anyhow;
 use axum::{
@@ -13,14 +13,8 @@ use axum::{
 	response::{IntoResponse, Response},
 	Json,
 };
-use bytes::BytesMut;
 use chrono::DateTime;
 use common::TimeRange;
-use http::StatusCode;
-use itertools::Itertools;
-use moka::sync::Cache;
-use opentelemetry_proto::tonic::resource::v1::Resource;
-use opentelemetry_semantic_conventions::SCHEMA_URL;
 use prost::Message;
 use serde::Deserialize;
 use validator::Validate;
@@ -35,6 +29,9 @@ pub struct GetTraceByIDRequest {
 	#[serde(rename = "end")]
 	#[validate(custom(function = "crate::utils::validate::unix_timestamp"))]
 	end_seconds: Option<u64>,
+	// skips limits.max_attribute_length
+	#[serde(default)]
+	full_attributes: bool,
 }
 
 impl From<GetTraceByIDRequest> for QueryLimits {
@@ -55,6 +52,8 @@ impl From<GetTraceByIDRequest> for QueryLimits {
 			},
 			direction: None,
 			step: None,
+			spss: None,
+			unbounded: false,
 		}
 	}
 }
@@ -63,100 +62,122 @@ pub async fn get_trace_by_id(
 	Path(trace_id): Path<String>,
 	header: HeaderMap,
 	State(state): State<AppState>,
+	tenant: Tenant,
 	Query(req): Query<GetTraceByIDRequest>,
 ) -> Result<GetTraceByIDResponse, AppError> {
-	macro_rules! output_trace {
-		($v:ident) => {
-			match header.get(header::ACCEPT) {
-				Some(enconding) if enconding == HEADER_ENCODING_PROTOBUF => {
-					GetTraceByIDResponse::Proto(Protobuf($v))
-				}
-				_ => GetTraceByIDResponse::Json(Json($v)),
-			}
-		};
-	}
-	if let Ok(Some(tr)) = get_cached_trace(&trace_id, state.cache.clone()) {
-		let val = output_trace!(tr);
-		return Ok(val);
+	let state = state.for_tenant(&tenant);
+	let trace_id = normalize_trace_id(&trace_id)
+		.ok_or(AppError::InvalidTraceID(trace_id))?;
+	let proto = matches!(
+		header.get(header::ACCEPT),
+		Some(enconding) if enconding == HEADER_ENCODING_PROTOBUF
+	);
+	let full = req.full_attributes;
+	let (trace, encoded) =
+		find_trace(&state, &trace_id, req.into(), full).await?;
+	trace_response(proto, trace, &encoded)
+}
+
+// find_trace is shared by the http and grpc endpoints, it returns the
+// encoded trace and, unless it came from the cache, the decoded one too.
+// A trace with full attributes is cached apart from the cut one
+pub(crate) async fn find_trace(
+	state: &AppState,
+	trace_id: &str,
+	limits: QueryLimits,
+	full_attributes: bool,
+) -> Result<(Option<Trace>, Arc<Vec<u8>>), AppError> {
+	let max_attr = match full_attributes {
+		true => 0,
+		false => state.config.limits.max_attribute_length,
+	};
+	let cache_key = get_trace_cache_key(trace_id, max_attr);
+	// the cache holds the encoded trace, protobuf clients get it as is
+	if let Some(encoded) = state.cache.get(&cache_key) {
+		return Ok((None, encoded));
 	}
-	let handle = state.trace_handle;
-	let spans = handle
-		.query_trace(&trace_id, req.into())
-		.await?
-		.into_iter()
-		.map(|span| spanitem_into_resourcespans(&span))
-		.collect_vec();
+	let mut spans = state.trace_handle.query_trace(trace_id, limits).await?;
 	// when not found, tempo returns 404
 	// https://github.com/grafana/tempo/blob/main/modules/querier/http.go#L75
 	if spans.is_empty() {
 		return Err(AppError::TraceNotFound);
 	}
-	let resp = Trace {
-		batches: reorder_spans(spans),
-	};
-	cache_trace(&trace_id, &resp, state.cache.clone());
-	let val = output_trace!(resp);
-	Ok(val)
+	// building and encoding tens of thousands of spans is cpu bound,
+	// keep it off the runtime threads
+	let (trace, encoded) = tokio::task::spawn_blocking(move || {
+		spans
+			.iter_mut()
+			.for_each(|sp| truncate_attributes(sp, max_attr));
+		let trace = Trace {
+			batches: spans_into_resourcespans(spans),
+		};
+		let encoded = trace.encode_to_vec();
+		(trace, encoded)
+	})
+	.await
+	.map_err(|e| anyhow!(e))?;
+	// json is larger than the protobuf, so either way it's over the limit
+	let max_bytes = state.config.limits.max_response_bytes;
+	if max_bytes > 0 && encoded.len() > max_bytes {
+		return Err(AppError::ResponseTooLarge(max_bytes));
+	}
+	let encoded = Arc::new(encoded);
+	state.cache.insert(cache_key, encoded.clone());
+	Ok((Some(trace), encoded))
 }
 
-fn cache_trace(
-	trace_id: &str,
-	trace: &Trace,
-	cache: Cache<String, Arc<Vec<u8>>>,
-) {
-	let d = trace.encode_to_vec();
-	let key = get_trace_cache_key(trace_id);
-	cache.insert(key.clone(), Arc::new(d.clone()));
+fn trace_response(
+	proto: bool,
+	trace: Option<Trace>,
+	encoded: &[u8],
+) -> Result<GetTraceByIDResponse, AppError> {
+	if proto {
+		return Ok(GetTraceByIDResponse::Proto(Bytes::copy_from_slice(
+			encoded,
+		)));
+	}
+	let trace = match trace {
+		Some(t) => t,
+		None => Message::decode(encoded).map_err(|e| anyhow!(e))?,
+	};
+	Ok(GetTraceByIDResponse::Json(Json(trace)))
 }
 
-fn get_cached_trace(
-	trace_id: &str,
-	cache: Cache<String, Arc<Vec<u8>>>,
-) -> Result<Option<Trace>, AppError> {
-	let data = cache.get(get_trace_cache_key(trace_id).as_str());
-	match data {
-		Some(data) => {
-			let s: &[u8] = data.as_ref();
-			let trace = Message::decode(s).map_err(|e| anyhow!(e))?;
-			Ok(Some(trace))
+// normalize_trace_id accepts what users tend to paste: upper case,
+// dashes, 16 digit short ids or a whole W3C traceparent, and returns
+// the 32 digit lower case hex the backends store
+pub(crate) fn normalize_trace_id(raw: &str) -> Option<String> {
+	let raw = raw.trim();
+	// version-traceid-parentid-flags
+	let parts: Vec<&str> = raw.split('-').collect();
+	let id = match parts.as_slice() {
+		[v, id, parent, flags]
+			if v.len() == 2 && parent.len() == 16 && flags.len() == 2 =>
+		{
+			id.to_string()
 		}
-		None => Ok(None),
+		_ => raw.replace('-', ""),
+	};
+	if id.is_empty()
+		|| id.len() > 32
+		|| !id.chars().all(|c| c.is_ascii_hexdigit())
+	{
+		return None;
 	}
+	Some(format!("{:0>32}", id.to_ascii_lowercase()))
 }
 
-fn get_trace_cache_key(trace_id: &str) -> String {
-	format!("cc:tr:{}", trace_id)
-}
-
-fn reorder_spans(spans: Vec<ResourceSpans>) -> Vec<ResourceSpans> {
-	spans
-		.into_iter()
-		.into_group_map_by(|sps| match &sps.resource {
-			Some(res) => {
-				let mut buf = vec![];
-				res.encode(&mut buf).unwrap();
-				buf
-			}
-			None => vec![],
-		})
-		.into_iter()
-		.map(|(k, arr)| {
-			let mut spss = ResourceSpans::default();
-			if !k.is_empty() {
-				let res: Resource = Message::decode(Bytes::from(k)).unwrap();
-				spss.resource = Some(res);
-			}
-			spss.schema_url = SCHEMA_URL.to_string();
-			spss.scope_spans =
-				arr.into_iter().flat_map(|x| x.scope_spans).collect();
-			spss
-		})
-		.collect()
+fn get_trace_cache_key(trace_id: &str, max_attr: usize) -> String {
+	match max_attr {
+		0 => format!("cc:tr:{}", trace_id),
+		n => format!("cc:tr:{}:{}", n, trace_id),
+	}
 }
 
 #[derive(Debug)]
 pub enum GetTraceByIDResponse {
-	Proto(Protobuf<Trace>),
+	// already encoded
+	Proto(Bytes),
 	Json(Json<Trace>),
 }
 
@@ -175,30 +196,38 @@ impl IntoResponse for GetTraceByIDResponse {
 	}
 }
 
-#[derive(Debug)]
-// When axum-extra fixes the incompetibility with prost 0.13.1
-// we can remove this struct and use the one from axum-extra
-pub struct Protobuf<T>(T);
-
-impl<T> IntoResponse for Protobuf<T>
-where
-	T: Message,
-{
-	fn into_response(self) -> Response {
-		let mut buf = BytesMut::with_capacity(128);
-		match self.0.encode(&mut buf) {
-			Ok(()) => buf.into_response(),
-			Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
-				.into_response(),
-		}
-	}
-}
-
 #[cfg(test)]
 mod tests {
 
 	use super::*;
 	use pretty_assertions::assert_eq;
+
+	#[test]
+	fn test_normalize_trace_id() {
+		let full = "4bf92f3577b34da6a3ce929d0e0e4736";
+		let cases = [
+			(full, Some(full.to_string())),
+			("4BF92F3577B34DA6A3CE929D0E0E4736", Some(full.to_string())),
+			(
+				"4bf92f35-77b3-4da6-a3ce-929d0e0e4736",
+				Some(full.to_string()),
+			),
+			(
+				"00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+				Some(full.to_string()),
+			),
+			(
+				"a3ce929d0e0e4736",
+				Some("0000000000000000a3ce929d0e0e4736".to_string()),
+			),
+			("not-a-trace' OR 1=1", None),
+			("", None),
+		];
+		for (raw, want) in cases {
+			assert_eq!(normalize_trace_id(raw), want, "{}", raw);
+		}
+	}
+
 	#[test]
 	fn it_works() {
 		let s = serde_json::to_string(&Trace { batches: vec![] }).unwrap();
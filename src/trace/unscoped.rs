@@ -0,0 +1,96 @@
+use crate::{
+	config::{AttributeScope, UnscopedRule},
+	utils::glob::glob,
+};
+use regex::Regex;
+use traceql::{Expression, FieldExpr, FieldType, SpanSet};
+
+// Resolver gives unscoped attributes the scope configured for them, an
+// unscoped condition is otherwise expanded into (span OR resource) by
+// every backend
+pub(crate) struct Resolver {
+	rules: Vec<(Regex, AttributeScope)>,
+}
+
+impl Resolver {
+	pub(crate) fn new(rules: &[UnscopedRule]) -> Self {
+		Self {
+			rules: rules.iter().map(|r| (glob(&r.pattern), r.scope)).collect(),
+		}
+	}
+
+	pub(crate) fn expression(&self, e: Expression) -> Expression {
+		if self.rules.is_empty() {
+			return e;
+		}
+		match e {
+			Expression::SpanSet(s) => Expression::SpanSet(self.spanset(s)),
+			Expression::Logical(l, op, r) => Expression::Logical(
+				Box::new(self.expression(*l)),
+				op,
+				Box::new(self.expression(*r)),
+			),
+			Expression::Structural(l, op, r) => Expression::Structural(
+				Box::new(self.expression(*l)),
+				op,
+				Box::new(self.expression(*r)),
+			),
+		}
+	}
+
+	pub(super) fn spanset(&self, s: SpanSet) -> SpanSet {
+		match s {
+			SpanSet::Expr(e) => SpanSet::Expr(FieldExpr {
+				kv: self.field(e.kv),
+				operator: e.operator,
+			}),
+			SpanSet::Logical(l, op, r) => SpanSet::Logical(
+				Box::new(self.spanset(*l)),
+				op,
+				Box::new(self.spanset(*r)),
+			),
+		}
+	}
+
+	fn field(&self, kv: FieldType) -> FieldType {
+		let FieldType::Unscoped(k, v) = kv else {
+			return kv;
+		};
+		let scope = self
+			.rules
+			.iter()
+			.find(|(re, _)| re.is_match(&k))
+			.map_or(AttributeScope::Both, |(_, s)| *s);
+		match scope {
+			AttributeScope::Span => FieldType::Span(k, v),
+			AttributeScope::Resource => FieldType::Resource(k, v),
+			AttributeScope::Both => FieldType::Unscoped(k, v),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use pretty_assertions::assert_eq;
+
+	#[test]
+	fn test_resolve_unscoped() {
+		let rule = |pattern: &str, scope| UnscopedRule {
+			pattern: pattern.to_string(),
+			scope,
+		};
+		let resolver = Resolver::new(&[
+			rule("env", AttributeScope::Resource),
+			rule("http.*", AttributeScope::Span),
+		]);
+		let parse = |q| traceql::parse_traceql(q).unwrap();
+		let got = resolver.expression(parse(
+			r#"{env="prod" && http.method="GET"} >> {user="a" || env="dev"}"#,
+		));
+		let want = parse(
+			r#"{resource.env="prod" && span.http.method="GET"} >> {user="a" || resource.env="dev"}"#,
+		);
+		assert_eq!(got, want);
+	}
+}
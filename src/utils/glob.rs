@@ -0,0 +1,9 @@
+use regex::Regex;
+
+// only * and ? are special, everything else matches literally
+pub fn glob(pattern: &str) -> Regex {
+	let re = regex::escape(pattern)
+		.replace(r"\*", ".*")
+		.replace(r"\?", ".");
+	Regex::new(&format!("^{}$", re)).expect("escaped glob is a valid regex")
+}
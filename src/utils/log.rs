@@ -1,4 +1,7 @@
+use crate::config::Redact;
 use anyhow::Result;
+use regex::Regex;
+use std::{borrow::Cow, sync::OnceLock};
 use tracing::error;
 
 pub trait ResultLogger {
@@ -13,3 +16,145 @@ impl<T> ResultLogger for Result<T> {
 		})
 	}
 }
+
+const MASK: &str = "***";
+
+static REDACTION: OnceLock<Redaction> = OnceLock::new();
+
+// set once at startup from server.log.redact, patterns are validated
+// with the config
+pub fn set_redaction(cfg: &Redact) {
+	let _ = REDACTION.set(Redaction::new(cfg));
+}
+
+// redact masks the configured patterns in s
+pub fn redact(s: &str) -> Cow<str> {
+	match REDACTION.get() {
+		Some(r) => r.apply(s),
+		None => Cow::Borrowed(s),
+	}
+}
+
+// redact_sql also masks the string literals if asked to, and the s3
+// credentials in any case. Also meant for ck errors, which echo the sql
+pub fn redact_sql(sql: &str) -> Cow<str> {
+	let sql = hide_credentials(sql);
+	match (REDACTION.get(), sql) {
+		(Some(r), Cow::Borrowed(s)) => r.apply_sql(s),
+		(Some(r), Cow::Owned(s)) => Cow::Owned(r.apply_sql(&s).into_owned()),
+		(None, sql) => sql,
+	}
+}
+
+// redact_query decodes the query string first, otherwise a pattern
+// like an email would never match its percent-encoded form
+pub fn redact_query(q: &str) -> Cow<str> {
+	match REDACTION.get() {
+		Some(r) if !r.patterns.is_empty() => {
+			let decoded = url::form_urlencoded::parse(q.as_bytes())
+				.map(|(k, v)| format!("{}={}", k, v))
+				.collect::<Vec<_>>()
+				.join("&");
+			Cow::Owned(r.apply(&decoded).into_owned())
+		}
+		_ => Cow::Borrowed(q),
+	}
+}
+
+// hide_credentials masks the key and secret given to ck's s3 table
+// function, whatever the redaction config says
+pub fn hide_credentials(sql: &str) -> Cow<str> {
+	static S3: OnceLock<Regex> = OnceLock::new();
+	let lit = r"'(?:[^'\\]|\\.)*'";
+	S3.get_or_init(|| {
+		Regex::new(&format!(r"s3\(({}), {}, {}, ", lit, lit, lit)).unwrap()
+	})
+	.replace_all(sql, "s3($1, '***', '***', ")
+}
+
+struct Redaction {
+	patterns: Vec<Regex>,
+	sql_literals: Option<Regex>,
+}
+
+impl Redaction {
+	fn new(cfg: &Redact) -> Self {
+		Self {
+			patterns: cfg
+				.patterns
+				.iter()
+				.filter_map(|p| Regex::new(p).ok())
+				.collect(),
+			sql_literals: cfg
+				.sql_literals
+				.then(|| Regex::new(r"'(?:[^'\\]|\\.)*'").unwrap()),
+		}
+	}
+	fn apply<'a>(&self, s: &'a str) -> Cow<'a, str> {
+		let mut out = Cow::Borrowed(s);
+		for re in &self.patterns {
+			let replaced = match re.replace_all(&out, MASK) {
+				Cow::Owned(o) => Some(o),
+				Cow::Borrowed(_) => None,
+			};
+			if let Some(o) = replaced {
+				out = Cow::Owned(o);
+			}
+		}
+		out
+	}
+	fn apply_sql<'a>(&self, sql: &'a str) -> Cow<'a, str> {
+		let Some(lit) = &self.sql_literals else {
+			return self.apply(sql);
+		};
+		match lit.replace_all(sql, "'***'") {
+			Cow::Borrowed(s) => self.apply(s),
+			Cow::Owned(s) => Cow::Owned(self.apply(&s).into_owned()),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use pretty_assertions::assert_eq;
+
+	#[test]
+	fn test_redaction() {
+		let r = Redaction::new(&Redact {
+			patterns: vec![
+				r"[\w.+-]+@[\w-]+\.[\w.]+".to_string(),
+				r"token=\w+".to_string(),
+			],
+			sql_literals: true,
+		});
+		assert_eq!(
+			r.apply(r#"{user="bob@example.com"} |= "token=abc123""#),
+			r#"{user="***"} |= "***""#
+		);
+		assert!(matches!(r.apply("nothing here"), Cow::Borrowed(_)));
+		assert_eq!(
+			r.apply_sql(
+				"SELECT * FROM logs WHERE Body LIKE '%it''s%' AND x = 'a\\'b' LIMIT 10"
+			),
+			"SELECT * FROM logs WHERE Body LIKE '***''***' AND x = '***' LIMIT 10"
+		);
+	}
+
+	#[test]
+	fn test_hide_credentials() {
+		assert_eq!(
+			hide_credentials(
+				"SELECT * FROM s3('https://b/logs/*.parquet', 'AKIA', 's\\'x', 'Parquet')"
+			),
+			"SELECT * FROM s3('https://b/logs/*.parquet', '***', '***', 'Parquet')"
+		);
+		let public = "SELECT * FROM s3('https://b/logs/*.parquet', 'Parquet')";
+		assert_eq!(hide_credentials(public), public);
+		// whatever the redaction config, redact_sql hides them too
+		assert_eq!(
+			redact_sql("Code: 62. s3('u', 'AKIA', 'secret', 'Parquet')"),
+			"Code: 62. s3('u', '***', '***', 'Parquet')"
+		);
+	}
+}
@@ -1,3 +1,7 @@
+pub mod glob;
 pub mod log;
 pub mod serde;
+pub mod spill;
+pub mod time;
+pub mod truncate;
 pub mod validate;
@@ -1,3 +1,41 @@
+use serde::Serialize;
+use std::io;
+
+// json_size is the length of v serialized as json, counted without
+// keeping the bytes around
+pub fn json_size<T: Serialize + ?Sized>(v: &T) -> usize {
+	let mut w = Counter(0);
+	// writing to the counter never fails
+	let _ = serde_json::to_writer(&mut w, v);
+	w.0
+}
+
+// json_str_size is the length of s as a json string, quotes and escapes
+// included, found without serializing it
+pub fn json_str_size(s: &str) -> usize {
+	let escaped = s
+		.bytes()
+		.map(|b| match b {
+			b'"' | b'\\' | b'\n' | b'\r' | b'\t' | 0x08 | 0x0c => 2,
+			0..=0x1f => 6,
+			_ => 1,
+		})
+		.sum::<usize>();
+	escaped + 2
+}
+
+struct Counter(usize);
+
+impl io::Write for Counter {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		self.0 += buf.len();
+		Ok(buf.len())
+	}
+	fn flush(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
+
 pub mod jsonstr {
 	use std::fmt::Display;
 	use std::str::FromStr;
@@ -23,3 +61,21 @@ pub mod jsonstr {
 			.map_err(de::Error::custom)
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_json_str_size() {
+		for s in [
+			"",
+			"plain",
+			"a \"quoted\" \\ path",
+			"tab\tnl\n\u{1}",
+			"中文",
+		] {
+			assert_eq!(json_str_size(s), json_size(s), "{:?}", s);
+		}
+	}
+}
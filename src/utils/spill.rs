@@ -0,0 +1,107 @@
+use axum::body::Body;
+use std::{io::SeekFrom, path::Path};
+use tokio::{
+	fs::File,
+	io::{AsyncSeekExt, AsyncWriteExt, BufWriter},
+	task::spawn_blocking,
+};
+use tokio_util::io::ReaderStream;
+
+// SpillBuffer collects a response that may be too big to keep in memory.
+// Up to budget bytes stay in memory, past that everything goes to an
+// unnamed temp file, which the os removes once the response is sent
+pub struct SpillBuffer<'a> {
+	budget: usize,
+	dir: Option<&'a Path>,
+	mem: Vec<u8>,
+	file: Option<BufWriter<File>>,
+	len: usize,
+}
+
+impl<'a> SpillBuffer<'a> {
+	pub fn new(budget: usize, dir: Option<&'a Path>) -> Self {
+		Self {
+			budget,
+			dir,
+			mem: Vec::new(),
+			file: None,
+			len: 0,
+		}
+	}
+
+	pub async fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+		self.len += data.len();
+		if self.file.is_none() && self.len > self.budget {
+			// creating the file blocks
+			let dir = self.dir.map(Path::to_path_buf);
+			let f = spawn_blocking(move || match dir {
+				Some(dir) => tempfile::tempfile_in(dir),
+				None => tempfile::tempfile(),
+			})
+			.await
+			.map_err(std::io::Error::other)??;
+			let mut f = BufWriter::new(File::from_std(f));
+			f.write_all(&self.mem).await?;
+			self.mem = Vec::new();
+			self.file = Some(f);
+		}
+		match &mut self.file {
+			Some(f) => f.write_all(data).await,
+			None => {
+				self.mem.extend_from_slice(data);
+				Ok(())
+			}
+		}
+	}
+
+	pub fn len(&self) -> usize {
+		self.len
+	}
+
+	pub fn spilled(&self) -> bool {
+		self.file.is_some()
+	}
+
+	// into_body reads the file back as a stream, the memory one is sent
+	// as it is
+	pub async fn into_body(self) -> std::io::Result<Body> {
+		let Some(mut f) = self.file else {
+			return Ok(Body::from(self.mem));
+		};
+		f.flush().await?;
+		let mut f = f.into_inner();
+		f.seek(SeekFrom::Start(0)).await?;
+		Ok(Body::from_stream(ReaderStream::new(f)))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use pretty_assertions::assert_eq;
+
+	async fn collect(buf: SpillBuffer<'_>) -> Vec<u8> {
+		let body = buf.into_body().await.unwrap();
+		axum::body::to_bytes(body, usize::MAX)
+			.await
+			.unwrap()
+			.to_vec()
+	}
+
+	#[tokio::test]
+	async fn test_spill() {
+		let mut buf = SpillBuffer::new(8, None);
+		buf.write(b"abc\n").await.unwrap();
+		buf.write(b"def\n").await.unwrap();
+		assert!(!buf.spilled());
+		assert_eq!(collect(buf).await, b"abc\ndef\n");
+
+		let mut buf = SpillBuffer::new(8, None);
+		for line in [b"abc\n", b"def\n", b"ghi\n"] {
+			buf.write(line).await.unwrap();
+		}
+		assert!(buf.spilled());
+		assert_eq!(buf.len(), 12);
+		assert_eq!(collect(buf).await, b"abc\ndef\nghi\n");
+	}
+}
@@ -0,0 +1,110 @@
+use crate::errors::AppError;
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{de, Deserialize, Deserializer};
+use std::str::FromStr;
+
+// parse_timestamp reads the times loki and tempo clients send: unix
+// seconds, nanoseconds or fractional seconds, RFC3339 with any offset,
+// and grafana's relative "now", "now-1h" or "now+5m"
+pub fn parse_timestamp(value: &str) -> Result<DateTime<Utc>, AppError> {
+	let invalid = || AppError::InvalidTimeFormat(value.to_string());
+	if let Ok(seconds) = value.parse::<i64>() {
+		if seconds.to_string().len() <= 10 {
+			return DateTime::from_timestamp(seconds, 0).ok_or_else(invalid);
+		}
+		let nanos = (seconds % 1_000_000_000) as u32;
+		let secs = seconds / 1_000_000_000;
+		return DateTime::from_timestamp(secs, nanos).ok_or_else(invalid);
+	}
+	if let Ok(timestamp) = value.parse::<f64>() {
+		let secs = timestamp.trunc() as i64;
+		let nanos = (timestamp.fract() * 1_000_000_000.0) as u32;
+		return DateTime::from_timestamp(secs, nanos).ok_or_else(invalid);
+	}
+	if let Some(rest) = value.strip_prefix("now") {
+		return relative(Utc::now(), rest).ok_or_else(invalid);
+	}
+	if let Ok(dt) = DateTime::parse_from_rfc3339(value) {
+		return Ok(dt.with_timezone(&Utc));
+	}
+	// a space instead of the T and the like
+	DateTime::<Utc>::from_str(value).map_err(|_| invalid())
+}
+
+// relative moves now by rest, which is empty, or a sign and a duration
+fn relative(now: DateTime<Utc>, rest: &str) -> Option<DateTime<Utc>> {
+	let rest = rest.trim();
+	if rest.is_empty() {
+		return Some(now);
+	}
+	let parse = |d: &str| {
+		let d = humantime::parse_duration(d.trim()).ok()?;
+		TimeDelta::from_std(d).ok()
+	};
+	if let Some(d) = rest.strip_prefix('-') {
+		return now.checked_sub_signed(parse(d)?);
+	}
+	now.checked_add_signed(parse(rest.strip_prefix('+')?)?)
+}
+
+// unix_seconds reads an optional parameter with parse_timestamp for the
+// requests that keep it as seconds
+pub fn unix_seconds<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+	D: Deserializer<'de>,
+{
+	let Some(value) = Option::<String>::deserialize(deserializer)? else {
+		return Ok(None);
+	};
+	let dt = parse_timestamp(&value).map_err(de::Error::custom)?;
+	u64::try_from(dt.timestamp())
+		.map(Some)
+		.map_err(|_| de::Error::custom(format!("{} is before 1970", value)))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parse_timestamp() {
+		let want = DateTime::from_timestamp(1717207200, 0).unwrap();
+		let cases = [
+			"1717207200",
+			"1717207200000000000",
+			"1717207200.0",
+			"2024-06-01T02:00:00Z",
+			"2024-06-01T10:00:00+08:00",
+			"2024-05-31T21:00:00-05:00",
+			"2024-06-01 10:00:00+08:00",
+		];
+		for c in cases {
+			assert_eq!(parse_timestamp(c).unwrap(), want, "{}", c);
+		}
+		for c in ["", "yesterday", "2024-06-01T10:00:00", "now*1h", "now-1x"] {
+			assert!(parse_timestamp(c).is_err(), "{}", c);
+		}
+	}
+
+	#[test]
+	fn test_relative() {
+		let now = DateTime::from_timestamp(1717207200, 0).unwrap();
+		let cases = [
+			("", 0),
+			("-1h", -3600),
+			("- 1h30m", -5400),
+			("+5m", 300),
+			("-2d", -172800),
+		];
+		for (rest, secs) in cases {
+			let got = relative(now, rest).unwrap();
+			assert_eq!((got - now).num_seconds(), secs, "{}", rest);
+		}
+		assert!(relative(now, "1h").is_none());
+		// the sign may be any char the client sends
+		assert!(relative(now, "é1h").is_none());
+		assert!(relative(now, "\u{2212}1h").is_none());
+		let got = parse_timestamp("now-1h").unwrap();
+		assert!((Utc::now() - got - TimeDelta::hours(1)).num_seconds() < 5);
+	}
+}
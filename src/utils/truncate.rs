@@ -0,0 +1,52 @@
+// the marker ending a shortened value, a single char so a limit counts
+// the same for the value and its marker
+const ELLIPSIS: char = '…';
+
+// truncate shortens s to max chars, the last one being the ellipsis.
+// 0 keeps s whole
+pub fn truncate(s: &mut String, max: usize) {
+	if max == 0 {
+		return;
+	}
+	if let Some((i, _)) = s.char_indices().nth(max) {
+		let cut = s[..i].char_indices().last().map_or(0, |(j, _)| j);
+		s.truncate(cut);
+		s.push(ELLIPSIS);
+	}
+}
+
+// truncate_json shortens the strings in v, arrays and objects included
+pub fn truncate_json(v: &mut serde_json::Value, max: usize) {
+	use serde_json::Value::*;
+	match v {
+		String(s) => truncate(s, max),
+		Array(items) => items.iter_mut().for_each(|i| truncate_json(i, max)),
+		Object(m) => m.values_mut().for_each(|i| truncate_json(i, max)),
+		_ => {}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde_json::json;
+
+	#[test]
+	fn test_truncate() {
+		let cases = [
+			("select 1", 0, "select 1"),
+			("select 1", 8, "select 1"),
+			("select 1", 5, "sele…"),
+			("日本語のテキスト", 3, "日本…"),
+			("ab", 1, "…"),
+		];
+		for (s, max, want) in cases {
+			let mut s = s.to_string();
+			truncate(&mut s, max);
+			assert_eq!(s, want);
+		}
+		let mut v = json!({"stack": ["at main.rs", 1], "sql": "select 1"});
+		truncate_json(&mut v, 4);
+		assert_eq!(v, json!({"stack": ["at …", 1], "sql": "sel…"}));
+	}
+}
@@ -1,8 +1,83 @@
+use axum::extract::{rejection::QueryRejection, Query};
+use axum_valid::{Valid, ValidRejection, ValidationRejection};
 use chrono::DateTime;
-use validator::ValidationError;
+use itertools::Itertools;
+use std::borrow::Cow;
+use validator::{ValidationError, ValidationErrors};
+
+// ValidQuery lets a handler word the rejection of its query string itself
+pub type ValidQuery<T> =
+	Result<Valid<Query<T>>, ValidRejection<QueryRejection>>;
+
+// params returns the request or what is wrong with its parameters
+pub fn params<T>(req: ValidQuery<T>) -> Result<T, String> {
+	match req {
+		Ok(Valid(Query(req))) => Ok(req),
+		Err(ValidationRejection::Valid(errors)) => Err(describe(&errors)),
+		Err(ValidationRejection::Inner(e)) => Err(e.body_text()),
+	}
+}
+
+// describe lists every failing parameter as "<name> <problem>", the
+// field names are those of the query string as long as none is renamed
+pub fn describe(errors: &ValidationErrors) -> String {
+	errors
+		.field_errors()
+		.into_iter()
+		.sorted_by_key(|(field, _)| *field)
+		.flat_map(|(field, errs)| {
+			errs.iter()
+				.map(move |e| format!("{} {}", field, problem(e)))
+		})
+		.join(", ")
+}
+
+fn problem(e: &ValidationError) -> String {
+	if let Some(m) = &e.message {
+		return m.to_string();
+	}
+	match (e.code.as_ref(), e.params.get("min")) {
+		("length", Some(min)) => {
+			format!("must be at least {} characters long", min)
+		}
+		(code, _) => format!("is invalid ({})", code),
+	}
+}
 
 pub fn unix_timestamp(secs: u64) -> Result<(), ValidationError> {
 	DateTime::from_timestamp(secs as i64, 0)
-		.ok_or(ValidationError::new("invalid unix timestamp"))
+		.ok_or(
+			ValidationError::new("invalid unix timestamp").with_message(
+				Cow::Borrowed("must be a unix timestamp in seconds"),
+			),
+		)
 		.map(|_| ())
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use validator::Validate;
+
+	#[derive(Validate)]
+	struct Req {
+		#[validate(length(min = 6))]
+		query: String,
+		#[validate(custom(function = "unix_timestamp"))]
+		start: Option<u64>,
+	}
+
+	#[test]
+	fn test_describe() {
+		let req = Req {
+			query: "{}".to_string(),
+			start: Some(1 << 62),
+		};
+		let errors = req.validate().unwrap_err();
+		assert_eq!(
+			describe(&errors),
+			"query must be at least 6 characters long, \
+			 start must be a unix timestamp in seconds"
+		);
+	}
+}
@@ -0,0 +1,181 @@
+// format renders a parsed query back into traceql
+
+use crate::*;
+use qlcommon::string::quote;
+
+pub fn format_query(expr: &Expression) -> String {
+	expression(expr)
+}
+
+// how tightly each node binds, && and || are right associative
+// while > and >> are left associative
+fn precedence(expr: &Expression) -> u8 {
+	match expr {
+		Expression::Logical(_, LogicalOperator::Or, _) => 1,
+		Expression::Logical(_, LogicalOperator::And, _) => 2,
+		Expression::Structural(..) => 3,
+		Expression::SpanSet(_) => 4,
+	}
+}
+
+fn expression(expr: &Expression) -> String {
+	let p = precedence(expr);
+	let wrap = |e: &Expression, needs: bool| match needs {
+		true => format!("({})", expression(e)),
+		false => expression(e),
+	};
+	match expr {
+		Expression::SpanSet(sp) => format!("{{ {} }}", spanset(sp)),
+		Expression::Logical(l, op, r) => format!(
+			"{} {} {}",
+			wrap(l, precedence(l) <= p),
+			logical(*op),
+			wrap(r, precedence(r) < p),
+		),
+		Expression::Structural(l, op, r) => format!(
+			"{} {} {}",
+			wrap(l, precedence(l) < p),
+			op,
+			wrap(r, precedence(r) <= p),
+		),
+	}
+}
+
+fn logical(op: LogicalOperator) -> &'static str {
+	match op {
+		LogicalOperator::And => "&&",
+		LogicalOperator::Or => "||",
+	}
+}
+
+fn spanset(sp: &SpanSet) -> String {
+	let precedence = |s: &SpanSet| match s {
+		SpanSet::Logical(_, LogicalOperator::Or, _) => 1,
+		SpanSet::Logical(_, LogicalOperator::And, _) => 2,
+		SpanSet::Expr(_) => 3,
+	};
+	let wrap = |s: &SpanSet, needs: bool| match needs {
+		true => format!("({})", spanset(s)),
+		false => spanset(s),
+	};
+	match sp {
+		SpanSet::Expr(e) => field_expr(e),
+		SpanSet::Logical(l, op, r) => {
+			let p = precedence(sp);
+			format!(
+				"{} {} {}",
+				wrap(l, precedence(l) <= p),
+				logical(*op),
+				wrap(r, precedence(r) < p),
+			)
+		}
+	}
+}
+
+fn field_expr(e: &FieldExpr) -> String {
+	let (name, value) = match &e.kv {
+		FieldType::Intrinsic(i) => intrinsic(i),
+		FieldType::Span(k, v) => (scoped("span", k), field_value(v)),
+		FieldType::Resource(k, v) => (scoped("resource", k), field_value(v)),
+		FieldType::Unscoped(k, v) => (unscoped(k), field_value(v)),
+	};
+	format!("{} {} {}", name, operator(e.operator), value)
+}
+
+fn operator(op: ComparisonOperator) -> String {
+	match op {
+		ComparisonOperator::RegularExpression => "=~".to_string(),
+		ComparisonOperator::NegatedRegularExpression => "!~".to_string(),
+		op => op.to_string(),
+	}
+}
+
+fn intrinsic(i: &IntrisincField) -> (String, String) {
+	use IntrisincField::*;
+	let (name, value) = match i {
+		Status(s) => ("status", status(*s).to_string()),
+		StatusMessage(s) => ("statusMessage", quote(s)),
+		Duraion(d) => ("duration", duration(*d)),
+		Name(s) => ("name", quote(s)),
+		Kind(k) => ("kind", format!("{:?}", k).to_lowercase()),
+		TraceDuration(d) => ("traceDuration", duration(*d)),
+		RootName(s) => ("rootName", quote(s)),
+		RootServiceName(s) => ("rootServiceName", quote(s)),
+		ServiceName(s) => ("serviceName", quote(s)),
+	};
+	(name.to_string(), value)
+}
+
+fn field_value(v: &FieldValue) -> String {
+	match v {
+		FieldValue::Integer(i) => i.to_string(),
+		// keep the fraction so it doesn't come back as an integer
+		FieldValue::Float(f) => format!("{:?}", f.0),
+		FieldValue::String(s) => quote(s),
+		FieldValue::Bool(b) => b.to_string(),
+		FieldValue::Status(s) => status(*s).to_string(),
+		FieldValue::Duration(d) => duration(*d),
+	}
+}
+
+fn status(s: StatusCode) -> &'static str {
+	match s {
+		StatusCode::Ok => "ok",
+		StatusCode::Err => "error",
+		StatusCode::Unset => "unset",
+	}
+}
+
+fn duration(d: std::time::Duration) -> String {
+	humantime::format_duration(d).to_string().replace(' ', "")
+}
+
+fn plain(k: &str) -> bool {
+	!k.is_empty()
+		&& k.chars()
+			.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+}
+
+fn scoped(scope: &str, k: &str) -> String {
+	match plain(k) {
+		true => format!("{}.{}", scope, k),
+		false => format!("{}.{}", scope, quote(k)),
+	}
+}
+
+fn unscoped(k: &str) -> String {
+	let starts_ok =
+		k.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_');
+	let looks_scoped = k.starts_with("span.") || k.starts_with("resource.");
+	match plain(k) && starts_ok && !looks_scoped {
+		true => k.to_string(),
+		false => format!(".{}", quote(k)),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use pretty_assertions::assert_eq;
+
+	#[test]
+	fn test_format_query() {
+		let cases = [
+			(
+				r#"{resource.app="camp"&&duration>1m30s&&status!=ok}"#,
+				r#"{ resource.app = "camp" && duration > 1m30s && status != ok }"#,
+			),
+			(
+				r#"({kind=server} || {span."my key"=~"a.*"}) && {name="x"}>>{."x"=1}"#,
+				r#"({ kind = server } || { span."my key" =~ "a.*" }) && { name = "x" } >> { x = 1 }"#,
+			),
+			(r#"{a=1&&b=2||c=3}"#, r#"{ a = 1 && b = 2 || c = 3 }"#),
+		];
+		for (input, want) in cases {
+			let expr = parse_traceql(input).unwrap();
+			let formatted = format_query(&expr);
+			assert_eq!(formatted, want);
+			assert_eq!(parse_traceql(&formatted).unwrap(), expr);
+		}
+	}
+}
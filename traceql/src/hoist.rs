@@ -0,0 +1,111 @@
+// hoist moves the conditions every branch of a `||` has in common in
+// front of the spanset, `{duration > 1s && name = "a" || duration > 1s &&
+// name = "b"}` is searched as `{duration > 1s && (...)}`. The spans
+// matched stay the same, but the condition is now one the backend can
+// prune rows with before looking at the branches
+
+use crate::*;
+
+pub fn expression(expr: Expression) -> Expression {
+	match expr {
+		Expression::SpanSet(sp) => Expression::SpanSet(spanset(sp)),
+		Expression::Logical(l, op, r) => Expression::Logical(
+			Box::new(expression(*l)),
+			op,
+			Box::new(expression(*r)),
+		),
+		Expression::Structural(l, op, r) => Expression::Structural(
+			Box::new(expression(*l)),
+			op,
+			Box::new(expression(*r)),
+		),
+	}
+}
+
+pub fn spanset(sp: SpanSet) -> SpanSet {
+	let mut top = vec![];
+	conjuncts(&sp, &mut top);
+	let missing: Vec<_> = required(&sp)
+		.into_iter()
+		.filter(|c| !top.contains(&c))
+		.collect();
+	missing.into_iter().rev().fold(sp, |acc, c| {
+		SpanSet::Logical(
+			Box::new(SpanSet::Expr(c)),
+			LogicalOperator::And,
+			Box::new(acc),
+		)
+	})
+}
+
+// required lists the conditions a span has to meet to match sp, the ones
+// of an `||` are those both sides require
+fn required(sp: &SpanSet) -> Vec<FieldExpr> {
+	match sp {
+		SpanSet::Expr(e) => vec![e.clone()],
+		SpanSet::Logical(l, LogicalOperator::And, r) => {
+			let mut out = required(l);
+			for c in required(r) {
+				if !out.contains(&c) {
+					out.push(c);
+				}
+			}
+			out
+		}
+		SpanSet::Logical(l, LogicalOperator::Or, r) => {
+			let r = required(r);
+			required(l).into_iter().filter(|c| r.contains(c)).collect()
+		}
+	}
+}
+
+// conjuncts are the conditions already at the top, joined by `&&`
+fn conjuncts<'a>(sp: &'a SpanSet, out: &mut Vec<&'a FieldExpr>) {
+	match sp {
+		SpanSet::Expr(e) => out.push(e),
+		SpanSet::Logical(l, LogicalOperator::And, r) => {
+			conjuncts(l, out);
+			conjuncts(r, out);
+		}
+		SpanSet::Logical(_, LogicalOperator::Or, _) => {}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::format::format_query;
+	use pretty_assertions::assert_eq;
+
+	#[test]
+	fn test_hoist() {
+		let cases = [
+			(
+				r#"{duration > 1s && name = "a" || duration > 1s && name = "b"}"#,
+				r#"{ duration > 1s && (duration > 1s && name = "a" || duration > 1s && name = "b") }"#,
+			),
+			// only what every branch has
+			(
+				r#"{duration > 1s && status = error || duration > 1s && name = "b" || status = error && duration > 1s}"#,
+				r#"{ duration > 1s && (duration > 1s && status = error || duration > 1s && name = "b" || status = error && duration > 1s) }"#,
+			),
+			// nothing in common, or no || at all
+			(
+				r#"{name = "a" || name = "b"}"#,
+				r#"{ name = "a" || name = "b" }"#,
+			),
+			(
+				r#"{name = "a" && duration > 1s}"#,
+				r#"{ name = "a" && duration > 1s }"#,
+			),
+			(
+				r#"{name = "a" && span.x = 1 || name = "a" && span.y = 2} > {name = "b"}"#,
+				r#"{ name = "a" && (name = "a" && span.x = 1 || name = "a" && span.y = 2) } > { name = "b" }"#,
+			),
+		];
+		for (input, want) in cases {
+			let expr = parse_traceql(input).unwrap();
+			assert_eq!(format_query(&expression(expr)), want, "{}", input);
+		}
+	}
+}
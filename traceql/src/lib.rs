@@ -2,23 +2,23 @@ use std::{fmt::Display, time::Duration};
 
 use nom::{
 	branch::alt,
-	bytes::complete::{is_not, tag, tag_no_case, take_while1, take_while_m_n},
-	character::{
-		complete::{
-			alpha1, alphanumeric1, char, i64 as ni64, multispace0, multispace1,
-		},
-		is_alphanumeric,
-	},
-	combinator::{
-		all_consuming, map, map_opt, map_res, recognize, value, verify,
-	},
-	error::{FromExternalError, ParseError},
-	multi::{fold_many0, many0_count},
+	bytes::complete::{tag, tag_no_case, take_while1},
+	character::complete::{char, digit0, digit1, i64 as ni64},
+	combinator::{all_consuming, map, map_opt, opt, recognize, value},
+	multi::{fold_many0, fold_many1},
 	number::complete::double,
-	sequence::{delimited, pair, preceded, tuple},
+	sequence::{delimited, pair, tuple},
 	IResult, Parser,
 };
 use ordered_float::OrderedFloat;
+use qlcommon::{
+	combinator::{self, furthest, ws},
+	error::{did_you_mean, ParseError},
+	string::parse_string,
+};
+
+pub mod format;
+pub mod hoist;
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum ComparisonOperator {
@@ -64,157 +64,24 @@ impl Display for LogicalOperator {
 	}
 }
 
-// parser combinators are constructed from the bottom up:
-// first we write parsers for the smallest elements (escaped characters),
-// then combine them into larger parsers.
-
-/// Parse a unicode sequence, of the form u{XXXX}, where XXXX is 1 to 6
-/// hexadecimal numerals. We will combine this later with parse_escaped_char
-/// to parse sequences like \u{00AC}.
-fn parse_unicode<'a, E>(input: &'a str) -> IResult<&'a str, char, E>
-where
-	E: ParseError<&'a str>
-		+ FromExternalError<&'a str, std::num::ParseIntError>,
-{
-	// `take_while_m_n` parses between `m` and `n` bytes (inclusive) that match
-	// a predicate. `parse_hex` here parses between 1 and 6 hexadecimal numerals.
-	let parse_hex = take_while_m_n(1, 6, |c: char| c.is_ascii_hexdigit());
-
-	// `preceded` takes a prefix parser, and if it succeeds, returns the result
-	// of the body parser. In this case, it parses u{XXXX}.
-	let parse_delimited_hex = preceded(
-		char('u'),
-		// `delimited` is like `preceded`, but it parses both a prefix and a suffix.
-		// It returns the result of the middle parser. In this case, it parses
-		// {XXXX}, where XXXX is 1 to 6 hex numerals, and returns XXXX
-		delimited(char('{'), parse_hex, char('}')),
-	);
-
-	// `map_res` takes the result of a parser and applies a function that returns
-	// a Result. In this case we take the hex bytes from parse_hex and attempt to
-	// convert them to a u32.
-	let parse_u32 =
-		map_res(parse_delimited_hex, move |hex| u32::from_str_radix(hex, 16));
-
-	// map_opt is like map_res, but it takes an Option instead of a Result. If
-	// the function returns None, map_opt returns an error. In this case, because
-	// not all u32 values are valid unicode code points, we have to fallibly
-	// convert to char with from_u32.
-	map_opt(parse_u32, std::char::from_u32).parse(input)
-}
-
-/// Parse an escaped character: \n, \t, \r, \u{00AC}, etc.
-fn parse_escaped_char<'a, E>(input: &'a str) -> IResult<&'a str, char, E>
-where
-	E: ParseError<&'a str>
-		+ FromExternalError<&'a str, std::num::ParseIntError>,
-{
-	preceded(
-		char('\\'),
-		// `alt` tries each parser in sequence, returning the result of
-		// the first successful match
-		alt((
-			parse_unicode,
-			// The `value` parser returns a fixed value (the first argument) if its
-			// parser (the second argument) succeeds. In these cases, it looks for
-			// the marker characters (n, r, t, etc) and returns the matching
-			// character (\n, \r, \t, etc).
-			value('\n', char('n')),
-			value('\r', char('r')),
-			value('\t', char('t')),
-			value('\u{08}', char('b')),
-			value('\u{0C}', char('f')),
-			value('\\', char('\\')),
-			value('/', char('/')),
-			value('"', char('"')),
-		)),
-	)
-	.parse(input)
-}
-
-/// Parse a backslash, followed by any amount of whitespace. This is used later
-/// to discard any escaped whitespace.
-fn parse_escaped_whitespace<'a, E: ParseError<&'a str>>(
-	input: &'a str,
-) -> IResult<&'a str, &'a str, E> {
-	preceded(char('\\'), multispace1).parse(input)
-}
-
-/// Parse a non-empty block of text that doesn't include \ or "
-fn parse_literal<'a, E: ParseError<&'a str>>(
-	input: &'a str,
-) -> IResult<&'a str, &'a str, E> {
-	// `is_not` parses a string of 0 or more characters that aren't one of the
-	// given characters.
-	let not_quote_slash = is_not("\"\\");
-
-	// `verify` runs a parser, then runs a verification function on the output of
-	// the parser. The verification function accepts out output only if it
-	// returns true. In this case, we want to ensure that the output of is_not
-	// is non-empty.
-	verify(not_quote_slash, |s: &str| !s.is_empty()).parse(input)
-}
-
-/// A string fragment contains a fragment of a string being parsed: either
-/// a non-empty Literal (a series of non-escaped characters), a single
-/// parsed escaped character, or a block of escaped whitespace.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum StringFragment<'a> {
-	Literal(&'a str),
-	EscapedChar(char),
-	EscapedWS,
-}
-
-/// Combine parse_literal, parse_escaped_whitespace, and parse_escaped_char
-/// into a StringFragment.
-fn parse_fragment<'a, E>(
-	input: &'a str,
-) -> IResult<&'a str, StringFragment<'a>, E>
-where
-	E: ParseError<&'a str>
-		+ FromExternalError<&'a str, std::num::ParseIntError>,
-{
-	alt((
-		// The `map` combinator runs a parser, then applies a function to the output
-		// of that parser.
-		map(parse_literal, StringFragment::Literal),
-		map(parse_escaped_char, StringFragment::EscapedChar),
-		value(StringFragment::EscapedWS, parse_escaped_whitespace),
-	))
-	.parse(input)
+// StructuralOperator relates the spans of two spansets by their position
+// in the trace tree, the right hand side is what gets returned
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum StructuralOperator {
+	// `{A} > {B}`, B whose parent is A
+	Child,
+	// `{A} >> {B}`, B with A somewhere above it
+	Descendant,
 }
 
-/// Parse a string. Use a loop of parse_fragment and push all of the fragments
-/// into an output string.
-fn parse_string<'a, E>(input: &'a str) -> IResult<&'a str, String, E>
-where
-	E: ParseError<&'a str>
-		+ FromExternalError<&'a str, std::num::ParseIntError>,
-{
-	// fold is the equivalent of iterator::fold. It runs a parser in a loop,
-	// and for each output value, calls a folding function on each output value.
-	let build_string = fold_many0(
-		// Our parser function– parses a single string fragment
-		parse_fragment,
-		// Our init value, an empty string
-		String::new,
-		// Our folding function. For each fragment, append the fragment to the
-		// string.
-		|mut string, fragment| {
-			match fragment {
-				StringFragment::Literal(s) => string.push_str(s),
-				StringFragment::EscapedChar(c) => string.push(c),
-				StringFragment::EscapedWS => {}
-			}
-			string
-		},
-	);
-
-	// Finally, parse the string. Note that, if `build_string` could accept a raw
-	// " character, the closing delimiter " would never match. When using
-	// `delimited` with a looping parser (like fold), be sure that the
-	// loop won't accidentally match your closing delimiter!
-	delimited(char('"'), build_string, char('"')).parse(input)
+impl Display for StructuralOperator {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		use StructuralOperator::*;
+		match self {
+			Child => write!(f, ">"),
+			Descendant => write!(f, ">>"),
+		}
+	}
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -222,6 +89,7 @@ pub enum FieldValue {
 	Integer(i64),
 	Float(ordered_float::OrderedFloat<f64>),
 	String(String),
+	Bool(bool),
 	Status(StatusCode),
 	Duration(Duration),
 }
@@ -262,26 +130,79 @@ impl Display for FieldValue {
 			FieldValue::Integer(i) => write!(f, "{}", i),
 			FieldValue::Float(v) => write!(f, "{}", v),
 			FieldValue::String(s) => write!(f, "'{}'", s),
+			FieldValue::Bool(b) => write!(f, "{}", b),
 			FieldValue::Status(s) => write!(f, "{}", s),
 			FieldValue::Duration(d) => write!(f, "{}", d.as_nanos()),
 		}
 	}
 }
 
-fn humantime_duration(input: &str) -> IResult<&str, Duration> {
-	map_res(
-		recognize(pair(ni64, take_while1(|c| is_alphanumeric(c as u8)))),
-		|s: &str| s.parse::<humantime::Duration>().map(|hd| hd.into()),
+// nanoseconds per unit, spelled the way humantime accepts them;
+// months and years have no fixed length so they are left out
+fn unit_nanos(unit: &str) -> Option<u128> {
+	Some(match unit {
+		"ns" | "nsec" | "nanos" => 1,
+		"us" | "µs" | "usec" => 1_000,
+		"ms" | "msec" | "millis" => 1_000_000,
+		"s" | "sec" | "secs" | "second" | "seconds" => 1_000_000_000,
+		"m" | "min" | "mins" | "minute" | "minutes" => 60_000_000_000,
+		"h" | "hr" | "hrs" | "hour" | "hours" => 3_600_000_000_000,
+		"d" | "day" | "days" => 86_400_000_000_000,
+		"w" | "week" | "weeks" => 604_800_000_000_000,
+		_ => return None,
+	})
+}
+
+// decimal_nanos scales a number like `1.25` by the unit without going
+// through floats, digits finer than a nanosecond are dropped
+fn decimal_nanos(num: &str, unit: u128) -> Option<u128> {
+	let (int, frac) = num.split_once('.').unwrap_or((num, ""));
+	let int = match int {
+		"" => 0,
+		v => v.parse::<u128>().ok()?,
+	};
+	let mut nanos = int.checked_mul(unit)?;
+	let mut scale = unit;
+	for d in frac.bytes() {
+		scale /= 10;
+		nanos += u128::from(d - b'0') * scale;
+	}
+	Some(nanos)
+}
+
+fn duration_part(input: &str) -> IResult<&str, u128> {
+	let number = alt((
+		recognize(pair(digit1, opt(pair(char('.'), digit0)))),
+		recognize(pair(char('.'), digit1)),
+	));
+	let unit = take_while1(|c: char| c.is_ascii_alphabetic() || c == 'µ');
+	map_opt(pair(number, unit), |(num, unit)| {
+		decimal_nanos(num, unit_nanos(unit)?)
+	})(input)
+}
+
+// decimal_duration accepts one or more `<number><unit>` parts, e.g.
+// 1h30m, 1.5s or 0.25ms
+fn decimal_duration(input: &str) -> IResult<&str, Duration> {
+	map_opt(
+		fold_many1(duration_part, || Some(0u128), |acc, n| acc?.checked_add(n)),
+		|nanos| {
+			let nanos = nanos?;
+			let secs = u64::try_from(nanos / 1_000_000_000).ok()?;
+			Some(Duration::new(secs, (nanos % 1_000_000_000) as u32))
+		},
 	)(input)
 }
 
 fn field_value(input: &str) -> IResult<&str, FieldValue> {
 	alt((
-		map(ws(humantime_duration), FieldValue::Duration),
+		map(ws(decimal_duration), FieldValue::Duration),
 		map(ws(ni64), FieldValue::Integer),
 		map(ws(double), |v| FieldValue::Float(OrderedFloat(v))),
 		map(ws(parse_string), FieldValue::String),
 		alt((
+			value(FieldValue::Bool(true), ws(tag("true"))),
+			value(FieldValue::Bool(false), ws(tag("false"))),
 			value(FieldValue::Status(StatusCode::Ok), ws(tag("ok"))),
 			value(FieldValue::Status(StatusCode::Err), ws(tag("error"))),
 			value(FieldValue::Status(StatusCode::Unset), ws(tag("unset"))),
@@ -303,17 +224,26 @@ fn parse_comparison_operator(input: &str) -> IResult<&str, ComparisonOperator> {
 	))(input)
 }
 
-fn ws<'a, F, O, E: ParseError<&'a str>>(inner: F) -> impl Parser<&'a str, O, E>
-where
-	F: Parser<&'a str, O, E>,
-{
-	delimited(multispace0, inner, multispace0)
+// dashes are allowed after the first character,
+// e.g. span.http.request.header.x-request-id
+fn identifier(input: &str) -> IResult<&str, &str> {
+	combinator::identifier("-")(input)
 }
 
-fn identifier(input: &str) -> IResult<&str, &str> {
-	recognize(pair(
-		alt((alpha1, tag("_"))),
-		many0_count(alt((alphanumeric1, tag("_"), tag(".")))),
+// attribute names with other characters are quoted after the scope,
+// e.g. span."my attr" or ."my attr" for unscoped
+fn attribute_name(input: &str) -> IResult<&str, String> {
+	alt((
+		map(
+			pair(
+				alt((tag("span."), tag("resource."), tag("."))),
+				parse_string,
+			),
+			|(scope, name)| {
+				format!("{}{}", scope.trim_start_matches('.'), name)
+			},
+		),
+		map(identifier, str::to_string),
 	))(input)
 }
 
@@ -358,20 +288,17 @@ impl From<SpanKind> for i64 {
 fn parse_non_intrisinc_field(input: &str) -> IResult<&str, FieldExpr> {
 	map(
 		tuple((
-			ws(identifier),
+			ws(attribute_name),
 			ws(parse_comparison_operator),
 			ws(field_value),
 		)),
 		|(a, b, c)| {
-			let t = if a.starts_with("span.") {
-				FieldType::Span(a.trim_start_matches("span.").to_string(), c)
-			} else if a.starts_with("resource.") {
-				FieldType::Resource(
-					a.trim_start_matches("resource.").to_string(),
-					c,
-				)
+			let t = if let Some(k) = a.strip_prefix("span.") {
+				FieldType::Span(k.to_string(), c)
+			} else if let Some(k) = a.strip_prefix("resource.") {
+				FieldType::Resource(k.to_string(), c)
 			} else {
-				FieldType::Unscoped(a.to_string(), c)
+				FieldType::Unscoped(a, c)
 			};
 			FieldExpr { kv: t, operator: b }
 		},
@@ -402,7 +329,7 @@ fn parse_intrisinc_duration(input: &str) -> IResult<&str, FieldExpr> {
 		tuple((
 			ws(alt((tag("duration"), tag("traceDuration")))),
 			ws(parse_comparison_operator),
-			ws(humantime_duration),
+			ws(decimal_duration),
 		)),
 		|(a, b, c)| {
 			use IntrisincField::*;
@@ -539,16 +466,41 @@ fn spanset(input: &str) -> IResult<&str, SpanSet> {
 }
 
 fn spanset_expression(input: &str) -> IResult<&str, Expression> {
-	alt((
+	furthest(
 		map(ws(spanset), Expression::SpanSet),
 		delimited(ws(char('(')), ws(expression), ws(char(')'))),
+	)(input)
+}
+
+fn structural_operator(input: &str) -> IResult<&str, StructuralOperator> {
+	alt((
+		value(StructuralOperator::Descendant, tag(">>")),
+		value(StructuralOperator::Child, tag(">")),
 	))(input)
 }
 
+// structural operators bind tighter than && and || and are left associative,
+// `{A} > {B} > {C}` is C whose parent is a B that is a child of A
+fn structural_expression(input: &str) -> IResult<&str, Expression> {
+	let (input, first) = ws(spanset_expression).parse(input)?;
+	fold_many0(
+		pair(ws(structural_operator), ws(spanset_expression)),
+		move || first.clone(),
+		|acc, (op, rhs)| {
+			Expression::Structural(Box::new(acc), op, Box::new(rhs))
+		},
+	)
+	.parse(input)
+}
+
 fn and_expression(input: &str) -> IResult<&str, Expression> {
 	alt((
 		map(
-			tuple((ws(spanset_expression), ws(tag("&&")), ws(and_expression))),
+			tuple((
+				ws(structural_expression),
+				ws(tag("&&")),
+				ws(and_expression),
+			)),
 			|(a, _, c)| {
 				Expression::Logical(
 					Box::new(a),
@@ -557,7 +509,7 @@ fn and_expression(input: &str) -> IResult<&str, Expression> {
 				)
 			},
 		),
-		ws(spanset_expression),
+		ws(structural_expression),
 	))(input)
 }
 
@@ -577,18 +529,59 @@ fn expression(input: &str) -> IResult<&str, Expression> {
 	))(input)
 }
 
-pub type TraceQLError = nom::Err<nom::error::Error<String>>;
+pub type TraceQLError = ParseError;
+
+// operators from other query languages and what traceql uses instead
+static TRACEQL_OPERATOR_HINTS: [(&str, &str); 3] =
+	[("<>", "!="), ("and", "&&"), ("or", "||")];
+
+static TRACEQL_KEYWORDS: [&str; 12] = [
+	"ok",
+	"error",
+	"unset",
+	"span.",
+	"resource.",
+	"duration",
+	"traceDuration",
+	"status",
+	"statusMessage",
+	"kind",
+	"name",
+	"serviceName",
+];
+
+fn hint(token: &str) -> Option<String> {
+	if token.is_empty() {
+		return Some("is a closing bracket missing?".to_string());
+	}
+	if token.starts_with('\'') {
+		return Some("strings are quoted with \"".to_string());
+	}
+	// what's left of `==` after the first `=` was taken as the operator
+	if token.starts_with('=') {
+		return Some("equality is written with a single `=`".to_string());
+	}
+	let word = token.split(['{', '}', '(', ')']).next().unwrap_or(token);
+	TRACEQL_OPERATOR_HINTS
+		.iter()
+		.find(|(wrong, _)| {
+			token.starts_with(wrong) || word.eq_ignore_ascii_case(wrong)
+		})
+		.map(|(_, right)| format!("did you mean `{}`?", right))
+		.or_else(|| did_you_mean(token, &TRACEQL_KEYWORDS))
+}
 
 pub fn parse_traceql(input: &str) -> Result<Expression, TraceQLError> {
 	all_consuming(expression)(input)
 		.map(|(_, v)| v)
-		.map_err(|e| e.to_owned())
+		.map_err(|e| ParseError::new(input, e).with_hint(hint))
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum Expression {
 	SpanSet(SpanSet),
 	Logical(Box<Expression>, LogicalOperator, Box<Expression>),
+	Structural(Box<Expression>, StructuralOperator, Box<Expression>),
 }
 
 #[cfg(test)]
@@ -631,6 +624,12 @@ mod tests {
 		);
 	}
 
+	#[test]
+	fn test_bool_value() {
+		assert_eq!(field_value(" true"), Ok(("", FieldValue::Bool(true))));
+		assert_eq!(field_value("false "), Ok(("", FieldValue::Bool(false))));
+	}
+
 	#[test]
 	fn test_human_duration() {
 		use std::str::FromStr;
@@ -643,6 +642,28 @@ mod tests {
 		}
 	}
 
+	#[test]
+	fn test_decimal_duration() {
+		let cases = [
+			("1.5s", Duration::from_millis(1500)),
+			("0.25ms", Duration::from_micros(250)),
+			("250us", Duration::from_micros(250)),
+			("250µs", Duration::from_micros(250)),
+			(".5h", Duration::from_secs(1800)),
+			("1m0.5s", Duration::from_millis(60_500)),
+			("1.0000000015s", Duration::from_nanos(1_000_000_001)),
+		];
+		for (input, want) in cases {
+			assert_eq!(
+				field_value(input),
+				Ok(("", FieldValue::Duration(want))),
+				"{}",
+				input
+			);
+		}
+		assert!(decimal_duration("1.5y").is_err());
+	}
+
 	#[test]
 	fn traceql_with_human_time() {
 		use std::str::FromStr;
@@ -671,6 +692,53 @@ mod tests {
 		assert_eq!(actual, expected);
 	}
 
+	#[test]
+	fn test_attribute_names() {
+		let cases = [
+			(
+				r#"{span.http.request.header.x-request-id="1"}"#,
+				FieldType::Span(
+					"http.request.header.x-request-id".to_string(),
+					FieldValue::String("1".to_string()),
+				),
+			),
+			(
+				r#"{span."my attr" = "1"}"#,
+				FieldType::Span(
+					"my attr".to_string(),
+					FieldValue::String("1".to_string()),
+				),
+			),
+			(
+				r#"{resource."k8s \"pod\"" = "1"}"#,
+				FieldType::Resource(
+					r#"k8s "pod""#.to_string(),
+					FieldValue::String("1".to_string()),
+				),
+			),
+			(
+				r#"{."it's" = "1"}"#,
+				FieldType::Unscoped(
+					"it's".to_string(),
+					FieldValue::String("1".to_string()),
+				),
+			),
+		];
+		for (input, kv) in cases {
+			let (res, actual) = expression(input).unwrap();
+			assert_eq!(res, "", "{}", input);
+			assert_eq!(
+				actual,
+				Expression::SpanSet(SpanSet::Expr(FieldExpr {
+					kv,
+					operator: Equal,
+				})),
+				"{}",
+				input
+			);
+		}
+	}
+
 	#[test]
 	fn very_simple_traceql() {
 		let input = r#"{foo="bar"}"#;
@@ -888,4 +956,57 @@ mod tests {
 		));
 		assert_eq!(expect, expr);
 	}
+
+	#[test]
+	fn test_structural() {
+		let sp = |name: &str| {
+			Box::new(Expression::SpanSet(SpanSet::Expr(FieldExpr {
+				kv: FieldType::Intrinsic(IntrisincField::Name(
+					name.to_string(),
+				)),
+				operator: Equal,
+			})))
+		};
+		let expr =
+			parse_traceql(r#"{name="a"} > {name="b"} >> {name="c"}"#).unwrap();
+		let expected = Expression::Structural(
+			Box::new(Expression::Structural(
+				sp("a"),
+				StructuralOperator::Child,
+				sp("b"),
+			)),
+			StructuralOperator::Descendant,
+			sp("c"),
+		);
+		assert_eq!(expr, expected);
+		// binds tighter than &&, while > inside braces is still a comparison
+		let expr =
+			parse_traceql(r#"{name="a"} >> {duration > 1s} && {name="c"}"#)
+				.unwrap();
+		let Expression::Logical(l, And, r) = expr else {
+			panic!("expect logical expression");
+		};
+		assert_eq!(r, sp("c"));
+		assert!(matches!(
+			*l,
+			Expression::Structural(_, StructuralOperator::Descendant, _)
+		));
+	}
+
+	#[test]
+	fn test_parse_error_hint() {
+		let err = |q| parse_traceql(q).unwrap_err().to_string();
+		assert_eq!(
+			err(r#"{status = err}"#),
+			"unexpected `err}` at line 1, column 11, did you mean `error`?"
+		);
+		assert_eq!(
+			err(r#"{name="a"} and {name="b"}"#),
+			"unexpected `and` at line 1, column 12, did you mean `&&`?"
+		);
+		assert_eq!(
+			err(r#"{name=="a"}"#),
+			r#"unexpected `="a"}` at line 1, column 7, equality is written with a single `=`"#
+		);
+	}
 }